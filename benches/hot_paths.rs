@@ -0,0 +1,393 @@
+//! Benchmarks for the codec, the map and the message paths every request goes through.
+//!
+//! Run with `just bench`, or `cargo bench --features bench-internals --bench hot_paths`. Pass
+//! `--save-baseline <name>` before a change and `--baseline <name>` after it to compare.
+//!
+//! Baseline medians on a single-core Xeon VM, for optimizations to cite:
+//!
+//! ```text
+//! json/echo                encode 99ns      decode 255ns    envelope 538ns / 3.0us
+//! json/gossip_1k           encode 11.9us    decode 68us     envelope 26us / 119us
+//! json/read_ok_100k        encode 1.59ms    decode 10.8ms   envelope 2.95ms / 15.4ms
+//! async_dashmap (10k ops)  insert 762us / 1.08ms / 1.03ms   get 417us / 379us / 457us
+//!                          (1 / 4 / 16 tasks)
+//! echo_round_trip          spawn 13.3us     pool 15.8us
+//! echo_pipelined (1k)      spawn 4.44ms     pool 5.12ms
+//! ```
+//!
+//! With a single core, the pool has one worker, and handing messages to it costs more than
+//! spawning a task per message.
+//!
+//! The `spawn` and `pool` rows hand every echo to the executor. The `inline` rows answer echoes
+//! from the read loop instead, which ran about 10% faster than `spawn` one at a time, and faster
+//! than both when pipelined, when measured on the same VM in the same run.
+//!
+//! `echo_reply_100k` turns a 100KB echo into its reply. Sharing the payload took 23ns where
+//! copying it took 766us, in the same run.
+//!
+//! `replies_100k` frames 100k small replies to ten clients. With the envelopes cached it took
+//! 19.5ms where the codec took 24.5ms, in the same run.
+
+use std::{collections::HashSet, sync::Arc, time::Instant};
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use fly_systems_challenge::{
+    bench::{AsyncDashMap, Envelopes, SymmetricalJson},
+    message::{DataOrInit, Message, MessageBody},
+    node::{Execution, NodeOptions, NodeState},
+    services::{broadcast::BroadcastMessage, echo::EchoService, echo::EchoServiceMessage},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn envelope<Data>(data: Data) -> Message<DataOrInit<Data>> {
+    Message {
+        src: Arc::from("n1"),
+        dest: Arc::from("n2"),
+        body: MessageBody {
+            id: Some(42),
+            re: None,
+            trace_id: None,
+            seq: None,
+            sent_at_ms: None,
+            request_times: None,
+            data: DataOrInit::Data(data),
+        },
+    }
+}
+
+fn echo() -> EchoServiceMessage {
+    EchoServiceMessage::Echo {
+        echo: serde_json::json!("Please echo 35").into(),
+    }
+}
+
+/// An echo of about 100KB of nested JSON.
+fn echo_100k() -> EchoServiceMessage {
+    let items = (0..1_000)
+        .map(|id| serde_json::json!({ "id": id, "tags": ["a", "b"], "text": "x".repeat(64) }))
+        .collect::<Vec<_>>();
+    EchoServiceMessage::Echo {
+        echo: serde_json::json!({ "items": items }).into(),
+    }
+}
+
+fn gossip(values: u64) -> BroadcastMessage {
+    BroadcastMessage::Gossip {
+        seen: (0..values).collect(),
+    }
+}
+
+fn read_ok(values: u64) -> BroadcastMessage {
+    BroadcastMessage::ReadOk {
+        messages: (0..values).collect::<HashSet<_>>(),
+    }
+}
+
+/// Encoding and decoding a message body on its own, and with its envelope through the codec.
+fn bench_json<Data>(c: &mut Criterion, name: &str, data: impl Fn() -> Data)
+where
+    Data: Serialize + DeserializeOwned,
+{
+    let mut group = c.benchmark_group(format!("json/{name}"));
+    let encoded = serde_json::to_vec(&data()).unwrap();
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+    group.bench_function("encode", |b| {
+        let data = data();
+        b.iter(|| serde_json::to_vec(&data).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| serde_json::from_slice::<Data>(&encoded).unwrap())
+    });
+
+    group.bench_function("envelope_encode", |b| {
+        let mut codec = SymmetricalJson::<Message<DataOrInit<Data>>>::default();
+        b.iter_batched(
+            || envelope(data()),
+            |message| {
+                let mut frame = BytesMut::new();
+                codec.encode(message, &mut frame).unwrap();
+                frame
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("envelope_decode", |b| {
+        let mut codec = SymmetricalJson::<Message<DataOrInit<Data>>>::default();
+        let mut frame = BytesMut::new();
+        codec.encode(envelope(data()), &mut frame).unwrap();
+        b.iter_batched(
+            || frame.clone(),
+            |mut frame| codec.decode(&mut frame).unwrap().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn codec(c: &mut Criterion) {
+    bench_json(c, "echo", echo);
+    bench_json(c, "echo_100k", echo_100k);
+    bench_json(c, "gossip_1k", || gossip(1_000));
+    bench_json(c, "read_ok_100k", || read_ok(100_000));
+}
+
+/// Framing 100k small replies to ten clients, serializing every envelope through the codec against
+/// copying the cached `src` and `dest`, as the writer does.
+fn replies(c: &mut Criterion) {
+    const REPLIES: u64 = 100_000;
+
+    let clients = (0..10)
+        .map(|n| Arc::from(format!("c{n}")))
+        .collect::<Vec<Arc<str>>>();
+    let replies = || {
+        (0..REPLIES)
+            .map(|n| {
+                let mut reply = envelope(EchoServiceMessage::EchoOk {
+                    echo: serde_json::json!("Please echo 35").into(),
+                });
+                reply.dest = Arc::clone(&clients[n as usize % clients.len()]);
+                reply.body.re = Some(n);
+                reply
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut group = c.benchmark_group("replies_100k");
+    group.throughput(Throughput::Elements(REPLIES));
+    group.bench_function("codec", |b| {
+        let mut codec = SymmetricalJson::default();
+        b.iter_batched(
+            replies,
+            |replies| {
+                let mut frames = BytesMut::new();
+                for reply in replies {
+                    codec.encode(reply, &mut frames).unwrap();
+                }
+                frames
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("envelopes", |b| {
+        let mut envelopes = Envelopes::default();
+        b.iter_batched(
+            replies,
+            |replies| {
+                let mut frames = BytesMut::new();
+                for reply in replies {
+                    envelopes.encode(&reply, &mut frames).unwrap();
+                }
+                frames
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Turning a large echo into its reply, as the echo service does, against copying the payload.
+fn echo_reply(c: &mut Criterion) {
+    let mut group = c.benchmark_group("echo_reply_100k");
+    let EchoServiceMessage::Echo { echo } = echo_100k() else {
+        unreachable!()
+    };
+    group.bench_function("shared", |b| {
+        b.iter(|| EchoServiceMessage::EchoOk { echo: echo.clone() })
+    });
+    let value = serde_json::Value::clone(&echo);
+    group.bench_function("copied", |b| b.iter(|| value.clone()));
+    group.finish();
+}
+
+/// Inserts and gets of distinct keys, split across `tasks` tasks.
+fn async_dashmap(c: &mut Criterion) {
+    const OPS: u64 = 10_000;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("async_dashmap");
+    group.throughput(Throughput::Elements(OPS));
+
+    for tasks in [1, 4, 16] {
+        group.bench_with_input(BenchmarkId::new("insert", tasks), &tasks, |b, &tasks| {
+            b.to_async(&runtime).iter(|| async move {
+                let map = Arc::new(AsyncDashMap::new());
+                let handles = (0..tasks)
+                    .map(|task| {
+                        let map = Arc::clone(&map);
+                        tokio::spawn(async move {
+                            for key in (task..OPS).step_by(tasks as usize) {
+                                map.insert(key, key).await;
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.await.unwrap();
+                }
+            })
+        });
+
+        let map = Arc::new(AsyncDashMap::new());
+        runtime.block_on(async {
+            for key in 0..OPS {
+                map.insert(key, key).await;
+            }
+        });
+        group.bench_with_input(BenchmarkId::new("get", tasks), &tasks, |b, &tasks| {
+            b.to_async(&runtime).iter(|| {
+                let map = Arc::clone(&map);
+                async move {
+                    let handles = (0..tasks)
+                        .map(|task| {
+                            let map = Arc::clone(&map);
+                            tokio::spawn(async move {
+                                for key in (task..OPS).step_by(tasks as usize) {
+                                    assert!(map.get(&key).await.is_some());
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.await.unwrap();
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+type Requests = tokio::io::DuplexStream;
+type Replies = tokio::io::Lines<tokio::io::BufReader<tokio::io::DuplexStream>>;
+
+/// Start an in-process echo node, and initialize it.
+async fn echo_node(options: NodeOptions) -> (Requests, Replies) {
+    let (mut requests, node_stdin) = tokio::io::duplex(64 * 1024);
+    let (node_stdout, replies) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(NodeState::run_with_io(
+        EchoService,
+        options,
+        node_stdin,
+        node_stdout,
+    ));
+
+    let mut replies = tokio::io::BufReader::new(replies).lines();
+    requests
+        .write_all(
+            br#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":0,"node_id":"n0","node_ids":["n0"]}}
+"#,
+        )
+        .await
+        .unwrap();
+    replies.next_line().await.unwrap().unwrap();
+    (requests, replies)
+}
+
+fn echo_request(id: u64) -> String {
+    format!(
+        r#"{{"src":"c0","dest":"n0","body":{{"type":"echo","msg_id":{id},"echo":"Please echo {id}"}}}}
+"#
+    )
+}
+
+/// A client's echo requests to an in-process node, through its input and output pipes: one at a
+/// time, and many in flight at once, with handlers on a task each and on a worker pool, and with
+/// echoes answered inline.
+fn echo_node_round_trip(c: &mut Criterion) {
+    const PIPELINED: u64 = 1_000;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let handled = |execution| NodeOptions {
+        execution: Some(execution),
+        disable_inline: true,
+        ..Default::default()
+    };
+    let nodes_options = || {
+        [
+            ("spawn", handled(Execution::Spawn)),
+            ("pool", handled(Execution::pool())),
+            ("inline", NodeOptions::default()),
+        ]
+    };
+    // The runner spins on EOF, so nodes are kept open until the end rather than left to skew the
+    // benchmarks that run after theirs.
+    let mut nodes = Vec::new();
+
+    let mut group = c.benchmark_group("echo_round_trip");
+    for (name, options) in nodes_options() {
+        let (mut requests, mut replies) = runtime.block_on(echo_node(options));
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    for id in 1..=iters {
+                        requests
+                            .write_all(echo_request(id).as_bytes())
+                            .await
+                            .unwrap();
+                        replies.next_line().await.unwrap().unwrap();
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+        nodes.push(requests);
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("echo_pipelined");
+    group.throughput(Throughput::Elements(PIPELINED));
+    for (name, options) in nodes_options() {
+        let (requests, mut replies) = runtime.block_on(echo_node(options));
+        let requests = Arc::new(tokio::sync::Mutex::new(requests));
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        // Written on another task, so that the node's replies are read while
+                        // requests are still being written.
+                        let writer = tokio::spawn({
+                            let requests = Arc::clone(&requests);
+                            async move {
+                                let mut requests = requests.lock().await;
+                                for id in 1..=PIPELINED {
+                                    requests
+                                        .write_all(echo_request(id).as_bytes())
+                                        .await
+                                        .unwrap();
+                                }
+                            }
+                        });
+                        for _ in 0..PIPELINED {
+                            replies.next_line().await.unwrap().unwrap();
+                        }
+                        writer.await.unwrap();
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+        nodes.push(Arc::try_unwrap(requests).unwrap().into_inner());
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    codec,
+    replies,
+    echo_reply,
+    async_dashmap,
+    echo_node_round_trip
+);
+criterion_main!(benches);
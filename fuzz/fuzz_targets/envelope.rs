@@ -0,0 +1,42 @@
+//! Feeds arbitrary bytes to the envelope deserializer and to the stdin decoder, which must reject
+//! anything they can't read rather than panic.
+//!
+//! Run with `cargo +nightly fuzz run envelope -- -max_len=65536`. The seeds in
+//! `corpus/envelope/` cover the inputs most likely to go wrong: bodies that only the untagged
+//! `Data` variant can match, deep nesting, huge numbers and invalid UTF-8.
+
+#![no_main]
+
+use bytes::BytesMut;
+use fly_systems_challenge::fuzz::SymmetricalJson;
+use fly_systems_challenge::message::{DataOrInit, Message};
+use fly_systems_challenge::services::broadcast::BroadcastMessage;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder as _;
+
+type Envelope = Message<DataOrInit<BroadcastMessage>>;
+
+/// Longer inputs only make the fuzzer slower: frames are parsed a line at a time, and nothing
+/// the parser does grows with more than the line in front of it.
+const MAX_INPUT: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() > MAX_INPUT {
+        return;
+    }
+
+    // Anything that parses must serialize again, since the runner may echo it back.
+    if let Ok(message) = serde_json::from_slice::<Envelope>(data) {
+        serde_json::to_vec(&message).unwrap();
+        // The runner unwraps compressed bodies before dispatch.
+        if let DataOrInit::GossipZ(envelope) = &message.body.data {
+            let _ = envelope.open::<DataOrInit<BroadcastMessage>>();
+        }
+    }
+
+    let mut codec = SymmetricalJson::<Envelope>::default();
+    let mut buffer = BytesMut::from(data);
+    // Each call consumes at least one line, so this ends once the input is used up or fails.
+    while let Ok(Some(_)) = codec.decode(&mut buffer) {}
+    let _ = codec.decode_eof(&mut buffer);
+});
@@ -1,4 +1,15 @@
-use std::{future::Future, task::Poll};
+use std::{
+    future::Future,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Ask to be polled again. Dashmap has no way to notify us when a shard is unlocked, so a future
+/// that finds its shard locked has to wake itself, or it would never be polled again.
+fn retry<T>(cx: &mut Context<'_>) -> Poll<T> {
+    cx.waker().wake_by_ref();
+    Poll::Pending
+}
 
 pub trait DashMapAsync<'a, K, V>
 where
@@ -28,35 +39,35 @@ where
     K: std::hash::Hash + Eq + Clone + 'a,
     V: 'a,
 {
-    async fn entry_async(&'a self, key: K) -> dashmap::mapref::entry::Entry<'_, K, V> {
-        std::future::poll_fn(move |_| match self.try_entry(key.clone()) {
+    async fn entry_async(&'a self, key: K) -> dashmap::mapref::entry::Entry<'a, K, V> {
+        std::future::poll_fn(move |cx| match self.try_entry(key.clone()) {
             Some(entry) => Poll::Ready(entry),
-            None => Poll::Pending,
+            None => retry(cx),
         })
         .await
     }
 
-    async fn get_async(&'a self, key: &K) -> Option<dashmap::mapref::one::Ref<'_, K, V>> {
-        std::future::poll_fn(move |_| match self.try_get(key) {
+    async fn get_async(&'a self, key: &K) -> Option<dashmap::mapref::one::Ref<'a, K, V>> {
+        std::future::poll_fn(move |cx| match self.try_get(key) {
             dashmap::try_result::TryResult::Present(value) => Poll::Ready(Some(value)),
             dashmap::try_result::TryResult::Absent => Poll::Ready(None),
-            dashmap::try_result::TryResult::Locked => Poll::Pending,
+            dashmap::try_result::TryResult::Locked => retry(cx),
         })
         .await
     }
 
-    async fn get_mut_async(&'a self, key: &K) -> Option<dashmap::mapref::one::RefMut<'_, K, V>> {
-        std::future::poll_fn(move |_| match self.try_get_mut(key) {
+    async fn get_mut_async(&'a self, key: &K) -> Option<dashmap::mapref::one::RefMut<'a, K, V>> {
+        std::future::poll_fn(move |cx| match self.try_get_mut(key) {
             dashmap::try_result::TryResult::Present(value) => Poll::Ready(Some(value)),
             dashmap::try_result::TryResult::Absent => Poll::Ready(None),
-            dashmap::try_result::TryResult::Locked => Poll::Pending,
+            dashmap::try_result::TryResult::Locked => retry(cx),
         })
         .await
     }
 
     async fn insert_async(&'a self, key: K, value: V) -> Option<V> {
         let mut value = Some(value);
-        std::future::poll_fn(|_| match self.try_entry(key.clone()) {
+        std::future::poll_fn(|cx| match self.try_entry(key.clone()) {
             Some(dashmap::Entry::Vacant(entry)) => {
                 let Some(val) = std::mem::take(&mut value) else {
                     return Poll::Ready(None);
@@ -71,7 +82,7 @@ where
                 let (_, old) = entry.replace_entry(val);
                 Poll::Ready(Some(old))
             }
-            None => Poll::Pending,
+            None => retry(cx),
         })
         .await
     }
@@ -101,12 +112,21 @@ where
         }
     }
 
-    pub fn iter(&self) -> dashmap::iter::Iter<'_, K, V> {
-        self.inner.iter()
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn remove(&self, key: &K) -> Option<(K, V)> {
+        self.inner.remove(key)
+    }
+
+    pub fn remove_if(&self, key: &K, f: impl FnOnce(&K, &V) -> bool) -> Option<(K, V)> {
+        self.inner.remove_if(key, f)
     }
 
-    pub fn into_iter(self) -> dashmap::iter::OwningIter<K, V> {
-        self.inner.into_iter()
+    pub fn retain(&self, f: impl FnMut(&K, &mut V) -> bool) {
+        self.inner.retain(f)
     }
 
     pub async fn entry(&self, key: K) -> dashmap::mapref::entry::Entry<'_, K, V> {
@@ -124,6 +144,90 @@ where
     pub async fn insert(&self, key: K, value: V) -> Option<V> {
         DashMapAsync::insert_async(&self.inner, key, value).await
     }
+
+    /// Insert `value` only if `key` is absent, returning whether it was inserted. The check and
+    /// the insert happen atomically, so exactly one of several concurrent callers inserting the
+    /// same key sees `true`.
+    pub async fn insert_if_absent(&self, key: K, value: V) -> bool {
+        match self.entry(key).await {
+            dashmap::Entry::Occupied(_) => false,
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(value);
+                true
+            }
+        }
+    }
+}
+
+/// A snapshot of a value in an [`AsyncKeyedState`]. It holds no lock, so it is safe to keep across
+/// awaits; updates made after it was taken are not reflected in it.
+#[derive(Debug, Clone)]
+pub struct OwnedRef<V>(Arc<V>);
+
+impl<V> std::ops::Deref for OwnedRef<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.0
+    }
+}
+
+/// Per-key state whose locks can't be held across awaits.
+///
+/// Reads hand out [`OwnedRef`] snapshots instead of guards, and writes go through
+/// [`AsyncKeyedState::update`], whose closure runs with the key locked and can't await. Values are
+/// copied on write only while a snapshot of them is alive.
+pub struct AsyncKeyedState<K: PartialEq + Eq + std::hash::Hash + Clone, V> {
+    inner: AsyncDashMap<K, Arc<V>>,
+}
+
+impl<K, V> Default for AsyncKeyedState<K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> AsyncKeyedState<K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            inner: AsyncDashMap::new(),
+        }
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.inner.remove(key);
+    }
+
+    pub fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        self.inner.retain(|key, value| f(key, value))
+    }
+
+    pub async fn insert(&self, key: K, value: V) {
+        self.inner.insert(key, Arc::new(value)).await;
+    }
+
+    pub async fn get_owned(&self, key: &K) -> Option<OwnedRef<V>> {
+        let value = self.inner.get(key).await?;
+        Some(OwnedRef(Arc::clone(&value)))
+    }
+}
+
+impl<K, V> AsyncKeyedState<K, V>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Apply `f` to the value at `key`, returning its result, or `None` if there is no such key.
+    pub async fn update<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        let mut value = self.inner.get_mut(key).await?;
+        Some(f(Arc::make_mut(&mut value)))
+    }
 }
 
 #[cfg(test)]
@@ -177,4 +281,102 @@ mod tests {
         let result = map.get(&1).await;
         assert_eq!(*result.unwrap(), 200, "Expected value to be updated to 200");
     }
+
+    #[tokio::test]
+    async fn test_asyncdashmap_insert_if_absent() {
+        let map = AsyncDashMap::new();
+        assert!(map.insert_if_absent(1, 100).await);
+        assert!(!map.insert_if_absent(1, 200).await);
+        assert_eq!(
+            *map.get(&1).await.unwrap(),
+            100,
+            "Expected value to be kept"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_asyncdashmap_insert_if_absent_concurrent() {
+        let map = std::sync::Arc::new(AsyncDashMap::new());
+        for key in 0..100 {
+            let tasks = (0..8)
+                .map(|task| {
+                    let map = map.clone();
+                    tokio::spawn(async move { map.insert_if_absent(key, task).await })
+                })
+                .collect::<Vec<_>>();
+
+            let mut inserted = 0;
+            for task in tasks {
+                inserted += task.await.unwrap() as usize;
+            }
+            assert_eq!(inserted, 1, "Expected exactly one insert for key {key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keyed_state_snapshot_held_across_await() {
+        let state = Arc::new(AsyncKeyedState::new());
+        state.insert("peer", vec![1]).await;
+
+        // Holding a guard here while another task writes the same key would never complete.
+        let snapshot = state.get_owned(&"peer").await.unwrap();
+        let writer = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { state.update(&"peer", |values| values.push(2)).await }
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(1), writer)
+            .await
+            .expect("Expected the update to complete while a snapshot is held")
+            .unwrap();
+
+        assert_eq!(*snapshot, vec![1], "Expected the snapshot to be unchanged");
+        assert_eq!(*state.get_owned(&"peer").await.unwrap(), vec![1, 2]);
+        assert_eq!(state.update(&"missing", |values| values.len()).await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_keyed_state_concurrent_updates() {
+        let state = Arc::new(AsyncKeyedState::new());
+        for key in 0..4 {
+            state.insert(key, 0u64).await;
+        }
+
+        let tasks = (0..16)
+            .map(|task| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    for i in 0..1000 {
+                        let key = (task + i) % 4;
+                        let snapshot = state.get_owned(&key).await.unwrap();
+                        tokio::task::yield_now().await;
+                        state.update(&key, |count| *count += 1).await.unwrap();
+                        assert!(*state.get_owned(&key).await.unwrap() > *snapshot);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut total = 0;
+        for key in 0..4 {
+            total += *state.get_owned(&key).await.unwrap();
+        }
+        assert_eq!(total, 16 * 1000, "Expected no lost updates");
+    }
+
+    #[tokio::test]
+    async fn test_keyed_state_remove_and_retain() {
+        let state = AsyncKeyedState::new();
+        for key in 0..4 {
+            state.insert(key, key * 10).await;
+        }
+
+        state.retain(|key, _| *key < 2);
+        state.remove(&0);
+        assert!(state.get_owned(&0).await.is_none());
+        assert!(state.get_owned(&2).await.is_none());
+        assert_eq!(*state.get_owned(&1).await.unwrap(), 10);
+    }
 }
@@ -0,0 +1,137 @@
+//! Shutdown audit: a last check that every node ended up holding the same state.
+//!
+//! Maelstrom's checker only looks at what clients were told, so nodes that diverged on values
+//! nobody read again go unnoticed. Whatever stops the nodes can send each one a `drain` first.
+//! With [`crate::node::NodeOptions::audit_timeout_ms`] set, the node then sends its
+//! [`StateDigest`] to every peer in an `audit`, and each peer answers with its own in an
+//! `audit_ok`. Peers that hold something else are logged as errors. The [`AuditReport`] goes back
+//! in the `drain_ok`, and into the summary logged when the node stops.
+//!
+//! Only services that have a digest take part, see [`crate::node::Node::state_digest`].
+//! Maelstrom never sends `drain`; harnesses do, like `Cluster::drain` in `testing`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+/// What a node holds, as compared by the audit. Equal digests mean equal state, as for
+/// anti-entropy (see [`crate::services::gossip::CvState::digest`]); the count makes a mismatch
+/// easier to read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDigest {
+    pub digest: u64,
+    pub count: u64,
+}
+
+impl std::fmt::Display for StateDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} values (digest {:016x})", self.count, self.digest)
+    }
+}
+
+/// The outcome of an audit, see the [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditReport {
+    /// What this node held when the audit started.
+    pub local: StateDigest,
+    /// Peers that hold the same.
+    pub matched: BTreeSet<String>,
+    /// Peers that hold something else, with what they hold.
+    pub mismatched: BTreeMap<String, StateDigest>,
+    /// Peers that didn't answer in time.
+    pub unanswered: BTreeSet<String>,
+    /// How many messages from each peer never arrived, as far as this node can tell, if peers
+    /// number their messages (see [`crate::node::NodeOptions::sequence_peer_messages`]). Only
+    /// peers that lost any. Not part of consistency: the state may well have caught up since.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub lost: BTreeMap<String, u64>,
+}
+
+impl AuditReport {
+    /// An audit of `peers` that none has answered yet.
+    pub fn new(local: StateDigest, peers: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            local,
+            unanswered: peers.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Record that `peer` holds `theirs`. Returns whether it matches, or `None` if `peer` was
+    /// not waited on, say because it already answered.
+    pub fn record(&mut self, peer: &str, theirs: StateDigest) -> Option<bool> {
+        if !self.unanswered.remove(peer) {
+            return None;
+        }
+        let matches = theirs == self.local;
+        if matches {
+            self.matched.insert(peer.to_owned());
+        } else {
+            self.mismatched.insert(peer.to_owned(), theirs);
+        }
+        Some(matches)
+    }
+
+    /// Whether every peer has answered.
+    pub fn is_complete(&self) -> bool {
+        self.unanswered.is_empty()
+    }
+
+    /// Whether every peer answered, and holds what we do.
+    pub fn is_consistent(&self) -> bool {
+        self.is_complete() && self.mismatched.is_empty()
+    }
+}
+
+impl std::fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} peers hold {}", self.matched.len(), self.local)?;
+        for (peer, theirs) in &self.mismatched {
+            write!(f, ", {peer} holds {theirs}")?;
+        }
+        if !self.unanswered.is_empty() {
+            let unanswered = self.unanswered.iter().cloned().collect::<Vec<_>>();
+            write!(f, ", no answer from {}", unanswered.join(", "))?;
+        }
+        for (peer, lost) in &self.lost {
+            write!(f, ", {lost} messages from {peer} lost")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let local = StateDigest {
+            digest: 0xabc,
+            count: 3,
+        };
+        let peers = ["n1", "n2", "n3"].map(String::from);
+        let mut report = AuditReport::new(local, peers);
+        assert!(!report.is_complete());
+
+        assert_eq!(report.record("n1", local), Some(true));
+        let theirs = StateDigest {
+            digest: 0xdef,
+            count: 2,
+        };
+        assert_eq!(report.record("n2", theirs), Some(false));
+        // Duplicated answers and strangers are ignored.
+        assert_eq!(report.record("n2", local), None);
+        assert_eq!(report.record("n9", local), None);
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report.to_string(),
+            "1 peers hold 3 values (digest 0000000000000abc), \
+             n2 holds 2 values (digest 0000000000000def), no answer from n3"
+        );
+
+        assert_eq!(report.record("n3", local), Some(true));
+        assert!(report.is_complete());
+        assert!(!report.is_consistent());
+    }
+}
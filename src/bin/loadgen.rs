@@ -0,0 +1,102 @@
+//! Drive a single node binary with a workload and print a JSON summary.
+//!
+//! ```text
+//! loadgen [--workload echo|broadcast|unique-ids] [--rate N] [--duration SECS] [--timeout SECS] \
+//!     -- <node binary> [args...]
+//! ```
+
+use std::{process::Stdio, time::Duration};
+
+use fly_systems_challenge::loadgen::{self, LoadgenOptions};
+use snafu::{OptionExt as _, ResultExt as _, Whatever};
+
+fn parse_args() -> Result<(LoadgenOptions, Vec<String>), Whatever> {
+    let mut options = LoadgenOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            let command = args.collect::<Vec<_>>();
+            if command.is_empty() {
+                snafu::whatever!("Missing node binary after --");
+            }
+            return Ok((options, command));
+        }
+
+        let value = args
+            .next()
+            .with_whatever_context(|| format!("Missing value for {arg}"))?;
+        let seconds = |value: &str| {
+            value
+                .parse()
+                .map(Duration::from_secs_f64)
+                .with_whatever_context(|_| format!("Invalid value for {arg}: {value}"))
+        };
+        match arg.as_str() {
+            "--workload" => {
+                options.workload = value
+                    .parse()
+                    .with_whatever_context(|_| format!("Invalid workload {value}"))?
+            }
+            "--rate" => {
+                options.rate = value
+                    .parse()
+                    .with_whatever_context(|_| format!("Invalid rate {value}"))?
+            }
+            "--duration" => options.duration = seconds(&value)?,
+            "--timeout" => options.timeout = seconds(&value)?,
+            _ => snafu::whatever!("Unknown argument {arg}"),
+        }
+    }
+    snafu::whatever!("Usage: loadgen [options] -- <node binary> [args...]")
+}
+
+async fn run() -> Result<bool, Whatever> {
+    let (options, command) = parse_args()?;
+
+    let mut child = tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_whatever_context(|_| format!("Failed to start {}", command[0]))?;
+    let stdin = child.stdin.take().whatever_context("No stdin")?;
+    let stdout = child.stdout.take().whatever_context("No stdout")?;
+
+    let mut summary = loadgen::run(&options, stdout, stdin)
+        .await
+        .whatever_context("Load generation failed")?;
+
+    // A node that is still running is killed; one that already exited is reported if it failed.
+    match child.try_wait() {
+        Ok(Some(status)) if !status.success() => {
+            summary.node_exit_code = Some(status.code().unwrap_or(-1));
+        }
+        Ok(Some(_)) => {}
+        _ => {
+            child.kill().await.ok();
+        }
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&summary).whatever_context("Failed to encode summary")?
+    );
+    Ok(summary.is_clean())
+}
+
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    match run().await {
+        Ok(true) => std::process::ExitCode::SUCCESS,
+        Ok(false) => std::process::ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", snafu::Report::from_error(e));
+            std::process::ExitCode::from(2)
+        }
+    }
+}
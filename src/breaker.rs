@@ -0,0 +1,201 @@
+//! Per-peer circuit breakers, so that a dead peer isn't sent everything a live one is.
+//!
+//! A circuit starts closed. After [`BreakerOptions::failure_threshold`] failures in a row without a
+//! success in between, it opens, and sends to the peer are skipped. Once
+//! [`BreakerOptions::cooldown`] has passed, a single probe is let through; any success closes the
+//! circuit again, and without one, another probe goes out a cooldown later.
+//!
+//! What counts as a send, a failure or a success is up to the caller: the breakers only keep
+//! count. Like [`crate::flush`], everything takes the current time so it can be tested without a
+//! clock.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerOptions {
+    /// Consecutive failures after which a peer's circuit opens.
+    pub failure_threshold: u32,
+    /// How long an open circuit waits between probes.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerOptions {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Sends go through.
+    Closed,
+    /// Sends are skipped, apart from a probe every cooldown.
+    Open,
+}
+
+/// What to do about a send, see [`CircuitBreakers::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Send,
+    /// The circuit is open, but it is time to find out whether the peer is back.
+    Probe,
+    Skip,
+}
+
+#[derive(Debug)]
+enum Circuit {
+    Closed { failures: u32 },
+    Open { next_probe: Instant },
+}
+
+/// Circuit breakers keyed by destination, see the [module docs](self).
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    options: BreakerOptions,
+    circuits: std::sync::Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(options: BreakerOptions) -> Self {
+        Self {
+            options,
+            circuits: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether to send to `peer` at `now`. A [`Admission::Probe`] is only handed out once per
+    /// cooldown.
+    pub fn check(&self, peer: &str, now: Instant) -> Admission {
+        let mut circuits = self.circuits.lock().unwrap();
+        match circuits.get_mut(peer) {
+            None | Some(Circuit::Closed { .. }) => Admission::Send,
+            Some(Circuit::Open { next_probe }) if now >= *next_probe => {
+                *next_probe = now + self.options.cooldown;
+                Admission::Probe
+            }
+            Some(Circuit::Open { .. }) => Admission::Skip,
+        }
+    }
+
+    /// Record that `peer` failed to answer, opening its circuit once
+    /// [`BreakerOptions::failure_threshold`] failures have piled up.
+    pub fn failure(&self, peer: &str, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(peer.to_owned())
+            .or_insert(Circuit::Closed { failures: 0 });
+        if let Circuit::Closed { failures } = circuit {
+            *failures += 1;
+            if *failures >= self.options.failure_threshold {
+                tracing::warn!(
+                    "Opening the circuit to {} after {} failures",
+                    peer,
+                    failures
+                );
+                *circuit = Circuit::Open {
+                    next_probe: now + self.options.cooldown,
+                };
+            }
+        }
+    }
+
+    /// Record that `peer` answered, closing its circuit.
+    pub fn success(&self, peer: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(Circuit::Open { .. }) =
+            circuits.insert(peer.to_owned(), Circuit::Closed { failures: 0 })
+        {
+            tracing::info!("Closing the circuit to {}", peer);
+        }
+    }
+
+    /// Forget `peer`'s circuit, say because it left the cluster.
+    pub fn forget(&self, peer: &str) {
+        self.circuits.lock().unwrap().remove(peer);
+    }
+
+    pub fn state(&self, peer: &str) -> CircuitState {
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map_or(CircuitState::Closed, CircuitState::from)
+    }
+
+    /// The state of every circuit that has seen a failure or a success.
+    pub fn states(&self) -> BTreeMap<String, CircuitState> {
+        let circuits = self.circuits.lock().unwrap();
+        circuits
+            .iter()
+            .map(|(peer, circuit)| (peer.clone(), circuit.into()))
+            .collect()
+    }
+}
+
+impl From<&Circuit> for CircuitState {
+    fn from(circuit: &Circuit) -> Self {
+        match circuit {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { .. } => CircuitState::Open,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let breakers = CircuitBreakers::new(BreakerOptions::default());
+
+        breakers.failure("n1", now);
+        breakers.failure("n1", now);
+        breakers.success("n1");
+        breakers.failure("n1", now);
+        breakers.failure("n1", now);
+        assert_eq!(breakers.check("n1", now), Admission::Send);
+
+        breakers.failure("n1", now);
+        assert_eq!(breakers.state("n1"), CircuitState::Open);
+        assert_eq!(breakers.check("n1", now), Admission::Skip);
+        assert_eq!(breakers.check("n2", now), Admission::Send);
+    }
+
+    #[test]
+    fn test_one_probe_per_cooldown() {
+        let mut now = Instant::now();
+        let options = BreakerOptions::default();
+        let breakers = CircuitBreakers::new(options.clone());
+        for _ in 0..options.failure_threshold {
+            breakers.failure("n1", now);
+        }
+
+        let mut probes = 0;
+        for _ in 0..100 {
+            now += options.cooldown / 10;
+            match breakers.check("n1", now) {
+                Admission::Probe => probes += 1,
+                Admission::Skip => {}
+                Admission::Send => panic!("sent through an open circuit"),
+            }
+        }
+        assert_eq!(probes, 10);
+
+        breakers.success("n1");
+        assert_eq!(breakers.check("n1", now), Admission::Send);
+        assert_eq!(
+            breakers.states(),
+            BTreeMap::from([("n1".to_owned(), CircuitState::Closed)])
+        );
+    }
+}
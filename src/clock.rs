@@ -0,0 +1,240 @@
+//! Wall clocks, and how far each peer's is from ours.
+//!
+//! Maelstrom runs every node in a process of its own, and nothing promises that their clocks
+//! agree. Anything that compares timestamps taken on different nodes, like last-writer-wins or
+//! latencies measured end to end, is off by however far apart the clocks are.
+//!
+//! With [`crate::node::NodeOptions::estimate_clock_offsets`] on, messages to peers that can read
+//! them carry the time they were sent, `sent_at_ms`, and replies echo back when the request was
+//! sent and received, `request_times_ms`. Each reply gives the four timestamps of an NTP exchange,
+//! from which [`ClockOffsets`] estimates the peer's offset. Exchanges delayed on the way give the
+//! worst estimates, so like NTP's clock filter, the estimate is the offset of the exchange with the
+//! shortest round trip among the last few. See [`crate::node::NodeState::estimated_offset_ms`].
+//!
+//! Tests skew a node's clock with [`SkewedClock`], see [`crate::node::NodeOptions::clock`].
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::message::MessageId;
+
+/// How many exchanges with each peer the estimate is picked from.
+pub const SAMPLES: usize = 8;
+
+/// How many requests from each peer are remembered until they are answered, to echo their
+/// timestamps in the reply. Requests that are never answered, like gossip, are forgotten oldest
+/// first.
+const PENDING_ECHOES: usize = 16;
+
+/// A wall clock.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+/// The system clock, used unless [`crate::node::NodeOptions::clock`] says otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+}
+
+/// The system clock, `skew_ms` ahead of it, or behind if negative. For tests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SkewedClock {
+    pub skew_ms: i64,
+}
+
+impl Clock for SkewedClock {
+    fn now_ms(&self) -> u64 {
+        SystemClock.now_ms().saturating_add_signed(self.skew_ms)
+    }
+}
+
+/// One request and its reply, timed by both clocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exchange {
+    /// When we sent the request, by our clock.
+    pub sent: u64,
+    /// When the peer received it, by its clock.
+    pub received: u64,
+    /// When the peer sent the reply, by its clock.
+    pub replied: u64,
+    /// When we received the reply, by our clock.
+    pub answered: u64,
+}
+
+impl Exchange {
+    /// How far the peer's clock is ahead of ours, assuming the request and reply took as long as
+    /// each other on the way.
+    pub fn offset_ms(&self) -> i64 {
+        let there = self.received as i64 - self.sent as i64;
+        let back = self.replied as i64 - self.answered as i64;
+        (there + back) / 2
+    }
+
+    /// How long the request and reply spent on the way, not counting the time in between at the
+    /// peer. Negative only if a clock jumped.
+    pub fn round_trip_ms(&self) -> i64 {
+        (self.answered as i64 - self.sent as i64) - (self.replied as i64 - self.received as i64)
+    }
+}
+
+/// The clock offset of every peer we exchanged timestamps with, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct ClockOffsets {
+    /// When each peer's unanswered requests were sent and received.
+    pending: HashMap<String, VecDeque<(MessageId, u64, u64)>>,
+    exchanges: BTreeMap<String, VecDeque<Exchange>>,
+}
+
+impl ClockOffsets {
+    /// Take the timestamps of a message from `peer`, received at `now` by our clock: remember
+    /// when a request was sent, to echo in our reply, and time the exchange a reply completes.
+    pub fn received(
+        &mut self,
+        peer: &str,
+        id: Option<MessageId>,
+        sent_at: u64,
+        request_times: Option<(u64, u64)>,
+        now: u64,
+    ) {
+        if let Some(id) = id {
+            let pending = self.pending.entry(peer.to_owned()).or_default();
+            if pending.len() == PENDING_ECHOES {
+                pending.pop_front();
+            }
+            pending.push_back((id, sent_at, now));
+        }
+        if let Some((sent, received)) = request_times {
+            self.record(
+                peer,
+                Exchange {
+                    sent,
+                    received,
+                    replied: sent_at,
+                    answered: now,
+                },
+            );
+        }
+    }
+
+    /// When `peer`'s request `re` was sent and received, to echo in the reply to it.
+    pub fn echo(&mut self, peer: &str, re: MessageId) -> Option<(u64, u64)> {
+        let pending = self.pending.get_mut(peer)?;
+        let position = pending.iter().position(|(id, ..)| *id == re)?;
+        pending
+            .remove(position)
+            .map(|(_, sent, received)| (sent, received))
+    }
+
+    /// Keep `exchange` with `peer`, unless a clock jumped during it.
+    pub fn record(&mut self, peer: &str, exchange: Exchange) {
+        if exchange.round_trip_ms() < 0 {
+            return;
+        }
+        let exchanges = self.exchanges.entry(peer.to_owned()).or_default();
+        if exchanges.len() == SAMPLES {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    /// How far `peer`'s clock is ahead of ours, in milliseconds, by the quickest of the last
+    /// [`SAMPLES`] exchanges with it.
+    pub fn offset_ms(&self, peer: &str) -> Option<i64> {
+        self.exchanges
+            .get(peer)?
+            .iter()
+            .min_by_key(|exchange| exchange.round_trip_ms())
+            .map(Exchange::offset_ms)
+    }
+
+    /// The median of every peer's offset, or of the middle two. `None` until some peer has
+    /// answered.
+    pub fn median_ms(&self) -> Option<i64> {
+        let mut offsets = self
+            .exchanges
+            .keys()
+            .filter_map(|peer| self.offset_ms(peer))
+            .collect::<Vec<_>>();
+        offsets.sort_unstable();
+        let middle = offsets.len() / 2;
+        match offsets.len() {
+            0 => None,
+            len if len % 2 == 1 => Some(offsets[middle]),
+            _ => Some((offsets[middle - 1] + offsets[middle]) / 2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quickest_exchange_wins() {
+        let mut offsets = ClockOffsets::default();
+        // The peer is 100ms ahead. Requests take 10ms, replies 10ms, except for one reply held up
+        // for 200ms, which alone would put the peer 100ms behind the truth.
+        offsets.received("n1", Some(1), 1000, None, 1010);
+        assert_eq!(offsets.echo("n1", 1), Some((1000, 1010)));
+        assert_eq!(offsets.echo("n1", 1), None);
+
+        let exchange = |sent: u64, delay: u64| Exchange {
+            sent,
+            received: sent + 10 + 100,
+            replied: sent + 15 + 100,
+            answered: sent + 25 + delay,
+        };
+        offsets.record("n1", exchange(0, 200));
+        assert_eq!(offsets.offset_ms("n1"), Some(0));
+        offsets.record("n1", exchange(1000, 0));
+        offsets.record("n1", exchange(2000, 3));
+        assert_eq!(offsets.offset_ms("n1"), Some(100));
+
+        // A clock that jumped back mid-exchange says nothing.
+        offsets.record(
+            "n2",
+            Exchange {
+                sent: 100,
+                received: 50,
+                replied: 500,
+                answered: 110,
+            },
+        );
+        assert_eq!(offsets.offset_ms("n2"), None);
+    }
+
+    #[test]
+    fn test_median() {
+        let mut offsets = ClockOffsets::default();
+        assert_eq!(offsets.median_ms(), None);
+        for (peer, offset) in [("n1", -40), ("n2", 10), ("n3", 500)] {
+            offsets.record(
+                peer,
+                Exchange {
+                    sent: 1000,
+                    received: (1005 + offset) as u64,
+                    replied: (1005 + offset) as u64,
+                    answered: 1010,
+                },
+            );
+        }
+        assert_eq!(offsets.median_ms(), Some(10));
+    }
+
+    #[test]
+    fn test_unanswered_requests_are_forgotten() {
+        let mut offsets = ClockOffsets::default();
+        for id in 0..PENDING_ECHOES as u64 + 1 {
+            offsets.received("n1", Some(id), id, None, id);
+        }
+        assert_eq!(offsets.echo("n1", 0), None);
+        assert_eq!(offsets.echo("n1", 1), Some((1, 1)));
+    }
+}
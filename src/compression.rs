@@ -0,0 +1,93 @@
+//! Compressed message bodies, for peer messages too large to send as plain JSON.
+//!
+//! A compressed body is sent as `{"type":"gossip_z","data":"<base64(zstd(json))>"}` in place of
+//! the original body. The runner unwraps it before the service sees the message, and only sends
+//! it to peers that advertised [`Capabilities::compression`](crate::message::Capabilities).
+
+use std::io::{self, Read as _};
+
+use base64::Engine as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The zstd level bodies are compressed with. Catch-up deltas are large but sent rarely, so this
+/// favours speed over ratio.
+const LEVEL: i32 = 3;
+
+/// Decompressed bodies larger than this are rejected rather than read into memory.
+pub const MAX_DECOMPRESSED_LEN: u64 = 64 * 1024 * 1024;
+
+/// The payload of a `gossip_z` message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressedEnvelope {
+    /// The base64 of the zstd-compressed JSON body.
+    pub data: String,
+}
+
+impl CompressedEnvelope {
+    /// Compress an already serialized JSON body.
+    pub fn seal(json: &[u8]) -> io::Result<Self> {
+        let compressed = zstd::encode_all(json, LEVEL)?;
+        Ok(Self {
+            data: base64::engine::general_purpose::STANDARD.encode(compressed),
+        })
+    }
+
+    /// Decompress and deserialize the body.
+    pub fn open<T: DeserializeOwned>(&self) -> io::Result<T> {
+        let compressed = base64::engine::general_purpose::STANDARD
+            .decode(&self.data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut json = Vec::new();
+        zstd::Decoder::new(compressed.as_slice())?
+            .take(MAX_DECOMPRESSED_LEN + 1)
+            .read_to_end(&mut json)?;
+        if json.len() as u64 > MAX_DECOMPRESSED_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompresses to more than {MAX_DECOMPRESSED_LEN} bytes"),
+            ));
+        }
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let small = serde_json::json!({ "type": "gossip", "seen": [1, 2, 3] });
+        let large =
+            serde_json::json!({ "type": "gossip", "seen": (0..50_000).collect::<Vec<_>>() });
+
+        for body in [small, large] {
+            let json = serde_json::to_vec(&body).unwrap();
+            let envelope = CompressedEnvelope::seal(&json).unwrap();
+            assert_eq!(envelope.open::<serde_json::Value>().unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn test_large_bodies_shrink() {
+        let body = serde_json::json!({ "seen": (0..50_000).collect::<Vec<_>>() });
+        let json = serde_json::to_vec(&body).unwrap();
+        let envelope = CompressedEnvelope::seal(&json).unwrap();
+        assert!(envelope.data.len() < json.len() / 2);
+    }
+
+    #[test]
+    fn test_corrupt_envelope() {
+        let not_base64 = CompressedEnvelope {
+            data: "not base64!".into(),
+        };
+        assert!(not_base64.open::<serde_json::Value>().is_err());
+
+        let not_zstd = CompressedEnvelope {
+            data: base64::engine::general_purpose::STANDARD.encode(b"{}"),
+        };
+        assert!(not_zstd.open::<serde_json::Value>().is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Settings read from an optional JSON file, for what is too structured for the command line.
+//!
+//! ```json
+//! {
+//!     "node": { "flush": { "max_batch": 128 }, "execution": "spawn" },
+//!     "services": { "broadcast": { "gossip": { "fanout": 4 }, "bootstrap": true } }
+//! }
+//! ```
+//!
+//! `node` overrides fields of [`NodeOptions`], and each section of `services` the
+//! [`Configurable::Config`] of the service with that name. Anything left out keeps its default,
+//! and keys that match nothing are logged and ignored. Environment variables named
+//! `MAELSTROM_<FIELD>`, e.g. `MAELSTROM_STRICT_INIT=true`, override `node` fields in turn. Their
+//! values are read as JSON, or as a string if they aren't valid JSON.
+
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use snafu::{ResultExt as _, Snafu};
+
+use crate::node::{Node, NodeOptions, NodeState};
+
+/// The prefix of environment variables that override [`NodeOptions`] fields.
+pub const ENV_PREFIX: &str = "MAELSTROM_";
+
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("Failed to read config file {}", path.display()))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to parse config file {}", path.display()))]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Invalid {section} config"))]
+    Invalid {
+        section: String,
+        source: serde_path_to_error::Error<serde_json::Error>,
+    },
+    #[snafu(display("Invalid {section} config: {message}"))]
+    Rejected { section: String, message: String },
+}
+
+/// A service that can be built from its own section of the config file.
+pub trait Configurable: Node {
+    /// The service's section in `services`.
+    const NAME: &'static str;
+
+    /// Serialized to find the defaults that the config file overrides, so every field should be
+    /// serialized, including `None`s.
+    type Config: Serialize + DeserializeOwned + Default;
+
+    /// Build the service. Fails with a description of the problem if `config` is invalid.
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String>;
+}
+
+/// The contents of a config file, see the [module docs](self).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub node: Map<String, Value>,
+    pub services: Map<String, Value>,
+    #[serde(flatten)]
+    unknown: Map<String, Value>,
+}
+
+impl Config {
+    /// Read the config file at `path`. A missing file is logged and treated as empty.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("Config file {} not found, using defaults", path.display());
+                return Ok(Self::default());
+            }
+            Err(source) => {
+                return Err(ConfigError::Read {
+                    path: path.into(),
+                    source,
+                })
+            }
+        };
+        let config = serde_json::from_slice::<Self>(&contents).context(ParseSnafu { path })?;
+        for key in config.unknown.keys() {
+            tracing::warn!("Ignoring unknown config section {}", key);
+        }
+        Ok(config)
+    }
+
+    /// The node options, with the `node` section applied, and the environment variables in `env`
+    /// applied over it.
+    pub fn node_options(
+        &self,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<NodeOptions, ConfigError> {
+        let env = env
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let field = key.strip_prefix(ENV_PREFIX)?.to_lowercase();
+                let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+                Some((field, value))
+            })
+            .collect::<Map<_, _>>();
+        resolve("node", &NodeOptions::default(), &[&self.node, &env])
+    }
+
+    /// The config of service `S`, from its section of `services`.
+    pub fn service<S: Configurable>(&self) -> Result<S::Config, ConfigError> {
+        let section = self
+            .services
+            .get(S::NAME)
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        resolve(S::NAME, &S::Config::default(), &[&section])
+    }
+
+    /// Build service `S` from its section of `services`.
+    pub fn build<S: Configurable>(&self) -> Result<S, ConfigError> {
+        S::from_config(self.service::<S>()?).map_err(|message| {
+            RejectedSnafu {
+                section: S::NAME,
+                message,
+            }
+            .build()
+        })
+    }
+}
+
+/// Run service `S` on stdin and stdout, configured by `config` and the process's environment.
+pub async fn run<S: Configurable>(config: &Config) -> crate::Result<(), S::Error> {
+    let bad_config = |source: ConfigError| crate::Error::Whatever {
+        message: "Bad config".into(),
+        source: Some(source.into()),
+    };
+    let options = config.node_options(std::env::vars()).map_err(bad_config)?;
+    let node = config.build::<S>().map_err(bad_config)?;
+    NodeState::run_stdio(node, options).await
+}
+
+/// `defaults` with each of `layers` applied over it in turn, logging the keys that match nothing.
+fn resolve<T: Serialize + DeserializeOwned>(
+    section: &str,
+    defaults: &T,
+    layers: &[&Map<String, Value>],
+) -> Result<T, ConfigError> {
+    let (resolved, unknown) = resolve_reporting(section, defaults, layers)?;
+    for key in unknown {
+        tracing::warn!("Ignoring unknown {} config key {}", section, key);
+    }
+    Ok(resolved)
+}
+
+fn resolve_reporting<T: Serialize + DeserializeOwned>(
+    section: &str,
+    defaults: &T,
+    layers: &[&Map<String, Value>],
+) -> Result<(T, Vec<String>), ConfigError> {
+    let mut value = serde_json::to_value(defaults).expect("config defaults serialize");
+    let mut unknown = Vec::new();
+    for layer in layers {
+        overlay(&mut value, layer, "", &mut unknown);
+    }
+    let resolved = serde_path_to_error::deserialize(value).context(InvalidSnafu { section })?;
+    Ok((resolved, unknown))
+}
+
+/// Replace the values in `target` with those in `overrides`, recursing into objects. Keys that
+/// `target` doesn't have are added to `unknown` as dotted paths instead.
+fn overlay(
+    target: &mut Value,
+    overrides: &Map<String, Value>,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    for (key, value) in overrides {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        match (target.get_mut(key), value) {
+            (Some(target @ Value::Object(_)), Value::Object(value)) => {
+                overlay(target, value, &key_path, unknown)
+            }
+            (Some(target), value) => *target = value.clone(),
+            (None, _) => unknown.push(key_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flush::FlushOptions;
+    use crate::node::Execution;
+    use crate::services::broadcast::{BroadcastConfig, BroadcastService};
+    use crate::services::echo::EchoService;
+    use crate::services::g_set::{GSetConfig, GSetService};
+
+    fn parse(json: Value) -> Config {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_node_options_precedence() {
+        let config = parse(serde_json::json!({
+            "node": {
+                "strict_init": true,
+                "compress_above": 4096,
+                "flush": { "max_batch": 128 },
+            },
+        }));
+
+        let options = config.node_options(env(&[])).unwrap();
+        assert!(options.strict_init);
+        assert_eq!(options.compress_above, Some(4096));
+        assert_eq!(options.flush.max_batch, 128);
+        // Only the fields given are changed, even in nested sections.
+        assert_eq!(
+            options.flush.deadline_us,
+            FlushOptions::default().deadline_us
+        );
+        assert_eq!(options.execution, None);
+
+        let options = config
+            .node_options(env(&[
+                ("MAELSTROM_STRICT_INIT", "false"),
+                ("MAELSTROM_COMPRESS_ABOVE", "null"),
+                ("MAELSTROM_EXECUTION", "spawn"),
+                ("PATH", "/bin"),
+            ]))
+            .unwrap();
+        assert!(!options.strict_init);
+        assert_eq!(options.compress_above, None);
+        assert_eq!(options.execution, Some(Execution::Spawn));
+        assert_eq!(options.flush.max_batch, 128);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_reported() {
+        let node = serde_json::json!({
+            "strict_init": true,
+            "stirct_init": true,
+            "flush": { "max_batch": 128, "max_bytes": 1 },
+        });
+        let (options, unknown) = resolve_reporting(
+            "node",
+            &NodeOptions::default(),
+            &[node.as_object().unwrap()],
+        )
+        .unwrap();
+        assert!(options.strict_init);
+        assert_eq!(unknown, ["flush.max_bytes", "stirct_init"]);
+    }
+
+    #[test]
+    fn test_invalid_values_fail_with_their_path() {
+        let config = parse(serde_json::json!({ "node": { "flush": { "max_batch": "lots" } } }));
+        let error = config.node_options(env(&[])).unwrap_err();
+        let ConfigError::Invalid { source, .. } = &error else {
+            panic!("unexpected error {error:?}");
+        };
+        assert_eq!(source.path().to_string(), "flush.max_batch");
+
+        let config = parse(serde_json::json!({
+            "services": { "broadcast": { "gossip": { "fanout": 0 } } },
+        }));
+        assert!(matches!(
+            config.build::<BroadcastService>(),
+            Err(ConfigError::Rejected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_service_config_precedence() {
+        assert_eq!(
+            serde_json::to_value(Config::default().service::<BroadcastService>().unwrap()).unwrap(),
+            serde_json::to_value(BroadcastConfig::default()).unwrap()
+        );
+
+        let config = parse(serde_json::json!({
+            "services": {
+                "broadcast": { "gossip": { "fanout": 4 }, "bootstrap": true },
+                "g_set": { "gossip": { "fanout": 7 } },
+            },
+        }));
+        let broadcast = config.service::<BroadcastService>().unwrap();
+        assert_eq!(broadcast.gossip.fanout, 4);
+        assert_eq!(
+            broadcast.gossip.gossip_interval_ms,
+            BroadcastConfig::default().gossip.gossip_interval_ms
+        );
+        assert!(broadcast.bootstrap);
+        let g_set = config.service::<GSetService>().unwrap();
+        assert_eq!(g_set.gossip.fanout, 7);
+        assert_eq!(
+            g_set.anti_entropy_every,
+            GSetConfig::default().anti_entropy_every
+        );
+    }
+
+    #[test]
+    fn test_services_without_config() {
+        assert!(Config::default().build::<EchoService>().is_ok());
+        // There is nothing to set, so every key is unknown, and ignored.
+        let section = serde_json::json!({ "fanout": 4 });
+        let (_, unknown) = resolve_reporting("echo", &(), &[section.as_object().unwrap()]).unwrap();
+        assert_eq!(unknown, ["fanout"]);
+    }
+
+    #[test]
+    fn test_missing_file_means_defaults() {
+        let path = std::env::temp_dir().join(format!("missing-{}.json", ulid::Ulid::new()));
+        let config = Config::load(&path).unwrap();
+        assert!(config.node.is_empty() && config.services.is_empty());
+
+        std::fs::write(&path, b"{\"node\": ").unwrap();
+        let error = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(error, Err(ConfigError::Parse { .. })));
+    }
+}
@@ -0,0 +1,278 @@
+//! Machine-readable descriptions of the messages each service speaks, printed by `--describe`.
+//!
+//! A description lists every message type a service reads or writes: its `type` tag, its fields
+//! and their JSON types, which direction it travels, and the reply a request expects. It also
+//! lists the error codes a client may get back, including those the runner answers with itself.
+//! The runner's own messages, like `init`, are the same for every service and left out.
+//!
+//! Services defined with `define_service_messages!` get their message descriptions generated;
+//! the others list them by hand, and a test checks every description against
+//! [`Node::message_tags`](crate::node::Node::message_tags).
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::message::{ArcValue, ErrorCode};
+use crate::services::counter::GCounter;
+use crate::services::gossip::GSet;
+
+/// The error codes the runner answers with, whatever the service: `malformed_request` for
+/// requests that fail to parse, `not_supported` for unknown types, `temporarily_unavailable` while
+/// read-only or standing by, and `crash` for anything else.
+pub const RUNNER_ERROR_CODES: [ErrorCode; 4] = [
+    ErrorCode::Crash,
+    ErrorCode::MalformedRequest,
+    ErrorCode::NotSupported,
+    ErrorCode::TemporarilyUnavailable,
+];
+
+/// The names `--describe` accepts, those of the Maelstrom workloads each service runs.
+pub const SERVICES: [&str; 5] = ["echo", "unique-ids", "broadcast", "counter", "g-set"];
+
+/// A service whose messages can be described.
+pub trait Describe {
+    fn describe() -> ServiceDescription;
+}
+
+/// The description of the service running workload `name`, or `None` if there is none.
+pub fn service(name: &str) -> Option<ServiceDescription> {
+    use crate::services::*;
+
+    Some(match name {
+        "echo" => echo::EchoService::describe(),
+        "unique-ids" => unique_ids::UniqueIdService::describe(),
+        "broadcast" => broadcast::BroadcastService::describe(),
+        "counter" => counter::CounterService::describe(),
+        "g-set" => g_set::GSetService::describe(),
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceDescription {
+    pub service: &'static str,
+    pub messages: Vec<MessageDescription>,
+    /// Sorted by code.
+    pub error_codes: Vec<ErrorCodeDescription>,
+}
+
+impl ServiceDescription {
+    /// Describe `service`, which may fail with `error_codes` on top of the
+    /// [`RUNNER_ERROR_CODES`].
+    pub fn new(
+        service: &'static str,
+        messages: Vec<MessageDescription>,
+        error_codes: &[ErrorCode],
+    ) -> Self {
+        let mut codes = error_codes
+            .iter()
+            .chain(&RUNNER_ERROR_CODES)
+            .copied()
+            .collect::<Vec<_>>();
+        codes.sort_by_key(|code| *code as u64);
+        codes.dedup();
+        Self {
+            service,
+            messages,
+            error_codes: codes.into_iter().map(ErrorCodeDescription::new).collect(),
+        }
+    }
+}
+
+/// Which way a message travels, relative to the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    /// Sent and received, e.g. between peers.
+    Both,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDescription {
+    /// The `type` tag.
+    pub tag: &'static str,
+    pub direction: Direction,
+    pub fields: Vec<FieldDescription>,
+    /// The tag of the reply, for requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply: Option<&'static str>,
+}
+
+impl MessageDescription {
+    /// Describe the message tagged `tag`. A request, with a `reply`, travels inbound, a reply to
+    /// one of `replies` outbound, and anything else both ways, as does anything marked `peer`.
+    pub fn new(
+        tag: &'static str,
+        fields: Vec<FieldDescription>,
+        reply: Option<&'static str>,
+        peer: bool,
+        replies: &[&str],
+    ) -> Self {
+        let direction = if peer {
+            Direction::Both
+        } else if reply.is_some() {
+            Direction::Inbound
+        } else if replies.contains(&tag) {
+            Direction::Outbound
+        } else {
+            Direction::Both
+        };
+        Self {
+            tag,
+            direction,
+            fields,
+            reply,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldDescription {
+    pub name: &'static str,
+    /// `integer`, `string`, `boolean`, `array`, `object`, or `any`.
+    #[serde(rename = "type")]
+    pub json_type: &'static str,
+}
+
+impl FieldDescription {
+    pub fn of<T: JsonType + ?Sized>(name: &'static str) -> Self {
+        Self {
+            name,
+            json_type: T::JSON_TYPE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorCodeDescription {
+    pub code: ErrorCode,
+    pub name: String,
+}
+
+impl ErrorCodeDescription {
+    fn new(code: ErrorCode) -> Self {
+        // The variant name in snake case, as Maelstrom's docs name the codes.
+        let mut name = String::new();
+        for c in format!("{code:?}").chars() {
+            if c.is_uppercase() && !name.is_empty() {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+        }
+        Self { code, name }
+    }
+}
+
+/// How a field's type appears in JSON.
+pub trait JsonType {
+    const JSON_TYPE: &'static str;
+}
+
+macro_rules! json_type {
+    ($json_type:literal: $($ty:ty),*) => {
+        $(impl JsonType for $ty {
+            const JSON_TYPE: &'static str = $json_type;
+        })*
+    };
+}
+
+json_type!("integer": u64, ErrorCode);
+json_type!("string": String, str);
+json_type!("boolean": bool);
+json_type!("any": serde_json::Value, ArcValue);
+
+impl<T> JsonType for Vec<T> {
+    const JSON_TYPE: &'static str = "array";
+}
+
+impl<T> JsonType for HashSet<T> {
+    const JSON_TYPE: &'static str = "array";
+}
+
+impl<T: Eq + std::hash::Hash> JsonType for GSet<T> {
+    const JSON_TYPE: &'static str = "array";
+}
+
+impl JsonType for GCounter {
+    const JSON_TYPE: &'static str = "object";
+}
+
+impl<K, V> JsonType for HashMap<K, V> {
+    const JSON_TYPE: &'static str = "object";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::services::*;
+
+    fn tags<S: Node>(service: S) -> Vec<String> {
+        let mut tags = service
+            .message_tags()
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn test_descriptions_match_tags() {
+        for name in SERVICES {
+            let expected = match name {
+                "echo" => tags(echo::EchoService),
+                "unique-ids" => tags(unique_ids::UniqueIdService::default()),
+                "broadcast" => tags(broadcast::BroadcastService::default()),
+                "counter" => tags(counter::CounterService::default()),
+                "g-set" => tags(g_set::GSetService::default()),
+                _ => panic!("no service named {name}"),
+            };
+
+            // As `--describe` prints it.
+            let json = serde_json::to_string_pretty(&service(name).unwrap()).unwrap();
+            let description = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+            let messages = description["messages"].as_array().unwrap();
+            let mut described = messages
+                .iter()
+                .map(|message| message["tag"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            described.sort();
+            assert_eq!(described, expected, "{name}");
+
+            for message in messages {
+                if let Some(reply) = message["reply"].as_str() {
+                    assert!(expected.iter().any(|tag| tag == reply), "{name}: {reply}");
+                }
+            }
+            let codes = description["error_codes"].as_array().unwrap();
+            assert!(codes.contains(&serde_json::json!({ "code": 13, "name": "crash" })));
+        }
+        assert!(service("lin-kv").is_none());
+    }
+
+    #[test]
+    fn test_generated_description() {
+        let description = counter::CounterService::describe();
+        let add = &description.messages[1];
+        assert_eq!(add.tag, "add");
+        assert_eq!(add.direction, Direction::Inbound);
+        assert_eq!(add.reply, Some("add_ok"));
+        assert_eq!(add.fields[0].name, "delta");
+        assert_eq!(add.fields[0].json_type, "integer");
+        assert_eq!(description.messages[2].direction, Direction::Outbound);
+        assert_eq!(description.messages[0].direction, Direction::Both);
+
+        let g_set = g_set::GSetService::describe();
+        let replicate = g_set
+            .messages
+            .iter()
+            .find(|m| m.tag == "replicate")
+            .unwrap();
+        assert_eq!(replicate.direction, Direction::Both);
+        assert_eq!(replicate.fields[0].json_type, "array");
+    }
+}
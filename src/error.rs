@@ -14,7 +14,7 @@ pub enum Error<E: std::error::Error + Send + Sync + Sized + 'static> {
         source: E,
     },
 
-    #[snafu(display("Node error: {}", source))]
+    #[snafu(display("Internal error: {}", source))]
     Internal {
         #[snafu(source)]
         source: crate::node::InternalError,
@@ -28,6 +28,29 @@ pub enum Error<E: std::error::Error + Send + Sync + Sized + 'static> {
     },
 }
 
+impl<E: std::error::Error + Send + Sync + 'static> Error<E> {
+    /// A service error, from one that converts to the service's error type, like that of a
+    /// service it runs inside it.
+    pub fn node(source: impl Into<E>) -> Self {
+        Self::Node {
+            source: source.into(),
+        }
+    }
+
+    /// Whether the node can't go on after this, and should stop rather than limp along: the
+    /// runner's own errors say so themselves (see [`crate::node::InternalError::is_fatal`]), and
+    /// `is_fatal_node_error` classifies the service's, like
+    /// [`Node::is_fatal`](crate::node::Node::is_fatal). Anything else only fails the message at
+    /// hand.
+    pub fn is_fatal(&self, is_fatal_node_error: impl FnOnce(&E) -> bool) -> bool {
+        match self {
+            Self::Internal { source } => source.is_fatal(),
+            Self::Node { source } => is_fatal_node_error(source),
+            Self::Io { .. } | Self::Whatever { .. } => false,
+        }
+    }
+}
+
 impl<E: std::error::Error + Send + Sync + 'static> From<std::io::Error> for Error<E> {
     fn from(source: std::io::Error) -> Self {
         Self::Io { source }
@@ -35,3 +58,104 @@ impl<E: std::error::Error + Send + Sync + 'static> From<std::io::Error> for Erro
 }
 
 pub type Result<T, E = Box<dyn std::error::Error + 'static>> = std::result::Result<T, Error<E>>;
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use snafu::Report;
+
+    use super::*;
+    use crate::node::InternalError;
+    use crate::services::broadcast::BroadcastError;
+
+    fn chain(e: &(dyn std::error::Error + 'static)) -> Vec<String> {
+        std::iter::successors(Some(e), |e| (*e).source())
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_send_failure_keeps_io_source() {
+        let e: Error<BroadcastError> = InternalError::Send {
+            dest: "n2".into(),
+            source: std::io::Error::new(std::io::ErrorKind::BrokenPipe, "pipe closed"),
+        }
+        .into();
+
+        assert_eq!(
+            chain(&e),
+            [
+                "Internal error: Failed to send message to n2",
+                "Failed to send message to n2",
+                "pipe closed",
+            ]
+        );
+        let report = Report::from_error(e).to_string();
+        assert!(
+            report.contains("1: Failed to send message to n2"),
+            "{report}"
+        );
+        assert!(report.contains("2: pipe closed"), "{report}");
+    }
+
+    #[test]
+    fn test_closed_output_keeps_io_source() {
+        let e: Error<BroadcastError> = InternalError::OutputClosed {
+            dest: "c1".into(),
+            source: std::io::Error::new(std::io::ErrorKind::BrokenPipe, "pipe closed"),
+        }
+        .into();
+
+        assert_eq!(
+            chain(&e),
+            [
+                "Internal error: Output closed while sending message to c1",
+                "Output closed while sending message to c1",
+                "pipe closed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fatal_errors() {
+        let closed: Error<std::io::Error> = InternalError::OutputClosed {
+            dest: "c1".into(),
+            source: std::io::ErrorKind::BrokenPipe.into(),
+        }
+        .into();
+        assert!(closed.is_fatal(|_| false));
+        let unknown: Error<std::io::Error> =
+            InternalError::UnknownDestination { dest: "x1".into() }.into();
+        assert!(!unknown.is_fatal(|_| true));
+
+        let corrupted = Error::Node {
+            source: std::io::Error::from(std::io::ErrorKind::InvalidData),
+        };
+        let is_corruption = |e: &std::io::Error| e.kind() == std::io::ErrorKind::InvalidData;
+        assert!(corrupted.is_fatal(is_corruption));
+        let other = Error::Node {
+            source: std::io::Error::other("out of luck"),
+        };
+        assert!(!other.is_fatal(is_corruption));
+    }
+
+    #[test]
+    fn test_node_error_chain() {
+        let e: Error<BroadcastError> = BroadcastError::UnknownPeer { peer: "n3".into() }.into();
+
+        assert_eq!(
+            chain(&e),
+            [
+                "Node error: No known messages for peer n3",
+                "No known messages for peer n3"
+            ]
+        );
+        assert!(e.source().unwrap().source().is_none());
+        let report = Report::from_error(e).to_string();
+        assert!(
+            report.contains("1: No known messages for peer n3"),
+            "{report}"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! When the runner flushes its output.
+//!
+//! Flushing after every message keeps replies fast while the node is quiet, but under heavy load
+//! most of the time spent writing goes to flushing. [`FlushPolicy`] flushes every message until
+//! the write rate climbs above [`FlushOptions::batch_above`], then batches messages until either
+//! [`FlushOptions::max_batch`] are waiting or the oldest has waited [`FlushOptions::deadline_us`].
+//! It goes back to flushing every message once the rate falls below
+//! [`FlushOptions::unbatch_below`]; the gap between the two keeps it from flapping.
+//!
+//! A client that pipelines its requests gets one reply per request. With
+//! [`FlushOptions::ack_window_us`] set, replies to clients are held for that long, or until
+//! [`FlushOptions::max_acks`] are waiting for one client, so that they share a flush whatever the
+//! rate. They are still separate frames, written in the order they were sent, so an error reply
+//! never overtakes an ack sent before it.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// How often the write rate is measured.
+pub const RATE_WINDOW: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlushOptions {
+    /// Messages per second above which writes are batched.
+    pub batch_above: u64,
+    /// Messages per second below which writes are flushed one by one again.
+    pub unbatch_below: u64,
+    /// The longest a message waits for its batch, in microseconds.
+    pub deadline_us: u64,
+    /// Flush a batch as soon as this many messages are waiting.
+    pub max_batch: usize,
+    /// Hold replies to clients for up to this many microseconds, so that replies to a client's
+    /// pipelined requests share a flush. Off by default.
+    pub ack_window_us: Option<u64>,
+    /// Flush held replies as soon as this many are waiting for one client.
+    pub max_acks: usize,
+}
+
+impl Default for FlushOptions {
+    fn default() -> Self {
+        Self {
+            batch_above: 20_000,
+            unbatch_below: 5_000,
+            deadline_us: 1_000,
+            max_batch: 64,
+            ack_window_us: None,
+            max_acks: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushMode {
+    /// Every message is flushed as soon as it is written.
+    Immediate,
+    /// Messages are flushed in batches.
+    Batching,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushStats {
+    pub mode: FlushMode,
+    pub messages: u64,
+    pub flushes: u64,
+}
+
+/// Decides when to flush, see the [module docs](self).
+#[derive(Debug)]
+pub struct FlushPolicy {
+    options: FlushOptions,
+    mode: FlushMode,
+    /// The start of the current rate measurement, and the messages written since.
+    window: (Instant, u64),
+    /// When the oldest message written since the last flush was written.
+    oldest_pending: Option<Instant>,
+    pending: usize,
+    /// How many replies to each client are held since the last flush, see
+    /// [`FlushOptions::ack_window_us`].
+    held: HashMap<Arc<str>, usize>,
+    messages: u64,
+    flushes: u64,
+}
+
+impl FlushPolicy {
+    pub fn new(options: FlushOptions, now: Instant) -> Self {
+        Self {
+            options,
+            mode: FlushMode::Immediate,
+            window: (now, 0),
+            oldest_pending: None,
+            pending: 0,
+            held: HashMap::new(),
+            messages: 0,
+            flushes: 0,
+        }
+    }
+
+    /// Record a message written at `now`, returning whether to flush right away. If not, the
+    /// output must be flushed by [`FlushPolicy::deadline`].
+    pub fn written(&mut self, now: Instant) -> bool {
+        let oldest = self.record(now);
+        match self.mode {
+            FlushMode::Immediate => true,
+            FlushMode::Batching => {
+                self.pending >= self.options.max_batch || now >= oldest + self.max_wait()
+            }
+        }
+    }
+
+    /// Like [`FlushPolicy::written`], for a reply to `client`, which is held for
+    /// [`FlushOptions::ack_window_us`] if that is set.
+    pub fn written_reply(&mut self, now: Instant, client: &Arc<str>) -> bool {
+        if self.options.ack_window_us.is_none() {
+            return self.written(now);
+        }
+        let oldest = self.record(now);
+        let held = self.held.entry(Arc::clone(client)).or_default();
+        *held += 1;
+        *held >= self.options.max_acks
+            || (self.mode == FlushMode::Batching && self.pending >= self.options.max_batch)
+            || now >= oldest + self.max_wait()
+    }
+
+    /// Record a flush of everything written so far.
+    pub fn flushed(&mut self) {
+        if self.pending > 0 {
+            self.flushes += 1;
+        }
+        self.pending = 0;
+        self.oldest_pending = None;
+        self.held.clear();
+    }
+
+    /// When the messages waiting for a flush must be flushed, if any are waiting.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest_pending.map(|oldest| oldest + self.max_wait())
+    }
+
+    /// How many messages were written since the last flush.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn stats(&self) -> FlushStats {
+        FlushStats {
+            mode: self.mode,
+            messages: self.messages,
+            flushes: self.flushes,
+        }
+    }
+
+    /// Count a message written at `now`, returning when the oldest one waiting was written.
+    fn record(&mut self, now: Instant) -> Instant {
+        self.measure(now);
+        self.messages += 1;
+        self.pending += 1;
+        *self.oldest_pending.get_or_insert(now)
+    }
+
+    /// How long the oldest message waiting may wait. Only held replies wait while flushing every
+    /// message.
+    fn max_wait(&self) -> Duration {
+        let batch = Duration::from_micros(self.options.deadline_us);
+        let held = (!self.held.is_empty())
+            .then_some(self.options.ack_window_us)
+            .flatten()
+            .map(Duration::from_micros);
+        match (self.mode, held) {
+            (FlushMode::Immediate, Some(held)) => held,
+            (FlushMode::Batching, Some(held)) => batch.min(held),
+            (_, None) => batch,
+        }
+    }
+
+    /// Count a write towards the current window, and once the window is over, switch modes if
+    /// its rate calls for it.
+    fn measure(&mut self, now: Instant) {
+        let (start, writes) = &mut self.window;
+        let elapsed = now.duration_since(*start);
+        if elapsed < RATE_WINDOW {
+            *writes += 1;
+            return;
+        }
+
+        let rate = (*writes as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64;
+        self.window = (now, 1);
+        let mode = match self.mode {
+            FlushMode::Immediate if rate > self.options.batch_above => FlushMode::Batching,
+            FlushMode::Batching if rate < self.options.unbatch_below => FlushMode::Immediate,
+            mode => mode,
+        };
+        if mode != self.mode {
+            tracing::info!("Switching to {:?} flushes at {} messages/s", mode, rate);
+            self.mode = mode;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `count` messages `gap` apart, flushing whenever the policy says so or a deadline
+    /// passes, and return how many flushes that took.
+    fn drive(policy: &mut FlushPolicy, now: &mut Instant, count: usize, gap: Duration) -> u64 {
+        let before = policy.stats().flushes;
+        for _ in 0..count {
+            *now += gap;
+            if policy.deadline().is_some_and(|deadline| deadline <= *now) {
+                policy.flushed();
+            }
+            if policy.written(*now) {
+                policy.flushed();
+            }
+        }
+        policy.stats().flushes - before
+    }
+
+    #[test]
+    fn test_low_rate_flushes_every_message() {
+        let mut now = Instant::now();
+        let mut policy = FlushPolicy::new(FlushOptions::default(), now);
+
+        // 1000 messages/s.
+        assert_eq!(
+            drive(&mut policy, &mut now, 500, Duration::from_millis(1)),
+            500
+        );
+        assert_eq!(policy.stats().mode, FlushMode::Immediate);
+        assert_eq!(policy.deadline(), None);
+    }
+
+    #[test]
+    fn test_high_rate_batches() {
+        let mut now = Instant::now();
+        let mut policy = FlushPolicy::new(FlushOptions::default(), now);
+
+        // 100,000 messages/s. The first window is flushed message by message.
+        let flushes = drive(&mut policy, &mut now, 10_000, Duration::from_micros(10));
+        assert_eq!(policy.stats().mode, FlushMode::Batching);
+        let first_window = 1000;
+        let batches = (10_000 - first_window) / FlushOptions::default().max_batch as u64;
+        assert!(
+            flushes <= first_window + batches + 1,
+            "{flushes} flushes for 10000 messages"
+        );
+    }
+
+    #[test]
+    fn test_deadline_bounds_the_wait() {
+        let mut now = Instant::now();
+        let mut policy = FlushPolicy::new(FlushOptions::default(), now);
+        drive(&mut policy, &mut now, 2_000, Duration::from_micros(10));
+        assert_eq!(policy.stats().mode, FlushMode::Batching);
+
+        policy.flushed();
+        assert!(!policy.written(now));
+        assert_eq!(policy.deadline(), Some(now + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_client_replies_are_held() {
+        let mut now = Instant::now();
+        let options = FlushOptions {
+            ack_window_us: Some(2_000),
+            max_acks: 4,
+            ..Default::default()
+        };
+        let mut policy = FlushPolicy::new(options, now);
+        let (c1, c2) = (Arc::from("c1"), Arc::from("c2"));
+
+        // Held until one client has four waiting.
+        for client in [&c1, &c2, &c1, &c1] {
+            assert!(!policy.written_reply(now, client));
+        }
+        assert!(policy.written_reply(now, &c1));
+        policy.flushed();
+
+        // Or until the first has waited out the window.
+        assert!(!policy.written_reply(now, &c2));
+        assert_eq!(policy.deadline(), Some(now + Duration::from_millis(2)));
+        now += Duration::from_millis(2);
+        assert!(policy.written_reply(now, &c2));
+        policy.flushed();
+
+        // Anything else still flushes everything right away.
+        assert!(!policy.written_reply(now, &c1));
+        assert!(policy.written(now));
+    }
+
+    #[test]
+    fn test_transitions_have_hysteresis() {
+        let mut now = Instant::now();
+        let mut policy = FlushPolicy::new(FlushOptions::default(), now);
+        drive(&mut policy, &mut now, 2_000, Duration::from_micros(10));
+        assert_eq!(policy.stats().mode, FlushMode::Batching);
+
+        // 10,000 messages/s is below the rate that started batching, but above the one that
+        // stops it.
+        drive(&mut policy, &mut now, 1_000, Duration::from_micros(100));
+        assert_eq!(policy.stats().mode, FlushMode::Batching);
+
+        // 2,000 messages/s.
+        drive(&mut policy, &mut now, 100, Duration::from_micros(500));
+        assert_eq!(policy.stats().mode, FlushMode::Immediate);
+        assert_eq!(
+            drive(&mut policy, &mut now, 100, Duration::from_micros(500)),
+            100
+        );
+
+        // Back up to 10,000 messages/s: not enough to start batching again.
+        drive(&mut policy, &mut now, 1_000, Duration::from_micros(100));
+        assert_eq!(policy.stats().mode, FlushMode::Immediate);
+    }
+}
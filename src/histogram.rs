@@ -0,0 +1,148 @@
+//! A latency histogram of fixed size, for keeping percentiles over a run of any length.
+//!
+//! Durations are counted in microsecond buckets that grow with the duration: exact below 8µs, then
+//! 8 buckets per power of two. A percentile is read as the top of its bucket, so it overestimates
+//! by at most an eighth, and never exceeds the largest duration recorded.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Buckets per power of two, beyond the exact ones.
+const SUB_BUCKETS: u64 = 8;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// Enough to cover every microsecond count a `u64` holds.
+const BUCKETS: usize = (SUB_BUCKETS + (64 - SUB_BUCKET_BITS as u64) * SUB_BUCKETS) as usize;
+
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: Box<[u64; BUCKETS]>,
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: Box::new([0; BUCKETS]),
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+/// The bucket counting `micros`.
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let shift = micros.ilog2() - SUB_BUCKET_BITS;
+    let sub = (micros >> shift) - SUB_BUCKETS;
+    (SUB_BUCKETS * (shift as u64 + 1) + sub) as usize
+}
+
+/// The largest microsecond count in `bucket`.
+fn bucket_max(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    // Wraps to `u64::MAX` for the last bucket, whose end is 2^64.
+    ((SUB_BUCKETS + sub + 1) << shift).wrapping_sub(1)
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket(micros)] += 1;
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        self.total
+            .checked_div(self.count as u32)
+            .unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The duration that `fraction` of the recorded durations are at most, see the
+    /// [module docs](self) for its precision. Zero if nothing was recorded.
+    pub fn quantile(&self, fraction: f64) -> Duration {
+        let rank = ((fraction * self.count as f64).ceil() as u64).clamp(1, self.count.max(1));
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_max(bucket)).min(self.max);
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean: self.mean(),
+            p99: self.quantile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_cover_every_duration() {
+        let mut previous_max = None;
+        for bucket in 0..BUCKETS {
+            let max = bucket_max(bucket);
+            let min = previous_max.map_or(0, |previous: u64| previous + 1);
+            assert_eq!(super::bucket(min), bucket, "{min}");
+            assert_eq!(super::bucket(max), bucket, "{max}");
+            // Within an eighth of the smallest duration counted.
+            assert!(max - min <= min / SUB_BUCKETS, "{min}..={max}");
+            previous_max = Some(max);
+        }
+        assert_eq!(previous_max, Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.99), Duration::ZERO);
+
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_millis(40));
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 1001);
+        assert_eq!(summary.max, Duration::from_millis(40));
+        assert!((990..=990 * 9 / 8).contains(&(summary.p99.as_micros() as u64)));
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(511));
+        assert_eq!(histogram.quantile(1.0), Duration::from_millis(40));
+    }
+}
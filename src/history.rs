@@ -0,0 +1,196 @@
+//! The messages a node received and sent last, for working out what led up to a failure.
+//!
+//! The runner keeps a summary of each message in a [`History`] of
+//! [`crate::node::NodeOptions::history_size`] entries and logs it as JSON when a handler panics,
+//! when the node stops on a fatal error, and when asked to with a `dump_history`. Summaries are
+//! cheap to take, so keeping them doesn't slow the node down: the tag is the one the service
+//! already knows, and the body is only serialized as far as [`BODY_LIMIT`].
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::message::{Message, MessageId};
+
+/// How many bytes of each body's JSON a summary keeps.
+pub const BODY_LIMIT: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// What the [`History`] keeps of a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageSummary {
+    pub direction: Direction,
+    pub src: Arc<str>,
+    pub dest: Arc<str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<&'static str>,
+    pub msg_id: Option<MessageId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<MessageId>,
+    /// The start of the body's JSON, at most [`BODY_LIMIT`] bytes of it.
+    pub body: String,
+    /// Whether `body` was cut short.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl MessageSummary {
+    pub fn new<Data: Serialize>(
+        direction: Direction,
+        message: &Message<Data>,
+        tag: Option<&'static str>,
+    ) -> Self {
+        let (body, truncated) = json_prefix(&message.body.data, BODY_LIMIT);
+        Self {
+            direction,
+            src: Arc::clone(&message.src),
+            dest: Arc::clone(&message.dest),
+            tag,
+            msg_id: message.body.id,
+            in_reply_to: message.body.re,
+            body,
+            truncated,
+        }
+    }
+}
+
+/// The first `limit` bytes of `value`'s JSON, and whether there was more. Serialization stops
+/// once the limit is reached, so a large value costs no more than a small one.
+pub fn json_prefix(value: &impl Serialize, limit: usize) -> (String, bool) {
+    let mut prefix = Prefix {
+        bytes: Vec::with_capacity(limit.min(64)),
+        limit,
+        cut: false,
+    };
+    // Fails once the prefix is full, which is how serialization stops early.
+    let _ = serde_json::to_writer(&mut prefix, value);
+    // Don't leave half a character at the end.
+    let valid = match std::str::from_utf8(&prefix.bytes) {
+        Ok(_) => prefix.bytes.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    prefix.bytes.truncate(valid);
+    let json = String::from_utf8(prefix.bytes).unwrap_or_default();
+    (json, prefix.cut)
+}
+
+struct Prefix {
+    bytes: Vec<u8>,
+    limit: usize,
+    cut: bool,
+}
+
+impl std::io::Write for Prefix {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let room = self.limit - self.bytes.len();
+        if buf.len() > room {
+            self.bytes.extend_from_slice(&buf[..room]);
+            self.cut = true;
+            return Err(std::io::Error::other("prefix is full"));
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The last `capacity` message summaries, oldest first.
+#[derive(Debug)]
+pub struct History {
+    messages: VecDeque<MessageSummary>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add `summary`, forgetting the oldest if the history is full.
+    pub fn record(&mut self, summary: MessageSummary) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(summary);
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The summaries, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &MessageSummary> {
+        self.messages.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageBody;
+
+    fn message(id: MessageId, text: &str) -> Message<serde_json::Value> {
+        Message {
+            src: "c1".into(),
+            dest: "n1".into(),
+            body: MessageBody {
+                id: Some(id),
+                re: None,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data: serde_json::json!({ "type": "echo", "echo": text }),
+            },
+        }
+    }
+
+    #[test]
+    fn test_bodies_are_truncated() {
+        let short = MessageSummary::new(Direction::Received, &message(1, "hi"), Some("echo"));
+        assert_eq!(short.body, r#"{"echo":"hi","type":"echo"}"#);
+        assert!(!short.truncated);
+
+        let long = MessageSummary::new(Direction::Sent, &message(2, &"é".repeat(1000)), None);
+        assert!(long.truncated);
+        assert!(long.body.len() <= BODY_LIMIT && long.body.len() > BODY_LIMIT - 2);
+        assert!(long.body.starts_with(r#"{"echo":"éé"#));
+    }
+
+    #[test]
+    fn test_oldest_are_forgotten() {
+        let mut history = History::new(3);
+        for id in 0..5 {
+            history.record(MessageSummary::new(
+                Direction::Received,
+                &message(id, ""),
+                None,
+            ));
+        }
+        let ids = history
+            .iter()
+            .map(|s| s.msg_id.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [2, 3, 4]);
+    }
+}
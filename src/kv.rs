@@ -1 +1,178 @@
+//! Keys for Maelstrom's key-value services.
+//!
+//! A [`Key`] is a namespace followed by any number of segments, rendered as one string with the
+//! parts joined by `/`. Slashes and backslashes inside a part are escaped with a backslash, so
+//! two different lists of parts never render to the same key, whatever the parts contain:
+//!
+//! ```
+//! # use fly_systems_challenge::kv::Key;
+//! let key = Key::ns("kafka").segment("a/b").segment(7);
+//! assert_eq!(key.as_str(), r"kafka/a\/b/7");
+//! assert_eq!(key.segments().unwrap(), ["kafka", "a/b", "7"]);
+//! ```
+//!
+//! Plain strings convert into keys unchanged, for keys that predate namespacing.
 
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Key(String);
+
+#[derive(Debug, Snafu, PartialEq, Eq)]
+pub enum KeyError {
+    #[snafu(display("Key {key:?} ends in an unfinished escape"))]
+    UnfinishedEscape { key: String },
+    #[snafu(display("Key {key:?} escapes {escaped:?}, which needs no escaping"))]
+    UnknownEscape { key: String, escaped: char },
+}
+
+impl Key {
+    /// A key in namespace `ns`, with no segments yet.
+    pub fn ns(ns: impl fmt::Display) -> Self {
+        let mut key = Self(String::new());
+        key.push(ns);
+        key
+    }
+
+    /// The key with `segment` added to the end.
+    pub fn segment(mut self, segment: impl fmt::Display) -> Self {
+        self.0.push('/');
+        self.push(segment);
+        self
+    }
+
+    /// The key the counter service keeps its total under.
+    pub fn counter() -> Self {
+        Self::ns("counter")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The namespace and segments the key was built from.
+    pub fn segments(&self) -> Result<Vec<String>, KeyError> {
+        Self::parse(&self.0)
+    }
+
+    /// Split a rendered key back into its namespace and segments.
+    pub fn parse(key: &str) -> Result<Vec<String>, KeyError> {
+        let mut segments = vec![String::new()];
+        let mut chars = key.chars();
+        while let Some(c) = chars.next() {
+            let current = segments.last_mut().unwrap();
+            match c {
+                '/' => segments.push(String::new()),
+                '\\' => match chars.next() {
+                    Some(escaped @ ('/' | '\\')) => current.push(escaped),
+                    Some(escaped) => {
+                        return UnknownEscapeSnafu { key, escaped }.fail();
+                    }
+                    None => return UnfinishedEscapeSnafu { key }.fail(),
+                },
+                c => current.push(c),
+            }
+        }
+        Ok(segments)
+    }
+
+    fn push(&mut self, part: impl fmt::Display) {
+        for c in part.to_string().chars() {
+            if matches!(c, '/' | '\\') {
+                self.0.push('\\');
+            }
+            self.0.push(c);
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for Key {
+    fn from(key: &str) -> Self {
+        Self(key.to_owned())
+    }
+}
+
+impl From<String> for Key {
+    fn from(key: String) -> Self {
+        Self(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const ADVERSARIAL: &[&str] = &["", "/", "\\", "\\/", "/\\", "a/b", "a\\/b", "\\\\", "//"];
+
+    #[test]
+    fn test_round_trip() {
+        for ns in ADVERSARIAL {
+            for segment in ADVERSARIAL {
+                let key = Key::ns(ns).segment(segment).segment(42);
+                assert_eq!(key.segments().unwrap(), [*ns, *segment, "42"], "{key}");
+            }
+        }
+        assert_eq!(Key::counter().segments().unwrap(), ["counter"]);
+    }
+
+    #[test]
+    fn test_namespaces_never_collide() {
+        let mut lists = HashSet::new();
+        for ns in ["kafka", "kafka/commit", "commit"] {
+            for a in ADVERSARIAL {
+                lists.insert(vec![ns.to_owned(), a.to_string()]);
+                for b in ADVERSARIAL {
+                    lists.insert(vec![ns.to_owned(), format!("{a}/{b}")]);
+                    lists.insert(vec![ns.to_owned(), a.to_string(), b.to_string()]);
+                }
+            }
+        }
+
+        let keys = lists
+            .iter()
+            .map(|parts| {
+                let (ns, segments) = parts.split_first().unwrap();
+                segments.iter().fold(Key::ns(ns), Key::segment)
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(keys.len(), lists.len());
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        let raw = Key::from("commit/topic");
+        assert_eq!(raw.as_str(), "commit/topic");
+        assert_eq!(raw, Key::ns("commit").segment("topic"));
+        assert_eq!(
+            serde_json::to_value(Key::ns("counter")).unwrap(),
+            serde_json::json!("counter")
+        );
+    }
+
+    #[test]
+    fn test_malformed_keys() {
+        assert_eq!(
+            Key::parse("a\\"),
+            Err(KeyError::UnfinishedEscape { key: "a\\".into() })
+        );
+        assert_eq!(
+            Key::parse("a\\b"),
+            Err(KeyError::UnknownEscape {
+                key: "a\\b".into(),
+                escaped: 'b'
+            })
+        );
+    }
+}
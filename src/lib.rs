@@ -0,0 +1,46 @@
+mod async_dashmap;
+// Vendored, so not every item is used.
+#[allow(dead_code)]
+mod tokio_serde;
+
+pub mod audit;
+pub mod breaker;
+pub mod clock;
+pub mod compression;
+pub mod config;
+pub mod describe;
+mod error;
+pub mod flush;
+pub mod histogram;
+pub mod history;
+pub mod kv;
+pub mod loadgen;
+pub mod logging;
+mod macros;
+pub mod message;
+pub mod node;
+pub mod services;
+pub mod standby;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod timer;
+pub mod util;
+mod writer;
+
+pub use error::*;
+
+/// Internals used by the benchmarks in `benches/`. Not a stable API.
+#[cfg(feature = "bench-internals")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::async_dashmap::AsyncDashMap;
+    pub use crate::tokio_serde::formats::SymmetricalJson;
+    pub use crate::writer::Envelopes;
+}
+
+/// Internals fed arbitrary input by the fuzz targets in `fuzz/`. Not a stable API.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz {
+    pub use crate::tokio_serde::formats::SymmetricalJson;
+}
@@ -0,0 +1,331 @@
+//! A load generator for a single node, talking to it over its stdio the way Maelstrom does.
+//!
+//! Unlike the test harness this drives one real node process (see `src/bin/loadgen.rs`), so it
+//! can be used to hammer a node without spinning up a cluster.
+
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use futures::SinkExt as _;
+use serde::Serialize;
+use serde_json::Value;
+use snafu::{ResultExt as _, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
+use tokio_stream::StreamExt as _;
+
+use crate::{
+    message::{Message, MessageBody, MessageId},
+    tokio_serde::formats::SymmetricalJson,
+};
+
+const CLIENT_ID: &str = "c0";
+const NODE_ID: &str = "n0";
+
+#[derive(Debug, Snafu)]
+pub enum LoadgenError {
+    #[snafu(display("Unknown workload {name:?}"))]
+    UnknownWorkload { name: String },
+    #[snafu(display("Failed to talk to the node"))]
+    Io { source: std::io::Error },
+    #[snafu(display("Node did not acknowledge {request}"))]
+    Handshake { request: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    Echo,
+    /// Alternates broadcasts of distinct values with reads.
+    Broadcast,
+    UniqueIds,
+}
+
+impl FromStr for Workload {
+    type Err = LoadgenError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "echo" => Ok(Self::Echo),
+            "broadcast" => Ok(Self::Broadcast),
+            "unique-ids" => Ok(Self::UniqueIds),
+            _ => UnknownWorkloadSnafu { name }.fail(),
+        }
+    }
+}
+
+impl Workload {
+    fn request(self, k: u64) -> Value {
+        match self {
+            Self::Echo => serde_json::json!({ "type": "echo", "echo": k }),
+            Self::Broadcast if k.is_multiple_of(2) => {
+                serde_json::json!({ "type": "broadcast", "message": k / 2 })
+            }
+            Self::Broadcast => serde_json::json!({ "type": "read" }),
+            Self::UniqueIds => serde_json::json!({ "type": "generate" }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadgenOptions {
+    pub workload: Workload,
+    /// Requests per second.
+    pub rate: f64,
+    pub duration: Duration,
+    /// How long to wait for each reply before counting the request as timed out.
+    pub timeout: Duration,
+}
+
+impl Default for LoadgenOptions {
+    fn default() -> Self {
+        Self {
+            workload: Workload::Echo,
+            rate: 100.0,
+            duration: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Reply latency percentiles, in milliseconds.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Latencies {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latencies {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let quantile = |fraction: f64| {
+            samples
+                .len()
+                .checked_sub(1)
+                .map(|last| samples[((last as f64) * fraction).round() as usize])
+                .unwrap_or_default()
+                .as_secs_f64()
+                * 1000.0
+        };
+        Self {
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+            max: quantile(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Summary {
+    pub sent: usize,
+    pub ok: usize,
+    /// Requests answered with an `error` message.
+    pub errors: usize,
+    /// Requests with no reply within the timeout.
+    pub timeouts: usize,
+    pub latency_ms: Latencies,
+    /// The node's exit code, if it exited unsuccessfully.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_exit_code: Option<i32>,
+}
+
+impl Summary {
+    pub fn is_clean(&self) -> bool {
+        self.errors == 0 && self.timeouts == 0 && self.node_exit_code.is_none()
+    }
+}
+
+type Frames<R> = tokio_util::codec::FramedRead<R, SymmetricalJson<Message<Value>>>;
+type Sink<W> = tokio_util::codec::FramedWrite<W, SymmetricalJson<Message<Value>>>;
+
+fn frame(id: MessageId, data: Value) -> Message<Value> {
+    Message {
+        src: Arc::from(CLIENT_ID),
+        dest: Arc::from(NODE_ID),
+        body: MessageBody {
+            id: Some(id),
+            re: None,
+            trace_id: None,
+            seq: None,
+            sent_at_ms: None,
+            request_times: None,
+            data,
+        },
+    }
+}
+
+/// Send `data` and wait for a reply of type `expected`, ignoring anything else the node says.
+async fn handshake<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    frames: &mut Frames<R>,
+    sink: &mut Sink<W>,
+    timeout: Duration,
+    id: MessageId,
+    data: Value,
+    expected: &'static str,
+) -> Result<(), LoadgenError> {
+    sink.send(frame(id, data)).await.context(IoSnafu)?;
+
+    let reply = async {
+        while let Some(msg) = frames.next().await {
+            let msg = msg.context(IoSnafu)?;
+            if msg.body.re == Some(id) {
+                return Ok(msg.body.data["type"] == expected);
+            }
+        }
+        Ok(false)
+    };
+    match tokio::time::timeout(timeout, reply).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        _ => HandshakeSnafu { request: expected }.fail(),
+    }
+}
+
+/// Initialize the node on the other end of `input`/`output`, then issue requests at the configured
+/// rate and report on the replies.
+pub async fn run(
+    options: &LoadgenOptions,
+    input: impl AsyncRead + Unpin,
+    output: impl AsyncWrite + Unpin,
+) -> Result<Summary, LoadgenError> {
+    let mut frames = Frames::new(input, SymmetricalJson::default());
+    let mut sink = Sink::new(output, SymmetricalJson::default());
+
+    let init = serde_json::json!({ "type": "init", "node_id": NODE_ID, "node_ids": [NODE_ID] });
+    handshake(&mut frames, &mut sink, options.timeout, 0, init, "init_ok").await?;
+    if options.workload == Workload::Broadcast {
+        let topology = serde_json::json!({ "type": "topology", "topology": { NODE_ID: [] } });
+        handshake(
+            &mut frames,
+            &mut sink,
+            options.timeout,
+            1,
+            topology,
+            "topology_ok",
+        )
+        .await?;
+    }
+
+    let mut summary = Summary::default();
+    let mut latencies = Vec::new();
+    let mut pending = HashMap::<MessageId, Instant>::new();
+
+    let deadline = Instant::now() + options.duration;
+    let give_up = tokio::time::sleep_until(deadline + options.timeout);
+    tokio::pin!(give_up);
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rate));
+    let mut next_id = 2;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if Instant::now() < deadline => {
+                let id = next_id;
+                next_id += 1;
+                let request = options.workload.request(summary.sent as u64);
+                sink.send(frame(id, request)).await.context(IoSnafu)?;
+                pending.insert(id, Instant::now());
+                summary.sent += 1;
+            }
+            msg = frames.next() => {
+                let Some(msg) = msg else {
+                    tracing::warn!("Node closed its output");
+                    break;
+                };
+                let msg = msg.context(IoSnafu)?;
+                let Some(invoked) = msg.body.re.and_then(|re| pending.remove(&re)) else {
+                    continue;
+                };
+
+                let latency = invoked.elapsed();
+                if latency > options.timeout {
+                    summary.timeouts += 1;
+                } else if msg.body.data["type"] == "error" {
+                    summary.errors += 1;
+                } else {
+                    summary.ok += 1;
+                    latencies.push(latency);
+                }
+            }
+            _ = &mut give_up => break,
+        }
+
+        if Instant::now() >= deadline && pending.is_empty() {
+            break;
+        }
+    }
+
+    summary.timeouts += pending.len();
+    summary.latency_ms = Latencies::from_samples(latencies);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::node::{NodeOptions, NodeState};
+    use crate::services::echo::EchoService;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loadgen_against_echo() {
+        let (client, node_stdin) = tokio::io::duplex(64 * 1024);
+        let (node_stdout, replies) = tokio::io::duplex(64 * 1024);
+        let node = tokio::spawn(NodeState::run_with_io(
+            EchoService,
+            NodeOptions::default(),
+            node_stdin,
+            node_stdout,
+        ));
+
+        let options = LoadgenOptions {
+            rate: 50.0,
+            duration: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let summary = run(&options, replies, client).await.unwrap();
+        node.abort();
+
+        assert!(summary.sent >= 99, "{summary:?}");
+        assert_eq!(summary.ok, summary.sent, "{summary:?}");
+        assert!(summary.is_clean(), "{summary:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loadgen_counts_missing_replies() {
+        let (client, _node_stdin) = tokio::io::duplex(64 * 1024);
+        let (mut node_stdout, replies) = tokio::io::duplex(64 * 1024);
+
+        // Acknowledge init, then never say anything again.
+        tokio::io::AsyncWriteExt::write_all(
+            &mut node_stdout,
+            br#"{"src":"n0","dest":"c0","body":{"type":"init_ok","in_reply_to":0}}
+"#,
+        )
+        .await
+        .unwrap();
+
+        let options = LoadgenOptions {
+            rate: 10.0,
+            duration: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let summary = run(&options, replies, client).await.unwrap();
+
+        assert_eq!(summary.ok, 0);
+        assert_eq!(summary.timeouts, summary.sent);
+        assert!(!summary.is_clean());
+    }
+
+    #[test]
+    fn test_workload_names() {
+        assert_eq!("echo".parse::<Workload>().unwrap(), Workload::Echo);
+        assert_eq!(
+            "unique-ids".parse::<Workload>().unwrap(),
+            Workload::UniqueIds
+        );
+        assert!("txn".parse::<Workload>().is_err());
+    }
+}
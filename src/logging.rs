@@ -0,0 +1,194 @@
+//! Logging setup that can't take the node down with it.
+//!
+//! Maelstrom only reads stdout, so logs are a convenience: if stderr is closed or full, or a
+//! global subscriber is already installed (as when nodes are embedded in the test harness), the
+//! node keeps running without them.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+/// After this many failed writes in a row, the writer stops trying.
+pub const MAX_CONSECUTIVE_FAILURES: usize = 8;
+
+#[derive(Debug, Default)]
+struct WriterState {
+    consecutive_failures: AtomicUsize,
+    dropped_bytes: AtomicU64,
+    disabled: AtomicBool,
+}
+
+/// Wraps a [`MakeWriter`] so that writes never fail. Bytes that could not be written are counted
+/// and dropped, and after [`MAX_CONSECUTIVE_FAILURES`] the writer is disabled for good.
+#[derive(Debug, Clone)]
+pub struct LogWriter<M> {
+    make_writer: M,
+    state: Arc<WriterState>,
+}
+
+impl<M> LogWriter<M> {
+    pub fn new(make_writer: M) -> Self {
+        Self {
+            make_writer,
+            state: Arc::default(),
+        }
+    }
+
+    /// The number of log bytes that were dropped so far.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.state.dropped_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.state.disabled.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for LogWriter<M> {
+    type Writer = GuardedWriter<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        GuardedWriter {
+            inner: (!self.is_disabled()).then(|| self.make_writer.make_writer()),
+            state: &self.state,
+        }
+    }
+}
+
+/// A writer handed out by [`LogWriter`].
+pub struct GuardedWriter<'a, W> {
+    /// `None` once the writer is disabled.
+    inner: Option<W>,
+    state: &'a WriterState,
+}
+
+impl<W> GuardedWriter<'_, W> {
+    fn drop_bytes(&self, len: usize) {
+        self.state
+            .dropped_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+impl<W: Write> Write for GuardedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(inner) = self.inner.as_mut() else {
+            self.drop_bytes(buf.len());
+            return Ok(buf.len());
+        };
+
+        match inner.write(buf) {
+            Ok(written) if written > 0 || buf.is_empty() => {
+                self.state.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(written)
+            }
+            _ => {
+                self.drop_bytes(buf.len());
+                let failures = self
+                    .state
+                    .consecutive_failures
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1;
+                if failures >= MAX_CONSECUTIVE_FAILURES {
+                    self.state.disabled.store(true, Ordering::Relaxed);
+                    self.inner = None;
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.flush().ok();
+        }
+        Ok(())
+    }
+}
+
+/// Install the node's log subscriber, writing to `make_writer`, which should be stderr when running
+/// under Maelstrom.
+///
+/// If a global subscriber can't be installed, e.g. because one already is, logging falls back to
+/// whatever is already installed, or to nothing, and `None` is returned.
+pub fn init<M>(make_writer: M) -> Option<LogWriter<M>>
+where
+    M: for<'a> MakeWriter<'a> + Clone + Send + Sync + 'static,
+{
+    let writer = LogWriter::new(make_writer);
+    let installed = tracing_subscriber::fmt()
+        .with_ansi(true)
+        .with_writer(writer.clone())
+        .with_thread_names(false)
+        .with_file(true)
+        .try_init();
+
+    match installed {
+        Ok(()) => Some(writer),
+        Err(_) => {
+            tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default())
+                .ok();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    #[test]
+    fn test_broken_writer_is_disabled() {
+        let writer = LogWriter::new(|| Broken);
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..MAX_CONSECUTIVE_FAILURES * 2 {
+                tracing::info!("Log line {}", i);
+            }
+        });
+
+        assert!(writer.is_disabled());
+        assert!(writer.dropped_bytes() > 0);
+    }
+
+    #[test]
+    fn test_writer_recovers_from_transient_failures() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let writer = LogWriter::new({
+            let writes = Arc::clone(&writes);
+            move || -> Box<dyn Write> {
+                match writes.fetch_add(1, Ordering::Relaxed) % 2 {
+                    0 => Box::new(Broken),
+                    _ => Box::new(io::sink()),
+                }
+            }
+        });
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES * 2 {
+            writer.make_writer().write_all(b"line\n").unwrap();
+        }
+        assert!(!writer.is_disabled());
+        assert_eq!(writer.dropped_bytes(), 5 * MAX_CONSECUTIVE_FAILURES as u64);
+    }
+}
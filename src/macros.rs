@@ -0,0 +1,309 @@
+//! Macros that generate the boilerplate every service repeats.
+
+/// Define a service's message enum and error enum, along with everything the runner needs to know
+/// about them.
+///
+/// ```ignore
+/// define_service_messages! {
+///     /// The message body of a Maelstrom message.
+///     pub enum CounterMessage {
+///         Error { code: ErrorCode, text: String },
+///         #[mutating]
+///         Add { delta: u64 } => AddOk,
+///         AddOk,
+///         Read => ReadOk,
+///         ReadOk { value: u64 },
+///     }
+///
+///     #[derive(Debug, Snafu)]
+///     pub enum CounterError {
+///         #[code(MalformedRequest)]
+///         #[snafu(display("Missing message ID"))]
+///         MissingMessageId,
+///     }
+/// }
+/// ```
+///
+/// The message enum is (de)serialized with its `type` tag in snake case, and gets:
+/// - `TAGS`, every tag, for [`Node::message_tags`](crate::node::Node::message_tags);
+/// - `tag`, the message's own tag, for [`Node::message_tag`](crate::node::Node::message_tag);
+/// - `is_mutating`, true for the variants marked `#[mutating]`, for
+///   [`Node::is_mutating`](crate::node::Node::is_mutating);
+/// - `reply_tag`, the tag of the reply named after `=>`, for requests;
+/// - `describe`, every message's [`MessageDescription`](crate::describe::MessageDescription).
+///   Variants marked `#[peer]` are described as travelling both ways, since nodes send them to
+///   each other.
+///
+/// The error enum gets a `Whatever` variant added, is convertible into
+/// [`crate::Error`], and gets `code`, the [`ErrorCode`](crate::message::ErrorCode) of each
+/// variant's `#[code(...)]` (`crash` for `Whatever`), for
+/// [`Node::error_code`](crate::node::Node::error_code), and `CODES`, every one of them. It has to derive `Snafu` itself, since
+/// snafu looks up the fields named in display strings where the derive is written.
+///
+/// Variants and fields can't carry attributes or doc comments. Services that need them, like
+/// broadcast, write these by hand.
+macro_rules! define_service_messages {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$flag:ident])*
+                $variant:ident $({ $($field:ident : $ty:ty),* $(,)? })? $(=> $reply:ident)?
+            ),* $(,)?
+        }
+
+        $(#[$error_meta:meta])*
+        $error_vis:vis enum $error:ident {
+            $(
+                #[code($code:ident)]
+                $(#[$($variant_meta:tt)*])*
+                $error_variant:ident $({ $($error_field:ident : $error_ty:ty),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        paste::paste! {
+            $(#[$meta])*
+            #[derive(Debug, serde::Serialize, serde::Deserialize)]
+            #[serde(tag = "type", rename_all = "snake_case")]
+            $vis enum $name {
+                $($variant $({ $($field: $ty),* })?,)*
+            }
+
+            impl $name {
+                /// The `type` tag of every message.
+                pub const TAGS: &'static [&'static str] =
+                    &[$(stringify!([<$variant:snake>])),*];
+
+                /// The message's `type` tag.
+                pub fn tag(&self) -> &'static str {
+                    match self {
+                        $(Self::$variant { .. } => stringify!([<$variant:snake>]),)*
+                    }
+                }
+
+                /// Whether the message changes the service's state.
+                pub fn is_mutating(&self) -> bool {
+                    match self {
+                        $(
+                            Self::$variant { .. } => {
+                                false $(|| define_service_messages!(@mutating $flag))*
+                            }
+                        )*
+                    }
+                }
+
+                /// The `type` tag of the reply a request expects, or `None` if the message isn't
+                /// a request.
+                pub fn reply_tag(&self) -> Option<&'static str> {
+                    // Fails to compile if a reply names no variant.
+                    $($(let _ = matches!(self, Self::$reply { .. });)?)*
+                    match self {
+                        $(
+                            Self::$variant { .. } => {
+                                None $(.or(Some(stringify!([<$reply:snake>]))))?
+                            }
+                        )*
+                    }
+                }
+
+                /// Every message's description, see [`crate::describe`].
+                pub fn describe() -> Vec<$crate::describe::MessageDescription> {
+                    let replies: &[&str] = &[$($(stringify!([<$reply:snake>]),)?)*];
+                    vec![$(
+                        $crate::describe::MessageDescription::new(
+                            stringify!([<$variant:snake>]),
+                            vec![$($(
+                                $crate::describe::FieldDescription::of::<$ty>(stringify!($field)),
+                            )*)?],
+                            None $(.or(Some(stringify!([<$reply:snake>]))))?,
+                            false $(|| define_service_messages!(@peer $flag))*,
+                            replies,
+                        ),
+                    )*]
+                }
+            }
+        }
+
+        $(#[$error_meta])*
+        $error_vis enum $error {
+            $(
+                $(#[$($variant_meta)*])*
+                $error_variant $({ $($error_field: $error_ty),* })?,
+            )*
+            #[snafu(whatever, display("{}", message))]
+            Whatever {
+                message: String,
+                #[snafu(source(from(Box<dyn std::error::Error + Send + Sync + 'static>, Some)))]
+                source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
+            },
+        }
+
+        impl From<$error> for $crate::Error<$error> {
+            fn from(source: $error) -> Self {
+                $crate::Error::Node { source }
+            }
+        }
+
+        impl $error {
+            /// Every code [`Self::code`] returns.
+            pub const CODES: &'static [$crate::message::ErrorCode] = &[
+                $($crate::message::ErrorCode::$code,)*
+                $crate::message::ErrorCode::Crash,
+            ];
+
+            /// The error code a client is sent when its request fails with this error.
+            pub fn code(&self) -> $crate::message::ErrorCode {
+                match self {
+                    $(Self::$error_variant { .. } => $crate::message::ErrorCode::$code,)*
+                    Self::Whatever { .. } => $crate::message::ErrorCode::Crash,
+                }
+            }
+        }
+    };
+
+    (@mutating mutating) => {
+        true
+    };
+    (@mutating peer) => {
+        false
+    };
+    (@peer peer) => {
+        true
+    };
+    (@peer mutating) => {
+        false
+    };
+}
+
+pub(crate) use define_service_messages;
+
+#[cfg(test)]
+mod tests {
+    use crate::message::ErrorCode;
+
+    define_service_messages! {
+        enum KvMessage {
+            Error { code: ErrorCode, text: String },
+            Read { key: u64 } => ReadOk,
+            ReadOk { value: u64 },
+            #[mutating]
+            Write { key: u64, value: u64 } => WriteOk,
+            WriteOk,
+            #[mutating]
+            CompareAndSwap { key: u64, from: u64, to: u64 } => CompareAndSwapOk,
+            CompareAndSwapOk,
+        }
+
+        #[derive(Debug, snafu::Snafu)]
+        enum KvError {
+            #[code(KeyDoesNotExist)]
+            #[snafu(display("No such key {key}"))]
+            NoSuchKey { key: u64 },
+            #[code(MalformedRequest)]
+            #[snafu(display("Missing message ID"))]
+            MissingMessageId,
+        }
+    }
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(
+            KvMessage::TAGS,
+            [
+                "error",
+                "read",
+                "read_ok",
+                "write",
+                "write_ok",
+                "compare_and_swap",
+                "compare_and_swap_ok"
+            ]
+        );
+
+        // The tags are the ones serde uses.
+        let messages = [
+            KvMessage::Error {
+                code: ErrorCode::Crash,
+                text: String::new(),
+            },
+            KvMessage::Read { key: 1 },
+            KvMessage::ReadOk { value: 1 },
+            KvMessage::Write { key: 1, value: 2 },
+            KvMessage::WriteOk,
+            KvMessage::CompareAndSwap {
+                key: 1,
+                from: 2,
+                to: 3,
+            },
+            KvMessage::CompareAndSwapOk,
+        ];
+        for (message, tag) in messages.iter().zip(KvMessage::TAGS) {
+            let json = serde_json::to_value(message).unwrap();
+            assert_eq!(json["type"], *tag);
+            assert_eq!(message.tag(), *tag);
+            serde_json::from_value::<KvMessage>(json).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_annotations() {
+        let read = KvMessage::Read { key: 1 };
+        let write = KvMessage::Write { key: 1, value: 2 };
+        let cas = KvMessage::CompareAndSwap {
+            key: 1,
+            from: 2,
+            to: 3,
+        };
+
+        assert!(!read.is_mutating());
+        assert!(write.is_mutating());
+        assert!(cas.is_mutating());
+        assert!(!KvMessage::WriteOk.is_mutating());
+
+        assert_eq!(read.reply_tag(), Some("read_ok"));
+        assert_eq!(write.reply_tag(), Some("write_ok"));
+        assert_eq!(cas.reply_tag(), Some("compare_and_swap_ok"));
+        assert_eq!(KvMessage::WriteOk.reply_tag(), None);
+        assert_eq!(KvMessage::ReadOk { value: 1 }.reply_tag(), None);
+
+        let described = KvMessage::describe();
+        assert_eq!(described.len(), KvMessage::TAGS.len());
+        let cas = &described[5];
+        assert_eq!(cas.tag, "compare_and_swap");
+        assert_eq!(cas.reply, Some("compare_and_swap_ok"));
+        assert_eq!(
+            cas.fields
+                .iter()
+                .map(|field| field.name)
+                .collect::<Vec<_>>(),
+            ["key", "from", "to"]
+        );
+        assert_eq!(described[6].direction, crate::describe::Direction::Outbound);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let no_such_key = KvError::NoSuchKey { key: 3 };
+        assert_eq!(no_such_key.to_string(), "No such key 3");
+        assert_eq!(no_such_key.code(), ErrorCode::KeyDoesNotExist);
+        assert_eq!(
+            KvError::MissingMessageId.code(),
+            ErrorCode::MalformedRequest
+        );
+
+        let whatever = snafu::FromString::without_source("oops".to_owned());
+        assert!(matches!(whatever, KvError::Whatever { .. }));
+        assert_eq!(whatever.code(), ErrorCode::Crash);
+        assert_eq!(
+            KvError::CODES,
+            [
+                ErrorCode::KeyDoesNotExist,
+                ErrorCode::MalformedRequest,
+                ErrorCode::Crash
+            ]
+        );
+
+        let e: crate::Error<KvError> = KvError::MissingMessageId.into();
+        assert!(matches!(e, crate::Error::Node { .. }));
+    }
+}
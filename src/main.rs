@@ -1,31 +1,190 @@
-use services::broadcast::BroadcastService;
-use snafu::Report;
+use std::path::{Path, PathBuf};
 
-mod async_dashmap;
-mod tokio_serde;
+use fly_systems_challenge::{
+    config::{self, Config, Configurable},
+    describe, logging,
+    services::{
+        broadcast::BroadcastService, counter::CounterService, echo::EchoService,
+        proxy::ProxiedGSetService, unique_ids::UniqueIdService,
+    },
+};
+use snafu::{OptionExt as _, Report, ResultExt as _, Whatever};
 
-mod error;
-mod kv;
-mod message;
-mod node;
-mod services;
+/// The Maelstrom workloads the node can serve, each by a service of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Workload {
+    Echo,
+    UniqueIds,
+    #[default]
+    Broadcast,
+    Counter,
+    /// Maelstrom's g-set, with any other message answered by the child process that the config
+    /// file's `proxy` section names, see [`ProxiedGSetService`].
+    Proxy,
+}
+
+impl Workload {
+    const NAMES: [&'static str; 5] = ["echo", "unique-ids", "broadcast", "counter", "proxy"];
+
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "echo" => Self::Echo,
+            "unique-ids" => Self::UniqueIds,
+            "broadcast" => Self::Broadcast,
+            "counter" => Self::Counter,
+            "proxy" => Self::Proxy,
+            _ => return None,
+        })
+    }
+
+    /// The workload named on the command line, broadcast if none or an unknown one is.
+    fn from_arg(name: Option<&str>) -> Self {
+        let Some(name) = name else {
+            return Self::default();
+        };
+        Self::parse(name).unwrap_or_else(|| {
+            tracing::error!(
+                "No workload named {name}, expected one of {:?}. Serving broadcast",
+                Self::NAMES
+            );
+            Self::default()
+        })
+    }
+
+    /// Run the workload's service on stdin and stdout until the input ends.
+    async fn run(self, config: &Config) {
+        match self {
+            Self::Echo => run::<EchoService>(config).await,
+            Self::UniqueIds => run::<UniqueIdService>(config).await,
+            Self::Broadcast => run::<BroadcastService>(config).await,
+            Self::Counter => run::<CounterService>(config).await,
+            Self::Proxy => run::<ProxiedGSetService>(config).await,
+        }
+    }
+}
+
+async fn run<S: Configurable>(config: &Config) {
+    if let Err(e) = config::run::<S>(config).await {
+        tracing::error!("{}", Report::from_error(e));
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    /// The workload to serve, see [`Workload`]. Broadcast if not given.
+    workload: Option<String>,
+    /// `--config <path>`, see [`config`] for the file's format.
+    config: Option<PathBuf>,
+    /// `--describe <service>`: print the messages of the service running that workload instead of
+    /// running, see [`describe`].
+    describe: Option<String>,
+}
 
-pub use error::*;
+fn parse_args() -> Result<Args, Whatever> {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let value = args.next().whatever_context("Missing value for --config")?;
+                parsed.config = Some(PathBuf::from(value));
+            }
+            "--describe" => {
+                let value = args
+                    .next()
+                    .whatever_context("Missing value for --describe")?;
+                parsed.describe = Some(value);
+            }
+            _ if arg.starts_with("--") || parsed.workload.is_some() => {
+                snafu::whatever!("Unknown argument {arg}")
+            }
+            _ => parsed.workload = Some(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Read the config file at `path`, if any.
+fn load_config(path: Option<&Path>) -> Result<Config, Whatever> {
+    match path {
+        Some(path) => Config::load(path).whatever_context("Failed to load config"),
+        None => Ok(Config::default()),
+    }
+}
 
-#[allow(unused)]
-use services::{echo::EchoService, unique_ids::UniqueIdService};
+/// Print the description of `service` as JSON.
+fn print_description(service: &str) -> Result<(), Whatever> {
+    let description = describe::service(service).with_whatever_context(|| {
+        format!(
+            "No service named {service}, expected one of {:?}",
+            describe::SERVICES
+        )
+    })?;
+    let json = serde_json::to_string_pretty(&description)
+        .whatever_context("Failed to serialize description")?;
+    println!("{json}");
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_ansi(true)
-        // Output logs to stderr to conform with Maelstrom spec.
-        .with_writer(std::io::stderr)
-        .with_thread_names(false)
-        .with_file(true)
-        .init();
-
-    if let Err(e) = node::run(BroadcastService::default()).await {
-        tracing::error!("{}", Report::from_error(e));
+    // Logs go to stderr to conform with Maelstrom spec. The node runs without them if that fails.
+    logging::init(std::io::stderr);
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            tracing::error!("{}", Report::from_error(e));
+            return;
+        }
+    };
+    if let Some(service) = &args.describe {
+        if let Err(e) = print_description(service) {
+            tracing::error!("{}", Report::from_error(e));
+        }
+        return;
+    }
+
+    let config = match load_config(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", Report::from_error(e));
+            return;
+        }
+    };
+    Workload::from_arg(args.workload.as_deref())
+        .run(&config)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_workload() {
+        let parsed = Workload::NAMES.map(Workload::parse);
+        assert_eq!(
+            parsed,
+            [
+                Some(Workload::Echo),
+                Some(Workload::UniqueIds),
+                Some(Workload::Broadcast),
+                Some(Workload::Counter),
+                Some(Workload::Proxy),
+            ]
+        );
+        assert_eq!(Workload::parse("counter"), Some(Workload::Counter));
+        assert_eq!(Workload::parse("g-counter"), None);
+        assert_eq!(Workload::parse("unique_ids"), None);
+        assert_eq!(Workload::parse(""), None);
+    }
+
+    #[test]
+    fn test_workload_defaults_to_broadcast() {
+        assert_eq!(Workload::from_arg(None), Workload::Broadcast);
+        assert_eq!(Workload::from_arg(Some("unknown")), Workload::Broadcast);
+        assert_eq!(Workload::from_arg(Some("echo")), Workload::Echo);
+        assert_eq!(Workload::from_arg(Some("counter")), Workload::Counter);
     }
 }
@@ -1,9 +1,204 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::Arc,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use crate::audit::{AuditReport, StateDigest};
+use crate::compression::CompressedEnvelope;
 
 pub type MessageId = u64;
 
+/// The part of a node a message was sent by, kept in the top byte of the message's ID so that an
+/// ID read in a log says where it came from. Each subsystem numbers its messages on its own, from
+/// zero, in the other bits, so IDs stay unique. Tasks pick theirs with
+/// [`crate::node::in_subsystem`]; peers and Maelstrom still just see a number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    /// The service's handlers and timers, replies to clients included. Tagged zero, so a service
+    /// that marks nothing numbers its messages 0, 1, 2, ...
+    #[default]
+    Service,
+    /// The runner's own messages, like `init_ok`, capabilities, audits and the answers to its
+    /// requests.
+    Runner,
+    /// Gossip rounds and forwards.
+    Gossip,
+}
+
+impl Subsystem {
+    pub const ALL: [Self; 3] = [Self::Service, Self::Runner, Self::Gossip];
+
+    /// Where the tag starts in an ID.
+    const SHIFT: u32 = 56;
+
+    /// The ID of this subsystem's `n`th message.
+    pub fn id(self, n: u64) -> MessageId {
+        (self as u64) << Self::SHIFT | n & ((1 << Self::SHIFT) - 1)
+    }
+
+    /// The subsystem that sent message `id`, or `None` if its tag is none of ours. Only
+    /// meaningful for IDs this node picked, e.g. the `in_reply_to` of a reply to it.
+    pub fn of(id: MessageId) -> Option<Self> {
+        Self::ALL.get((id >> Self::SHIFT) as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Service => "service",
+            Self::Runner => "runner",
+            Self::Gossip => "gossip",
+        }
+    }
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A Maelstrom error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize_repr, Deserialize_repr)]
+#[serde(rename_all = "snake_case")]
+#[repr(u64)]
+pub enum ErrorCode {
+    Timeout = 0,
+    NodeNotFound = 1,
+    NotSupported = 10,
+    TemporarilyUnavailable = 11,
+    MalformedRequest = 12,
+    Crash = 13,
+    Abort = 14,
+    KeyDoesNotExist = 20,
+    KeyAlreadyExists = 21,
+    PreconditionFailed = 22,
+    TxnConflict = 30,
+}
+
+/// An arbitrary JSON value, shared rather than copied when cloned. For payloads a service passes
+/// along untouched, like an echo, which may be large and deeply nested. On the wire it is the
+/// value itself, and values compare by content.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ArcValue(Arc<serde_json::Value>);
+
+impl ArcValue {
+    pub fn new(value: serde_json::Value) -> Self {
+        Self(Arc::new(value))
+    }
+
+    /// Whether `self` and `other` share the same value, rather than equal ones.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl std::ops::Deref for ArcValue {
+    type Target = serde_json::Value;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<serde_json::Value> for ArcValue {
+    fn from(value: serde_json::Value) -> Self {
+        Self::new(value)
+    }
+}
+
+impl PartialEq for ArcValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || self.0 == other.0
+    }
+}
+
+impl Eq for ArcValue {}
+
+/// Serialize a set in sorted order, so that the same set always produces the same bytes whatever
+/// order it was built in, e.g. for diffing the output of two runs. Use with `serialize_with`.
+pub fn serialize_set<T, S>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize + Ord,
+    S: Serializer,
+{
+    serializer.collect_seq(set.iter().collect::<BTreeSet<_>>())
+}
+
+/// Check the body of a client request against `T`, a strict mirror of one of the service's
+/// request types, for [`Node::check_strict`](crate::node::Node::check_strict).
+///
+/// `T` describes the fields besides `type` and `msg_id`, and should deny unknown fields. Unlike
+/// the lenient envelope, `msg_id` must be an integer. Problems are reported with the JSON path
+/// they were found at, e.g. `topology.n2[0]: invalid type: ...`.
+pub fn check_strict<T: DeserializeOwned>(body: &serde_json::Value) -> Result<(), String> {
+    let mut fields = body
+        .as_object()
+        .cloned()
+        .ok_or_else(|| format!("invalid type: {body}, expected an object"))?;
+    fields.remove("type");
+    match fields.remove("msg_id") {
+        Some(id) if id.is_u64() => {}
+        Some(id) => return Err(format!("msg_id: invalid type: {id}, expected an integer")),
+        None => return Err("missing field `msg_id`".to_owned()),
+    }
+
+    serde_path_to_error::deserialize::<_, T>(serde_json::Value::Object(fields))
+        .map(drop)
+        .map_err(|e| match e.path().iter().next() {
+            Some(_) => format!("{}: {}", e.path(), e.inner()),
+            None => e.inner().to_string(),
+        })
+}
+
+/// Deserialize an optional message ID leniently, accepting `null` and integers encoded as strings
+/// as well as plain integers.
+fn deserialize_message_id<'de, D>(deserializer: D) -> Result<Option<MessageId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum LenientId {
+        Int(MessageId),
+        Str(String),
+    }
+
+    match Option::<LenientId>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(LenientId::Int(id)) => Ok(Some(id)),
+        Some(LenientId::Str(id)) => id
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid message ID {:?}", id))),
+    }
+}
+
+/// What a node tells its peers about itself after init, so that peers running a different build
+/// can be spotted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The message types the node's service understands.
+    pub tags: Vec<String>,
+    pub version: String,
+    /// Whether the node reads `gossip_z` messages, see [`crate::compression`]. Missing from
+    /// nodes built before compression existed.
+    #[serde(default)]
+    pub compression: bool,
+    /// Whether the node counts the `peer_seq`s on messages from its peers, see
+    /// [`MessageBody::seq`]. Missing from nodes built before they existed.
+    #[serde(default)]
+    pub sequence_numbers: bool,
+    /// Whether the node reads the timestamps on messages from its peers, see [`crate::clock`].
+    /// Missing from nodes built before they existed.
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum DataOrInit<Data> {
@@ -12,6 +207,81 @@ pub enum DataOrInit<Data> {
         node_id: String,
         node_ids: Vec<String>,
     },
+    /// Turn read-only mode on or off, see [`crate::node::Node::is_mutating`]. Handled by the
+    /// runner rather than the service.
+    SetReadOnly {
+        read_only: bool,
+    },
+    SetReadOnlyOk,
+    /// Change the service's runtime parameters, see [`crate::node::Node::reconfigure`]. Handled
+    /// by the runner.
+    Tune {
+        tune: serde_json::Value,
+    },
+    TuneOk,
+    /// Promote a standby node so that it serves clients, see [`crate::standby`]. Handled by the
+    /// runner.
+    Promote,
+    PromoteOk,
+    /// Sent by a standby to the node it stands in for, to check that it is alive. Handled by the
+    /// runner.
+    Heartbeat,
+    HeartbeatOk,
+    /// Sent to every peer once after init. Handled by the runner.
+    Capabilities(Capabilities),
+    CapabilitiesOk,
+    /// The cluster now consists of `node_ids`. Maelstrom clusters never change, so only harnesses
+    /// send this, see [`crate::node::Node::on_membership_change`]. Handled by the runner.
+    MembershipChange {
+        node_ids: Vec<String>,
+    },
+    MembershipChangeOk,
+    /// Start draining: audit every peer before the node is stopped, see [`crate::audit`]. Only
+    /// harnesses send this. Handled by the runner.
+    Drain,
+    DrainOk {
+        /// `None` if audits are off, or the service has no digest.
+        audit: Option<AuditReport>,
+    },
+    /// The sender's state, answered with ours. Handled by the runner.
+    Audit(StateDigest),
+    AuditOk(StateDigest),
+    /// Gossip now rather than at the next round, see [`crate::node::Node::flush_gossip`]. Only
+    /// harnesses and people debugging send this. Handled by the runner.
+    FlushGossip {
+        /// Send every peer everything, as anti-entropy would, rather than what it is missing.
+        #[serde(default)]
+        full: bool,
+    },
+    FlushGossipOk {
+        /// How many values were sent to each peer.
+        sent: BTreeMap<String, u64>,
+    },
+    /// Log the messages the node handled last, see [`crate::history`]. Only people debugging send
+    /// this. Handled by the runner.
+    DumpHistory,
+    DumpHistoryOk {
+        /// How many messages were logged.
+        dumped: usize,
+    },
+    /// A compressed body, unwrapped by the runner before dispatch. See [`crate::compression`].
+    #[serde(rename = "gossip_z")]
+    GossipZ(CompressedEnvelope),
+    /// An error reply from the runner. Never deserialized, so that errors addressed to the
+    /// service still reach it.
+    #[serde(skip_deserializing)]
+    Error {
+        code: ErrorCode,
+        text: String,
+    },
+    /// A standby's error reply to a client, naming the node to ask instead. Never deserialized,
+    /// like [`DataOrInit::Error`].
+    #[serde(rename = "error", skip_deserializing)]
+    StandbyError {
+        code: ErrorCode,
+        text: String,
+        active: String,
+    },
     #[serde(untagged)]
     Data(Data),
 }
@@ -21,6 +291,59 @@ impl<Data: PartialEq> std::cmp::PartialEq for DataOrInit<Data> {
         match (self, other) {
             (DataOrInit::Data(a), DataOrInit::Data(b)) => a == b,
             (DataOrInit::InitOk, DataOrInit::InitOk) => true,
+            (DataOrInit::SetReadOnlyOk, DataOrInit::SetReadOnlyOk) => true,
+            (DataOrInit::Tune { tune: l }, DataOrInit::Tune { tune: r }) => l == r,
+            (DataOrInit::TuneOk, DataOrInit::TuneOk) => true,
+            (DataOrInit::Promote, DataOrInit::Promote) => true,
+            (DataOrInit::PromoteOk, DataOrInit::PromoteOk) => true,
+            (DataOrInit::Heartbeat, DataOrInit::Heartbeat) => true,
+            (DataOrInit::HeartbeatOk, DataOrInit::HeartbeatOk) => true,
+            (DataOrInit::Capabilities(l), DataOrInit::Capabilities(r)) => l == r,
+            (DataOrInit::CapabilitiesOk, DataOrInit::CapabilitiesOk) => true,
+            (
+                DataOrInit::MembershipChange { node_ids: l },
+                DataOrInit::MembershipChange { node_ids: r },
+            ) => l == r,
+            (DataOrInit::MembershipChangeOk, DataOrInit::MembershipChangeOk) => true,
+            (DataOrInit::Drain, DataOrInit::Drain) => true,
+            (DataOrInit::DrainOk { audit: l }, DataOrInit::DrainOk { audit: r }) => l == r,
+            (DataOrInit::Audit(l), DataOrInit::Audit(r)) => l == r,
+            (DataOrInit::AuditOk(l), DataOrInit::AuditOk(r)) => l == r,
+            (DataOrInit::FlushGossip { full: l }, DataOrInit::FlushGossip { full: r }) => l == r,
+            (DataOrInit::FlushGossipOk { sent: l }, DataOrInit::FlushGossipOk { sent: r }) => {
+                l == r
+            }
+            (DataOrInit::DumpHistory, DataOrInit::DumpHistory) => true,
+            (DataOrInit::DumpHistoryOk { dumped: l }, DataOrInit::DumpHistoryOk { dumped: r }) => {
+                l == r
+            }
+            (DataOrInit::GossipZ(l), DataOrInit::GossipZ(r)) => l == r,
+            (
+                DataOrInit::SetReadOnly { read_only: l },
+                DataOrInit::SetReadOnly { read_only: r },
+            ) => l == r,
+            (
+                DataOrInit::Error {
+                    code: l_code,
+                    text: l_text,
+                },
+                DataOrInit::Error {
+                    code: r_code,
+                    text: r_text,
+                },
+            ) => l_code == r_code && l_text == r_text,
+            (
+                DataOrInit::StandbyError {
+                    code: l_code,
+                    text: l_text,
+                    active: l_active,
+                },
+                DataOrInit::StandbyError {
+                    code: r_code,
+                    text: r_text,
+                    active: r_active,
+                },
+            ) => l_code == r_code && l_text == r_text && l_active == r_active,
             (
                 DataOrInit::Init {
                     node_id: l_id,
@@ -36,13 +359,75 @@ impl<Data: PartialEq> std::cmp::PartialEq for DataOrInit<Data> {
     }
 }
 
+impl<Data> DataOrInit<Data> {
+    /// The `type` of a runner message, or `None` for the service's own, whose tags only the
+    /// service knows (see [`crate::node::Node::message_tag`]).
+    pub fn runner_tag(&self) -> Option<&'static str> {
+        Some(match self {
+            DataOrInit::InitOk => "init_ok",
+            DataOrInit::Init { .. } => "init",
+            DataOrInit::SetReadOnly { .. } => "set_read_only",
+            DataOrInit::SetReadOnlyOk => "set_read_only_ok",
+            DataOrInit::Tune { .. } => "tune",
+            DataOrInit::TuneOk => "tune_ok",
+            DataOrInit::Promote => "promote",
+            DataOrInit::PromoteOk => "promote_ok",
+            DataOrInit::Heartbeat => "heartbeat",
+            DataOrInit::HeartbeatOk => "heartbeat_ok",
+            DataOrInit::Capabilities(_) => "capabilities",
+            DataOrInit::CapabilitiesOk => "capabilities_ok",
+            DataOrInit::MembershipChange { .. } => "membership_change",
+            DataOrInit::MembershipChangeOk => "membership_change_ok",
+            DataOrInit::Drain => "drain",
+            DataOrInit::DrainOk { .. } => "drain_ok",
+            DataOrInit::Audit(_) => "audit",
+            DataOrInit::AuditOk(_) => "audit_ok",
+            DataOrInit::FlushGossip { .. } => "flush_gossip",
+            DataOrInit::FlushGossipOk { .. } => "flush_gossip_ok",
+            DataOrInit::DumpHistory => "dump_history",
+            DataOrInit::DumpHistoryOk { .. } => "dump_history_ok",
+            DataOrInit::GossipZ(_) => "gossip_z",
+            DataOrInit::Error { .. } | DataOrInit::StandbyError { .. } => "error",
+            DataOrInit::Data(_) => return None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct MessageBody<Data> {
-    #[serde(rename = "msg_id")]
+    #[serde(
+        rename = "msg_id",
+        default,
+        deserialize_with = "deserialize_message_id"
+    )]
     pub id: Option<MessageId>,
     /// The ID of the message this message is in reply to.
-    #[serde(rename = "in_reply_to")]
+    #[serde(
+        rename = "in_reply_to",
+        default,
+        deserialize_with = "deserialize_message_id"
+    )]
     pub re: Option<MessageId>,
+    /// Identifies the client request that caused this message, across every node it touches. Not
+    /// part of Maelstrom's protocol, see [`crate::node::trace_id`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<u64>,
+    /// How many messages the sender had sent to this peer, this one included, if it numbers them.
+    /// Not part of Maelstrom's protocol, see [`crate::node::NodeOptions::sequence_peer_messages`].
+    #[serde(rename = "peer_seq", default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// When the sender sent the message, by its clock, if it times its messages to this peer.
+    /// Not part of Maelstrom's protocol, see [`crate::clock`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at_ms: Option<u64>,
+    /// When the request this message replies to was sent, by the requester's clock, and received,
+    /// by the sender's. Not part of Maelstrom's protocol, see [`crate::clock`].
+    #[serde(
+        rename = "request_times_ms",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_times: Option<(u64, u64)>,
     #[serde(flatten)]
     pub data: Data,
 }
@@ -60,20 +445,56 @@ pub struct Message<Data> {
     pub body: MessageBody<Data>,
 }
 
+impl<Data> Message<Data> {
+    /// The message without its data, and the data, so that it can be handled as another type.
+    pub fn split(self) -> (Message<()>, Data) {
+        let Message { src, dest, body } = self;
+        let body_only = MessageBody {
+            id: body.id,
+            re: body.re,
+            trace_id: body.trace_id,
+            seq: body.seq,
+            sent_at_ms: body.sent_at_ms,
+            request_times: body.request_times,
+            data: (),
+        };
+        (
+            Message {
+                src,
+                dest,
+                body: body_only,
+            },
+            body.data,
+        )
+    }
+}
+
+impl Message<()> {
+    /// The message, carrying `data`. Undoes [`Message::split`].
+    pub fn with_data<Data>(self, data: Data) -> Message<Data> {
+        let Message { src, dest, body } = self;
+        Message {
+            src,
+            dest,
+            body: MessageBody {
+                id: body.id,
+                re: body.re,
+                trace_id: body.trace_id,
+                seq: body.seq,
+                sent_at_ms: body.sent_at_ms,
+                request_times: body.request_times,
+                data,
+            },
+        }
+    }
+}
+
 impl<Data> Message<DataOrInit<Data>> {
     pub fn into_data<E: std::error::Error + Send + Sync + 'static>(
         self,
     ) -> crate::Result<Message<Data>, E> {
-        match self.body.data {
-            DataOrInit::Data(data) => Ok(Message {
-                src: self.src,
-                dest: self.dest,
-                body: MessageBody {
-                    id: self.body.id,
-                    re: self.body.re,
-                    data,
-                },
-            }),
+        match self.split() {
+            (message, DataOrInit::Data(data)) => Ok(message.with_data(data)),
             _ => Err(crate::Error::Internal {
                 source: crate::node::InternalError::UnexpectedInit,
             }),
@@ -129,6 +550,10 @@ mod tests {
             body: MessageBody {
                 id: Some(1),
                 re: None,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
                 data: MessageData::Test { value: 5 },
             },
         };
@@ -201,6 +626,10 @@ mod tests {
                 body: MessageBody {
                     id: Some(1),
                     re: Some(2),
+                    trace_id: None,
+                    seq: None,
+                    sent_at_ms: None,
+                    request_times: None,
                     data: DataOrInit::Data(MessageData::Test { value: 5 }),
                 },
             }
@@ -232,6 +661,10 @@ mod tests {
                 body: MessageBody {
                     id: Some(1),
                     re: Some(2),
+                    trace_id: None,
+                    seq: None,
+                    sent_at_ms: None,
+                    request_times: None,
                     data: DataOrInit::Init {
                         node_id: "a".to_string(),
                         node_ids: vec!["a".to_string(), "b".to_string()],
@@ -246,4 +679,131 @@ mod tests {
             data.replace(" ", "").replace("\n", "").replace("\t", "")
         );
     }
+
+    #[test]
+    fn test_lenient_message_ids() {
+        let parse = |body: &str| serde_json::from_str::<MessageBody<DataOrInit<u32>>>(body);
+
+        let body = parse(r#"{"type":"init_ok","msg_id":1,"in_reply_to":2}"#).unwrap();
+        assert_eq!((body.id, body.re), (Some(1), Some(2)));
+
+        let body = parse(r#"{"type":"init_ok","msg_id":"3","in_reply_to":" 4 "}"#).unwrap();
+        assert_eq!((body.id, body.re), (Some(3), Some(4)));
+
+        let body = parse(r#"{"type":"init_ok","msg_id":null}"#).unwrap();
+        assert_eq!((body.id, body.re), (None, None));
+
+        assert!(parse(r#"{"type":"init_ok","msg_id":"one"}"#).is_err());
+    }
+
+    #[test]
+    fn test_arc_value() {
+        #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum MessageData {
+            Echo { echo: ArcValue },
+        }
+
+        // Through the envelope and the tagged enum, out as it came in.
+        let json = r#"{"src":"a","dest":"b","body":{"type":"echo","echo":{"nested":[1,{"deep":null}]},"msg_id":1}}"#;
+        let message = serde_json::from_str::<Message<DataOrInit<MessageData>>>(json).unwrap();
+        let DataOrInit::Data(MessageData::Echo { echo }) = &message.body.data else {
+            panic!("not an echo: {message:?}");
+        };
+        assert_eq!(echo["nested"][1]["deep"], serde_json::Value::Null);
+        assert_eq!(
+            serde_json::to_string(&message.body.data).unwrap(),
+            r#"{"type":"echo","echo":{"nested":[1,{"deep":null}]}}"#
+        );
+
+        let copy = echo.clone();
+        assert!(copy.ptr_eq(echo));
+        let equal = ArcValue::new(serde_json::json!({ "nested": [1, { "deep": null }] }));
+        assert!(!equal.ptr_eq(echo));
+        assert_eq!(&equal, echo);
+    }
+
+    /// The edge cases the fuzz target's seeds start from (see `fuzz/`), with a `Data` type that
+    /// accepts anything, so that only the envelope itself can fail.
+    #[test]
+    fn test_hostile_envelopes_fail_cleanly() {
+        let parse = |json: &[u8]| {
+            serde_json::from_slice::<Message<DataOrInit<serde_json::Value>>>(json).map(drop)
+        };
+        let envelope = |body: &str| format!(r#"{{"src":"c1","dest":"n1","body":{body}}}"#);
+        let nested = |depth| {
+            let body = format!(
+                r#"{{"type":"echo","echo":{}{}}}"#,
+                "[".repeat(depth),
+                "]".repeat(depth)
+            );
+            envelope(&body)
+        };
+
+        // Untagged bodies are buffered before matching, which must not lift serde_json's
+        // recursion limit.
+        assert!(parse(nested(100).as_bytes()).is_ok());
+        assert!(parse(nested(10_000).as_bytes()).is_err());
+
+        for body in [
+            r#"{"type":"read","msg_id":18446744073709551616}"#,
+            r#"{"type":"read","msg_id":-1}"#,
+            r#"{"type":"read","msg_id":1e400}"#,
+            r#"{"type":"read","msg_id":"\ud800"}"#,
+        ] {
+            assert!(parse(envelope(body).as_bytes()).is_err(), "{body}");
+        }
+        assert!(parse(b"{\"src\":\"c\xff\",\"dest\":\"n1\",\"body\":{}}").is_err());
+    }
+
+    #[test]
+    fn test_check_strict() {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Add {
+            delta: u64,
+            tags: Vec<String>,
+        }
+        let check = |body: serde_json::Value| check_strict::<Add>(&body);
+
+        assert_eq!(
+            check(serde_json::json!({ "type": "add", "msg_id": 1, "delta": 2, "tags": [] })),
+            Ok(())
+        );
+        assert_eq!(
+            check(serde_json::json!({ "type": "add", "delta": 2, "tags": [] })),
+            Err("missing field `msg_id`".to_owned())
+        );
+        assert!(
+            check(serde_json::json!({ "type": "add", "msg_id": "1", "delta": 2, "tags": [] }))
+                .unwrap_err()
+                .starts_with("msg_id: ")
+        );
+        assert!(
+            check(serde_json::json!({ "type": "add", "msg_id": 1, "delat": 2, "tags": [] }))
+                .unwrap_err()
+                .starts_with("delat: unknown field")
+        );
+        assert!(check(
+            serde_json::json!({ "type": "add", "msg_id": 1, "delta": 2, "tags": ["a", 1] })
+        )
+        .unwrap_err()
+        .starts_with("tags[1]: invalid type"));
+    }
+
+    #[test]
+    fn test_subsystem_ids() {
+        for subsystem in Subsystem::ALL {
+            for n in [0, 1, 4812] {
+                assert_eq!(Subsystem::of(subsystem.id(n)), Some(subsystem));
+            }
+        }
+        // A node that marks nothing numbers its messages as it always has.
+        assert_eq!(Subsystem::Service.id(4812), 4812);
+        assert_ne!(Subsystem::Gossip.id(4812), Subsystem::Runner.id(4812));
+        // A counter that runs out of bits wraps rather than changing the tag.
+        assert_eq!(Subsystem::Gossip.id(1 << 56), Subsystem::Gossip.id(0));
+        assert_eq!(Subsystem::of(u64::MAX), None);
+    }
 }
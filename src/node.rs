@@ -1,21 +1,55 @@
 use std::{
+    collections::BTreeMap,
     future::Future,
+    io::IsTerminal as _,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
+    time::Duration,
 };
 
-use futures::SinkExt as _;
+use futures::future::Either;
+use rand::Rng as _;
 use serde::{Deserialize, Serialize};
-use snafu::Snafu;
-use tokio::sync::Mutex;
+use snafu::{ResultExt as _, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{Mutex, Notify},
+    task::{AbortHandle, JoinSet},
+};
 use tokio_stream::StreamExt;
+use tracing::Instrument as _;
 
 use crate::{
-    message::{DataOrInit, Message, MessageBody, MessageId},
+    async_dashmap::AsyncDashMap,
+    audit::{AuditReport, StateDigest},
+    clock::{Clock, ClockOffsets, SystemClock},
+    compression::CompressedEnvelope,
+    flush::{FlushOptions, FlushPolicy, FlushStats},
+    histogram::LatencyHistogram,
+    history::{Direction, History, MessageSummary},
+    message::{Capabilities, DataOrInit, ErrorCode, Message, MessageBody, MessageId, Subsystem},
+    standby::{Standby, StandbyOptions},
+    timer::{TimerSpec, TimerStats},
     tokio_serde,
+    writer::{Codec, Outgoing, Writer, OUTPUT_QUEUE},
+};
+
+mod executor;
+mod ids;
+mod metrics;
+mod rpc;
+
+pub use executor::Execution;
+use executor::Executor;
+use ids::TRACE_ID;
+pub use ids::{in_subsystem, subsystem, trace_id};
+use metrics::{ClientSessions, Counted};
+pub use metrics::{
+    ClientStats, ErrorCounts, Gauges, PeerSequence, RpcCounts, ServiceGauges, UNTAGGED,
 };
+use rpc::{FinishedRpcs, PendingReply};
 
 #[derive(Debug, Snafu)]
 pub enum InternalError {
@@ -25,6 +59,70 @@ pub enum InternalError {
     UnexpectedInit,
     #[snafu(display("Node was queried before init"))]
     NeedsInit,
+    #[snafu(display("Init message has no msg_id"))]
+    MalformedInit,
+    #[snafu(display("Refusing to change node ID from {current} to {requested}"))]
+    IdentityChange {
+        current: Arc<str>,
+        requested: String,
+    },
+    #[snafu(display("Failed to receive message"))]
+    Receive { source: std::io::Error },
+    #[snafu(display("Failed to send message to {dest}"))]
+    Send {
+        dest: Arc<str>,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to serialize message to {dest}"))]
+    SendSerialization {
+        dest: Arc<str>,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Output closed while sending message to {dest}"))]
+    OutputClosed {
+        dest: Arc<str>,
+        source: std::io::Error,
+    },
+    #[snafu(display("Refusing to send message to unknown destination {dest:?}"))]
+    UnknownDestination { dest: Arc<str> },
+    #[snafu(display("Refusing to reply to {dest}'s reply {re}"))]
+    ReplyToReply { dest: Arc<str>, re: MessageId },
+    #[snafu(display("Refusing to send invalid message {frame}: {reason}"))]
+    InvalidMessage { frame: String, reason: String },
+    #[snafu(display("Stopped waiting for {dest}'s reply to message {id}"))]
+    NoReply { dest: Arc<str>, id: MessageId },
+    #[snafu(display("No reply from {dest} to message {id} after {elapsed:?}"))]
+    Timeout {
+        dest: Arc<str>,
+        id: MessageId,
+        elapsed: Duration,
+    },
+    #[snafu(display("No reply from {dest} after {attempts} attempts over {elapsed:?}"))]
+    RetriesExhausted {
+        dest: Arc<str>,
+        attempts: u32,
+        elapsed: Duration,
+    },
+    #[snafu(display(
+        "Gave up on {dest}'s reply to message {id} to make room: {limit} requests await a reply"
+    ))]
+    RpcEvicted {
+        dest: Arc<str>,
+        id: MessageId,
+        limit: usize,
+    },
+    #[snafu(display("Gave up on {dest}'s reply to message {id} after {elapsed:?}"))]
+    RpcExpired {
+        dest: Arc<str>,
+        id: MessageId,
+        elapsed: Duration,
+    },
+    #[snafu(display("Failed to decompress {dest}'s reply to message {id}"))]
+    ReplyDecompression {
+        dest: Arc<str>,
+        id: MessageId,
+        source: std::io::Error,
+    },
     #[snafu(whatever, display("{message}"))]
     Whatever {
         message: String,
@@ -33,45 +131,656 @@ pub enum InternalError {
     },
 }
 
-impl Into<crate::Error<InternalError>> for InternalError {
-    fn into(self) -> crate::Error<InternalError> {
-        crate::Error::Internal { source: self }
+impl InternalError {
+    /// The error for failing to write a message to `dest`, by what went wrong: the message
+    /// couldn't be serialized, the output is gone, or anything else.
+    pub(crate) fn sending(dest: Arc<str>, source: std::io::Error) -> Self {
+        if source
+            .get_ref()
+            .is_some_and(|inner| inner.is::<serde_json::Error>())
+        {
+            let inner = source.into_inner().expect("checked above");
+            let source = *inner
+                .downcast::<serde_json::Error>()
+                .expect("checked above");
+            return Self::SendSerialization { dest, source };
+        }
+        match source.kind() {
+            std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::WriteZero => Self::OutputClosed { dest, source },
+            _ => Self::Send { dest, source },
+        }
+    }
+
+    /// Whether nothing more can be sent, because the output is gone.
+    pub fn is_output_closed(&self) -> bool {
+        matches!(self, Self::OutputClosed { .. })
+    }
+
+    /// Whether the node can't go on after this, see [`crate::Error::is_fatal`]: it can neither
+    /// send nor receive any more.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::OutputClosed { .. } | Self::Receive { .. })
+    }
+}
+
+/// The error for sending to `dest` once the [`Writer`] has stopped, which it only does when the
+/// output is gone.
+fn output_closed(dest: Arc<str>) -> InternalError {
+    InternalError::OutputClosed {
+        dest,
+        source: std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the writer has stopped"),
+    }
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<InternalError> for crate::Error<E> {
+    fn from(source: InternalError) -> Self {
+        crate::Error::Internal { source }
     }
 }
 
+/// The services Maelstrom runs alongside the nodes, which are always valid destinations.
+pub const MAELSTROM_SERVICES: [&str; 4] = ["seq-kv", "lin-kv", "lww-kv", "lin-tso"];
+
+/// How many clients [`NodeState::client_sessions`] keeps track of. Beyond this, the least recently
+/// seen client is forgotten.
+pub const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// How many of the replies a node received it remembers, to refuse replies to them. See
+/// [`NodeOptions::allow_reply_to_reply`].
+pub const RECENT_REPLIES: usize = 1024;
+
+/// How many of the requests that stopped waiting for a reply a node remembers, to tell their late
+/// replies from others, see [`Node::on_late_reply`].
+pub const RECENT_RPCS: usize = 4096;
+
+/// The most queued messages handed to [`Node::handle_batch`] at once.
+pub const MAX_HANDLER_BATCH: usize = 64;
+
+/// How many messages that arrive before init are kept to be handled after it. Beyond this, the
+/// peer is clearly not waiting for us to initialize and we give up.
+const MAX_EARLY_MESSAGES: usize = 1024;
+
+/// Where a running audit gets each peer's `audit_ok`, see [`NodeState::audit`].
+type AuditAnswers = tokio::sync::mpsc::UnboundedSender<(Arc<str>, StateDigest)>;
+
 pub struct NodeStateInner<NodeImpl: Node + Send + Sync + 'static> {
-    // stdin: tokio_util::codec::FramedRead<
-    //     Stdin,
-    //     tokio_serde::formats::SymmetricalJson<Message<DataOrInit<NodeImpl::Message>>>,
-    // >,
-    /// The channel used to send and receive messages
-    // channel: tokio_util::codec::Framed<
-    //     tokio::io::Join<Stdin, Stdout>,
-    //     tokio_serde::formats::SymmetricalJson<Message<DataOrInit<NodeImpl::Message>>>,
-    // >,
-    next_id: AtomicU64,
-    // rpc: tokio::sync::mpsc::UnboundedSender<Message<DataOrInit<NodeImpl::Message>>>,
+    /// The next message number of each [`Subsystem`].
+    next_ids: [AtomicU64; Subsystem::ALL.len()],
+    /// Replies awaited by [`NodeState::rpc`], by the ID of the request.
+    pending_replies: AsyncDashMap<MessageId, PendingReply<NodeImpl::Message>>,
+    /// See [`NodeOptions::rpc_timeout_ms`].
+    rpc_timeout: Duration,
+    /// See [`NodeOptions::max_pending_rpcs`].
+    max_pending_rpcs: usize,
+    /// See [`NodeOptions::max_rpc_lifetime_ms`].
+    max_rpc_lifetime: Duration,
+    /// See [`RpcCounts::expired`].
+    rpcs_expired: AtomicU64,
+    /// See [`RpcCounts::evicted`].
+    rpcs_evicted: AtomicU64,
+    /// The requests that stopped waiting last, see [`Node::on_late_reply`].
+    finished_rpcs: std::sync::Mutex<FinishedRpcs>,
+    /// See [`NodeOptions::lenient_replies`].
+    lenient_replies: bool,
+    /// See [`RpcCounts::matched`].
+    replies_matched: AtomicU64,
+    /// See [`RpcCounts::late`].
+    replies_late: AtomicU64,
+    /// See [`RpcCounts::unknown`].
+    replies_unknown: AtomicU64,
     node: NodeImpl,
-    output: Mutex<
-        tokio_util::codec::FramedWrite<
-            tokio::io::Stdout,
-            tokio_serde::formats::SymmetricalJson<Message<DataOrInit<NodeImpl::Message>>>,
-        >,
-    >,
+    /// Where outgoing messages are queued for the [`Writer`].
+    outbox: tokio::sync::mpsc::Sender<Outgoing<NodeImpl::Message>>,
+    /// Held while a message is given its ID and queued, so that each subsystem's IDs on the wire
+    /// are always in write order.
+    queueing: Mutex<()>,
+    /// The codec the [`Writer`] serializes with, to find the size of a message to compress.
+    codec: Codec<NodeImpl::Message>,
+    /// When the [`Writer`] flushes.
+    flush: Arc<std::sync::Mutex<FlushPolicy>>,
+    /// Background tasks started with [`NodeState::spawn`].
+    tasks: std::sync::Mutex<Tasks>,
+    task_counter: TaskCounter,
+    /// Whether mutating client requests are currently rejected.
+    read_only: AtomicBool,
+    /// See [`NodeOptions::standby`].
+    standby: Option<Standby>,
+    clients: std::sync::Mutex<ClientSessions>,
+    /// Every node in the cluster, this one included. Set by `init`.
+    node_ids: arc_swap::ArcSwapOption<std::collections::HashSet<String>>,
+    /// See [`NodeOptions::lenient_destinations`].
+    lenient_destinations: bool,
+    /// The replies received last, see [`NodeOptions::allow_reply_to_reply`].
+    recent_replies: std::sync::Mutex<RecentReplies>,
+    /// See [`NodeOptions::allow_reply_to_reply`].
+    allow_reply_to_reply: bool,
+    /// Set once the node is initialized, see [`NodeState::start_executor`].
+    executor: OnceLock<Executor<NodeImpl::Message>>,
+    decode_errors: AtomicU64,
+    handler_errors: AtomicU64,
+    replies_to_replies: AtomicU64,
+    /// See [`NodeOptions::validate_output`].
+    validate_output: bool,
+    /// See [`NodeOptions::compress_above`].
+    compress_above: Option<usize>,
+    /// See [`NodeOptions::expose_trace_ids`].
+    expose_trace_ids: bool,
+    /// Whether handler futures are boxed before being spawned, see
+    /// [`NodeOptions::box_handlers_above`].
+    box_handlers: bool,
+    /// See [`NodeOptions::disable_inline`].
+    disable_inline: bool,
+    /// See [`NodeOptions::pool_queue`].
+    pool_queue: usize,
+    /// Whether to send our capabilities to peers, see [`NodeOptions::exchange_capabilities`].
+    exchange_capabilities: bool,
+    /// What each peer told us about itself.
+    peer_capabilities: dashmap::DashMap<String, Capabilities>,
+    /// Peers that have acknowledged our capabilities.
+    capabilities_acked: dashmap::DashSet<String>,
+    /// How each of the service's [`Node::timers`] has been doing.
+    timers: std::sync::Mutex<BTreeMap<&'static str, TimerStats>>,
+    /// How long handlers take, by message tag, see [`NodeState::handler_latency`].
+    handler_latency: std::sync::Mutex<BTreeMap<&'static str, LatencyHistogram>>,
+    /// See [`NodeOptions::slow_handler_ms`].
+    slow_handler: Option<Duration>,
+    /// See [`NodeOptions::audit_timeout_ms`].
+    audit_timeout: Option<Duration>,
+    /// Where `audit_ok`s go while an audit is waiting for them, see [`NodeState::audit`].
+    audit_answers: std::sync::Mutex<Option<AuditAnswers>>,
+    /// The last audit's outcome, for the summary logged when the node stops.
+    audit_report: std::sync::Mutex<Option<AuditReport>>,
+    /// See [`NodeOptions::sequence_peer_messages`].
+    sequence_peer_messages: bool,
+    /// The first fatal error a handler failed with, for the run loop to return, see
+    /// [`Node::is_fatal`].
+    fatal: std::sync::Mutex<Option<crate::Error<NodeImpl::Error>>>,
+    fatal_raised: tokio::sync::Notify,
+    /// The last sequence number sent to each peer.
+    sent_seqs: std::sync::Mutex<std::collections::HashMap<Arc<str>, u64>>,
+    /// The sequence numbers received from each peer, see [`NodeState::peer_sequences`].
+    received_seqs: std::sync::Mutex<BTreeMap<String, PeerSequence>>,
+    /// See [`NodeOptions::gauge_interval_ms`].
+    gauge_interval: Option<Duration>,
+    /// See [`Gauges::mailbox`].
+    mailbox: AtomicU64,
+    /// See [`Gauges::active_handlers`].
+    active_handlers: AtomicU64,
+    /// The messages received and sent last. `None` if [`NodeOptions::history_size`] turns it off.
+    history: Option<std::sync::Mutex<History>>,
+    /// See [`NodeOptions::clock`].
+    clock: Arc<dyn Clock>,
+    /// `None` unless [`NodeOptions::estimate_clock_offsets`] is on.
+    clock_offsets: Option<std::sync::Mutex<ClockOffsets>>,
+
+    /// The node ID. Variable sized to allow all copies of the state to share the same ID memory,
+    /// and swappable so that anything caching it has a single source of truth, even though the
+    /// runner currently refuses to change it (see [`InternalError::IdentityChange`]).
+    id: arc_swap::ArcSwap<Arc<str>>,
+}
+
+/// Options controlling how the node runner behaves.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeOptions {
+    /// Fail with [`InternalError::MalformedInit`] when `init` has no `msg_id`. By default the node
+    /// logs an error and initializes anyway, since there is nothing to acknowledge.
+    pub strict_init: bool,
+    /// Check every outgoing message with [`validate_outgoing`] and refuse to send invalid ones,
+    /// failing with [`InternalError::InvalidMessage`]. Always on in debug builds.
+    pub validate_output: bool,
+    /// Compress the bodies of peer messages larger than this many bytes, for peers that can read
+    /// them (see [`crate::compression`]). Off by default.
+    pub compress_above: Option<usize>,
+    /// Check requests from clients with [`Node::check_strict`] before handling them, and answer
+    /// those that fail with `malformed_request`. Messages from peers are read as leniently as
+    /// ever.
+    pub strict_client_input: bool,
+    /// Include trace IDs (see [`trace_id`]) in messages to clients too. Off by default, since
+    /// Maelstrom's clients don't expect the extra field.
+    pub expose_trace_ids: bool,
+    /// How message handlers are run. `None` uses the service's [`Node::execution`].
+    pub execution: Option<Execution>,
+    /// How long [`NodeState::rpc`] waits for a reply before failing with
+    /// [`InternalError::Timeout`]. `None` uses [`DEFAULT_RPC_TIMEOUT_MS`].
+    pub rpc_timeout_ms: Option<u64>,
+    /// How many [`NodeState::rpc`]s may wait for a reply at once. A request beyond that makes room
+    /// by failing the one that has waited longest with [`InternalError::RpcEvicted`]. `None` uses
+    /// [`DEFAULT_MAX_PENDING_RPCS`].
+    pub max_pending_rpcs: Option<usize>,
+    /// How long any request may wait for a reply, whatever its own timeout, before it fails with
+    /// [`InternalError::RpcExpired`]. Expired requests are swept from the run loop, so that even
+    /// those whose caller never polls them again are let go. `None` uses
+    /// [`DEFAULT_MAX_RPC_LIFETIME_MS`].
+    pub max_rpc_lifetime_ms: Option<u64>,
+    /// How many messages each pool queue holds, see [`Execution::Pool`] and
+    /// [`Execution::OrderedPool`]. While a queue is full, no more input is read. `None` uses
+    /// [`DEFAULT_POOL_QUEUE`].
+    pub pool_queue: Option<usize>,
+    /// When to batch writes to stdout, see [`crate::flush`].
+    pub flush: FlushOptions,
+    /// Box handler futures larger than this many bytes before spawning them, so that the task
+    /// only holds a pointer. `None` uses [`DEFAULT_BOX_HANDLERS_ABOVE`].
+    pub box_handlers_above: Option<usize>,
+    /// Hand every message to the executor without offering it to [`Node::try_handle_inline`]
+    /// first. Mostly for measuring what inline handling saves.
+    pub disable_inline: bool,
+    /// Log every handler that takes longer than this many milliseconds, with the message's tag,
+    /// source and size. Off by default, since finding the size serializes every message again.
+    pub slow_handler_ms: Option<u64>,
+    /// Start as a warm standby for another node, turning clients away until promoted, see
+    /// [`crate::standby`]. Serves clients from the start if `None`.
+    pub standby: Option<StandbyOptions>,
+    /// Log messages to unknown destinations and send them anyway, instead of failing with
+    /// [`InternalError::UnknownDestination`]. See [`NodeState::is_known_destination`].
+    pub lenient_destinations: bool,
+    /// Hand replies to messages this node never sent to the service, instead of dropping them,
+    /// see [`RpcCounts::unknown`].
+    pub lenient_replies: bool,
+    /// Send replies to messages that were themselves replies. By default these fail with
+    /// [`InternalError::ReplyToReply`], since two nodes that answer each other's replies never
+    /// stop; protocols that deliberately chain replies can turn this on.
+    pub allow_reply_to_reply: bool,
+    /// Run for a person typing messages into a terminal rather than for Maelstrom: messages are
+    /// pretty-printed, and lines that fail to decode are explained and skipped instead of stopping
+    /// the node. Turned on by [`NodeState::run_stdio`] when stdin is a terminal.
+    pub interactive: bool,
+    /// Log a diagnostic if nothing has arrived on the input this many milliseconds after starting,
+    /// which usually means it isn't wired to Maelstrom. `None` uses
+    /// [`DEFAULT_STARTUP_TIMEOUT_MS`]. Not checked in [`interactive`](Self::interactive) mode.
+    pub startup_timeout_ms: Option<u64>,
+    /// Audit every peer when sent a `drain`, waiting this many milliseconds for their answers, see
+    /// [`crate::audit`]. Off if `None`.
+    pub audit_timeout_ms: Option<u64>,
+    /// Number the messages sent to each peer that can count them, so that the peer notices those
+    /// that never arrive, see [`NodeState::peer_sequences`]. Only for spotting loss: nothing is
+    /// resent, that is up to the service. Off by default.
+    pub sequence_peer_messages: bool,
+    /// Log the node's [`Gauges`] every this many milliseconds, unless it is idle. `None` uses
+    /// [`DEFAULT_GAUGE_INTERVAL_MS`], and `Some(0)` turns them off.
+    pub gauge_interval_ms: Option<u64>,
+    /// Keep a summary of this many of the messages received and sent last, logged when a handler
+    /// panics or the node stops on a fatal error, see [`crate::history`]. `None` uses
+    /// [`DEFAULT_HISTORY_SIZE`], and `Some(0)` turns it off.
+    pub history_size: Option<usize>,
+    /// Time the messages sent to each peer that can read the timestamps, and estimate how far
+    /// its clock is from ours, see [`crate::clock`]. Off by default.
+    pub estimate_clock_offsets: bool,
+    /// Send our [`Capabilities`] to every peer on startup and to each that joins, retrying until
+    /// acknowledged, see [`NodeState::peer_capabilities`]. Off by default, since it is traffic
+    /// Maelstrom didn't ask for; turned on by [`compress_above`](Self::compress_above),
+    /// [`sequence_peer_messages`](Self::sequence_peer_messages) and
+    /// [`estimate_clock_offsets`](Self::estimate_clock_offsets), which only apply to peers that
+    /// told us they can read them. Capabilities peers send us are answered either way.
+    pub exchange_capabilities: bool,
+    /// The node's wall clock. `None` uses the system clock; tests skew it with
+    /// [`crate::clock::SkewedClock`].
+    #[serde(skip)]
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Tracks the background tasks of every node sharing this counter.
+    #[serde(skip)]
+    pub task_counter: TaskCounter,
+}
+
+/// See [`NodeOptions::rpc_timeout_ms`].
+pub const DEFAULT_RPC_TIMEOUT_MS: u64 = 5000;
+
+/// See [`NodeOptions::max_pending_rpcs`].
+pub const DEFAULT_MAX_PENDING_RPCS: usize = 4096;
+
+/// See [`NodeOptions::max_rpc_lifetime_ms`].
+pub const DEFAULT_MAX_RPC_LIFETIME_MS: u64 = 30_000;
+
+/// See [`NodeOptions::pool_queue`].
+pub const DEFAULT_POOL_QUEUE: usize = 1024;
+
+/// See [`NodeOptions::box_handlers_above`].
+pub const DEFAULT_BOX_HANDLERS_ABOVE: usize = 4096;
+
+/// See [`NodeOptions::startup_timeout_ms`]. Maelstrom sends `init` as soon as the node starts.
+pub const DEFAULT_STARTUP_TIMEOUT_MS: u64 = 5000;
+
+/// See [`NodeOptions::gauge_interval_ms`].
+pub const DEFAULT_GAUGE_INTERVAL_MS: u64 = 5000;
+
+/// How long a node whose stdin was closed waits for the messages it already read to be handled
+/// before it stops anyway.
+pub const EOF_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often a node whose stdin was closed checks whether its handlers are done.
+const SETTLE_POLL: Duration = Duration::from_millis(10);
+
+/// See [`NodeOptions::history_size`].
+pub const DEFAULT_HISTORY_SIZE: usize = 256;
+
+/// How many times [`Node::admit`] can defer a request before it is handled anyway.
+pub const MAX_DEFERRALS: u32 = 8;
+
+/// Printed by [`NodeState::run_stdio`] when stdin is a terminal.
+const INTERACTIVE_HINT: &str = "\
+stdin is a terminal, so the node is running interactively. Type one JSON message per line, \
+starting with an init:
+  {\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"init\",\"msg_id\":1,\"node_id\":\"n1\",\"node_ids\":[\"n1\"]}}
+To run under Maelstrom instead, pass the binary to `maelstrom test --bin`.";
+
+/// Build information and configuration, logged when a node starts and stops so that results from
+/// many runs can be traced back to the binary and options that produced them.
+#[derive(Debug, Serialize)]
+pub struct Banner<'a> {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub features: Vec<&'static str>,
+    pub options: &'a NodeOptions,
+    /// The size in bytes of the service's handler futures, see
+    /// [`NodeState::handler_future_size`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handler_future_size: Option<usize>,
+}
+
+/// The version and commit this node was built from.
+fn build_version() -> String {
+    format!("{}-{}", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"))
+}
+
+impl<'a> Banner<'a> {
+    pub fn new(options: &'a NodeOptions) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            features: env!("ENABLED_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            options,
+            handler_future_size: None,
+        }
+    }
+
+    pub fn with_handler_future_size(self, size: usize) -> Self {
+        Self {
+            handler_future_size: Some(size),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for Banner<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&json)
+    }
+}
 
-    /// The node ID. Variable sized to allow all copies of the state to share the same ID memory.
-    pub id: Arc<str>,
+/// What happens to a background task when its node shuts down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnShutdown {
+    /// Cancel the task.
+    #[default]
+    Abort,
+    /// Let the task run to completion before the node exits.
+    Await,
 }
+
+#[derive(Default)]
+struct Tasks {
+    abort: JoinSet<()>,
+    wait: JoinSet<()>,
+}
+
+impl Tasks {
+    fn abort_all(&mut self) {
+        self.abort.abort_all();
+        self.wait.abort_all();
+    }
+}
+
+#[derive(Debug, Default)]
+struct TaskCounterInner {
+    live: AtomicUsize,
+    idle: Notify,
+}
+
+/// Counts the background tasks spawned through [`NodeState::spawn`] that have not yet finished or
+/// been cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct TaskCounter {
+    inner: Arc<TaskCounterInner>,
+}
+
+impl TaskCounter {
+    pub fn live(&self) -> usize {
+        self.inner.live.load(Ordering::SeqCst)
+    }
+
+    /// Wait until every counted task is gone.
+    pub async fn idle(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            if self.live() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    fn track(&self) -> TaskGuard {
+        self.inner.live.fetch_add(1, Ordering::SeqCst);
+        TaskGuard {
+            counter: self.clone(),
+        }
+    }
+}
+
+/// Lives inside a spawned task, so it is dropped whether the task finishes or is cancelled.
+struct TaskGuard {
+    counter: TaskCounter,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.counter.inner.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.counter.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// Where a message came from, kept for reporting once the message itself has been handed off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageMeta {
+    pub src: Arc<str>,
+    pub id: Option<MessageId>,
+    pub re: Option<MessageId>,
+}
+
+/// Whether [`Node::try_handle_inline`] took care of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InlineResult {
+    Handled,
+    /// Handle the message the usual way, with [`Node::handle_message`].
+    Fallback,
+}
+
+/// What to do with a client request, see [`Node::admit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// Handle it.
+    Accept,
+    /// Answer it with an error instead of handling it.
+    Reject { code: ErrorCode, text: String },
+    /// Ask again after `delay`. A request deferred [`MAX_DEFERRALS`] times is handled anyway, so
+    /// that it can't wait forever.
+    Defer { delay: Duration },
+}
+
+/// A client request about to be handled, see [`Node::admit`].
+#[derive(Debug)]
+pub struct AdmissionRequest<'a, Data> {
+    pub src: &'a str,
+    pub id: Option<MessageId>,
+    pub data: &'a Data,
+    /// See [`Node::message_tag`].
+    pub tag: Option<&'static str>,
+    /// How many times the request has been deferred so far.
+    pub deferrals: u32,
+}
+
+impl<Data: Serialize> AdmissionRequest<'_, Data> {
+    /// The size in bytes of the request's body. Serializes it, so only worth asking for when the
+    /// answer matters.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self.data).map_or(0, |encoded| encoded.len())
+    }
+}
+
+/// The last few replies received, as `(src, msg_id)`, oldest first.
+#[derive(Debug)]
+struct RecentReplies {
+    order: std::collections::VecDeque<(Arc<str>, MessageId)>,
+    replies: std::collections::HashSet<(Arc<str>, MessageId)>,
+    capacity: usize,
+}
+
+impl RecentReplies {
+    fn new(capacity: usize) -> Self {
+        Self {
+            order: std::collections::VecDeque::new(),
+            replies: std::collections::HashSet::new(),
+            capacity,
+        }
+    }
+
+    fn record(&mut self, src: &Arc<str>, id: MessageId) {
+        let reply = (Arc::clone(src), id);
+        if !self.replies.insert(reply.clone()) {
+            return;
+        }
+        self.order.push_back(reply);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, src: &str, id: MessageId) -> bool {
+        self.replies.contains(&(Arc::from(src), id))
+    }
+}
+
+/// A handle to a task started with [`NodeState::spawn`].
+#[derive(Debug, Clone)]
+pub struct TaskHandle {
+    handle: AbortHandle,
+}
+
+impl TaskHandle {
+    pub fn abort(&self) {
+        self.handle.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
 impl<NodeImpl: Node + Send + Sync + 'static> NodeStateInner<NodeImpl> {
-    pub fn new(node: NodeImpl, id: Arc<str>) -> Self {
+    fn with_outbox(
+        node: NodeImpl,
+        id: Arc<str>,
+        options: &NodeOptions,
+        outbox: tokio::sync::mpsc::Sender<Outgoing<NodeImpl::Message>>,
+        codec: Codec<NodeImpl::Message>,
+        flush: Arc<std::sync::Mutex<FlushPolicy>>,
+    ) -> Self {
         Self {
-            next_id: AtomicU64::new(0),
+            next_ids: Default::default(),
             node,
-            output: Mutex::new(tokio_util::codec::FramedWrite::new(
-                tokio::io::stdout(),
-                tokio_serde::formats::SymmetricalJson::default(),
-            )),
-            id,
+            outbox,
+            queueing: Mutex::new(()),
+            codec,
+            flush,
+            tasks: std::sync::Mutex::default(),
+            task_counter: options.task_counter.clone(),
+            read_only: AtomicBool::new(false),
+            standby: options
+                .standby
+                .clone()
+                .map(|standby| Standby::new(standby, tokio::time::Instant::now())),
+            clients: std::sync::Mutex::new(ClientSessions::new(MAX_TRACKED_CLIENTS)),
+            node_ids: arc_swap::ArcSwapOption::empty(),
+            lenient_destinations: options.lenient_destinations,
+            recent_replies: std::sync::Mutex::new(RecentReplies::new(RECENT_REPLIES)),
+            allow_reply_to_reply: options.allow_reply_to_reply,
+            executor: OnceLock::new(),
+            decode_errors: AtomicU64::new(0),
+            handler_errors: AtomicU64::new(0),
+            replies_to_replies: AtomicU64::new(0),
+            validate_output: options.validate_output,
+            compress_above: options.compress_above,
+            expose_trace_ids: options.expose_trace_ids,
+            box_handlers: NodeState::<NodeImpl>::handler_future_size()
+                > options
+                    .box_handlers_above
+                    .unwrap_or(DEFAULT_BOX_HANDLERS_ABOVE),
+            disable_inline: options.disable_inline,
+            pool_queue: options.pool_queue.unwrap_or(DEFAULT_POOL_QUEUE).max(1),
+            exchange_capabilities: options.exchange_capabilities
+                || options.compress_above.is_some()
+                || options.sequence_peer_messages
+                || options.estimate_clock_offsets,
+            peer_capabilities: dashmap::DashMap::new(),
+            capabilities_acked: dashmap::DashSet::new(),
+            timers: std::sync::Mutex::default(),
+            handler_latency: std::sync::Mutex::default(),
+            slow_handler: options.slow_handler_ms.map(Duration::from_millis),
+            audit_timeout: options.audit_timeout_ms.map(Duration::from_millis),
+            audit_answers: std::sync::Mutex::new(None),
+            pending_replies: AsyncDashMap::new(),
+            rpc_timeout: Duration::from_millis(
+                options.rpc_timeout_ms.unwrap_or(DEFAULT_RPC_TIMEOUT_MS),
+            ),
+            max_pending_rpcs: options.max_pending_rpcs.unwrap_or(DEFAULT_MAX_PENDING_RPCS),
+            max_rpc_lifetime: Duration::from_millis(
+                options
+                    .max_rpc_lifetime_ms
+                    .unwrap_or(DEFAULT_MAX_RPC_LIFETIME_MS),
+            ),
+            rpcs_expired: AtomicU64::new(0),
+            rpcs_evicted: AtomicU64::new(0),
+            finished_rpcs: std::sync::Mutex::new(FinishedRpcs::new(RECENT_RPCS)),
+            lenient_replies: options.lenient_replies,
+            replies_matched: AtomicU64::new(0),
+            replies_late: AtomicU64::new(0),
+            replies_unknown: AtomicU64::new(0),
+            audit_report: std::sync::Mutex::new(None),
+            sequence_peer_messages: options.sequence_peer_messages,
+            fatal: std::sync::Mutex::new(None),
+            fatal_raised: tokio::sync::Notify::new(),
+            sent_seqs: std::sync::Mutex::new(std::collections::HashMap::new()),
+            received_seqs: std::sync::Mutex::new(BTreeMap::new()),
+            gauge_interval: match options.gauge_interval_ms {
+                Some(0) => None,
+                ms => Some(Duration::from_millis(
+                    ms.unwrap_or(DEFAULT_GAUGE_INTERVAL_MS),
+                )),
+            },
+            mailbox: AtomicU64::new(0),
+            active_handlers: AtomicU64::new(0),
+            history: match options.history_size.unwrap_or(DEFAULT_HISTORY_SIZE) {
+                0 => None,
+                size => Some(std::sync::Mutex::new(History::new(size))),
+            },
+            clock: options
+                .clock
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemClock)),
+            clock_offsets: options
+                .estimate_clock_offsets
+                .then(std::sync::Mutex::default),
+            id: arc_swap::ArcSwap::from_pointee(id),
         }
     }
 }
@@ -82,10 +791,41 @@ pub struct NodeState<NodeImpl: Node + Send + Sync + 'static> {
 }
 
 impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
-    pub fn new(node: NodeImpl, id: Arc<str>) -> Self {
-        Self {
-            inner: Arc::new(NodeStateInner::new(node, id)),
+    /// Create a node state that writes its messages to `output`. Messages are written by a task
+    /// of their own, which stops once the state is dropped.
+    pub fn with_output(
+        node: NodeImpl,
+        id: Arc<str>,
+        options: &NodeOptions,
+        output: impl AsyncWrite + Send + Sync + Unpin + 'static,
+    ) -> Self {
+        let mut codec = Codec::default();
+        if options.interactive {
+            codec = codec.pretty();
         }
+        let flush = Arc::new(std::sync::Mutex::new(FlushPolicy::new(
+            options.flush.clone(),
+            tokio::time::Instant::now(),
+        )));
+        let (outbox, queued) = tokio::sync::mpsc::channel(OUTPUT_QUEUE);
+        let inner = Arc::new(NodeStateInner::with_outbox(
+            node,
+            id,
+            options,
+            outbox,
+            codec,
+            Arc::clone(&flush),
+        ));
+
+        // Holds on to the state only weakly, so that dropping the state stops the writer.
+        let state = Arc::downgrade(&inner);
+        let writer = Writer::new(output, codec, queued, flush, move |error| {
+            if let Some(inner) = state.upgrade() {
+                NodeState { inner }.stop_on(error.into());
+            }
+        });
+        tokio::spawn(writer.run());
+        Self { inner }
     }
 }
 
@@ -104,39 +844,418 @@ where
     type Message: Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static;
     type Error: std::error::Error + Send + Sync + 'static;
 
+    /// Handle a message from a client or peer.
+    ///
+    /// Handlers run concurrently: with [`Execution::Spawn`] every message gets a task of its own,
+    /// and with [`Execution::Pool`] any worker may take any message. Only
+    /// [`Execution::OrderedPool`] handles messages from one source one at a time, in arrival
+    /// order. The returned future is moved to the task that runs it, so it must be `Send`, but it
+    /// is never shared, so it needn't be `Sync`: holding a `RefCell` or a `Cell` across an
+    /// `.await` is fine, holding an `Rc` is not.
+    ///
+    /// ```compile_fail
+    /// use std::rc::Rc;
+    ///
+    /// use fly_systems_challenge::{message::Message, node::{Node, NodeState}};
+    ///
+    /// #[derive(Clone)]
+    /// struct RcService;
+    ///
+    /// impl Node for RcService {
+    ///     type Message = serde_json::Value;
+    ///     type Error = std::io::Error;
+    ///
+    ///     async fn handle_message(
+    ///         &self,
+    ///         _message: Message<Self::Message>,
+    ///         _state: &NodeState<Self>,
+    ///     ) -> fly_systems_challenge::Result<(), Self::Error> {
+    ///         let shared = Rc::new(());
+    ///         tokio::task::yield_now().await;
+    ///         drop(shared);
+    ///         Ok(())
+    ///     }
+    /// }
+    /// ```
     fn handle_message(
         &self,
         message: Message<Self::Message>,
         state: &NodeState<Self>,
-    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send + Sync;
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send;
 
+    /// Called once the node knows its ID and its peers, before any other message is handled. Like
+    /// [`Node::handle_message`], the future must be `Send` but needn't be `Sync`.
     fn init(
         &self,
         state: &NodeState<Self>,
         node_ids: Vec<String>,
-    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send + Sync {
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
+        let _ = state;
+        let _ = node_ids;
+        async { Ok(()) }
+    }
+
+    /// Whether `message` changes the service's state when it comes from a client. While the node
+    /// is read-only, such requests are answered with `temporarily_unavailable` instead of being
+    /// handled. Messages from other nodes are always handled.
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        let _ = message;
+        false
+    }
+
+    /// The `type` tags of the messages the service understands, advertised to peers so that
+    /// mismatched builds can be spotted.
+    fn message_tags(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// The `type` tag of `message`, one of [`Node::message_tags`], under which its handler's
+    /// latency is recorded, see [`NodeState::handler_latency`]. By default it is looked up by
+    /// serializing the message, so services with many messages should override this.
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        if self.message_tags().is_empty() {
+            return None;
+        }
+        let encoded = serde_json::to_value(message).ok()?;
+        let tag = encoded.get("type")?.as_str()?;
+        self.message_tags()
+            .iter()
+            .copied()
+            .find(|known| *known == tag)
+    }
+
+    /// Check the body of a client request more strictly than `Self::Message`'s `Deserialize` impl
+    /// does, describing the first problem found and where it is. Only called with
+    /// [`NodeOptions::strict_client_input`] on. See [`crate::message::check_strict`].
+    fn check_strict(&self, body: &serde_json::Value) -> Result<(), String> {
+        let _ = body;
+        Ok(())
+    }
+
+    /// How this service's handlers are run unless [`NodeOptions::execution`] says otherwise.
+    fn execution(&self) -> Execution {
+        Execution::Spawn
+    }
+
+    /// With [`Execution::EventLoop`], the one task that handles every message for the service, in
+    /// the order they were read. Suits services that are simplest written as a loop that owns
+    /// their state and `select!`s over `inbox` and timers of their own, like Raft. It starts
+    /// alongside [`Node::init`] and gets messages once init is done; once it returns, messages for
+    /// the service are dropped. By default, each message is handled with
+    /// [`Node::handle_message`] in turn.
+    fn run_event_loop(
+        &self,
+        mut inbox: tokio::sync::mpsc::Receiver<Message<Self::Message>>,
+        state: NodeState<Self>,
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
+        async move {
+            while let Some(message) = inbox.recv().await {
+                let trace_id = message.body.trace_id;
+                TRACE_ID.scope(trace_id, state.handle_data(message)).await;
+            }
+            Ok(())
+        }
+    }
+
+    /// Apply the runtime parameters of a `tune` message, e.g. `{"gossip_interval_ms": 100}`.
+    /// Either every change is applied or, on error, none is and the error is sent back. The
+    /// default accepts nothing.
+    fn reconfigure(&self, params: &serde_json::Value) -> Result<(), String> {
+        let _ = params;
+        Err("the service has no runtime parameters".to_owned())
+    }
+
+    /// Whether to handle a client's request, reject it, or look at it again later, for shedding
+    /// load before a handler runs. [`NodeState::gauges`] tells how busy the node is. Only asked
+    /// about service messages from clients, as they are read, before anything else looks at them.
+    /// Accepts everything by default.
+    fn admit(
+        &self,
+        request: &AdmissionRequest<'_, Self::Message>,
+        node: &NodeState<Self>,
+    ) -> Admission {
+        let _ = (request, node);
+        Admission::Accept
+    }
+
+    /// Handle `message` right away, on the task reading input, instead of handing it to the
+    /// executor. For messages so cheap that spawning a handler costs more than handling them,
+    /// like acks. The hook can't wait on anything: replies go out with
+    /// [`NodeState::try_send_message`], and anything that would have to wait should return
+    /// [`InlineResult::Fallback`]. Only service messages are offered, never ones rejected for
+    /// read-only mode. Falls back by default.
+    fn try_handle_inline(
+        &self,
+        message: &Message<Self::Message>,
+        node: &NodeState<Self>,
+    ) -> InlineResult {
+        let _ = (message, node);
+        InlineResult::Fallback
+    }
+
+    /// Whether `next` can be handled in the same [`Node::handle_batch`] call as `first`. Only asked
+    /// about messages from the same source, queued back to back. Never by default.
+    fn batches_with(&self, first: &Self::Message, next: &Self::Message) -> bool {
+        let _ = first;
+        let _ = next;
+        false
+    }
+
+    /// Handle several messages from the same source at once, returning a result per message, in
+    /// order. Only used with a pool [`Execution`], where messages queue up, for runs of messages
+    /// that [`Node::batches_with`] accepts. Handles them one at a time by default.
+    fn handle_batch(
+        &self,
+        messages: Vec<Message<Self::Message>>,
+        state: &NodeState<Self>,
+    ) -> impl Future<Output = Vec<crate::Result<(), Self::Error>>> + Send {
+        async move {
+            let mut results = Vec::with_capacity(messages.len());
+            for message in messages {
+                results.push(self.handle_message(message, state).await);
+            }
+            results
+        }
+    }
+
+    /// Periodic timers to start once the node is initialized, see [`crate::timer`]. None by
+    /// default.
+    fn timers(&self) -> Vec<TimerSpec> {
+        Vec::new()
+    }
+
+    /// Called every period of the timer named `name`, one of [`Node::timers`]. Errors are logged.
+    fn on_timer(
+        &self,
+        name: &str,
+        state: &NodeState<Self>,
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
+        let _ = name;
+        let _ = state;
+        async { Ok(()) }
+    }
+
+    /// Called once when a standby node is promoted, before it starts serving clients. Errors are
+    /// logged, and the node serves clients anyway. See [`crate::standby`].
+    fn on_promote(
+        &self,
+        state: &NodeState<Self>,
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
+        let _ = state;
+        async { Ok(()) }
+    }
+
+    /// Called when the cluster changes to `node_ids`, ourselves included, after the runner has
+    /// updated [`NodeState::is_known_destination`]. Maelstrom clusters never change, so only
+    /// harnesses send the `membership_change` behind this, like `Cluster::add_node` in
+    /// `testing`. Errors are sent back to the sender.
+    fn on_membership_change(
+        &self,
+        state: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
         let _ = state;
         let _ = node_ids;
         async { Ok(()) }
     }
+
+    /// A digest of the service's state, compared with every peer's by the shutdown audit, see
+    /// [`crate::audit`]. Services whose nodes needn't converge return `None`, the default, and
+    /// take no part.
+    fn state_digest(&self) -> Option<StateDigest> {
+        None
+    }
+
+    /// The service's part of the [`Gauges`] logged every [`NodeOptions::gauge_interval_ms`]. Read
+    /// from the logging task while handlers run, so it should be cheap. All zero by default.
+    fn gauges(&self) -> ServiceGauges {
+        ServiceGauges::default()
+    }
+
+    /// Run a gossip round right away, for a `flush_gossip`, through the same code as the rounds
+    /// the service runs on its own. Returns how many values went to each peer, or `None`, the
+    /// default, if the service doesn't gossip. With `full`, every peer is sent everything.
+    fn flush_gossip(
+        &self,
+        state: &NodeState<Self>,
+        full: bool,
+    ) -> impl Future<Output = crate::Result<Option<BTreeMap<String, u64>>, Self::Error>> + Send
+    {
+        let _ = state;
+        let _ = full;
+        async { Ok(None) }
+    }
+
+    /// The error code a client is sent when its request fails with `error`. `crash` by default.
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        let _ = error;
+        ErrorCode::Crash
+    }
+
+    /// Whether the node can't go on after `error`, say because its persisted state turned out to
+    /// be corrupted. A handler failing with a fatal error stops the node: the runner stops
+    /// reading, and [`NodeState::run`] returns the error. Under Maelstrom, a crashed node beats
+    /// one that limps along. `false` by default.
+    fn is_fatal(&self, error: &Self::Error) -> bool {
+        let _ = error;
+        false
+    }
+
+    /// Called with a reply to a [`NodeState::rpc`] that stopped waiting for it, e.g. because it
+    /// timed out, instead of [`Node::handle_message`]. A late reply can still carry news, like a
+    /// peer acknowledging what it was sent. Dropped by default; errors are logged.
+    fn on_late_reply(
+        &self,
+        reply: Message<Self::Message>,
+        state: &NodeState<Self>,
+    ) -> impl Future<Output = crate::Result<(), Self::Error>> + Send {
+        let _ = reply;
+        let _ = state;
+        async { Ok(()) }
+    }
+
+    /// Called when [`Node::handle_message`] fails for the message described by `meta`, before
+    /// the runner answers a client request with an error (see [`Node::error_code`]). Logs the
+    /// error by default.
+    fn on_handler_error(&self, meta: &MessageMeta, error: &crate::Error<Self::Error>) {
+        tracing::error!(
+            "Error handling message {:?} from {}: {}",
+            meta.id,
+            meta.src,
+            snafu::Report::from_error(error)
+        );
+    }
 }
 
-impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
-    fn next_message_id(&self) -> crate::message::MessageId {
-        self.inner.next_id.fetch_add(1, Ordering::SeqCst)
+/// A frame read from the input.
+enum Inbound<Data> {
+    Message(Message<DataOrInit<Data>>),
+    /// A client request that failed [`Node::check_strict`].
+    Rejected {
+        src: Arc<str>,
+        id: Option<MessageId>,
+        reason: String,
+    },
+}
+
+/// A frame whose body hasn't been decoded yet.
+#[derive(Deserialize)]
+struct RawMessage {
+    src: Arc<str>,
+    dest: Arc<str>,
+    body: serde_json::Value,
+}
+
+/// Log why reading input failed, with the offending bytes if a frame failed to decode. By the time
+/// the error is returned from [`NodeState::run_with_io`], only its message is left.
+fn log_receive_error(error: &std::io::Error) {
+    match tokio_serde::formats::CodecError::from_io(error) {
+        Some(codec) => tracing::error!("Failed to decode {}", codec),
+        None => tracing::error!("Failed to read input: {}", error),
+    }
+}
+
+/// Explain a line typed in [`NodeOptions::interactive`] mode that failed to decode, pointing at
+/// where it went wrong.
+fn log_malformed_line(error: &tokio_serde::formats::CodecError) {
+    let reason = std::error::Error::source(error)
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    tracing::error!(
+        "Skipping line {}, which is not a message: {}\n  {}\n  {}^",
+        error.frame(),
+        reason,
+        error.line(),
+        " ".repeat(error.offset())
+    );
+}
+
+/// The frames read from `input`. Frames that fail to decode end the input with an error, except in
+/// [`NodeOptions::interactive`] mode, where they are explained and skipped.
+fn read_frames<T>(
+    input: impl AsyncRead + Unpin,
+    interactive: bool,
+) -> impl tokio_stream::Stream<Item = std::io::Result<T>> + Unpin
+where
+    for<'a> T: serde::de::DeserializeOwned + Deserialize<'a>,
+{
+    let lenient = tokio_serde::formats::Lenient::default();
+    tokio_util::codec::FramedRead::new(input, lenient).filter_map(move |frame| match frame {
+        Ok(Ok(frame)) => Some(Ok(frame)),
+        Ok(Err(e)) if interactive => {
+            log_malformed_line(&e);
+            None
+        }
+        Ok(Err(e)) => Some(Err(e.into())),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Decode a frame read with [`NodeOptions::strict_client_input`] on, rejecting client requests
+/// that fail [`Node::check_strict`] or don't decode at all.
+fn decode_strict<NodeImpl: Node>(
+    node: &NodeImpl,
+    RawMessage { src, dest, body }: RawMessage,
+) -> std::io::Result<Inbound<NodeImpl::Message>> {
+    let decoded = MessageBody::<DataOrInit<NodeImpl::Message>>::deserialize(&body);
+    // The runner's own messages, like Maelstrom's init, are checked by the runner.
+    let is_request = is_client(&src)
+        && !matches!(&decoded, Ok(decoded) if !matches!(decoded.data, DataOrInit::Data(_)));
+    if is_request {
+        let problem = match (node.check_strict(&body), &decoded) {
+            (Err(problem), _) => Some(problem),
+            (Ok(()), Err(e)) => Some(e.to_string()),
+            (Ok(()), Ok(_)) => None,
+        };
+        if let Some(reason) = problem {
+            return Ok(Inbound::Rejected {
+                src,
+                id: body.get("msg_id").and_then(serde_json::Value::as_u64),
+                reason,
+            });
+        }
+    }
+
+    Ok(Inbound::Message(Message {
+        src,
+        dest,
+        body: decoded?,
+    }))
+}
+
+/// Why a peer with capabilities `theirs` is likely unable to talk to us, if it is.
+pub fn incompatibility(ours: &Capabilities, theirs: &Capabilities) -> Option<String> {
+    if ours.version != theirs.version {
+        return Some(format!(
+            "runs version {}, we run {}",
+            theirs.version, ours.version
+        ));
     }
+    let disjoint = !ours.tags.is_empty()
+        && !theirs.tags.is_empty()
+        && !ours.tags.iter().any(|tag| theirs.tags.contains(tag));
+    if disjoint {
+        return Some(format!(
+            "understands {:?}, which has nothing in common with our {:?}",
+            theirs.tags, ours.tags
+        ));
+    }
+    None
+}
 
+impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
     /// Get the node ID. Panics if called before init.
     pub fn id(&self) -> Arc<str> {
-        Arc::clone(&self.inner.id)
+        Arc::clone(&self.inner.id.load())
     }
 
     pub async fn send_init_ok(
         &mut self,
         re: MessageId,
         dest: impl Into<Arc<str>>,
-    ) -> crate::Result<(), NodeImpl::Error> {
-        self.send_message(dest, Some(re), DataOrInit::InitOk).await
+    ) -> crate::Result<MessageId, NodeImpl::Error> {
+        let init_ok = self.send_message(dest, Some(re), DataOrInit::InitOk);
+        in_subsystem(Subsystem::Runner, init_ok).await
     }
 
     pub async fn reply(
@@ -144,111 +1263,3064 @@ impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
         dest: impl Into<Arc<str>>,
         re: MessageId,
         data: NodeImpl::Message,
-    ) -> crate::Result<(), NodeImpl::Error> {
+    ) -> crate::Result<MessageId, NodeImpl::Error> {
         self.send_message(dest, Some(re), DataOrInit::Data(data))
             .await
     }
 
-    #[allow(unused)]
     pub async fn send(
         &self,
         dest: impl Into<Arc<str>>,
         data: NodeImpl::Message,
-    ) -> crate::Result<(), NodeImpl::Error> {
+    ) -> crate::Result<MessageId, NodeImpl::Error> {
         self.send_message(dest, None, DataOrInit::Data(data)).await
     }
 
-    pub async fn send_message(
-        &self,
-        dest: impl Into<Arc<str>>,
-        re: Option<MessageId>,
-        data: DataOrInit<NodeImpl::Message>,
-    ) -> crate::Result<(), NodeImpl::Error> {
-        Ok(self
-            .inner
-            .output
-            .lock()
-            .await
-            .send(Message {
-                src: self.id(),
-                dest: dest.into(),
-                body: MessageBody {
-                    id: Some(self.next_message_id()),
-                    re,
-                    data,
-                },
-            })
-            .await
-            .map_err(|e| crate::Error::Internal {
-                source: InternalError::Whatever {
-                    message: format!("Error sending message: {}", e),
-                    source: None,
-                },
-            })?)
+    /// Run `future` in the background for as long as the node is alive. It is cancelled when the
+    /// node shuts down.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {
+        self.spawn_with(OnShutdown::Abort, future)
     }
 
-    pub async fn run(node: NodeImpl) -> crate::Result<(), NodeImpl::Error> {
-        let json = tokio_serde::formats::SymmetricalJson::default();
-        let mut stdin = tokio_util::codec::FramedRead::new(tokio::io::stdin(), json);
-
-        tracing::info!("Starting Maelstrom node");
+    /// Like [`NodeState::spawn`], with control over what happens to the task on shutdown.
+    pub fn spawn_with(
+        &self,
+        on_shutdown: OnShutdown,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> TaskHandle {
+        let guard = self.inner.task_counter.track();
+        let future = async move {
+            let _guard = guard;
+            future.await;
+        };
 
-        let Message { src, body, .. } =
-            stdin.next().await.ok_or_else(|| crate::Error::Internal {
-                source: InternalError::Eof,
-            })??;
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        // Reap finished tasks so that short-lived ones don't pile up in the set.
+        while tasks.abort.try_join_next().is_some() {}
+        while tasks.wait.try_join_next().is_some() {}
 
-        let (node_id, node_ids) = match body.data {
-            DataOrInit::Init { node_id, node_ids } => {
-                tracing::info!("Received Init message from {}", node_id);
+        let handle = match on_shutdown {
+            OnShutdown::Abort => tasks.abort.spawn(future),
+            OnShutdown::Await => tasks.wait.spawn(future),
+        };
+        TaskHandle { handle }
+    }
 
-                (node_id, node_ids)
+    /// Wait until every message read so far has been handled, or `grace` has passed.
+    async fn settle(&self, grace: Duration) {
+        let deadline = tokio::time::Instant::now() + grace;
+        loop {
+            let gauges = self.gauges();
+            if gauges.mailbox == 0 && gauges.active_handlers == 0 {
+                return;
             }
-            _ => {
-                return Err(crate::Error::Internal {
-                    source: crate::node::InternalError::NeedsInit,
-                });
+            if tokio::time::Instant::now() >= deadline {
+                tracing::warn!(
+                    "Stopping with {} messages unhandled and {} handlers running after {:?}",
+                    gauges.mailbox,
+                    gauges.active_handlers,
+                    grace
+                );
+                return;
             }
-        };
+            tokio::time::sleep(SETTLE_POLL).await;
+        }
+    }
 
-        let mut state = NodeState::new(node, node_id.into());
+    /// Cancel background tasks, then wait for the ones that asked to be awaited.
+    async fn shutdown(&self) {
+        let (mut abort, mut wait) = {
+            let mut tasks = self.inner.tasks.lock().unwrap();
+            let tasks = std::mem::take(&mut *tasks);
+            (tasks.abort, tasks.wait)
+        };
+        abort.shutdown().await;
+        while wait.join_next().await.is_some() {}
+    }
 
-        state
-            .send_init_ok(body.id.expect("init message ID"), src)
-            .await?;
+    /// Send a message, returning the ID it was assigned.
+    pub async fn send_message(
+        &self,
+        dest: impl Into<Arc<str>>,
+        re: Option<MessageId>,
+        data: DataOrInit<NodeImpl::Message>,
+    ) -> crate::Result<MessageId, NodeImpl::Error> {
+        let dest = dest.into();
+        let permit = self.reserve(&dest).await?;
+        let _queueing = self.inner.queueing.lock().await;
+        // Allocate the ID only once we hold the lock, so that IDs reflect write order.
+        let id = self.next_message_id();
+        self.queue(permit, dest, id, re, data)?;
+        Ok(id)
+    }
 
-        state.inner.node.init(&state, node_ids).await?;
+    /// Send a message with an ID previously obtained from [`NodeState::reserve_message_id`].
+    pub async fn send_message_with_id(
+        &self,
+        dest: impl Into<Arc<str>>,
+        id: MessageId,
+        re: Option<MessageId>,
+        data: DataOrInit<NodeImpl::Message>,
+    ) -> crate::Result<(), NodeImpl::Error> {
+        let dest = dest.into();
+        let permit = self.reserve(&dest).await?;
+        let _queueing = self.inner.queueing.lock().await;
+        self.queue(permit, dest, id, re, data)
+    }
+
+    /// Send a message without waiting, for [`Node::try_handle_inline`]. Returns `Ok(None)`,
+    /// sending nothing, if the output queue is full or another task is queueing a message.
+    pub fn try_send_message(
+        &self,
+        dest: impl Into<Arc<str>>,
+        re: Option<MessageId>,
+        data: DataOrInit<NodeImpl::Message>,
+    ) -> crate::Result<Option<MessageId>, NodeImpl::Error> {
+        let Ok(_queueing) = self.inner.queueing.try_lock() else {
+            return Ok(None);
+        };
+        let dest = dest.into();
+        let permit = match self.inner.outbox.try_reserve() {
+            Ok(permit) => permit,
+            Err(tokio::sync::mpsc::error::TrySendError::Full(())) => return Ok(None),
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(())) => {
+                return Err(output_closed(dest).into())
+            }
+        };
+        let id = self.next_message_id();
+        self.queue(permit, dest, id, re, data)?;
+        Ok(Some(id))
+    }
+
+    /// Wait for room in the output queue. Fails with [`InternalError::OutputClosed`] once the
+    /// [`Writer`] has stopped.
+    async fn reserve(
+        &self,
+        dest: &Arc<str>,
+    ) -> crate::Result<tokio::sync::mpsc::Permit<'_, Outgoing<NodeImpl::Message>>, NodeImpl::Error>
+    {
+        self.inner
+            .outbox
+            .reserve()
+            .await
+            .map_err(|_| output_closed(Arc::clone(dest)).into())
+    }
+
+    /// Frame a message and queue it for the [`Writer`] in the room `permit` holds. Called while
+    /// `queueing` is held.
+    fn queue(
+        &self,
+        permit: tokio::sync::mpsc::Permit<'_, Outgoing<NodeImpl::Message>>,
+        dest: Arc<str>,
+        id: MessageId,
+        re: Option<MessageId>,
+        data: DataOrInit<NodeImpl::Message>,
+    ) -> crate::Result<(), NodeImpl::Error> {
+        let message = self.frame_message(Arc::clone(&dest), id, re, data)?;
+        permit.send(Outgoing::Message(message));
+        if re.is_some() && is_client(&dest) {
+            self.inner.clients.lock().unwrap().reply(&dest);
+        }
+        Ok(())
+    }
+
+    /// Build the message to write, checking and compressing it, see [`validate_outgoing`] and
+    /// [`NodeState::compress`].
+    fn frame_message(
+        &self,
+        dest: Arc<str>,
+        id: MessageId,
+        re: Option<MessageId>,
+        data: DataOrInit<NodeImpl::Message>,
+    ) -> crate::Result<Message<DataOrInit<NodeImpl::Message>>, NodeImpl::Error> {
+        if !self.is_known_destination(&dest) {
+            if !self.inner.lenient_destinations {
+                return Err(UnknownDestinationSnafu { dest }.build().into());
+            }
+            tracing::warn!("Sending message to unknown destination {:?}", dest);
+        }
+        if let Some(re) = re.filter(|_| !self.inner.allow_reply_to_reply) {
+            if self
+                .inner
+                .recent_replies
+                .lock()
+                .unwrap()
+                .contains(&dest, re)
+            {
+                self.inner
+                    .replies_to_replies
+                    .fetch_add(1, Ordering::Relaxed);
+                let e = ReplyToReplySnafu { dest, re }.build();
+                tracing::error!("{}", e);
+                return Err(e.into());
+            }
+        }
+        let (sent_at_ms, request_times) = self.timestamps(&dest, re);
+        let message = Message {
+            src: self.id(),
+            dest: Arc::clone(&dest),
+            body: MessageBody {
+                id: Some(id),
+                re,
+                trace_id: trace_id().filter(|_| !is_client(&dest) || self.inner.expose_trace_ids),
+                seq: self.next_peer_seq(&dest),
+                sent_at_ms,
+                request_times,
+                data,
+            },
+        };
+
+        self.record(Direction::Sent, &message);
+
+        if cfg!(debug_assertions) || self.inner.validate_output {
+            if let Err(reason) = validate_outgoing(&message) {
+                let frame = serde_json::to_string(&message).unwrap_or_default();
+                tracing::error!("Invalid outgoing message {}: {}", frame, reason);
+                return Err(InvalidMessageSnafu { frame, reason }.build().into());
+            }
+        }
+        Ok(self.compress(message))
+    }
+
+    /// Whether `dest` is somewhere a message can go: a node in the cluster, a client this node has
+    /// heard from or named like one, or one of the [`MAELSTROM_SERVICES`]. Maelstrom silently
+    /// drops anything else. Everything is allowed until the node learns its cluster from `init`.
+    pub fn is_known_destination(&self, dest: &str) -> bool {
+        let Some(node_ids) = &*self.inner.node_ids.load() else {
+            return true;
+        };
+        node_ids.contains(dest)
+            || MAELSTROM_SERVICES.contains(&dest)
+            || is_client_id(dest)
+            || self.inner.clients.lock().unwrap().contains(dest)
+    }
+
+    /// Wait until everything queued so far is written and flushed.
+    async fn flush_output(&self) {
+        let (done, flushed) = tokio::sync::oneshot::channel();
+        if self.inner.outbox.send(Outgoing::Flush(done)).await.is_ok() {
+            // Fails only if the writer stopped, and then there is nothing left to flush.
+            let _ = flushed.await;
+        }
+    }
+
+    /// How the node has been flushing its output.
+    pub fn flush_stats(&self) -> FlushStats {
+        self.inner.flush.lock().unwrap().stats()
+    }
+
+    /// Start each of the service's [`Node::timers`] on a task of its own.
+    fn start_timers(&self) {
+        for spec in self.inner.node.timers() {
+            self.inner
+                .timers
+                .lock()
+                .unwrap()
+                .insert(spec.name, TimerStats::default());
+            let state = self.clone();
+            self.spawn(async move { state.run_timer(spec).await });
+        }
+    }
+
+    async fn run_timer(self, spec: TimerSpec) {
+        let start = tokio::time::Instant::now() + spec.period;
+        let mut interval = tokio::time::interval_at(start, spec.period);
+        interval.set_missed_tick_behavior(spec.missed_tick);
 
         loop {
-            match stdin.next().await.transpose() {
-                Ok(Some(msg)) => {
-                    tokio::spawn({
-                        let state = state.clone();
-                        async move {
-                            match msg.into_data::<NodeImpl::Error>() {
-                                Ok(data) => {
-                                    state.inner.node.handle_message(data, &state).await.ok();
-                                }
-                                Err(e) => {
-                                    tracing::warn!("Error decoding message: {}", e);
-                                }
-                            };
-                        }
+            interval.tick().await;
+            if !spec.jitter.is_zero() {
+                let jitter = rand::thread_rng().gen_range(std::time::Duration::ZERO..=spec.jitter);
+                tokio::time::sleep(jitter).await;
+            }
+
+            let started = tokio::time::Instant::now();
+            if let Err(e) = self.inner.node.on_timer(spec.name, &self).await {
+                tracing::error!(
+                    "Timer {} failed: {}",
+                    spec.name,
+                    snafu::Report::from_error(e)
+                );
+            }
+            if let Some(stats) = self.inner.timers.lock().unwrap().get_mut(spec.name) {
+                stats.record(started.elapsed(), spec.period);
+            }
+        }
+    }
+
+    /// How each of the service's [`Node::timers`] has been doing, by name.
+    pub fn timer_stats(&self) -> BTreeMap<&'static str, TimerStats> {
+        self.inner.timers.lock().unwrap().clone()
+    }
+
+    /// Replace the body of a large message to a peer with its compressed form, if the peer can
+    /// read it and it comes out smaller. Anything else is sent as is.
+    fn compress(
+        &self,
+        mut message: Message<DataOrInit<NodeImpl::Message>>,
+    ) -> Message<DataOrInit<NodeImpl::Message>> {
+        let Some(threshold) = self.inner.compress_above else {
+            return message;
+        };
+        let readable = self
+            .inner
+            .peer_capabilities
+            .get(&*message.dest)
+            .is_some_and(|capabilities| capabilities.compression);
+        if !readable || !matches!(message.body.data, DataOrInit::Data(_)) {
+            return message;
+        }
+
+        let json = match self.inner.codec.serialize(&message.body.data) {
+            Ok(json) if json.len() > threshold => json,
+            _ => return message,
+        };
+        match CompressedEnvelope::seal(&json) {
+            Ok(envelope) if envelope.data.len() < json.len() => {
+                message.body.data = DataOrInit::GossipZ(envelope);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("Failed to compress message to {}: {}", message.dest, e);
+            }
+        }
+        message
+    }
+
+    pub async fn run(node: NodeImpl) -> crate::Result<(), NodeImpl::Error> {
+        Self::run_stdio(node, NodeOptions::default()).await
+    }
+
+    /// Run the node on stdin and stdout. If stdin is a terminal, someone is typing messages by
+    /// hand rather than running the node under Maelstrom, so it runs in
+    /// [`NodeOptions::interactive`] mode and says how to talk to it.
+    pub async fn run_stdio(
+        node: NodeImpl,
+        mut options: NodeOptions,
+    ) -> crate::Result<(), NodeImpl::Error> {
+        if std::io::stdin().is_terminal() {
+            options.interactive = true;
+            eprintln!("{INTERACTIVE_HINT}");
+        }
+        Self::run_with_io(node, options, tokio::io::stdin(), tokio::io::stdout()).await
+    }
+
+    /// Run the node, reading messages from `input` and writing them to `output`.
+    pub async fn run_with_io(
+        node: NodeImpl,
+        options: NodeOptions,
+        input: impl AsyncRead + Unpin,
+        output: impl AsyncWrite + Send + Sync + Unpin + 'static,
+    ) -> crate::Result<(), NodeImpl::Error> {
+        let mut stdin = if options.strict_client_input {
+            let node = node.clone();
+            Either::Right(
+                read_frames(input, options.interactive)
+                    .map(move |frame| frame.and_then(|message| decode_strict(&node, message))),
+            )
+        } else {
+            Either::Left(
+                read_frames(input, options.interactive).map(|frame| frame.map(Inbound::Message)),
+            )
+        };
+
+        let banner = Banner::new(&options)
+            .with_handler_future_size(NodeState::<NodeImpl>::handler_future_size());
+        tracing::info!("Starting Maelstrom node: {}", banner);
+
+        // Peers that finished init before us may already be talking to us. Hold on to their
+        // messages until we know who we are.
+        let mut early = Vec::new();
+        // Only the first frame is waited for with a timeout; after it, the input is clearly wired.
+        let mut startup_timeout = (!options.interactive).then(|| {
+            Duration::from_millis(
+                options
+                    .startup_timeout_ms
+                    .unwrap_or(DEFAULT_STARTUP_TIMEOUT_MS),
+            )
+        });
+        let (src, init_id, node_id, node_ids) = loop {
+            let next = match startup_timeout.take() {
+                Some(timeout) => match tokio::time::timeout(timeout, stdin.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        tracing::warn!(
+                            "Nothing received {:?} after starting, is stdin wired to Maelstrom?",
+                            timeout
+                        );
+                        stdin.next().await
+                    }
+                },
+                None => stdin.next().await,
+            };
+            let inbound = next
+                .ok_or(InternalError::Eof)?
+                .inspect_err(log_receive_error)
+                .context(ReceiveSnafu)?;
+            let Message { src, dest, body } = match inbound {
+                Inbound::Message(message) => message,
+                rejected if early.len() < MAX_EARLY_MESSAGES => {
+                    early.push(rejected);
+                    continue;
+                }
+                Inbound::Rejected { .. } => {
+                    return Err(crate::Error::Internal {
+                        source: crate::node::InternalError::NeedsInit,
                     });
                 }
+            };
+
+            match body.data {
+                DataOrInit::Init { node_id, node_ids } => {
+                    tracing::info!("Received Init message from {}", node_id);
+
+                    break (src, body.id, node_id, node_ids);
+                }
+                data if early.len() < MAX_EARLY_MESSAGES => {
+                    if !options.interactive {
+                        tracing::warn!("Received message from {} before init, deferring it", src);
+                    } else if early.is_empty() {
+                        tracing::info!("Messages are handled once the node receives an init");
+                    }
+                    early.push(Inbound::Message(Message {
+                        src,
+                        dest,
+                        body: MessageBody {
+                            id: body.id,
+                            re: body.re,
+                            trace_id: body.trace_id,
+                            seq: body.seq,
+                            sent_at_ms: body.sent_at_ms,
+                            request_times: body.request_times,
+                            data,
+                        },
+                    }));
+                }
+                _ => {
+                    return Err(crate::Error::Internal {
+                        source: crate::node::InternalError::NeedsInit,
+                    });
+                }
+            }
+        };
+
+        if init_id.is_none() && options.strict_init {
+            return Err(crate::Error::Internal {
+                source: InternalError::MalformedInit,
+            });
+        }
+
+        let mut state = NodeState::with_output(node, node_id.into(), &options, output);
+        state
+            .inner
+            .node_ids
+            .store(Some(Arc::new(node_ids.iter().cloned().collect())));
+        // Background tasks hold on to the state, so they have to be cancelled explicitly even if
+        // this future is dropped.
+        let _tasks = AbortTasksOnDrop(state.clone());
+
+        match init_id {
+            Some(id) => {
+                state.send_init_ok(id, src).await?;
+            }
+            None => {
+                tracing::error!(
+                    "Init message from {} has no msg_id, not acknowledging it",
+                    src
+                );
+            }
+        }
+
+        let peers = node_ids
+            .iter()
+            .filter(|peer| **peer != *state.id())
+            .cloned()
+            .collect();
+        state.start_executor(
+            options
+                .execution
+                .unwrap_or_else(|| state.inner.node.execution()),
+        );
+        state.inner.node.init(&state, node_ids).await?;
+        state.start_timers();
+        state.start_gauges();
+        state.start_rpc_sweeper();
+        state.start_standby();
+        state.exchange_capabilities(peers);
+
+        for inbound in early {
+            state.receive(inbound).await;
+        }
+
+        let result = loop {
+            let next = tokio::select! {
+                biased;
+                () = state.inner.fatal_raised.notified() => {
+                    let error = state.inner.fatal.lock().unwrap().take();
+                    break Err(error.expect("a fatal error was raised"));
+                }
+                next = stdin.next() => next,
+            };
+            match next.transpose() {
+                Ok(Some(inbound)) => state.receive(inbound).await,
                 Ok(None) => {
-                    tracing::warn!("EOF on stdin");
+                    tracing::info!("EOF on stdin, stopping once handlers are done");
+                    state.settle(EOF_GRACE_PERIOD).await;
+                    break match state.inner.fatal.lock().unwrap().take() {
+                        Some(error) => Err(error),
+                        None => Ok(()),
+                    };
+                }
+                Err(source) => {
+                    log_receive_error(&source);
+                    break Err(InternalError::Receive { source }.into());
+                }
+            }
+        };
+
+        state.shutdown().await;
+        state.flush_output().await;
+        let flush = state.flush_stats();
+        tracing::info!(
+            "{} messages written in {} flushes, {:?} at the end",
+            flush.messages,
+            flush.flushes,
+            flush.mode
+        );
+        for (name, stats) in state.timer_stats() {
+            tracing::info!(
+                "Timer {}: {} invocations, {:?} on average, {} overruns",
+                name,
+                stats.invocations,
+                stats.mean_duration(),
+                stats.overruns
+            );
+        }
+        for (tag, latency) in state.handler_latency() {
+            tracing::info!(
+                "Handler {}: {} invocations, {:?} on average, {:?} p99, {:?} max",
+                tag,
+                latency.count,
+                latency.mean,
+                latency.p99,
+                latency.max
+            );
+        }
+        for (client, stats) in state.client_sessions() {
+            tracing::info!(
+                "Client {}: {} requests, {} unanswered, last seen {:?} ago",
+                client,
+                stats.requests,
+                stats.outstanding,
+                stats.last_seen.elapsed()
+            );
+        }
+        let errors = state.error_counts();
+        tracing::info!(
+            "{} messages failed to decode, {} failed in the handler, {} replies to replies refused",
+            errors.decode,
+            errors.handler,
+            errors.replies_to_replies
+        );
+        let rpcs = state.rpc_counts();
+        tracing::info!(
+            "{} requests still awaiting a reply, {} expired, {} evicted",
+            rpcs.pending,
+            rpcs.expired,
+            rpcs.evicted
+        );
+        tracing::info!(
+            "{} replies matched their request, {} arrived late, {} answered nothing we sent",
+            rpcs.matched,
+            rpcs.late,
+            rpcs.unknown
+        );
+        for (peer, sequence) in state.peer_sequences() {
+            tracing::info!(
+                "Peer {}: {} numbered messages received, {} missing, {} out of order",
+                peer,
+                sequence.received,
+                sequence.missing,
+                sequence.reordered
+            );
+        }
+        match state.audit_report() {
+            Some(report) if report.is_consistent() => tracing::info!("Audit: {}", report),
+            Some(report) => tracing::error!("Audit: {}", report),
+            None => {}
+        }
+        tracing::info!("Stopping Maelstrom node: {}", Banner::new(&options));
+        result
+    }
+
+    /// What `peer` told us about itself, once its capabilities have arrived.
+    pub fn peer_capabilities(&self, peer: &str) -> Option<Capabilities> {
+        self.inner
+            .peer_capabilities
+            .get(peer)
+            .map(|capabilities| capabilities.clone())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            tags: self
+                .inner
+                .node
+                .message_tags()
+                .iter()
+                .map(|tag| tag.to_string())
+                .collect(),
+            version: build_version(),
+            compression: true,
+            sequence_numbers: true,
+            timestamps: self.inner.clock_offsets.is_some(),
+        }
+    }
+
+    /// Send our capabilities to every peer, retrying until each one has acknowledged them, if
+    /// [`NodeOptions::exchange_capabilities`] is on.
+    fn exchange_capabilities(&self, peers: Vec<String>) {
+        if !self.inner.exchange_capabilities || peers.is_empty() {
+            return;
+        }
+
+        let state = self.clone();
+        self.spawn(in_subsystem(Subsystem::Runner, async move {
+            let capabilities = state.capabilities();
+            let mut backoff = crate::util::Backoff::builder()
+                .base(std::time::Duration::from_millis(100))
+                .cap(std::time::Duration::from_secs(2))
+                .build();
+            loop {
+                // Peers that left the cluster will never answer.
+                let pending = peers
+                    .iter()
+                    .filter(|peer| !state.inner.capabilities_acked.contains(*peer))
+                    .filter(|peer| state.is_known_destination(peer))
+                    .collect::<Vec<_>>();
+                if pending.is_empty() {
+                    break;
+                }
+                for peer in pending {
+                    let data = DataOrInit::Capabilities(capabilities.clone());
+                    if let Err(e) = state.send_message(peer.as_str(), None, data).await {
+                        tracing::warn!("Failed to send capabilities to {}: {}", peer, e);
+                    }
+                }
+                backoff.wait().await;
+            }
+        }));
+    }
+
+    /// Whether mutating client requests are currently rejected.
+    pub fn is_read_only(&self) -> bool {
+        self.inner.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Whether the node is a standby that hasn't been promoted yet, turning every client request
+    /// away.
+    pub fn is_standby(&self) -> bool {
+        self.inner
+            .standby
+            .as_ref()
+            .is_some_and(|standby| !standby.is_serving())
+    }
+
+    /// Start serving clients, if the node is a standby, after running [`Node::on_promote`].
+    /// Promoting a node more than once does nothing.
+    pub async fn promote(&self) {
+        let Some(standby) = &self.inner.standby else {
+            return;
+        };
+        if !standby.begin_promotion() {
+            return;
+        }
+        tracing::info!("Promoted, taking over from {}", standby.active());
+        if let Err(e) = self.inner.node.on_promote(self).await {
+            tracing::error!("Promotion hook failed: {}", snafu::Report::from_error(e));
+        }
+        standby.finish_promotion();
+    }
+
+    /// Switch to a cluster of `node_ids`: messages to nodes that left are refused from now on, see
+    /// [`NodeState::is_known_destination`], capabilities are exchanged with nodes that joined, and
+    /// the service is told with [`Node::on_membership_change`].
+    async fn change_membership(&self, node_ids: Vec<String>) -> crate::Result<(), NodeImpl::Error> {
+        let previous = self.inner.node_ids.load_full().unwrap_or_default();
+        let joined = node_ids
+            .iter()
+            .filter(|node| !previous.contains(*node) && **node != *self.id())
+            .cloned()
+            .collect::<Vec<_>>();
+        tracing::info!("Cluster changed to {:?}", node_ids);
+        self.inner
+            .node_ids
+            .store(Some(Arc::new(node_ids.iter().cloned().collect())));
+        self.exchange_capabilities(joined);
+        self.inner.node.on_membership_change(self, node_ids).await
+    }
+
+    /// Compare our [`Node::state_digest`] with every peer's, waiting up to
+    /// [`NodeOptions::audit_timeout_ms`] for their answers, see [`crate::audit`]. Peers that hold
+    /// something else are logged as errors. Returns `None` if audits are off or the service has no
+    /// digest; otherwise the report is also kept for the summary logged when the node stops.
+    pub async fn audit(&self) -> Option<AuditReport> {
+        let timeout = self.inner.audit_timeout?;
+        let local = self.inner.node.state_digest()?;
+        let peers = self
+            .inner
+            .node_ids
+            .load_full()
+            .unwrap_or_default()
+            .iter()
+            .filter(|node| **node != *self.id())
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut report = AuditReport::new(local, peers.iter().cloned());
+
+        let (answers, mut received) = tokio::sync::mpsc::unbounded_channel();
+        *self.inner.audit_answers.lock().unwrap() = Some(answers);
+        for peer in &peers {
+            let audit = self.send_message(peer.as_str(), None, DataOrInit::Audit(local));
+            if let Err(e) = in_subsystem(Subsystem::Runner, audit).await {
+                tracing::warn!("Failed to send audit to {}: {}", peer, e);
+            }
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !report.is_complete() {
+            let Ok(Some((peer, theirs))) = tokio::time::timeout_at(deadline, received.recv()).await
+            else {
+                break;
+            };
+            if report.record(&peer, theirs) == Some(false) {
+                tracing::error!("{} holds {}, but we hold {}", peer, theirs, local);
+            }
+        }
+        self.inner.audit_answers.lock().unwrap().take();
+
+        if !report.is_complete() {
+            tracing::warn!("No audit answer from {:?}", report.unanswered);
+        }
+        // After the answers, which may reveal the last gaps.
+        report.lost = self
+            .peer_sequences()
+            .into_iter()
+            .filter(|(_, sequence)| sequence.missing > 0)
+            .map(|(peer, sequence)| (peer, sequence.missing))
+            .collect();
+        *self.inner.audit_report.lock().unwrap() = Some(report.clone());
+        Some(report)
+    }
+
+    /// The outcome of the last [`NodeState::audit`], if there was one.
+    pub fn audit_report(&self) -> Option<AuditReport> {
+        self.inner.audit_report.lock().unwrap().clone()
+    }
+
+    /// The sequence number of the next message to `dest`, if it is a peer that counts them. Only
+    /// called while `queueing` is held, so that numbers are in write order.
+    fn next_peer_seq(&self, dest: &Arc<str>) -> Option<u64> {
+        let counts = self.inner.sequence_peer_messages
+            && self
+                .inner
+                .peer_capabilities
+                .get(&**dest)
+                .is_some_and(|capabilities| capabilities.sequence_numbers);
+        if !counts {
+            return None;
+        }
+        let mut sent = self.inner.sent_seqs.lock().unwrap();
+        let seq = sent.entry(Arc::clone(dest)).or_default();
+        *seq += 1;
+        Some(*seq)
+    }
+
+    /// The timestamps for a message to `dest`, replying to `re`, if it is a peer that reads them:
+    /// when it was sent, and when the request it answers was sent and received.
+    fn timestamps(&self, dest: &str, re: Option<MessageId>) -> (Option<u64>, Option<(u64, u64)>) {
+        let Some(offsets) = &self.inner.clock_offsets else {
+            return (None, None);
+        };
+        let reads = self
+            .inner
+            .peer_capabilities
+            .get(dest)
+            .is_some_and(|capabilities| capabilities.timestamps);
+        if !reads {
+            return (None, None);
+        }
+        let request_times = re.and_then(|re| offsets.lock().unwrap().echo(dest, re));
+        (Some(self.now_ms()), request_times)
+    }
+
+    /// Milliseconds since the Unix epoch, by the node's clock, see [`NodeOptions::clock`].
+    pub fn now_ms(&self) -> u64 {
+        self.inner.clock.now_ms()
+    }
+
+    /// How far `peer`'s clock is ahead of ours, in milliseconds, or behind if negative. `None`
+    /// until `peer` has answered a timed request, or without
+    /// [`NodeOptions::estimate_clock_offsets`]. See [`crate::clock`].
+    pub fn estimated_offset_ms(&self, peer: &str) -> Option<i64> {
+        self.inner
+            .clock_offsets
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .offset_ms(peer)
+    }
+
+    /// The median of [`NodeState::estimated_offset_ms`] over every peer: how far the cluster's
+    /// clock is ahead of ours, by the majority.
+    pub fn cluster_offset_ms(&self) -> Option<i64> {
+        self.inner
+            .clock_offsets
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .median_ms()
+    }
+
+    /// Heartbeat the active node, and promote this one once it stops answering, if the node is a
+    /// standby that should.
+    fn start_standby(&self) {
+        let Some(period) = self
+            .inner
+            .standby
+            .as_ref()
+            .and_then(Standby::heartbeat_interval)
+        else {
+            return;
+        };
+        let state = self.clone();
+        self.spawn(in_subsystem(Subsystem::Runner, async move {
+            let Some(standby) = &state.inner.standby else {
+                return;
+            };
+            let mut interval = tokio::time::interval(period);
+            while !standby.is_serving() {
+                interval.tick().await;
+                if standby.active_is_dead(tokio::time::Instant::now()) {
+                    tracing::warn!("No word from {}, promoting", standby.active());
+                    state.promote().await;
+                    break;
                 }
+                let active = standby.active().to_owned();
+                if let Err(e) = state
+                    .send_message(active, None, DataOrInit::Heartbeat)
+                    .await
+                {
+                    tracing::warn!("Failed to send heartbeat: {}", snafu::Report::from_error(e));
+                }
+            }
+        }));
+    }
+
+    /// Handle the runner's own messages, returning any message meant for the service.
+    async fn handle_runner_message(
+        &self,
+        mut msg: Message<DataOrInit<NodeImpl::Message>>,
+    ) -> crate::Result<Option<Message<DataOrInit<NodeImpl::Message>>>, NodeImpl::Error> {
+        let (src, id) = (Arc::clone(&msg.src), msg.body.id);
+        if let DataOrInit::GossipZ(envelope) = &msg.body.data {
+            match envelope.open() {
+                Ok(data) => msg.body.data = data,
                 Err(e) => {
-                    return Err(e.into());
+                    tracing::warn!("Failed to decompress message from {}: {}", src, e);
+                    if let Some(id) = id {
+                        let reply = DataOrInit::Error {
+                            code: ErrorCode::MalformedRequest,
+                            text: format!("failed to decompress gossip_z: {e}"),
+                        };
+                        self.send_message(src, Some(id), reply).await?;
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+        let reply = match &msg.body.data {
+            // Maelstrom only initializes a node once, but harnesses may re-send init. The same ID
+            // is acknowledged again; a different one is refused, since peers and clients already
+            // know us by the old one.
+            DataOrInit::Init { node_id, .. } if **node_id == *self.id() => DataOrInit::InitOk,
+            DataOrInit::Init { node_id, .. } => {
+                let e = InternalError::IdentityChange {
+                    current: self.id(),
+                    requested: node_id.clone(),
+                };
+                tracing::error!("{}", e);
+                DataOrInit::Error {
+                    code: ErrorCode::NotSupported,
+                    text: e.to_string(),
                 }
             }
+            DataOrInit::Capabilities(theirs) => {
+                if let Some(problem) = incompatibility(&self.capabilities(), theirs) {
+                    tracing::warn!("Peer {} looks incompatible: it {}", src, problem);
+                }
+                self.inner
+                    .peer_capabilities
+                    .insert(src.to_string(), theirs.clone());
+                DataOrInit::CapabilitiesOk
+            }
+            DataOrInit::CapabilitiesOk => {
+                self.inner.capabilities_acked.insert(src.to_string());
+                return Ok(None);
+            }
+            DataOrInit::MembershipChange { node_ids } => {
+                match self.change_membership(node_ids.clone()).await {
+                    Ok(()) => DataOrInit::MembershipChangeOk,
+                    Err(e) => {
+                        tracing::error!(
+                            "Membership change hook failed: {}",
+                            snafu::Report::from_error(&e)
+                        );
+                        DataOrInit::Error {
+                            code: ErrorCode::Crash,
+                            text: e.to_string(),
+                        }
+                    }
+                }
+            }
+            DataOrInit::Drain => {
+                // Answered once the peers have, so the audit mustn't hold up this handler: the
+                // answers are handled like any other message.
+                let state = self.clone();
+                self.spawn(in_subsystem(Subsystem::Runner, async move {
+                    let audit = state.audit().await;
+                    if let Some(id) = id {
+                        let reply = DataOrInit::DrainOk { audit };
+                        if let Err(e) = state.send_message(src, Some(id), reply).await {
+                            tracing::warn!("Failed to answer drain: {}", e);
+                        }
+                    }
+                }));
+                return Ok(None);
+            }
+            DataOrInit::DumpHistory => DataOrInit::DumpHistoryOk {
+                dumped: self.dump_history("as requested"),
+            },
+            DataOrInit::FlushGossip { full } => {
+                // Like a drain, answered once the round's messages are written.
+                let (state, full) = (self.clone(), *full);
+                self.spawn(in_subsystem(Subsystem::Runner, async move {
+                    let reply = match state.inner.node.flush_gossip(&state, full).await {
+                        Ok(Some(sent)) => DataOrInit::FlushGossipOk { sent },
+                        Ok(None) => DataOrInit::Error {
+                            code: ErrorCode::NotSupported,
+                            text: "the service doesn't gossip".into(),
+                        },
+                        Err(e) => {
+                            tracing::warn!("Flushing gossip failed: {}", e);
+                            DataOrInit::Error {
+                                code: ErrorCode::Crash,
+                                text: e.to_string(),
+                            }
+                        }
+                    };
+                    if let Some(id) = id {
+                        if let Err(e) = state.send_message(src, Some(id), reply).await {
+                            tracing::warn!("Failed to answer flush_gossip: {}", e);
+                        }
+                    }
+                }));
+                return Ok(None);
+            }
+            DataOrInit::Audit(theirs) => match self.inner.node.state_digest() {
+                Some(ours) => {
+                    if ours != *theirs {
+                        tracing::debug!("{} holds {}, we hold {}", src, theirs, ours);
+                    }
+                    DataOrInit::AuditOk(ours)
+                }
+                None => DataOrInit::Error {
+                    code: ErrorCode::NotSupported,
+                    text: "the service has no state to audit".into(),
+                },
+            },
+            DataOrInit::AuditOk(theirs) => {
+                if let Some(answers) = &*self.inner.audit_answers.lock().unwrap() {
+                    answers.send((Arc::clone(&src), *theirs)).ok();
+                }
+                return Ok(None);
+            }
+            DataOrInit::SetReadOnly { read_only } => {
+                tracing::info!("Read-only mode {}", if *read_only { "on" } else { "off" });
+                self.inner.read_only.store(*read_only, Ordering::SeqCst);
+                DataOrInit::SetReadOnlyOk
+            }
+            DataOrInit::Promote => {
+                self.promote().await;
+                DataOrInit::PromoteOk
+            }
+            DataOrInit::Heartbeat => DataOrInit::HeartbeatOk,
+            DataOrInit::HeartbeatOk => return Ok(None),
+            DataOrInit::Tune { tune } => match self.inner.node.reconfigure(tune) {
+                Ok(()) => {
+                    tracing::info!("Tuned {}", tune);
+                    DataOrInit::TuneOk
+                }
+                Err(text) => {
+                    tracing::warn!("Rejected tune {}: {}", tune, text);
+                    DataOrInit::Error {
+                        code: ErrorCode::MalformedRequest,
+                        text,
+                    }
+                }
+            },
+            DataOrInit::Data(_) if is_client(&src) && self.is_standby() => {
+                let standby = self.inner.standby.as_ref().expect("standby");
+                DataOrInit::StandbyError {
+                    code: ErrorCode::TemporarilyUnavailable,
+                    text: format!("node is a standby for {}", standby.active()),
+                    active: standby.active().to_owned(),
+                }
+            }
+            DataOrInit::Data(data)
+                if self.is_read_only() && is_client(&src) && self.inner.node.is_mutating(data) =>
+            {
+                DataOrInit::Error {
+                    code: ErrorCode::TemporarilyUnavailable,
+                    text: "node is read-only".into(),
+                }
+            }
+            _ => return Ok(Some(msg)),
+        };
+
+        if let Some(id) = id {
+            self.send_message(src, Some(id), reply).await?;
         }
+        Ok(None)
     }
-}
 
-pub async fn run<NodeImpl: Node + Send + Sync + 'static>(
-    node: NodeImpl,
-) -> Result<(), crate::Error<NodeImpl::Error>> {
-    NodeState::run(node).await
+    async fn receive(&self, inbound: Inbound<NodeImpl::Message>) {
+        let (src, id, reason) = match inbound {
+            Inbound::Message(msg) => return self.dispatch(msg).await,
+            Inbound::Rejected { src, id, reason } => (src, id, reason),
+        };
+
+        tracing::warn!("Rejecting malformed request from {}: {}", src, reason);
+        self.inner.decode_errors.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .clients
+            .lock()
+            .unwrap()
+            .request(&src, id.is_some());
+        let Some(id) = id else {
+            return;
+        };
+        tokio::spawn({
+            let state = self.clone();
+            async move {
+                let reply = DataOrInit::Error {
+                    code: ErrorCode::MalformedRequest,
+                    text: reason,
+                };
+                if let Err(e) = state.send_message(src, Some(id), reply).await {
+                    tracing::warn!("Error rejecting request: {}", snafu::Report::from_error(e));
+                }
+            }
+        });
+    }
+
+    /// Hand a message to the executor, see [`Execution`]. Only waits with a pool or
+    /// [`Execution::EventLoop`], while its queue is full.
+    async fn dispatch(&self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        if is_client(&msg.src) {
+            self.inner
+                .clients
+                .lock()
+                .unwrap()
+                .request(&msg.src, msg.body.id.is_some());
+        }
+        if let Some(standby) = &self.inner.standby {
+            standby.heard_from(&msg.src, tokio::time::Instant::now());
+        }
+        if let (Some(id), Some(_)) = (msg.body.id, msg.body.re) {
+            self.inner
+                .recent_replies
+                .lock()
+                .unwrap()
+                .record(&msg.src, id);
+        }
+        if let Some(seq) = msg.body.seq {
+            self.record_peer_seq(&msg.src, seq);
+        }
+        if let (Some(offsets), Some(sent_at)) = (&self.inner.clock_offsets, msg.body.sent_at_ms) {
+            offsets.lock().unwrap().received(
+                &msg.src,
+                msg.body.id,
+                sent_at,
+                msg.body.request_times,
+                self.now_ms(),
+            );
+        }
+        self.record(Direction::Received, &msg);
+        let Some(msg) = self.complete_rpc(msg) else {
+            return;
+        };
+        let Some(msg) = self.admit(msg, 0) else {
+            return;
+        };
+        self.execute(msg).await
+    }
+
+    /// Ask [`Node::admit`] about `msg` if it is a client request, giving it back if it is to be
+    /// handled now. Rejected requests are answered, and deferred ones dispatched again later.
+    fn admit(
+        &self,
+        msg: Message<DataOrInit<NodeImpl::Message>>,
+        deferrals: u32,
+    ) -> Option<Message<DataOrInit<NodeImpl::Message>>> {
+        let DataOrInit::Data(data) = &msg.body.data else {
+            return Some(msg);
+        };
+        if !is_client(&msg.src) {
+            return Some(msg);
+        }
+        let request = AdmissionRequest {
+            src: &msg.src,
+            id: msg.body.id,
+            data,
+            tag: self.inner.node.message_tag(data),
+            deferrals,
+        };
+        match self.inner.node.admit(&request, self) {
+            Admission::Accept => Some(msg),
+            Admission::Reject { code, text } => {
+                // Dropped, with nothing to answer.
+                let id = msg.body.id?;
+                let state = self.clone();
+                self.spawn(async move {
+                    let reply = DataOrInit::Error { code, text };
+                    if let Err(e) = state.send_message(msg.src, Some(id), reply).await {
+                        tracing::warn!("Error rejecting request: {}", snafu::Report::from_error(e));
+                    }
+                });
+                None
+            }
+            Admission::Defer { .. } if deferrals >= MAX_DEFERRALS => {
+                tracing::warn!(
+                    "Handling request {:?} from {} after {} deferrals",
+                    msg.body.id,
+                    msg.src,
+                    deferrals
+                );
+                Some(msg)
+            }
+            Admission::Defer { delay } => {
+                let state = self.clone();
+                self.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    if let Some(msg) = state.admit(msg, deferrals + 1) {
+                        state.execute(msg).await;
+                    }
+                });
+                None
+            }
+        }
+    }
+
+    /// Handle `msg` in a span carrying its trace ID, with the ID in scope for every message the
+    /// handler sends, see [`trace_id`].
+    async fn handle(&self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        let trace_id = msg
+            .body
+            .trace_id
+            .or_else(|| is_client(&msg.src).then(rand::random));
+        // For a reply, what it answers, read from the tag of our ID it carries.
+        let replies_to = msg.body.re.and_then(Subsystem::of).map(Subsystem::name);
+        let span = tracing::info_span!(
+            "handle",
+            src = %msg.src,
+            msg_id = msg.body.id,
+            trace_id,
+            replies_to
+        );
+        let _active = Counted::new(&self.inner.active_handlers);
+        TRACE_ID
+            .scope(trace_id, self.process(msg))
+            .instrument(span)
+            .await
+    }
+
+    async fn process(&self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        if let Some(data) = self.prepare(msg).await {
+            self.handle_data(data).await;
+        }
+    }
+
+    /// The runner's part of handling `msg`: its own messages are handled here, and the rest
+    /// decoded for the service. Returns what is left for the service.
+    async fn prepare(
+        &self,
+        msg: Message<DataOrInit<NodeImpl::Message>>,
+    ) -> Option<Message<NodeImpl::Message>> {
+        let msg = match in_subsystem(Subsystem::Runner, self.handle_runner_message(msg)).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return None,
+            Err(e) => {
+                tracing::warn!(
+                    "Error handling runner message: {}",
+                    snafu::Report::from_error(e)
+                );
+                return None;
+            }
+        };
+
+        let (src, id) = (Arc::clone(&msg.src), msg.body.id);
+        match msg.into_data::<NodeImpl::Error>() {
+            Ok(data) => Some(data),
+            Err(e) => {
+                self.inner.decode_errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Message {:?} from {} is not a {} message: {}",
+                    id,
+                    src,
+                    std::any::type_name::<NodeImpl::Message>(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Hand `data` to [`Node::handle_message`], timing it and answering errors.
+    async fn handle_data(&self, data: Message<NodeImpl::Message>) {
+        let meta = MessageMeta {
+            src: Arc::clone(&data.src),
+            id: data.body.id,
+            re: data.body.re,
+        };
+        let timing = self.start_timing(&data.src, &data.body.data);
+        let result = self.inner.node.handle_message(data, self).await;
+        self.finish_timing(timing);
+        if let Err(e) = result {
+            self.handler_failed(&meta, e).await;
+        }
+    }
+
+    /// Report a failed handler, and answer the request with a `crash` error if a client is
+    /// waiting on it.
+    /// Stops the node if the error is fatal, see [`Node::is_fatal`].
+    async fn handler_failed(&self, meta: &MessageMeta, error: crate::Error<NodeImpl::Error>) {
+        self.inner.handler_errors.fetch_add(1, Ordering::Relaxed);
+        self.inner.node.on_handler_error(meta, &error);
+        self.reply_failed(meta, &error).await;
+        if error.is_fatal(|e| self.inner.node.is_fatal(e)) {
+            self.stop_on(error);
+        }
+    }
+
+    async fn reply_failed(&self, meta: &MessageMeta, error: &crate::Error<NodeImpl::Error>) {
+        let Some(id) = meta
+            .id
+            .filter(|_| meta.re.is_none() && is_client(&meta.src))
+        else {
+            return;
+        };
+        // The error reply would fail the same way.
+        if matches!(error, crate::Error::Internal { source } if source.is_output_closed()) {
+            return;
+        }
+        let code = match error {
+            crate::Error::Node { source } => self.inner.node.error_code(source),
+            crate::Error::Internal {
+                source:
+                    InternalError::Timeout { .. }
+                    | InternalError::RetriesExhausted { .. }
+                    | InternalError::RpcExpired { .. },
+            } => ErrorCode::Timeout,
+            crate::Error::Internal {
+                source: InternalError::RpcEvicted { .. },
+            } => ErrorCode::TemporarilyUnavailable,
+            _ => ErrorCode::Crash,
+        };
+        let reply = DataOrInit::Error {
+            code,
+            text: error.to_string(),
+        };
+        if let Err(e) = self
+            .send_message(Arc::clone(&meta.src), Some(id), reply)
+            .await
+        {
+            tracing::warn!(
+                "Error replying to failed request: {}",
+                snafu::Report::from_error(e)
+            );
+        }
+    }
+
+    /// Have the run loop stop and return `error`. Only the first fatal error is returned; later
+    /// ones are only logged.
+    fn stop_on(&self, error: crate::Error<NodeImpl::Error>) {
+        tracing::error!(
+            "Stopping on fatal error: {}",
+            snafu::Report::from_error(&error)
+        );
+        let mut fatal = self.inner.fatal.lock().unwrap();
+        if fatal.is_none() {
+            *fatal = Some(error);
+            self.inner.fatal_raised.notify_one();
+            drop(fatal);
+            self.dump_history("stopping on a fatal error");
+        }
+    }
+
+    /// Keep a summary of `message` in the history, see [`NodeOptions::history_size`].
+    fn record(&self, direction: Direction, message: &Message<DataOrInit<NodeImpl::Message>>) {
+        let Some(history) = &self.inner.history else {
+            return;
+        };
+        let tag = match &message.body.data {
+            DataOrInit::Data(data) => self.inner.node.message_tag(data),
+            runner => runner.runner_tag(),
+        };
+        let summary = MessageSummary::new(direction, message, tag);
+        history.lock().unwrap().record(summary);
+    }
+
+    /// The messages received and sent last, oldest first.
+    pub fn history(&self) -> Vec<MessageSummary> {
+        self.inner
+            .history
+            .as_ref()
+            .map(|history| history.lock().unwrap().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Log the history as a JSON array, saying `why`. Returns how many messages it held.
+    fn dump_history(&self, why: &str) -> usize {
+        let history = self.history();
+        if self.inner.history.is_some() {
+            let json = serde_json::to_string(&history).unwrap_or_default();
+            tracing::warn!("Recent messages, {}: {}", why, json);
+        }
+        history.len()
+    }
+}
+
+/// Check that a node of the same kind would read `message` back as the same message, and that its
+/// envelope is well-formed.
+///
+/// This catches `Serialize` and `Deserialize` impls that have drifted apart, e.g. a field renamed
+/// on one side only, before a peer or Maelstrom rejects the message.
+pub fn validate_outgoing<Data>(message: &Message<DataOrInit<Data>>) -> Result<(), String>
+where
+    Data: Serialize + for<'de> Deserialize<'de>,
+{
+    if message.src.is_empty() || message.dest.is_empty() {
+        return Err("empty src or dest".to_owned());
+    }
+    if message.body.id.is_none() {
+        return Err("no msg_id".to_owned());
+    }
+
+    let encoded = serde_json::to_value(message).map_err(|e| format!("failed to serialize: {e}"))?;
+    let kind = encoded["body"]["type"].as_str().unwrap_or_default();
+    // Service messages only follow Maelstrom's naming for replies, so a service message that
+    // doesn't end in `_ok` may still be a reply. The runner's own requests never are.
+    let is_reply = kind == "error" || kind.ends_with("_ok");
+    let is_request = matches!(
+        message.body.data,
+        DataOrInit::Init { .. }
+            | DataOrInit::SetReadOnly { .. }
+            | DataOrInit::Tune { .. }
+            | DataOrInit::Promote
+            | DataOrInit::Heartbeat
+            | DataOrInit::Capabilities(_)
+            | DataOrInit::MembershipChange { .. }
+            | DataOrInit::Drain
+            | DataOrInit::FlushGossip { .. }
+            | DataOrInit::DumpHistory
+            | DataOrInit::Audit(_)
+    );
+    match message.body.re {
+        None if is_reply => return Err(format!("{kind} is a reply but has no in_reply_to")),
+        Some(_) if is_request => return Err(format!("{kind} is not a reply but has in_reply_to")),
+        _ => {}
+    }
+
+    // Runner errors are never deserialized, see `DataOrInit::Error`.
+    if matches!(
+        message.body.data,
+        DataOrInit::Error { .. } | DataOrInit::StandbyError { .. }
+    ) {
+        return Ok(());
+    }
+    let decoded = serde_json::from_value::<Message<DataOrInit<Data>>>(encoded.clone())
+        .map_err(|e| format!("failed to deserialize: {e}"))?;
+    let reencoded =
+        serde_json::to_value(&decoded).map_err(|e| format!("failed to serialize: {e}"))?;
+    if reencoded != encoded {
+        return Err(format!("reads back as {reencoded}"));
+    }
+    Ok(())
+}
+
+/// Maelstrom names clients `c1`, `c2`, ... and nodes `n1`, `n2`, ...
+pub(crate) fn is_client(id: &str) -> bool {
+    id.starts_with('c')
+}
+
+/// Whether `id` is a well-formed client ID, as opposed to merely starting like one.
+fn is_client_id(id: &str) -> bool {
+    id.strip_prefix('c')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+struct AbortTasksOnDrop<NodeImpl: Node + Send + Sync + 'static>(NodeState<NodeImpl>);
+
+impl<NodeImpl: Node + Send + Sync + 'static> Drop for AbortTasksOnDrop<NodeImpl> {
+    fn drop(&mut self) {
+        self.0.inner.tasks.lock().unwrap().abort_all();
+    }
+}
+
+pub async fn run<NodeImpl: Node + Send + Sync + 'static>(
+    node: NodeImpl,
+) -> Result<(), crate::Error<NodeImpl::Error>> {
+    NodeState::run(node).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::flush::FlushMode;
+
+    #[derive(Clone)]
+    pub(super) struct NullService;
+
+    impl Node for NullService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Replies `{"type": "pong"}` to every message.
+    #[derive(Clone)]
+    pub(super) struct PingService;
+
+    impl Node for PingService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            state
+                .reply(
+                    message.src,
+                    message.body.id.unwrap_or_default(),
+                    serde_json::json!({ "type": "pong" }),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Replies `{"type": "pong"}` to everything, and counts its promotions.
+    #[derive(Clone, Default)]
+    struct PromotionService {
+        promotions: Arc<AtomicU64>,
+    }
+
+    impl Node for PromotionService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn on_promote(&self, _state: &NodeState<Self>) -> crate::Result<(), Self::Error> {
+            self.promotions.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let pong = serde_json::json!({ "type": "pong" });
+            state
+                .reply(message.src, message.body.id.unwrap_or_default(), pong)
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Has a fast timer and a slow one, the slow one taking longer than its period.
+    #[derive(Clone, Default)]
+    struct TimerService {
+        fired: Arc<std::sync::Mutex<Vec<(String, tokio::time::Instant)>>>,
+    }
+
+    impl Node for TimerService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn timers(&self) -> Vec<TimerSpec> {
+            vec![
+                TimerSpec::new("fast", Duration::from_millis(100)),
+                TimerSpec::new("slow", Duration::from_secs(1)),
+            ]
+        }
+
+        async fn on_timer(
+            &self,
+            name: &str,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let now = tokio::time::Instant::now();
+            self.fired.lock().unwrap().push((name.to_owned(), now));
+            if name == "slow" {
+                tokio::time::sleep(Duration::from_millis(2500)).await;
+            }
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Run a [`PingService`] node, feed it `frames`, and collect everything it writes until it
+    /// goes quiet.
+    async fn run_ping(
+        options: NodeOptions,
+        frames: &[&str],
+    ) -> (
+        Vec<serde_json::Value>,
+        Option<crate::Result<(), std::io::Error>>,
+    ) {
+        run_service(PingService, options, frames).await
+    }
+
+    /// Like [`run_ping`], for any service.
+    pub(super) async fn run_service<NodeImpl: Node + Send + Sync + 'static>(
+        service: NodeImpl,
+        options: NodeOptions,
+        frames: &[&str],
+    ) -> (
+        Vec<serde_json::Value>,
+        Option<crate::Result<(), NodeImpl::Error>>,
+    ) {
+        let (mut stdin, node_stdin) = tokio::io::duplex(4096);
+        let (node_stdout, stdout) = tokio::io::duplex(4096);
+        let node = tokio::spawn(NodeState::run_with_io(
+            service,
+            options,
+            node_stdin,
+            node_stdout,
+        ));
+
+        for frame in frames {
+            stdin.write_all(frame.as_bytes()).await.unwrap();
+            stdin.write_all(b"\n").await.unwrap();
+        }
+
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let mut output = Vec::new();
+        while let Ok(Ok(Some(line))) =
+            tokio::time::timeout(Duration::from_millis(100), lines.next_line()).await
+        {
+            output.push(serde_json::from_str(&line).unwrap());
+        }
+
+        let result = if node.is_finished() {
+            Some(node.await.unwrap())
+        } else {
+            node.abort();
+            None
+        };
+        (output, result)
+    }
+
+    /// Replies `{"type": "echo_ok", "echo": ...}` with the `echo` of every message, after
+    /// `sleep_ms` if it has one.
+    #[derive(Clone)]
+    pub(super) struct EchoBackService;
+
+    impl Node for EchoBackService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            if let Some(sleep) = message.body.data["sleep_ms"].as_u64() {
+                tokio::time::sleep(Duration::from_millis(sleep)).await;
+            }
+            let echo = message.body.data["echo"].clone();
+            state
+                .reply(
+                    message.src,
+                    message.body.id.unwrap_or_default(),
+                    serde_json::json!({ "type": "echo_ok", "echo": echo }),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Fails to handle `fail`, `busy` and `corrupt` messages, the last fatally, recording what
+    /// [`Node::on_handler_error`] was told. Panics on `panic`.
+    #[derive(Clone, Default)]
+    pub(super) struct FailingService {
+        pub(super) state: Arc<std::sync::OnceLock<NodeState<FailingService>>>,
+        pub(super) failures: Arc<std::sync::Mutex<Vec<MessageMeta>>>,
+    }
+
+    impl Node for FailingService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn init(
+            &self,
+            state: &NodeState<Self>,
+            _node_ids: Vec<String>,
+        ) -> crate::Result<(), Self::Error> {
+            self.state.set(state.clone()).ok();
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            match message.body.data["type"].as_str() {
+                Some("fail") => Err(crate::Error::Node {
+                    source: std::io::Error::other("out of luck"),
+                }),
+                Some("busy") => Err(crate::Error::Node {
+                    source: std::io::ErrorKind::WouldBlock.into(),
+                }),
+                Some("corrupt") => Err(crate::Error::Node {
+                    source: std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "persisted state is corrupted",
+                    ),
+                }),
+                Some("panic") => panic!("handler blew up"),
+                _ => Ok(()),
+            }
+        }
+
+        fn is_fatal(&self, error: &Self::Error) -> bool {
+            error.kind() == std::io::ErrorKind::InvalidData
+        }
+
+        fn error_code(&self, error: &Self::Error) -> ErrorCode {
+            match error.kind() {
+                std::io::ErrorKind::WouldBlock => ErrorCode::TemporarilyUnavailable,
+                _ => ErrorCode::Crash,
+            }
+        }
+
+        fn on_handler_error(&self, meta: &MessageMeta, _error: &crate::Error<Self::Error>) {
+            self.failures.lock().unwrap().push(meta.clone());
+        }
+    }
+
+    /// Records the `seq` of every message by sender, and of late replies apart. Yields a random
+    /// number of times first, so that messages handled concurrently finish out of order.
+    #[derive(Clone, Default)]
+    pub(super) struct RecordingService {
+        pub(super) handled: Arc<std::sync::Mutex<Vec<Handled>>>,
+        pub(super) late: Arc<std::sync::Mutex<Vec<Handled>>>,
+    }
+
+    /// The sender and `seq` of a message handled by [`RecordingService`].
+    pub(super) type Handled = (Arc<str>, u64);
+
+    impl Node for RecordingService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            for _ in 0..rand::random::<u8>() % 8 {
+                tokio::task::yield_now().await;
+            }
+            let seq = message.body.data["seq"].as_u64().unwrap();
+            self.handled.lock().unwrap().push((message.src, seq));
+            Ok(())
+        }
+
+        async fn on_late_reply(
+            &self,
+            reply: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let seq = reply.body.data["seq"].as_u64().unwrap();
+            self.late.lock().unwrap().push((reply.src, seq));
+            Ok(())
+        }
+    }
+
+    /// Starts one background task of each kind on init.
+    #[derive(Clone, Default)]
+    struct BackgroundService {
+        finished: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Node for BackgroundService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn init(
+            &self,
+            state: &NodeState<Self>,
+            _node_ids: Vec<String>,
+        ) -> crate::Result<(), Self::Error> {
+            state.spawn(std::future::pending());
+            let finished = Arc::clone(&self.finished);
+            state.spawn_with(OnShutdown::Await, async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                finished.store(true, Ordering::SeqCst);
+            });
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Advertises the given tags and keeps hold of its node state so tests can inspect it. Takes
+    /// `sleep_ms` to handle a message, if it has one.
+    #[derive(Clone)]
+    pub(super) struct TaggedService {
+        pub(super) tags: &'static [&'static str],
+        pub(super) state: Arc<std::sync::OnceLock<NodeState<TaggedService>>>,
+    }
+
+    impl Node for TaggedService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn message_tags(&self) -> &[&'static str] {
+            self.tags
+        }
+
+        async fn init(
+            &self,
+            state: &NodeState<Self>,
+            _node_ids: Vec<String>,
+        ) -> crate::Result<(), Self::Error> {
+            self.state.set(state.clone()).ok();
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            if let Some(sleep) = message.body.data["sleep_ms"].as_u64() {
+                tokio::time::sleep(Duration::from_millis(sleep)).await;
+            }
+            Ok(())
+        }
+    }
+
+    pub(super) const PING: &str = r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":5}}"#;
+    pub(super) const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_eof_stops_once_handlers_are_done() {
+        let echo = |id: u64, sleep_ms: u64| {
+            serde_json::json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": { "type": "echo", "msg_id": id, "echo": id, "sleep_ms": sleep_ms },
+            })
+            .to_string()
+        };
+        let run = |frames: Vec<String>| async move {
+            let input = frames.join("\n") + "\n";
+            let (node_stdout, stdout) = tokio::io::duplex(64 * 1024);
+            let started = tokio::time::Instant::now();
+            let result = NodeState::run_with_io(
+                EchoBackService,
+                NodeOptions::default(),
+                input.as_bytes(),
+                node_stdout,
+            )
+            .await;
+            let elapsed = started.elapsed();
+            let mut lines = tokio::io::BufReader::new(stdout).lines();
+            let mut replied = Vec::new();
+            // Only what was written by the time the node stopped: under Maelstrom, the process
+            // exits with it.
+            while let Ok(Ok(Some(line))) =
+                tokio::time::timeout(Duration::from_millis(100), lines.next_line()).await
+            {
+                let reply = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+                replied.push(reply["body"]["in_reply_to"].as_u64().unwrap());
+            }
+            replied.sort_unstable();
+            (result, replied, elapsed)
+        };
+
+        // Replies to everything read before the input closed are written before the node stops.
+        let frames = vec![INIT.to_owned(), echo(2, 0), echo(3, 500), echo(4, 50)];
+        let (result, replied, elapsed) = run(frames).await;
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(replied, [1, 2, 3, 4]);
+        assert!(elapsed < EOF_GRACE_PERIOD, "{elapsed:?}");
+
+        // A handler that takes too long is given up on.
+        let frames = vec![INIT.to_owned(), echo(2, 0), echo(3, 60_000)];
+        let (result, replied, elapsed) = run(frames).await;
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(replied, [1, 2]);
+        assert!(elapsed >= EOF_GRACE_PERIOD, "{elapsed:?}");
+        assert!(
+            elapsed < EOF_GRACE_PERIOD + Duration::from_secs(1),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_without_msg_id() {
+        let init =
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","node_id":"n1","node_ids":["n1"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[init, PING]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 1, "{:?}", output);
+        assert_eq!(output[0]["body"]["type"], "pong");
+        assert_eq!(output[0]["body"]["in_reply_to"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_init_with_null_msg_id() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":null,"node_id":"n1","node_ids":["n1"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[init, PING]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 1, "{:?}", output);
+        assert_eq!(output[0]["body"]["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_init_with_string_msg_id() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":"7","node_id":"n1","node_ids":["n1"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[init, PING]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 2, "{:?}", output);
+        assert_eq!(output[0]["body"]["type"], "init_ok");
+        assert_eq!(output[0]["body"]["in_reply_to"], 7);
+        assert_eq!(output[1]["body"]["type"], "pong");
+    }
+
+    /// The reply to the message with ID `re`.
+    pub(super) fn reply_to(output: &[serde_json::Value], re: u64) -> &serde_json::Value {
+        output
+            .iter()
+            .find(|frame| frame["body"]["in_reply_to"] == re)
+            .unwrap_or_else(|| panic!("no reply to {re} in {output:?}"))
+    }
+
+    #[tokio::test]
+    async fn test_reinit_with_same_id() {
+        let reinit = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":2,"node_id":"n1","node_ids":["n1"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[INIT, reinit, PING]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 3, "{:?}", output);
+        assert_eq!(reply_to(&output, 1)["body"]["type"], "init_ok");
+        assert_eq!(reply_to(&output, 2)["body"]["type"], "init_ok");
+        assert_eq!(reply_to(&output, 5)["body"]["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_reinit_with_different_id() {
+        let reinit = r#"{"src":"c0","dest":"n2","body":{"type":"init","msg_id":2,"node_id":"n2","node_ids":["n1","n2"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[INIT, reinit, PING]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 3, "{:?}", output);
+        let refusal = reply_to(&output, 2);
+        assert_eq!(refusal["body"]["type"], "error");
+        assert_eq!(refusal["body"]["code"], ErrorCode::NotSupported as u64);
+        assert_eq!(
+            refusal["body"]["text"],
+            "Refusing to change node ID from n1 to n2"
+        );
+
+        // The node keeps its original identity.
+        let pong = reply_to(&output, 5);
+        assert_eq!(pong["body"]["type"], "pong");
+        assert_eq!(pong["src"], "n1");
+    }
+
+    #[tokio::test]
+    async fn test_message_before_init_is_deferred() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        let (output, result) = run_ping(NodeOptions::default(), &[PING, init]).await;
+
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(output.len(), 2, "{:?}", output);
+        assert_eq!(output[0]["body"]["type"], "init_ok");
+        assert_eq!(output[1]["body"]["type"], "pong");
+        assert_eq!(output[1]["body"]["in_reply_to"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_strict_init_without_msg_id() {
+        let init =
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","node_id":"n1","node_ids":["n1"]}}"#;
+        let options = NodeOptions {
+            strict_init: true,
+            ..Default::default()
+        };
+        let (output, result) = run_ping(options, &[init]).await;
+
+        assert!(output.is_empty(), "{:?}", output);
+        assert!(matches!(
+            result,
+            Some(Err(crate::Error::Internal {
+                source: InternalError::MalformedInit
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_destinations_are_refused() {
+        let cluster = || ["n1", "n2"].map(String::from).into_iter().collect();
+        let state = NodeState::with_output(
+            NullService,
+            "n1".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        // Nothing is known about the cluster before init.
+        assert!(state.is_known_destination("n 2"));
+
+        state.inner.node_ids.store(Some(Arc::new(cluster())));
+        state
+            .inner
+            .clients
+            .lock()
+            .unwrap()
+            .request("maelstrom-client", false);
+        for dest in ["n1", "n2", "c7", "maelstrom-client"]
+            .into_iter()
+            .chain(MAELSTROM_SERVICES)
+        {
+            assert!(state.is_known_destination(dest), "{dest}");
+        }
+        for dest in ["n 2", "", "n3", "c", "c7x"] {
+            assert!(!state.is_known_destination(dest), "{dest:?}");
+        }
+
+        let error = state
+            .send("n3", serde_json::json!({ "type": "test" }))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::Error::Internal {
+                    source: InternalError::UnknownDestination { ref dest }
+                } if &**dest == "n3"
+            ),
+            "{error:?}"
+        );
+
+        let (writer, reader) = tokio::io::duplex(4096);
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let state = NodeState::with_output(NullService, "n1".into(), &options, writer);
+        state.inner.node_ids.store(Some(Arc::new(cluster())));
+        state
+            .send("n3", serde_json::json!({ "type": "test" }))
+            .await
+            .unwrap();
+        drop(state);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let frame: serde_json::Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(frame["dest"], "n3");
+    }
+
+    #[tokio::test]
+    async fn test_send_errors_are_typed() {
+        let (writer, reader) = tokio::io::duplex(4096);
+        drop(reader);
+        let state =
+            NodeState::with_output(NullService, "n1".into(), &NodeOptions::default(), writer);
+        // The message is only queued: the writer finds the output gone, and stops the node.
+        state
+            .send("c1", serde_json::json!({ "type": "test" }))
+            .await
+            .unwrap();
+        state.flush_output().await;
+        let error = state.inner.fatal.lock().unwrap().take().unwrap();
+        assert!(
+            matches!(
+                error,
+                crate::Error::Internal {
+                    source: InternalError::OutputClosed { ref dest, .. }
+                } if &**dest == "c1"
+            ),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "Internal error: Output closed while sending message to c1"
+        );
+        // Nothing more can be sent.
+        let error = state
+            .send("c2", serde_json::json!({ "type": "test" }))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::Error::Internal {
+                    source: InternalError::OutputClosed { ref dest, .. }
+                } if &**dest == "c2"
+            ),
+            "{error:?}"
+        );
+
+        let unserializable =
+            <serde_json::Error as serde::ser::Error>::custom("key must be a string");
+        let error = InternalError::sending("n2".into(), unserializable.into());
+        assert!(
+            matches!(error, InternalError::SendSerialization { .. }),
+            "{error:?}"
+        );
+        assert_eq!(error.to_string(), "Failed to serialize message to n2");
+        assert_eq!(
+            std::error::Error::source(&error).unwrap().to_string(),
+            "key must be a string"
+        );
+
+        let error = InternalError::sending("n2".into(), std::io::Error::other("disk on fire"));
+        assert!(matches!(error, InternalError::Send { .. }), "{error:?}");
+        assert_eq!(error.to_string(), "Failed to send message to n2");
+    }
+
+    /// Serializes `value` under a different name than it deserializes it from.
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum AsymmetricMessage {
+        Write {
+            #[serde(rename(serialize = "val", deserialize = "value"))]
+            value: u64,
+        },
+    }
+
+    #[derive(Clone)]
+    struct AsymmetricService;
+
+    impl Node for AsymmetricService {
+        type Message = AsymmetricMessage;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn outgoing<Data>(re: Option<MessageId>, data: DataOrInit<Data>) -> Message<DataOrInit<Data>> {
+        Message {
+            src: "n1".into(),
+            dest: "c1".into(),
+            body: MessageBody {
+                id: Some(0),
+                re,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data,
+            },
+        }
+    }
+
+    #[test]
+    fn test_validate_outgoing() {
+        let write = AsymmetricMessage::Write { value: 1 };
+        let reason = validate_outgoing(&outgoing(None, DataOrInit::Data(write))).unwrap_err();
+        assert!(reason.contains("failed to deserialize"), "{reason}");
+
+        let pong = serde_json::json!({ "type": "pong" });
+        assert_eq!(
+            validate_outgoing(&outgoing(Some(1), DataOrInit::Data(pong))),
+            Ok(())
+        );
+        assert!(validate_outgoing(&outgoing::<()>(None, DataOrInit::InitOk)).is_err());
+        assert!(validate_outgoing(&outgoing::<()>(
+            Some(1),
+            DataOrInit::Capabilities(Capabilities {
+                tags: Vec::new(),
+                version: build_version(),
+                compression: true,
+                sequence_numbers: true,
+                timestamps: true,
+            })
+        ))
+        .is_err());
+
+        let mut anonymous = outgoing::<()>(Some(1), DataOrInit::InitOk);
+        anonymous.src = "".into();
+        assert!(validate_outgoing(&anonymous).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_message_is_refused_in_debug_builds() {
+        let state = NodeState::with_output(
+            AsymmetricService,
+            "n1".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        let sent = state
+            .send("c1", AsymmetricMessage::Write { value: 1 })
+            .await;
+        assert!(
+            matches!(
+                sent,
+                Err(crate::Error::Internal {
+                    source: InternalError::InvalidMessage { .. }
+                })
+            ),
+            "{sent:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_background_tasks_end_with_node() {
+        let service = BackgroundService::default();
+        let options = NodeOptions::default();
+        let counter = options.task_counter.clone();
+
+        let (mut stdin, node_stdin) = tokio::io::duplex(4096);
+        let node = tokio::spawn(NodeState::run_with_io(
+            service.clone(),
+            options,
+            node_stdin,
+            tokio::io::sink(),
+        ));
+
+        stdin
+            .write_all(format!("{INIT}\n").as_bytes())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // The service's two, the one logging the gauges and the one expiring requests.
+        assert_eq!(counter.live(), 4);
+
+        // A malformed frame makes the node exit.
+        stdin.write_all(b"not json\n").await.unwrap();
+        assert!(node.await.unwrap().is_err());
+
+        assert!(service.finished.load(Ordering::SeqCst));
+        assert_eq!(counter.live(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_background_tasks_abort_when_node_is_dropped() {
+        let options = NodeOptions::default();
+        let counter = options.task_counter.clone();
+
+        let (mut stdin, node_stdin) = tokio::io::duplex(4096);
+        let node = tokio::spawn(NodeState::run_with_io(
+            BackgroundService::default(),
+            options,
+            node_stdin,
+            tokio::io::sink(),
+        ));
+
+        stdin
+            .write_all(format!("{INIT}\n").as_bytes())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // The service's two, the one logging the gauges and the one expiring requests.
+        assert_eq!(counter.live(), 4);
+
+        node.abort();
+        counter.idle().await;
+    }
+
+    #[test]
+    fn test_banner() {
+        let options = NodeOptions {
+            strict_init: true,
+            ..Default::default()
+        };
+        let banner = serde_json::to_value(Banner::new(&options)).unwrap();
+
+        assert_eq!(
+            banner.as_object().unwrap().keys().collect::<Vec<_>>(),
+            ["features", "git_hash", "options", "version"]
+        );
+        assert_eq!(banner["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!banner["git_hash"].as_str().unwrap().is_empty());
+        assert_eq!(
+            banner["options"],
+            serde_json::json!({
+                "strict_init": true,
+                "validate_output": false,
+                "compress_above": null,
+                "strict_client_input": false,
+                "expose_trace_ids": false,
+                "execution": null,
+                "rpc_timeout_ms": null,
+                "max_pending_rpcs": null,
+                "max_rpc_lifetime_ms": null,
+                "pool_queue": null,
+                "box_handlers_above": null,
+                "disable_inline": false,
+                "slow_handler_ms": null,
+                "standby": null,
+                "lenient_destinations": false,
+                "lenient_replies": false,
+                "allow_reply_to_reply": false,
+                "interactive": false,
+                "startup_timeout_ms": null,
+                "audit_timeout_ms": null,
+                "sequence_peer_messages": false,
+                "gauge_interval_ms": null,
+                "history_size": null,
+                "estimate_clock_offsets": false,
+                "exchange_capabilities": false,
+                "flush": {
+                    "batch_above": 20_000,
+                    "unbatch_below": 5_000,
+                    "deadline_us": 1_000,
+                    "max_batch": 64,
+                    "ack_window_us": null,
+                    "max_acks": 16,
+                },
+            })
+        );
+
+        // Nothing about the machine it was built or run on.
+        let banner = banner.to_string();
+        let cwd = std::env::current_dir().unwrap();
+        assert!(!banner.contains(cwd.to_str().unwrap()), "{banner}");
+        assert!(!banner.contains(env!("CARGO_MANIFEST_DIR")), "{banner}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_capability_exchange() {
+        let services = std::sync::Mutex::new(Vec::new());
+        let options = NodeOptions {
+            exchange_capabilities: true,
+            ..Default::default()
+        };
+        let cluster = crate::testing::Cluster::builder()
+            .nodes(3)
+            .options(options)
+            .service(|| {
+                let mut services = services.lock().unwrap();
+                // n2 runs a different service from the others.
+                let tags: &[&str] = if services.len() == 2 {
+                    &["txn", "txn_ok"]
+                } else {
+                    &["read", "read_ok"]
+                };
+                let service = TaggedService {
+                    tags,
+                    state: Arc::default(),
+                };
+                services.push(service.clone());
+                service
+            })
+            .build()
+            .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let n0 = services.lock().unwrap()[0].state.get().unwrap().clone();
+        let ours = n0.capabilities();
+        assert_eq!(ours.tags, ["read", "read_ok"]);
+
+        let n1 = n0.peer_capabilities("n1").expect("n1's capabilities");
+        assert_eq!(n1, ours);
+        assert_eq!(incompatibility(&ours, &n1), None);
+
+        let n2 = n0.peer_capabilities("n2").expect("n2's capabilities");
+        assert_eq!(n2.tags, ["txn", "txn_ok"]);
+        assert_eq!(n2.version, ours.version);
+        let problem = incompatibility(&ours, &n2).expect("n2 is incompatible");
+        assert!(problem.contains("nothing in common"), "{problem}");
+
+        // Every peer acknowledged, so nothing is left retrying: each node only logs its gauges and
+        // expires requests.
+        assert_eq!(n0.inner.capabilities_acked.len(), 2);
+        assert_eq!(cluster.live_node_tasks(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_capabilities_are_not_sent_by_default() {
+        let services = std::sync::Mutex::new(Vec::new());
+        let cluster = crate::testing::Cluster::new(3, || {
+            let service = TaggedService {
+                tags: &["read", "read_ok"],
+                state: Arc::default(),
+            };
+            services.lock().unwrap().push(service.clone());
+            service
+        })
+        .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(cluster.traffic().total(), 0);
+        let n0 = services.lock().unwrap()[0].state.get().unwrap().clone();
+        assert_eq!(n0.peer_capabilities("n1"), None);
+    }
+
+    /// Answers every message from a peer with a `pong`, replies included, until `hops` reaches 3.
+    /// A client's `start` sends the first `ping` to `n1`.
+    #[derive(Clone, Default)]
+    struct PingPongService {
+        state: Arc<std::sync::OnceLock<NodeState<PingPongService>>>,
+    }
+
+    impl Node for PingPongService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn init(
+            &self,
+            state: &NodeState<Self>,
+            _node_ids: Vec<String>,
+        ) -> crate::Result<(), Self::Error> {
+            self.state.set(state.clone()).ok();
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let Some(id) = message.body.id else {
+                return Ok(());
+            };
+            if message.body.data["type"] == "start" {
+                let ping = serde_json::json!({ "type": "ping", "hops": 0 });
+                state.send("n1", ping).await?;
+                let start_ok = serde_json::json!({ "type": "start_ok" });
+                state.reply(message.src, id, start_ok).await?;
+                return Ok(());
+            }
+            let hops = message.body.data["hops"].as_u64().unwrap_or_default();
+            if hops < 3 {
+                let pong = serde_json::json!({ "type": "pong", "hops": hops + 1 });
+                state.reply(message.src, id, pong).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock_offsets_are_estimated() {
+        let skewed = |skew_ms| NodeOptions {
+            estimate_clock_offsets: true,
+            clock: Some(Arc::new(crate::clock::SkewedClock { skew_ms })),
+            ..Default::default()
+        };
+        let services = std::sync::Mutex::new(Vec::new());
+        let _cluster = crate::testing::Cluster::builder()
+            .nodes(3)
+            .options(skewed(0))
+            .node_options("n1", skewed(300))
+            .node_options("n2", skewed(-120))
+            .service(|| {
+                let service = PingPongService::default();
+                services.lock().unwrap().push(service.clone());
+                service
+            })
+            .build()
+            .await;
+        // Only messages sent once capabilities are exchanged are timed.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let state = |n: usize| services.lock().unwrap()[n].state.get().unwrap().clone();
+        let (n0, n1) = (state(0), state(1));
+        assert_eq!(n0.estimated_offset_ms("n1"), None);
+
+        let ping = || serde_json::json!({ "type": "ping", "hops": 0 });
+        for _ in 0..3 {
+            n0.rpc("n1", ping()).await.unwrap();
+            n0.rpc("n2", ping()).await.unwrap();
+            n1.rpc("n0", ping()).await.unwrap();
+        }
+        let close_to = |estimate: Option<i64>, actual: i64| {
+            let estimate = estimate.expect("no estimate");
+            assert!((estimate - actual).abs() <= 20, "{estimate} vs {actual}");
+        };
+        close_to(n0.estimated_offset_ms("n1"), 300);
+        close_to(n0.estimated_offset_ms("n2"), -120);
+        close_to(n0.cluster_offset_ms(), 90);
+        close_to(n1.estimated_offset_ms("n0"), -300);
+        // n1 never asked n2 anything.
+        assert_eq!(n1.estimated_offset_ms("n2"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replies_to_replies_are_refused() {
+        let services = std::sync::Mutex::new(Vec::new());
+        let cluster = crate::testing::Cluster::new(2, || {
+            let service = PingPongService::default();
+            services.lock().unwrap().push(service.clone());
+            service
+        })
+        .await;
+        let reply = cluster
+            .client()
+            .rpc("n0", serde_json::json!({ "type": "start" }))
+            .await;
+        assert_eq!(reply.unwrap()["type"], "start_ok");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // n1 answered the ping, and n0 was stopped from answering n1's answer.
+        let traffic = cluster.traffic().by_type();
+        assert_eq!(traffic["ping"], 1);
+        assert_eq!(traffic["pong"], 1);
+        let n0 = services.lock().unwrap()[0].state.get().unwrap().clone();
+        assert_eq!(n0.error_counts().replies_to_replies, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replies_to_replies_can_be_allowed() {
+        let options = NodeOptions {
+            allow_reply_to_reply: true,
+            ..Default::default()
+        };
+        let services = std::sync::Mutex::new(Vec::new());
+        let cluster = crate::testing::Cluster::with_options(
+            2,
+            crate::testing::LatencyMatrix::default(),
+            options,
+            || {
+                let service = PingPongService::default();
+                services.lock().unwrap().push(service.clone());
+                service
+            },
+        )
+        .await;
+        cluster
+            .client()
+            .rpc("n0", serde_json::json!({ "type": "start" }))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(cluster.traffic().by_type()["pong"], 3);
+        let n0 = services.lock().unwrap()[0].state.get().unwrap().clone();
+        assert_eq!(n0.error_counts().replies_to_replies, 0);
+    }
+
+    /// Log lines written by a subscriber, for checking what was logged.
+    #[derive(Clone, Default)]
+    pub(super) struct CapturedLogs(pub(super) Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_frame_is_logged_with_context() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let corrupt = r#"{"src":"c1","dest":"n1","body":{"type":"echo",msg_id":2}}"#;
+        let (_, result) =
+            run_service(EchoBackService, NodeOptions::default(), &[INIT, corrupt]).await;
+        assert!(matches!(
+            result,
+            Some(Err(crate::Error::Internal {
+                source: InternalError::Receive { .. }
+            }))
+        ));
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Failed to decode"))
+            .expect("decode error not logged");
+        assert!(line.contains("frame 2 at byte 46"), "{line}");
+        assert!(line.contains(r#""type\":\"echo\",msg_id\":2}}"#), "{line}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_timeout_is_diagnosed() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let options = NodeOptions {
+            startup_timeout_ms: Some(1000),
+            ..Default::default()
+        };
+        let (mut stdin, node_stdin) = tokio::io::duplex(4096);
+        let (node_stdout, stdout) = tokio::io::duplex(4096);
+        let node = tokio::spawn(NodeState::run_with_io(
+            EchoBackService,
+            options,
+            node_stdin,
+            node_stdout,
+        ));
+        let diagnosed = || {
+            String::from_utf8(logs.0.lock().unwrap().clone())
+                .unwrap()
+                .contains("Nothing received 1s after starting")
+        };
+        tokio::time::sleep(Duration::from_millis(900)).await;
+        assert!(!diagnosed());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(diagnosed());
+
+        // The node still starts once something arrives.
+        stdin.write_all(INIT.as_bytes()).await.unwrap();
+        stdin.write_all(b"\n").await.unwrap();
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let init_ok = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&init_ok).unwrap()["body"]["type"],
+            "init_ok"
+        );
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_interactive_mode_skips_malformed_lines() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let options = NodeOptions {
+            interactive: true,
+            ..Default::default()
+        };
+        let (mut stdin, node_stdin) = tokio::io::duplex(4096);
+        let (node_stdout, stdout) = tokio::io::duplex(4096);
+        let node = tokio::spawn(NodeState::run_with_io(
+            EchoBackService,
+            options,
+            node_stdin,
+            node_stdout,
+        ));
+        let typo = r#"{"src":"c1","dest":"n1","body":{"type":"echo",msg_id":2}}"#;
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"hi"}}"#;
+        for line in [INIT, "hello?", typo, echo] {
+            stdin.write_all(line.as_bytes()).await.unwrap();
+            stdin.write_all(b"\n").await.unwrap();
+        }
+
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let mut output = String::new();
+        while let Ok(Ok(Some(line))) =
+            tokio::time::timeout(Duration::from_millis(100), lines.next_line()).await
+        {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        assert!(!node.is_finished(), "node exited on a malformed line");
+        node.abort();
+
+        // Replies are pretty-printed, one value over several lines.
+        let replies = serde_json::Deserializer::from_str(&output)
+            .into_iter::<serde_json::Value>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(replies.len(), 2, "{output}");
+        assert_eq!(replies[1]["body"]["echo"], "hi");
+        assert!(output.lines().count() > 2, "{output}");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("Skipping line 2, which is not a message"),
+            "{logs}"
+        );
+        let expected = format!("Skipping line 3, which is not a message: key must be a string at line 1 column 47\n  {typo}\n  {}^", " ".repeat(46));
+        assert!(logs.contains(&expected), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_fatal_handler_error_stops_node() {
+        let service = FailingService::default();
+        let fail = r#"{"src":"c1","dest":"n1","body":{"type":"fail","msg_id":2}}"#;
+        let fine = r#"{"src":"c1","dest":"n1","body":{"type":"ok","msg_id":3}}"#;
+        let corrupt = r#"{"src":"c1","dest":"n1","body":{"type":"corrupt","msg_id":4}}"#;
+
+        let started = tokio::time::Instant::now();
+        let (output, result) = run_service(
+            service.clone(),
+            NodeOptions::default(),
+            &[INIT, fail, fine, corrupt],
+        )
+        .await;
+        // The node keeps serving after the first failure, and stops on the second.
+        assert_eq!(
+            reply_to(&output, 2)["body"]["code"],
+            ErrorCode::Crash as u64
+        );
+        assert_eq!(
+            reply_to(&output, 4)["body"]["code"],
+            ErrorCode::Crash as u64
+        );
+        match result {
+            Some(Err(crate::Error::Node { source })) => {
+                assert_eq!(source.to_string(), "persisted state is corrupted");
+            }
+            other => panic!("node didn't stop on the fatal error: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(service.state.get().unwrap().error_counts().handler, 2);
+    }
+
+    /// Echoes like [`EchoBackService`], admitting requests as their `admit` says: `reject`,
+    /// `defer` forever, or defer `twice`. Records the deferrals each request was last asked about
+    /// with.
+    #[derive(Clone, Default)]
+    struct GatekeeperService {
+        asked: Arc<std::sync::Mutex<BTreeMap<MessageId, u32>>>,
+    }
+
+    impl Node for GatekeeperService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn admit(
+            &self,
+            request: &AdmissionRequest<'_, Self::Message>,
+            _node: &NodeState<Self>,
+        ) -> Admission {
+            let id = request.id.unwrap_or_default();
+            self.asked.lock().unwrap().insert(id, request.deferrals);
+            let defer = Admission::Defer {
+                delay: Duration::from_millis(10),
+            };
+            match request.data["admit"].as_str() {
+                Some("reject") => Admission::Reject {
+                    code: ErrorCode::TemporarilyUnavailable,
+                    text: "too busy".into(),
+                },
+                Some("defer") => defer,
+                Some("twice") if request.deferrals < 2 => defer,
+                _ => Admission::Accept,
+            }
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let echo = message.body.data["echo"].clone();
+            let reply = serde_json::json!({ "type": "echo_ok", "echo": echo });
+            state
+                .reply(message.src, message.body.id.unwrap_or_default(), reply)
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admission() {
+        let service = GatekeeperService::default();
+        let frames = [
+            INIT,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":3,"admit":"reject"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":4,"echo":4,"admit":"twice"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":5,"echo":5,"admit":"defer"}}"#,
+        ];
+        let (output, result) = run_service(service.clone(), NodeOptions::default(), &frames).await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        assert_eq!(reply_to(&output, 2)["body"]["echo"], 2);
+        let rejected = &reply_to(&output, 3)["body"];
+        assert_eq!(
+            (&rejected["code"], &rejected["text"]),
+            (
+                &(ErrorCode::TemporarilyUnavailable as u64).into(),
+                &"too busy".into()
+            )
+        );
+        // Deferred requests are handled once admitted, or once deferred too often.
+        assert_eq!(reply_to(&output, 4)["body"]["echo"], 4);
+        assert_eq!(reply_to(&output, 5)["body"]["echo"], 5);
+        let asked = service.asked.lock().unwrap().clone();
+        assert_eq!(
+            asked,
+            BTreeMap::from([(2, 0), (3, 0), (4, 2), (5, MAX_DEFERRALS)])
+        );
+        // In the order they were admitted.
+        let replied = output
+            .iter()
+            .map(|frame| frame["body"]["in_reply_to"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(replied, [1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_history_is_dumped_when_a_handler_panics() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let options = NodeOptions {
+            execution: Some(Execution::Pool { workers: 1 }),
+            history_size: Some(4),
+            ..Default::default()
+        };
+        let frames = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"ok","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"fail","msg_id":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"ok","msg_id":4}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"panic","msg_id":5,"blame":"me"}}"#,
+        ];
+        let (_, result) = run_service(
+            FailingService::default(),
+            options,
+            &[&[INIT], &frames[..]].concat(),
+        )
+        .await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let (_, dump) = logs
+            .lines()
+            .find_map(|line| line.split_once("Recent messages, a handler panicked: "))
+            .unwrap_or_else(|| panic!("no dump in {logs}"));
+        let dump = serde_json::from_str::<Vec<serde_json::Value>>(dump).unwrap();
+        // The last four, the crash reply to 3 among them, received ending with the message that
+        // panicked. Messages are recorded as they arrive, before the worker gets to them.
+        let received = dump
+            .iter()
+            .filter(|message| message["direction"] == "received")
+            .map(|message| message["msg_id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(received, [3, 4, 5]);
+        let sent = dump
+            .iter()
+            .find(|message| message["direction"] == "sent")
+            .unwrap();
+        assert_eq!(
+            (&sent["tag"], &sent["in_reply_to"]),
+            (&"error".into(), &3.into())
+        );
+        let panicked = dump
+            .iter()
+            .rfind(|message| message["direction"] == "received")
+            .unwrap();
+        assert!(panicked["body"]
+            .as_str()
+            .unwrap()
+            .contains(r#""blame":"me""#));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batched_writes_are_flushed_by_deadline() {
+        let options = NodeOptions {
+            flush: FlushOptions {
+                batch_above: 0,
+                unbatch_below: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (output, stdout) = tokio::io::duplex(4096);
+        let state = NodeState::with_output(PingService, "n1".into(), &options, output);
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let ping = || serde_json::json!({ "type": "ping" });
+
+        // The first message is written before any rate has been measured.
+        state.send("n2", ping()).await.unwrap();
+        lines.next_line().await.unwrap().unwrap();
+        assert_eq!(state.flush_stats().mode, FlushMode::Immediate);
+
+        tokio::time::sleep(crate::flush::RATE_WINDOW).await;
+        let start = tokio::time::Instant::now();
+        state.send("n2", ping()).await.unwrap();
+        lines.next_line().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(1));
+        assert_eq!(
+            state.flush_stats(),
+            FlushStats {
+                mode: FlushMode::Batching,
+                messages: 2,
+                flushes: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_codes() {
+        let busy = r#"{"src":"c1","dest":"n1","body":{"type":"busy","msg_id":2}}"#;
+        let fail = r#"{"src":"c1","dest":"n1","body":{"type":"fail","msg_id":3}}"#;
+        let (output, result) = run_service(
+            FailingService::default(),
+            NodeOptions::default(),
+            &[INIT, busy, fail],
+        )
+        .await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        assert_eq!(
+            reply_to(&output, 2)["body"]["code"],
+            ErrorCode::TemporarilyUnavailable as u64
+        );
+        assert_eq!(
+            reply_to(&output, 3)["body"]["code"],
+            ErrorCode::Crash as u64
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timers_fire_independently() {
+        let service = TimerService::default();
+        let state = NodeState::with_output(
+            service.clone(),
+            "n1".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        let start = tokio::time::Instant::now();
+        state.start_timers();
+        tokio::time::sleep(Duration::from_millis(5050)).await;
+
+        let fired = service.fired.lock().unwrap().clone();
+        let at = |name: &str| {
+            fired
+                .iter()
+                .filter(|(fired, _)| fired == name)
+                .map(|(_, at)| at.duration_since(start).as_millis())
+                .collect::<Vec<_>>()
+        };
+        // The slow timer's long invocations don't hold up the fast one. The slow timer's own
+        // missed ticks are skipped: it fires once as soon as it is done, not once per tick.
+        assert_eq!(at("fast"), (1..=50).map(|i| i * 100).collect::<Vec<_>>());
+        assert_eq!(at("slow"), [1000, 3500]);
+
+        let stats = state.timer_stats();
+        assert_eq!(stats["fast"].invocations, 50);
+        assert_eq!(stats["fast"].overruns, 0);
+        assert_eq!(
+            stats["slow"],
+            TimerStats {
+                invocations: 1,
+                total_duration: Duration::from_millis(2500),
+                overruns: 1,
+            }
+        );
+        assert_eq!(stats["slow"].mean_duration(), Duration::from_millis(2500));
+        state.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_standby_serves_once_promoted() {
+        let request = |src: &str, id: u64, kind: &str| {
+            let message = serde_json::json!({
+                "src": src,
+                "dest": "n1",
+                "body": { "type": kind, "msg_id": id },
+            });
+            serde_json::from_value(message).unwrap()
+        };
+        let options = NodeOptions {
+            standby: Some(StandbyOptions {
+                active: "n0".into(),
+                promote_after_ms: None,
+            }),
+            ..Default::default()
+        };
+        let service = PromotionService::default();
+        let (output, replies) = tokio::io::duplex(64 * 1024);
+        let state = NodeState::with_output(service.clone(), "n1".into(), &options, output);
+        state.start_executor(Execution::Spawn);
+        let mut replies = tokio::io::BufReader::new(replies).lines();
+        let mut next_reply = async || {
+            let line = replies.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"].take()
+        };
+
+        assert!(state.is_standby());
+        state.dispatch(request("c1", 1, "ping")).await;
+        let reply = next_reply().await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], ErrorCode::TemporarilyUnavailable as u64);
+        assert_eq!(reply["active"], "n0");
+
+        // Peers are served all along.
+        state.dispatch(request("n2", 2, "ping")).await;
+        assert_eq!(next_reply().await["type"], "pong");
+
+        for id in [3, 4] {
+            state.dispatch(request("c0", id, "promote")).await;
+            assert_eq!(next_reply().await["type"], "promote_ok");
+        }
+        assert!(!state.is_standby());
+        assert_eq!(service.promotions.load(Ordering::SeqCst), 1);
+        state.dispatch(request("c1", 5, "ping")).await;
+        assert_eq!(next_reply().await["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_compression_threshold() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+        // n2 reads compressed messages, n3 was built before they existed.
+        let n2 = r#"{"src":"n2","dest":"n1","body":{"type":"capabilities","msg_id":1,"tags":[],"version":"0","compression":true}}"#;
+        let n3 = r#"{"src":"n3","dest":"n1","body":{"type":"capabilities","msg_id":1,"tags":[],"version":"0"}}"#;
+        let large = "a".repeat(10_000);
+        let echo = |src: &str, id: u64, echo: &str| {
+            serde_json::json!({ "src": src, "dest": "n1", "body": { "type": "echo", "msg_id": id, "echo": echo } })
+                .to_string()
+        };
+        let frames = [
+            init,
+            n2,
+            n3,
+            &echo("n2", 2, "small"),
+            &echo("n2", 3, &large),
+            &echo("n3", 2, &large),
+            &echo("c1", 1, &large),
+        ];
+        let options = NodeOptions {
+            compress_above: Some(1024),
+            ..Default::default()
+        };
+        let (output, result) = run_service(EchoBackService, options, &frames).await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        let reply = |dest: &str, re: u64| {
+            output
+                .iter()
+                .find(|frame| frame["dest"] == dest && frame["body"]["in_reply_to"] == re)
+                .unwrap_or_else(|| panic!("no reply to {dest}'s {re} in {output:?}"))
+        };
+
+        // Only the large reply to the peer that can read it is compressed.
+        assert_eq!(reply("n2", 2)["body"]["echo"], "small");
+        let compressed = reply("n2", 3);
+        assert_eq!(compressed["body"]["type"], "gossip_z");
+        let envelope =
+            serde_json::from_value::<CompressedEnvelope>(compressed["body"].clone()).unwrap();
+        assert_eq!(
+            envelope.open::<serde_json::Value>().unwrap(),
+            serde_json::json!({ "type": "echo_ok", "echo": large })
+        );
+        assert_eq!(reply("n3", 2)["body"]["echo"], large);
+        assert_eq!(reply("c1", 1)["body"]["echo"], large);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_messages_are_unwrapped() {
+        let body = serde_json::json!({ "type": "echo", "echo": "hi" });
+        let envelope = CompressedEnvelope::seal(&serde_json::to_vec(&body).unwrap()).unwrap();
+        let compressed = serde_json::json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": { "type": "gossip_z", "msg_id": 2, "data": envelope.data },
+        })
+        .to_string();
+        let corrupt =
+            r#"{"src":"n2","dest":"n1","body":{"type":"gossip_z","msg_id":3,"data":"AAAA"}}"#;
+
+        // Replies only go to nodes the cluster knows.
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let (output, result) = run_service(
+            EchoBackService,
+            NodeOptions::default(),
+            &[init, &compressed, corrupt],
+        )
+        .await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        assert_eq!(reply_to(&output, 2)["body"]["echo"], "hi");
+        let error = reply_to(&output, 3);
+        assert_eq!(error["body"]["type"], "error");
+        assert_eq!(error["body"]["code"], ErrorCode::MalformedRequest as u64);
+    }
+
+    #[test]
+    fn test_version_mismatch_is_incompatible() {
+        let ours = Capabilities {
+            tags: vec!["read".into()],
+            version: "0.1.0-abc".into(),
+            compression: true,
+            sequence_numbers: true,
+            timestamps: true,
+        };
+        let theirs = Capabilities {
+            version: "0.2.0-def".into(),
+            ..ours.clone()
+        };
+        let problem = incompatibility(&ours, &theirs).unwrap();
+        assert!(problem.contains("0.2.0-def"), "{problem}");
+    }
 }
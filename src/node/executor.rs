@@ -0,0 +1,913 @@
+//! How the runner runs message handlers, see [`crate::node::Execution`].
+//!
+//! Messages are offered to [`Node::try_handle_inline`] first, on the read loop. The rest go to a
+//! task of their own, a worker of a pool, or the service's event loop.
+
+use std::{
+    hash::{Hash as _, Hasher as _},
+    sync::{atomic::Ordering, Arc},
+};
+
+use futures::FutureExt as _;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument as _;
+
+use super::{
+    in_subsystem, is_client, Counted, InlineResult, MessageMeta, Node, NodeState,
+    MAX_HANDLER_BATCH, TRACE_ID,
+};
+use crate::message::{DataOrInit, Message, MessageBody, Subsystem};
+
+/// How the runner runs message handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Execution {
+    /// Every message is handled on a task of its own.
+    Spawn,
+    /// `workers` long-lived tasks take messages from a shared queue of
+    /// [`crate::node::NodeOptions::pool_queue`] and handle them one at a time. While the queue is
+    /// full, no more input is read. Only suits services whose handlers never wait on another
+    /// message: a handler waiting on a reply ties up its worker, and once every worker waits,
+    /// nothing is left to handle the replies.
+    ///
+    /// Not a speedup: handing a message to a worker costs more than spawning a task for it, see
+    /// the echo benchmarks in `benches/hot_paths.rs`. Use it to bound how much work is queued, or
+    /// to batch messages, see [`Node::batches_with`].
+    Pool { workers: usize },
+    /// Like [`Execution::Pool`], but all messages from the same source go to the same worker, so
+    /// they are handled one at a time, in the order they arrived. Each worker has a queue of its
+    /// own.
+    OrderedPool { workers: usize },
+    /// Every message for the service goes to [`Node::run_event_loop`] through a queue of
+    /// `capacity`, in the order it was read. The runner still handles its own messages and turns
+    /// away the ones it would refuse first. While the queue is full, no more input is read.
+    EventLoop { capacity: usize },
+}
+
+impl Execution {
+    /// A pool with a worker per available CPU.
+    pub fn pool() -> Self {
+        let workers = std::thread::available_parallelism().map_or(1, usize::from);
+        Self::Pool { workers }
+    }
+}
+
+/// Where [`NodeState::dispatch`] sends messages, see [`crate::node::Execution`].
+pub(super) enum Executor<Data> {
+    Spawn,
+    Pool(async_channel::Sender<Message<DataOrInit<Data>>>),
+    OrderedPool(Vec<tokio::sync::mpsc::Sender<Message<DataOrInit<Data>>>>),
+    EventLoop(tokio::sync::mpsc::Sender<Message<Data>>),
+}
+
+impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
+    /// Handle `msg` inline if the service can, see [`Node::try_handle_inline`], or hand it to the
+    /// executor.
+    pub(super) async fn execute(&self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        let Some(msg) = self.try_inline(msg) else {
+            return;
+        };
+
+        match self.inner.executor.get() {
+            // Counted before they're queued, so that a worker can't uncount them first.
+            Some(Executor::Pool(queue)) => {
+                self.inner.mailbox.fetch_add(1, Ordering::Relaxed);
+                if queue.send(msg).await.is_err() {
+                    self.inner.mailbox.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+            Some(Executor::OrderedPool(queues)) => {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                msg.src.hash(&mut hasher);
+                let worker = hasher.finish() as usize % queues.len();
+                self.inner.mailbox.fetch_add(1, Ordering::Relaxed);
+                if queues[worker].send(msg).await.is_err() {
+                    self.inner.mailbox.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+            Some(Executor::EventLoop(inbox)) => {
+                let trace_id = msg
+                    .body
+                    .trace_id
+                    .or_else(|| is_client(&msg.src).then(rand::random));
+                let span =
+                    tracing::info_span!("forward", src = %msg.src, msg_id = msg.body.id, trace_id);
+                let forward = async {
+                    let Some(mut data) = self.prepare(msg).await else {
+                        return;
+                    };
+                    // The loop runs on a task of its own, so the trace ID travels with the message.
+                    data.body.trace_id = trace_id;
+                    if inbox.send(data).await.is_err() {
+                        tracing::warn!("The event loop has stopped, dropping a message");
+                    }
+                };
+                TRACE_ID.scope(trace_id, forward).instrument(span).await
+            }
+            Some(Executor::Spawn) | None => {
+                self.inner.mailbox.fetch_add(1, Ordering::Relaxed);
+                let handler = Self::run_handler(self.clone(), msg);
+                if self.inner.box_handlers {
+                    tokio::spawn(Box::pin(handler));
+                } else {
+                    tokio::spawn(handler);
+                }
+            }
+        }
+    }
+
+    /// Offer `msg` to [`Node::try_handle_inline`], giving it back unless it was handled.
+    fn try_inline(
+        &self,
+        msg: Message<DataOrInit<NodeImpl::Message>>,
+    ) -> Option<Message<DataOrInit<NodeImpl::Message>>> {
+        if self.inner.disable_inline {
+            return Some(msg);
+        }
+        let Message { src, dest, body } = msg;
+        let data = match body.data {
+            DataOrInit::Data(data)
+                if !(is_client(&src)
+                    && (self.is_standby()
+                        || self.is_read_only() && self.inner.node.is_mutating(&data))) =>
+            {
+                data
+            }
+            data => {
+                return Some(Message {
+                    src,
+                    dest,
+                    body: MessageBody { data, ..body },
+                })
+            }
+        };
+
+        // Kept on fallback, so that the handler traces under the same ID.
+        let trace_id = body.trace_id.or_else(|| is_client(&src).then(rand::random));
+        let msg = Message {
+            src,
+            dest,
+            body: MessageBody {
+                id: body.id,
+                re: body.re,
+                trace_id,
+                seq: body.seq,
+                sent_at_ms: body.sent_at_ms,
+                request_times: body.request_times,
+                data,
+            },
+        };
+        let span =
+            tracing::info_span!("handle_inline", src = %msg.src, msg_id = msg.body.id, trace_id);
+        let handled = span.in_scope(|| {
+            TRACE_ID.sync_scope(trace_id, || self.inner.node.try_handle_inline(&msg, self))
+        });
+        match handled {
+            InlineResult::Handled => None,
+            InlineResult::Fallback => {
+                let Message { src, dest, body } = msg;
+                let body = MessageBody {
+                    id: body.id,
+                    re: body.re,
+                    trace_id: body.trace_id,
+                    seq: body.seq,
+                    sent_at_ms: body.sent_at_ms,
+                    request_times: body.request_times,
+                    data: DataOrInit::Data(body.data),
+                };
+                Some(Message { src, dest, body })
+            }
+        }
+    }
+
+    /// Start running handlers the way `execution` says. Until this is called, every message is
+    /// handled on its own task.
+    pub(super) fn start_executor(&self, execution: Execution) {
+        let executor = match execution {
+            Execution::Spawn => Executor::Spawn,
+            Execution::Pool { workers } => {
+                let (queue, messages) = async_channel::bounded(self.inner.pool_queue);
+                for _ in 0..workers.max(1) {
+                    let (state, messages) = (self.clone(), messages.clone());
+                    self.spawn(async move {
+                        let mut next = None;
+                        loop {
+                            let msg = match next.take() {
+                                Some(msg) => msg,
+                                None => match messages.recv().await {
+                                    Ok(msg) => msg,
+                                    Err(_) => break,
+                                },
+                            };
+                            next = state.handle_queued(msg, || messages.try_recv().ok()).await;
+                        }
+                    });
+                }
+                Executor::Pool(queue)
+            }
+            Execution::OrderedPool { workers } => {
+                let queues = (0..workers.max(1))
+                    .map(|_| {
+                        let (queue, mut messages) =
+                            tokio::sync::mpsc::channel(self.inner.pool_queue);
+                        let state = self.clone();
+                        self.spawn(async move {
+                            let mut next = None;
+                            loop {
+                                let msg = match next.take() {
+                                    Some(msg) => msg,
+                                    None => match messages.recv().await {
+                                        Some(msg) => msg,
+                                        None => break,
+                                    },
+                                };
+                                next = state.handle_queued(msg, || messages.try_recv().ok()).await;
+                            }
+                        });
+                        queue
+                    })
+                    .collect();
+                Executor::OrderedPool(queues)
+            }
+            Execution::EventLoop { capacity } => {
+                let (queue, inbox) = tokio::sync::mpsc::channel(capacity.max(1));
+                let state = self.clone();
+                self.spawn(async move {
+                    let node = state.inner.node.clone();
+                    if let Err(e) = node.run_event_loop(inbox, state.clone()).await {
+                        if e.is_fatal(|e| node.is_fatal(e)) {
+                            state.stop_on(e);
+                        } else {
+                            tracing::error!("Event loop failed: {}", snafu::Report::from_error(e));
+                        }
+                    }
+                });
+                Executor::EventLoop(queue)
+            }
+        };
+        if self.inner.executor.set(executor).is_err() {
+            tracing::warn!("Executor already started, ignoring {:?}", execution);
+        }
+    }
+
+    /// Handle a message on a task of its own.
+    async fn run_handler(self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        self.inner.mailbox.fetch_sub(1, Ordering::Relaxed);
+        self.handle(msg).await
+    }
+
+    /// The size in bytes of the future a handler task runs. Futures hold everything a handler
+    /// keeps across an `.await`, so services with large handlers can end up with large tasks.
+    pub fn handler_future_size() -> usize {
+        fn returned_size<A, B, F>(_: impl FnOnce(A, B) -> F) -> usize {
+            std::mem::size_of::<F>()
+        }
+        returned_size(Self::run_handler)
+    }
+
+    /// Whether handler futures are boxed before being spawned, see
+    /// [`crate::node::NodeOptions::box_handlers_above`].
+    pub fn boxes_handlers(&self) -> bool {
+        self.inner.box_handlers
+    }
+
+    /// Handle a message on a pool worker. A panicking handler only loses its own message, as it
+    /// would on its own task, instead of taking the worker down with it.
+    async fn handle_inline(&self, msg: Message<DataOrInit<NodeImpl::Message>>) {
+        let (src, id) = (Arc::clone(&msg.src), msg.body.id);
+        let handled = std::panic::AssertUnwindSafe(self.handle(msg)).catch_unwind();
+        if handled.await.is_err() {
+            tracing::error!("Handler panicked on message {:?} from {}", id, src);
+            self.dump_history("a handler panicked");
+        }
+    }
+
+    /// Handle `msg` on a pool worker, along with the messages `queued` right behind it that can be
+    /// handled in the same batch, see [`Node::batches_with`]. Returns the first queued message
+    /// that couldn't be, which is to be handled next.
+    async fn handle_queued(
+        &self,
+        msg: Message<DataOrInit<NodeImpl::Message>>,
+        mut queued: impl FnMut() -> Option<Message<DataOrInit<NodeImpl::Message>>>,
+    ) -> Option<Message<DataOrInit<NodeImpl::Message>>> {
+        let mut batch = vec![msg];
+        let mut next = None;
+        while batch.len() < MAX_HANDLER_BATCH {
+            let Some(msg) = queued() else {
+                break;
+            };
+            if !self.batches_with(&batch[0], &msg) {
+                next = Some(msg);
+                break;
+            }
+            batch.push(msg);
+        }
+        // `next` is still waiting.
+        self.inner
+            .mailbox
+            .fetch_sub(batch.len() as u64, Ordering::Relaxed);
+
+        if batch.len() == 1 {
+            self.handle_inline(batch.pop().unwrap()).await;
+        } else {
+            let (src, count) = (Arc::clone(&batch[0].src), batch.len());
+            let handled = std::panic::AssertUnwindSafe(self.handle_batch(batch)).catch_unwind();
+            if handled.await.is_err() {
+                tracing::error!("Handler panicked on a batch of {} from {}", count, src);
+                self.dump_history("a handler panicked");
+            }
+        }
+        next
+    }
+
+    fn batches_with(
+        &self,
+        first: &Message<DataOrInit<NodeImpl::Message>>,
+        next: &Message<DataOrInit<NodeImpl::Message>>,
+    ) -> bool {
+        match (&first.body.data, &next.body.data) {
+            (DataOrInit::Data(a), DataOrInit::Data(b)) => {
+                first.src == next.src && self.inner.node.batches_with(a, b)
+            }
+            _ => false,
+        }
+    }
+
+    /// Like [`NodeState::handle`], for a batch of messages from one source. The batch shares the
+    /// first message's trace ID.
+    async fn handle_batch(&self, batch: Vec<Message<DataOrInit<NodeImpl::Message>>>) {
+        let src = Arc::clone(&batch[0].src);
+        let trace_id = batch[0]
+            .body
+            .trace_id
+            .or_else(|| is_client(&src).then(rand::random));
+        let span = tracing::info_span!("handle_batch", src = %src, count = batch.len(), trace_id);
+        let _active = Counted::new(&self.inner.active_handlers);
+        TRACE_ID
+            .scope(trace_id, self.process_batch(batch))
+            .instrument(span)
+            .await
+    }
+
+    async fn process_batch(&self, batch: Vec<Message<DataOrInit<NodeImpl::Message>>>) {
+        let mut metas = Vec::with_capacity(batch.len());
+        let mut messages = Vec::with_capacity(batch.len());
+        for msg in batch {
+            // Still goes through the runner, which may turn the message away, e.g. while
+            // read-only.
+            let msg = match in_subsystem(Subsystem::Runner, self.handle_runner_message(msg)).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!(
+                        "Error handling runner message: {}",
+                        snafu::Report::from_error(e)
+                    );
+                    continue;
+                }
+            };
+            metas.push(MessageMeta {
+                src: Arc::clone(&msg.src),
+                id: msg.body.id,
+                re: msg.body.re,
+            });
+            // Only data messages are batched, so this can't fail.
+            if let Ok(data) = msg.into_data::<NodeImpl::Error>() {
+                messages.push(data);
+            }
+        }
+
+        // Timed as a single invocation of the first message's tag.
+        let timing = messages
+            .first()
+            .map(|first| self.start_timing(&first.src, &first.body.data));
+        let results = self.inner.node.handle_batch(messages, self).await;
+        if let Some(timing) = timing {
+            self.finish_timing(timing);
+        }
+        for (meta, result) in metas.iter().zip(results) {
+            if let Err(e) = result {
+                self.handler_failed(meta, e).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::node::{
+        tests::{reply_to, run_service, PingService, RecordingService, INIT, PING},
+        NodeOptions, DEFAULT_BOX_HANDLERS_ABOVE,
+    };
+
+    /// Replies `{"type": "pong"}` after holding a large buffer across an `.await`.
+    #[derive(Clone)]
+    struct HugeService;
+
+    impl Node for HugeService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let scratch = [message.body.id.unwrap_or_default() as u8; 64 * 1024];
+            tokio::task::yield_now().await;
+            state
+                .reply(
+                    message.src,
+                    message.body.id.unwrap_or_default(),
+                    serde_json::json!({ "type": "pong", "scratch": scratch[scratch.len() - 1] }),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_handlers_are_boxed() {
+        assert!(NodeState::<PingService>::handler_future_size() < DEFAULT_BOX_HANDLERS_ABOVE);
+        assert!(NodeState::<HugeService>::handler_future_size() > 64 * 1024);
+
+        let options = NodeOptions::default();
+        let ping = NodeState::with_output(PingService, "n1".into(), &options, tokio::io::sink());
+        let huge = NodeState::with_output(HugeService, "n1".into(), &options, tokio::io::sink());
+        assert!(!ping.boxes_handlers());
+        assert!(huge.boxes_handlers());
+
+        let (output, result) = run_service(HugeService, options, &[INIT, PING]).await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+        assert_eq!(reply_to(&output, 5)["body"]["type"], "pong");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_pool_execution() {
+        const SOURCES: u64 = 8;
+        const PER_SOURCE: u64 = 200;
+
+        for execution in [
+            Execution::Pool { workers: 4 },
+            Execution::OrderedPool { workers: 4 },
+        ] {
+            let service = RecordingService::default();
+            let state = NodeState::with_output(
+                service.clone(),
+                "n1".into(),
+                &NodeOptions::default(),
+                tokio::io::sink(),
+            );
+            state.start_executor(execution);
+
+            for seq in 0..PER_SOURCE {
+                for source in 0..SOURCES {
+                    let message = serde_json::json!({
+                        "src": format!("c{source}"),
+                        "dest": "n1",
+                        "body": { "type": "record", "seq": seq },
+                    });
+                    state
+                        .dispatch(serde_json::from_value(message).unwrap())
+                        .await;
+                }
+            }
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while service.handled.lock().unwrap().len() < (SOURCES * PER_SOURCE) as usize {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{execution:?} lost messages"));
+
+            let handled = service.handled.lock().unwrap().clone();
+            for source in 0..SOURCES {
+                let src = format!("c{source}");
+                let mut seqs = handled
+                    .iter()
+                    .filter(|(from, _)| **from == *src)
+                    .map(|(_, seq)| *seq)
+                    .collect::<Vec<_>>();
+                if matches!(execution, Execution::Pool { .. }) {
+                    seqs.sort();
+                }
+                assert_eq!(
+                    seqs,
+                    (0..PER_SOURCE).collect::<Vec<_>>(),
+                    "{execution:?}, {src}"
+                );
+            }
+            // The workers are counted as the node's background tasks, and end with it.
+            assert_eq!(state.inner.task_counter.live(), 4);
+            state.inner.tasks.lock().unwrap().abort_all();
+        }
+    }
+
+    /// Records the `seq` of every message, each once a permit is added to `gate`.
+    #[derive(Clone)]
+    struct BlockedService {
+        gate: Arc<tokio::sync::Semaphore>,
+        seen: Arc<std::sync::Mutex<Vec<u64>>>,
+    }
+
+    impl Node for BlockedService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            self.gate.acquire().await.unwrap().forget();
+            let seq = message.body.data["seq"].as_u64().unwrap();
+            self.seen.lock().unwrap().push(seq);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pool_backpressure() {
+        let service = BlockedService {
+            gate: Arc::new(tokio::sync::Semaphore::new(0)),
+            seen: Arc::default(),
+        };
+        let options = NodeOptions {
+            pool_queue: Some(2),
+            ..Default::default()
+        };
+        let state =
+            NodeState::with_output(service.clone(), "n1".into(), &options, tokio::io::sink());
+        state.start_executor(Execution::Pool { workers: 1 });
+
+        let dispatched = Arc::new(AtomicUsize::new(0));
+        let reader = tokio::spawn({
+            let (state, dispatched) = (state.clone(), dispatched.clone());
+            async move {
+                for seq in 0..10 {
+                    let message = serde_json::json!({
+                        "src": "c1",
+                        "dest": "n1",
+                        "body": { "type": "record", "seq": seq },
+                    });
+                    state
+                        .dispatch(serde_json::from_value(message).unwrap())
+                        .await;
+                    dispatched.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        // The worker handles one message and holds the next, two more fill the queue, and then
+        // the reader has to wait.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(dispatched.load(Ordering::SeqCst), 4);
+        // Along with the one the reader is waiting to queue.
+        assert_eq!(state.gauges().mailbox, 4);
+
+        service.gate.add_permits(10);
+        reader.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while service.seen.lock().unwrap().len() < 10 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("lost messages");
+        assert_eq!(*service.seen.lock().unwrap(), (0..10).collect::<Vec<_>>());
+        state.inner.tasks.lock().unwrap().abort_all();
+    }
+
+    /// Holds state that is `Send` but not `Sync` across `.await`s in its hooks, and replies
+    /// `{"type": "echo_ok"}` to everything.
+    #[derive(Clone)]
+    struct RefCellService;
+
+    impl Node for RefCellService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn init(
+            &self,
+            _state: &NodeState<Self>,
+            node_ids: Vec<String>,
+        ) -> crate::Result<(), Self::Error> {
+            let peers = std::cell::Cell::new(0);
+            tokio::task::yield_now().await;
+            peers.set(node_ids.len() - 1);
+            Ok(())
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let reply = std::cell::RefCell::new(message.body.data);
+            tokio::task::yield_now().await;
+            reply.borrow_mut()["type"] = "echo_ok".into();
+            state
+                .reply(
+                    message.src,
+                    message.body.id.unwrap_or_default(),
+                    reply.into_inner(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handlers_may_hold_non_sync_state() {
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2}}"#;
+        for execution in [Execution::Spawn, Execution::Pool { workers: 2 }] {
+            let options = NodeOptions {
+                execution: Some(execution),
+                ..Default::default()
+            };
+            let (output, result) = run_service(RefCellService, options, &[INIT, echo]).await;
+            assert!(result.is_none(), "node exited: {:?}", result);
+            assert_eq!(output[1]["body"]["type"], "echo_ok");
+            assert_eq!(output[1]["body"]["in_reply_to"], 2);
+        }
+    }
+
+    /// Replies to pings inline with `{"type": "pong", "inline": true}`, and to everything else,
+    /// and pings that couldn't be answered inline, from the handler.
+    #[derive(Clone)]
+    struct InlineService;
+
+    impl Node for InlineService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn try_handle_inline(
+            &self,
+            message: &Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> InlineResult {
+            if message.body.data["type"] != "ping" {
+                return InlineResult::Fallback;
+            }
+            let pong = DataOrInit::Data(serde_json::json!({ "type": "pong", "inline": true }));
+            match state.try_send_message(Arc::clone(&message.src), message.body.id, pong) {
+                Ok(Some(_)) => InlineResult::Handled,
+                _ => InlineResult::Fallback,
+            }
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let pong = serde_json::json!({ "type": "pong", "inline": false });
+            state
+                .reply(message.src, message.body.id.unwrap_or_default(), pong)
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inline_handling_falls_back() {
+        let request = |id: u64, kind: &str| {
+            let message = serde_json::json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": { "type": kind, "msg_id": id },
+            });
+            serde_json::from_value(message).unwrap()
+        };
+        let node = |options: &NodeOptions| {
+            let (output, replies) = tokio::io::duplex(64 * 1024);
+            let state = NodeState::with_output(InlineService, "n1".into(), options, output);
+            state.start_executor(Execution::Spawn);
+            (state, tokio::io::BufReader::new(replies).lines())
+        };
+        let inline = |line: Option<String>| {
+            let reply = serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap();
+            reply["body"]["inline"].as_bool().unwrap()
+        };
+
+        let (state, mut replies) = node(&NodeOptions::default());
+        // Answered without a handler task ever running.
+        state.dispatch(request(1, "ping")).await;
+        assert!(inline(replies.next_line().await.unwrap()));
+
+        state.dispatch(request(2, "other")).await;
+        assert!(!inline(replies.next_line().await.unwrap()));
+
+        // With another task queueing a message, the ping goes to a handler, which waits its turn.
+        let queueing = state.inner.queueing.lock().await;
+        state.dispatch(request(3, "ping")).await;
+        tokio::task::yield_now().await;
+        assert!(replies.next_line().now_or_never().is_none());
+        drop(queueing);
+        assert!(!inline(replies.next_line().await.unwrap()));
+
+        let options = NodeOptions {
+            disable_inline: true,
+            ..Default::default()
+        };
+        let (state, mut replies) = node(&options);
+        state.dispatch(request(4, "ping")).await;
+        assert!(!inline(replies.next_line().await.unwrap()));
+    }
+
+    /// Records the `seq` of every message in each batch it is handed. Messages of type `solo`
+    /// are never batched.
+    #[derive(Clone, Default)]
+    struct BatchingService {
+        batches: Arc<std::sync::Mutex<Vec<Vec<u64>>>>,
+    }
+
+    impl Node for BatchingService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn batches_with(&self, first: &Self::Message, next: &Self::Message) -> bool {
+            first["type"] != "solo" && next["type"] != "solo"
+        }
+
+        async fn handle_batch(
+            &self,
+            messages: Vec<Message<Self::Message>>,
+            _state: &NodeState<Self>,
+        ) -> Vec<crate::Result<(), Self::Error>> {
+            let seqs = messages
+                .iter()
+                .map(|m| m.body.data["seq"].as_u64().unwrap());
+            self.batches.lock().unwrap().push(seqs.collect());
+            messages.iter().map(|_| Ok(())).collect()
+        }
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            let seq = message.body.data["seq"].as_u64().unwrap();
+            self.batches.lock().unwrap().push(vec![seq]);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_messages_are_batched() {
+        let service = BatchingService::default();
+        let state = NodeState::with_output(
+            service.clone(),
+            "n1".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        state.start_executor(Execution::OrderedPool { workers: 1 });
+
+        // Everything is queued before the worker first runs.
+        let mut seq = 0;
+        let mut message = |src: &str, kind: &str| {
+            let message = serde_json::json!({
+                "src": src,
+                "dest": "n1",
+                "body": { "type": kind, "seq": seq },
+            });
+            seq += 1;
+            serde_json::from_value(message).unwrap()
+        };
+        for _ in 0..100 {
+            state.dispatch(message("c1", "record")).await;
+        }
+        for (src, kind) in [
+            ("c1", "solo"),
+            ("c1", "record"),
+            ("c1", "record"),
+            ("c2", "record"),
+            ("c2", "record"),
+        ] {
+            state.dispatch(message(src, kind)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while service.batches.lock().unwrap().concat().len() < seq as usize {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("lost messages");
+
+        let batches = service.batches.lock().unwrap().clone();
+        assert_eq!(batches.concat(), (0..seq).collect::<Vec<_>>());
+        let sizes = batches.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(sizes, [MAX_HANDLER_BATCH, 100 - MAX_HANDLER_BATCH, 1, 2, 2]);
+        state.inner.tasks.lock().unwrap().abort_all();
+    }
+
+    /// Runs as an event loop that reads nothing until `gate` is opened, then records the `seq` of
+    /// every message.
+    #[derive(Clone, Default)]
+    struct GatedService {
+        gate: Arc<tokio::sync::Notify>,
+        seen: Arc<std::sync::Mutex<Vec<u64>>>,
+    }
+
+    impl Node for GatedService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn execution(&self) -> Execution {
+            Execution::EventLoop { capacity: 2 }
+        }
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            unreachable!("handled by the event loop")
+        }
+
+        async fn run_event_loop(
+            &self,
+            mut inbox: tokio::sync::mpsc::Receiver<Message<Self::Message>>,
+            _state: NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            self.gate.notified().await;
+            while let Some(message) = inbox.recv().await {
+                let seq = message.body.data["seq"].as_u64().unwrap();
+                self.seen.lock().unwrap().push(seq);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_loop_backpressure() {
+        let service = GatedService::default();
+        let (mut stdin, node_stdin) = tokio::io::duplex(256);
+        let (node_stdout, stdout) = tokio::io::duplex(64 * 1024);
+        let _node = tokio::spawn(NodeState::run_with_io(
+            service.clone(),
+            NodeOptions::default(),
+            node_stdin,
+            node_stdout,
+        ));
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let mut reply = async || {
+            let line = lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"]["type"].clone()
+        };
+
+        stdin
+            .write_all(format!("{INIT}\n").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(reply().await, "init_ok");
+        // Runner messages never reach the inbox, so they're answered while the loop is stuck.
+        let read_only = r#"{"src":"c1","dest":"n1","body":{"type":"set_read_only","msg_id":2,"read_only":false}}"#;
+        stdin
+            .write_all(format!("{read_only}\n").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(reply().await, "set_read_only_ok");
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let writer = tokio::spawn({
+            let written = written.clone();
+            async move {
+                for seq in 0..100 {
+                    let line = format!(
+                        r#"{{"src":"c1","dest":"n1","body":{{"type":"record","seq":{seq}}}}}"#
+                    );
+                    stdin
+                        .write_all(format!("{line}\n").as_bytes())
+                        .await
+                        .unwrap();
+                    written.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        // The queue and the pipe fill up, and then the writer has to wait.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let stalled = written.load(Ordering::SeqCst);
+        assert!(stalled < 100, "{stalled} messages written");
+        assert!(service.seen.lock().unwrap().is_empty());
+
+        service.gate.notify_one();
+        writer.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while service.seen.lock().unwrap().len() < 100 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("lost messages");
+        assert_eq!(*service.seen.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,193 @@
+//! Message IDs, and the task-local context numbering them.
+//!
+//! Each [`Subsystem`] counts its messages separately and tags their IDs, so a reply tells what it
+//! answers. Which subsystem a task's messages belong to, and the trace ID they carry, are
+//! task-local, see [`in_subsystem`] and [`trace_id`].
+
+use std::{future::Future, sync::atomic::Ordering};
+
+use super::{Node, NodeState};
+use crate::message::{MessageId, Subsystem};
+
+tokio::task_local! {
+    pub(super) static TRACE_ID: Option<u64>;
+    static SUBSYSTEM: Subsystem;
+}
+
+/// Run `future` with the messages it sends numbered as `subsystem`'s, see [`Subsystem`]. Tasks it
+/// spawns start out as [`Subsystem::Service`] again.
+///
+/// Not an `async fn`, which would hold `future` twice: once before it is first polled, and once
+/// inside the scope.
+pub fn in_subsystem<F: Future>(subsystem: Subsystem, future: F) -> impl Future<Output = F::Output> {
+    SUBSYSTEM.scope(subsystem, future)
+}
+
+/// The subsystem the current task's messages are numbered as, see [`in_subsystem`].
+pub fn subsystem() -> Subsystem {
+    SUBSYSTEM
+        .try_with(|subsystem| *subsystem)
+        .unwrap_or_default()
+}
+
+/// The trace ID of the client request that led to the message being handled, if any.
+///
+/// A random ID is picked when a request arrives from a client. It is attached to every message
+/// the handler sends to a peer, and the peer's handler picks it up again, so a request can be
+/// followed through the logs of every node it touched. Background tasks, including those spawned
+/// by a handler, have none.
+pub fn trace_id() -> Option<u64> {
+    TRACE_ID.try_with(|id| *id).ok().flatten()
+}
+
+impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
+    /// The next ID of the current task's [`Subsystem`], see [`in_subsystem`].
+    pub(super) fn next_message_id(&self) -> MessageId {
+        let subsystem = subsystem();
+        let n = self.inner.next_ids[subsystem as usize].fetch_add(1, Ordering::SeqCst);
+        subsystem.id(n)
+    }
+
+    /// Whether `id` was handed out by this node, to a message it sent or as a reserved ID.
+    pub(super) fn is_allocated(&self, id: MessageId) -> bool {
+        Subsystem::of(id).is_some_and(|subsystem| {
+            let next = self.inner.next_ids[subsystem as usize].load(Ordering::SeqCst);
+            id < subsystem.id(next)
+        })
+    }
+
+    /// Reserve a message ID without sending anything.
+    ///
+    /// This is meant for retries, where every attempt must carry the same ID. Messages sent with a
+    /// reserved ID are the one exception to a subsystem's IDs appearing on the wire in increasing
+    /// order.
+    pub fn reserve_message_id(&self) -> MessageId {
+        self.next_message_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncBufReadExt;
+
+    use super::*;
+    use crate::{
+        message::DataOrInit,
+        node::{
+            tests::{run_service, EchoBackService, NullService},
+            NodeOptions,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_wire_order_matches_id_order() {
+        const TASKS: u64 = 32;
+        const SENDS: u64 = 100;
+
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state =
+            NodeState::with_output(NullService, "n1".into(), &NodeOptions::default(), writer);
+
+        let reader = tokio::spawn(async move {
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            let mut ids = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let frame: serde_json::Value = serde_json::from_str(&line).unwrap();
+                ids.push(frame["body"]["msg_id"].as_u64().unwrap());
+            }
+            ids
+        });
+
+        let senders = (0..TASKS)
+            .map(|task| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for n in 0..SENDS {
+                        state
+                            .send(
+                                "c1",
+                                serde_json::json!({ "type": "test", "task": task, "n": n }),
+                            )
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        drop(state);
+
+        let ids = reader.await.unwrap();
+        assert_eq!(ids, (0..TASKS * SENDS).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_id_is_sent_as_is() {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state =
+            NodeState::with_output(NullService, "n1".into(), &NodeOptions::default(), writer);
+
+        let reserved = state.reserve_message_id();
+        let sent = state
+            .send("c1", serde_json::json!({ "type": "test" }))
+            .await
+            .unwrap();
+        state
+            .send_message_with_id(
+                "c1",
+                reserved,
+                None,
+                DataOrInit::Data(serde_json::json!({ "type": "retry" })),
+            )
+            .await
+            .unwrap();
+        drop(state);
+
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let mut ids = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let frame: serde_json::Value = serde_json::from_str(&line).unwrap();
+            ids.push(frame["body"]["msg_id"].as_u64().unwrap());
+        }
+        assert_eq!(ids, vec![sent, reserved]);
+        assert!(reserved < sent);
+    }
+
+    #[tokio::test]
+    async fn test_trace_ids() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let traced =
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo","msg_id":2,"trace_id":7,"echo":1}}"#;
+        let untraced = r#"{"src":"n2","dest":"n1","body":{"type":"echo","msg_id":3,"echo":2}}"#;
+        let client = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":3}}"#;
+        let frames = [init, traced, untraced, client];
+
+        for expose_trace_ids in [false, true] {
+            let options = NodeOptions {
+                expose_trace_ids,
+                ..Default::default()
+            };
+            let (output, result) = run_service(EchoBackService, options, &frames).await;
+            assert!(result.is_none(), "node exited: {:?}", result);
+            let reply = |dest: &str, re: u64| {
+                output
+                    .iter()
+                    .find(|frame| frame["dest"] == dest && frame["body"]["in_reply_to"] == re)
+                    .unwrap_or_else(|| panic!("no reply to {dest}'s {re} in {output:?}"))
+            };
+
+            // A peer's trace ID is carried on; a peer message without one gets none.
+            assert_eq!(reply("n2", 2)["body"]["trace_id"], 7);
+            assert!(reply("n2", 3)["body"].get("trace_id").is_none());
+            // Clients only see the ID picked for their request when asked to.
+            let to_client = &reply("c1", 1)["body"];
+            assert_eq!(
+                to_client["trace_id"].is_u64(),
+                expose_trace_ids,
+                "{to_client}"
+            );
+        }
+    }
+}
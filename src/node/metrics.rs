@@ -0,0 +1,674 @@
+//! What the runner counts about a node: [`Gauges`], error counts, handler latency, client
+//! sessions and peer sequence numbers.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use super::{Executor, Node, NodeState};
+use crate::histogram::LatencySummary;
+
+/// Counts itself in a gauge until dropped, so that a future cancelled while counted is uncounted
+/// too.
+pub(super) struct Counted<'a>(&'a AtomicU64);
+
+impl<'a> Counted<'a> {
+    pub(super) fn new(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What a node knows about one of its clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Messages received from the client.
+    pub requests: u64,
+    /// Requests with a `msg_id` that have not been replied to yet.
+    pub outstanding: u64,
+    pub last_seen: tokio::time::Instant,
+}
+
+/// What a node is busy with at a moment, see [`NodeState::gauges`]. Logged every
+/// [`crate::node::NodeOptions::gauge_interval_ms`], so that a slow run can be followed over time rather than
+/// only read from the summary at the end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Gauges {
+    /// Messages waiting to be written to the output, or written and waiting to be flushed.
+    pub writer_queue: u64,
+    /// Messages waiting for the executor to get to them, see [`crate::node::Execution`].
+    pub mailbox: u64,
+    /// Handlers running.
+    pub active_handlers: u64,
+    /// What the service reports, see [`Node::gauges`].
+    pub service: ServiceGauges,
+}
+
+impl Gauges {
+    /// Whether anything is queued or in flight. The service's element count doesn't count: a
+    /// node holding state isn't busy.
+    pub fn is_busy(&self) -> bool {
+        self.writer_queue > 0
+            || self.mailbox > 0
+            || self.active_handlers > 0
+            || self.service.pending_rpcs > 0
+            || self.service.gossip_lag > 0
+    }
+}
+
+/// The service's part of the [`Gauges`], see [`Node::gauges`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServiceGauges {
+    /// Requests to peers still waiting for an answer: those the service tracks itself, such as
+    /// unacknowledged gossip, and those awaited with [`NodeState::rpc`], added in by the runner.
+    pub pending_rpcs: u64,
+    /// The most values any one peer is known to be missing.
+    pub gossip_lag: u64,
+    /// How many elements the service's main maps hold, as a rough measure of its memory.
+    pub elements: u64,
+}
+
+/// How many messages the node failed to handle, by cause.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    /// Messages that didn't decode into the service's message type, or were rejected by
+    /// [`Node::check_strict`].
+    pub decode: u64,
+    /// Messages the service's handler returned an error for.
+    pub handler: u64,
+    /// Replies to replies that were refused, see [`crate::node::NodeOptions::allow_reply_to_reply`].
+    pub replies_to_replies: u64,
+}
+
+/// What became of the requests sent with [`NodeState::rpc`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcCounts {
+    /// Requests waiting for a reply right now.
+    pub pending: u64,
+    /// Requests that waited longer than [`crate::node::NodeOptions::max_rpc_lifetime_ms`].
+    pub expired: u64,
+    /// Requests given up on to make room for newer ones, once
+    /// [`crate::node::NodeOptions::max_pending_rpcs`] were waiting.
+    pub evicted: u64,
+    /// Replies handed to the request waiting for them.
+    pub matched: u64,
+    /// Replies to requests that had stopped waiting, handed to [`Node::on_late_reply`].
+    pub late: u64,
+    /// Replies to messages this node never sent, dropped unless
+    /// [`crate::node::NodeOptions::lenient_replies`] is on.
+    pub unknown: u64,
+}
+
+/// What a node made of the sequence numbers on a peer's messages, see
+/// [`crate::node::NodeOptions::sequence_peer_messages`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerSequence {
+    /// Numbered messages received from the peer.
+    pub received: u64,
+    /// The highest sequence number received.
+    pub last: u64,
+    /// Messages skipped over by a later one that haven't turned up since. Those sent after the
+    /// last one received aren't noticed.
+    pub missing: u64,
+    /// Messages that arrived after a later one.
+    pub reordered: u64,
+}
+
+impl PeerSequence {
+    /// Count message `seq`, returning how many were skipped before it.
+    fn record(&mut self, seq: u64) -> u64 {
+        self.received += 1;
+        if seq > self.last {
+            let skipped = seq - self.last - 1;
+            self.missing += skipped;
+            self.last = seq;
+            skipped
+        } else {
+            self.reordered += 1;
+            self.missing = self.missing.saturating_sub(1);
+            0
+        }
+    }
+}
+
+/// The tag [`NodeState::handler_latency`] counts messages without one under.
+pub const UNTAGGED: &str = "other";
+
+/// A handler being timed, see [`NodeState::handler_latency`].
+pub(super) struct HandlerTiming {
+    tag: &'static str,
+    src: Arc<str>,
+    /// The size of the message's body, if slow handlers are logged.
+    size: Option<usize>,
+    started: tokio::time::Instant,
+}
+
+/// Per-client statistics, keyed by client ID.
+#[derive(Debug)]
+pub(super) struct ClientSessions {
+    clients: std::collections::HashMap<String, ClientStats>,
+    capacity: usize,
+}
+
+impl ClientSessions {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            clients: std::collections::HashMap::new(),
+            capacity,
+        }
+    }
+
+    pub(super) fn request(&mut self, client: &str, expects_reply: bool) {
+        let now = tokio::time::Instant::now();
+        if !self.clients.contains_key(client) && self.clients.len() >= self.capacity {
+            let least_recent = self
+                .clients
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_seen)
+                .map(|(client, _)| client.clone());
+            if let Some(least_recent) = least_recent {
+                self.clients.remove(&least_recent);
+            }
+        }
+
+        let stats = self
+            .clients
+            .entry(client.to_owned())
+            .or_insert(ClientStats {
+                requests: 0,
+                outstanding: 0,
+                last_seen: now,
+            });
+        stats.requests += 1;
+        stats.outstanding += expects_reply as u64;
+        stats.last_seen = now;
+    }
+
+    pub(super) fn contains(&self, client: &str) -> bool {
+        self.clients.contains_key(client)
+    }
+
+    pub(super) fn reply(&mut self, client: &str) {
+        if let Some(stats) = self.clients.get_mut(client) {
+            stats.outstanding = stats.outstanding.saturating_sub(1);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<(String, ClientStats)> {
+        let mut clients = self
+            .clients
+            .iter()
+            .map(|(client, stats)| (client.clone(), stats.clone()))
+            .collect::<Vec<_>>();
+        clients.sort_by(|(a, _), (b, _)| a.cmp(b));
+        clients
+    }
+}
+
+impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
+    /// Log the [`Gauges`] every [`crate::node::NodeOptions::gauge_interval_ms`]. Nothing is logged while the
+    /// node is idle and holds as many elements as when last logged.
+    pub(super) fn start_gauges(&self) {
+        let Some(period) = self.inner.gauge_interval else {
+            return;
+        };
+        let state = self.clone();
+        self.spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            let mut elements = 0;
+            loop {
+                interval.tick().await;
+                let gauges = state.gauges();
+                if !gauges.is_busy() && gauges.service.elements == elements {
+                    continue;
+                }
+                elements = gauges.service.elements;
+                tracing::info!(
+                    writer_queue = gauges.writer_queue,
+                    mailbox = gauges.mailbox,
+                    active_handlers = gauges.active_handlers,
+                    pending_rpcs = gauges.service.pending_rpcs,
+                    gossip_lag = gauges.service.gossip_lag,
+                    elements = gauges.service.elements,
+                    "Gauges"
+                );
+            }
+        });
+    }
+
+    /// Statistics for the clients that have talked to this node, ordered by client ID. Only the
+    /// [`crate::node::MAX_TRACKED_CLIENTS`] most recently seen clients are included.
+    pub fn client_sessions(&self) -> Vec<(String, ClientStats)> {
+        self.inner.clients.lock().unwrap().snapshot()
+    }
+
+    /// The sequence numbers received from each peer that numbers its messages, by peer, see
+    /// [`crate::node::NodeOptions::sequence_peer_messages`].
+    pub fn peer_sequences(&self) -> BTreeMap<String, PeerSequence> {
+        self.inner.received_seqs.lock().unwrap().clone()
+    }
+
+    pub(super) fn record_peer_seq(&self, src: &str, seq: u64) {
+        let mut received = self.inner.received_seqs.lock().unwrap();
+        let sequence = received.entry(src.to_owned()).or_default();
+        let last = sequence.last;
+        match sequence.record(seq) {
+            0 if seq <= last => {
+                tracing::debug!("Message #{} from {} arrived after #{}", seq, src, last)
+            }
+            0 => {}
+            skipped => tracing::debug!("{} messages from {} missing before #{}", skipped, src, seq),
+        }
+    }
+
+    pub(super) fn start_timing(&self, src: &Arc<str>, data: &NodeImpl::Message) -> HandlerTiming {
+        HandlerTiming {
+            tag: self.inner.node.message_tag(data).unwrap_or(UNTAGGED),
+            src: Arc::clone(src),
+            size: self
+                .inner
+                .slow_handler
+                .and_then(|_| serde_json::to_vec(data).ok())
+                .map(|encoded| encoded.len()),
+            started: tokio::time::Instant::now(),
+        }
+    }
+
+    pub(super) fn finish_timing(&self, timing: HandlerTiming) {
+        let elapsed = timing.started.elapsed();
+        self.inner
+            .handler_latency
+            .lock()
+            .unwrap()
+            .entry(timing.tag)
+            .or_default()
+            .record(elapsed);
+        if self.inner.slow_handler.is_some_and(|slow| elapsed > slow) {
+            tracing::warn!(
+                "Slow handler: {} from {} ({} bytes) took {:?}",
+                timing.tag,
+                timing.src,
+                timing.size.unwrap_or_default(),
+                elapsed
+            );
+        }
+    }
+
+    /// How long handlers have taken so far, by the tag of the message handled, see
+    /// [`Node::message_tag`]. Messages without a tag are counted under `"other"`.
+    pub fn handler_latency(&self) -> BTreeMap<&'static str, LatencySummary> {
+        self.inner
+            .handler_latency
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tag, histogram)| (*tag, histogram.summary()))
+            .collect()
+    }
+
+    /// How many messages the node failed to handle so far.
+    pub fn error_counts(&self) -> ErrorCounts {
+        ErrorCounts {
+            decode: self.inner.decode_errors.load(Ordering::Relaxed),
+            handler: self.inner.handler_errors.load(Ordering::Relaxed),
+            replies_to_replies: self.inner.replies_to_replies.load(Ordering::Relaxed),
+        }
+    }
+
+    /// What has become of the node's requests so far, see [`NodeState::rpc`].
+    pub fn rpc_counts(&self) -> RpcCounts {
+        RpcCounts {
+            pending: self.inner.pending_replies.len() as u64,
+            expired: self.inner.rpcs_expired.load(Ordering::Relaxed),
+            evicted: self.inner.rpcs_evicted.load(Ordering::Relaxed),
+            matched: self.inner.replies_matched.load(Ordering::Relaxed),
+            late: self.inner.replies_late.load(Ordering::Relaxed),
+            unknown: self.inner.replies_unknown.load(Ordering::Relaxed),
+        }
+    }
+
+    /// What the node is busy with right now.
+    pub fn gauges(&self) -> Gauges {
+        // Messages forwarded to an event loop are counted by its inbox rather than the mailbox.
+        let forwarded = match self.inner.executor.get() {
+            Some(Executor::EventLoop(inbox)) => inbox.max_capacity() - inbox.capacity(),
+            _ => 0,
+        };
+        let unflushed = self.inner.flush.lock().unwrap().pending();
+        let mut service = self.inner.node.gauges();
+        service.pending_rpcs += self.inner.pending_replies.len() as u64;
+        Gauges {
+            writer_queue: (self.inner.outbox.max_capacity() - self.inner.outbox.capacity()
+                + unflushed) as u64,
+            mailbox: self.inner.mailbox.load(Ordering::Relaxed) + forwarded as u64,
+            active_handlers: self.inner.active_handlers.load(Ordering::Relaxed),
+            service,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{
+        message::{ErrorCode, Message},
+        node::{
+            tests::{reply_to, run_service, CapturedLogs, FailingService, TaggedService, INIT},
+            Execution, NodeOptions,
+        },
+    };
+
+    /// Replies to `ping` and ignores everything else.
+    #[derive(Clone)]
+    struct SelectiveService;
+
+    impl Node for SelectiveService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        async fn handle_message(
+            &self,
+            message: Message<Self::Message>,
+            state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            if let (Some(id), "ping") = (
+                message.body.id,
+                message.body.data["type"].as_str().unwrap_or_default(),
+            ) {
+                state
+                    .reply(message.src, id, serde_json::json!({ "type": "pong" }))
+                    .await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_sessions() {
+        let state = NodeState::with_output(
+            SelectiveService,
+            "n1".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        let request = |src: &str, id: u64, kind: &str| {
+            serde_json::from_value(serde_json::json!({
+                "src": src,
+                "dest": "n1",
+                "body": { "type": kind, "msg_id": id },
+            }))
+            .unwrap()
+        };
+
+        for id in 1..=3 {
+            state.dispatch(request("c1", id, "ping")).await;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        state.dispatch(request("c2", 1, "ping")).await;
+        state.dispatch(request("c2", 2, "ignore")).await;
+        state.dispatch(request("c2", 3, "ignore")).await;
+        // Peers aren't clients.
+        state.dispatch(request("n2", 1, "ping")).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let sessions = state.client_sessions();
+        let [(c1, c1_stats), (c2, c2_stats)] = &sessions[..] else {
+            panic!("Expected two clients, got {sessions:?}");
+        };
+        assert_eq!(
+            (c1.as_str(), c1_stats.requests, c1_stats.outstanding),
+            ("c1", 3, 0)
+        );
+        assert_eq!(
+            (c2.as_str(), c2_stats.requests, c2_stats.outstanding),
+            ("c2", 3, 2)
+        );
+        assert!(c1_stats.last_seen < c2_stats.last_seen);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_sessions_forget_least_recent() {
+        let mut sessions = ClientSessions::new(2);
+        for client in ["c1", "c2", "c1", "c3"] {
+            sessions.request(client, true);
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+
+        let clients = sessions
+            .snapshot()
+            .into_iter()
+            .map(|(client, stats)| (client, stats.requests))
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![("c1".to_owned(), 2), ("c3".to_owned(), 1)]);
+    }
+
+    /// Handles each message once a permit is added to `gate`, two at a time.
+    #[derive(Clone)]
+    struct StalledService {
+        gate: Arc<tokio::sync::Semaphore>,
+    }
+
+    impl Node for StalledService {
+        type Message = serde_json::Value;
+        type Error = std::io::Error;
+
+        fn execution(&self) -> Execution {
+            Execution::Pool { workers: 2 }
+        }
+
+        async fn handle_message(
+            &self,
+            _message: Message<Self::Message>,
+            _state: &NodeState<Self>,
+        ) -> crate::Result<(), Self::Error> {
+            self.gate.acquire().await.unwrap().forget();
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gauges_are_logged_while_busy() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let gauge_lines = || {
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            logs.lines()
+                .filter(|line| line.contains("Gauges"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        let service = StalledService {
+            gate: Arc::new(tokio::sync::Semaphore::new(0)),
+        };
+        let options = NodeOptions {
+            gauge_interval_ms: Some(1000),
+            ..Default::default()
+        };
+        let (mut stdin, node_stdin) = tokio::io::duplex(64 * 1024);
+        let _node = tokio::spawn(NodeState::run_with_io(
+            service.clone(),
+            options,
+            node_stdin,
+            tokio::io::sink(),
+        ));
+        stdin
+            .write_all(format!("{INIT}\n").as_bytes())
+            .await
+            .unwrap();
+        for id in 2..12 {
+            let frame =
+                format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"work","msg_id":{id}}}}}"#);
+            stdin
+                .write_all(format!("{frame}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // Both workers are stuck in a handler, with the other eight messages waiting for them.
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let lines = gauge_lines();
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(lines[0].contains("mailbox=8"), "{}", lines[0]);
+        assert!(lines[0].contains("active_handlers=2"), "{}", lines[0]);
+        assert!(lines[0].contains("writer_queue=0"), "{}", lines[0]);
+
+        service.gate.add_permits(5);
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        let lines = gauge_lines();
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        assert!(lines[1].contains("mailbox=3"), "{}", lines[1]);
+
+        // Once everything is handled, nothing more is logged.
+        service.gate.add_permits(5);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(gauge_lines().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handler_latency_by_tag() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let service = TaggedService {
+            tags: &["fast", "slow"],
+            state: Default::default(),
+        };
+        let options = NodeOptions {
+            slow_handler_ms: Some(20),
+            ..Default::default()
+        };
+        let message = |id: u64, body: &str| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"msg_id":{id},{body}}}}}"#)
+        };
+        let frames = [
+            INIT.to_owned(),
+            message(2, r#""type":"fast""#),
+            message(3, r#""type":"fast","sleep_ms":5"#),
+            message(4, r#""type":"slow","sleep_ms":50"#),
+            message(5, r#""type":"mystery""#),
+        ];
+        let frames = frames.iter().map(String::as_str).collect::<Vec<_>>();
+        let (_, result) = run_service(service.clone(), options, &frames).await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        let latency = service.state.get().unwrap().handler_latency();
+        assert_eq!(
+            latency.keys().copied().collect::<Vec<_>>(),
+            ["fast", UNTAGGED, "slow"]
+        );
+        assert_eq!(latency["fast"].count, 2);
+        assert_eq!(latency["fast"].max, Duration::from_millis(5));
+        assert_eq!(latency["slow"].count, 1);
+        assert_eq!(latency["slow"].p99, Duration::from_millis(50));
+        assert_eq!(latency[UNTAGGED].count, 1);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let slow = logs
+            .lines()
+            .filter(|line| line.contains("Slow handler"))
+            .collect::<Vec<_>>();
+        assert_eq!(slow.len(), 1, "{logs}");
+        assert!(
+            slow[0].contains("Slow handler: slow from c1 (29 bytes) took 50ms"),
+            "{}",
+            slow[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_and_decode_errors_are_separate() {
+        let service = FailingService::default();
+        let fail = |src: &str, id: u64| {
+            format!(r#"{{"src":"{src}","dest":"n1","body":{{"type":"fail","msg_id":{id}}}}}"#)
+        };
+        // Runner replies are not service messages, so this one doesn't decode.
+        let stray = r#"{"src":"c1","dest":"n1","body":{"type":"set_read_only_ok","msg_id":3}}"#;
+        let fine = r#"{"src":"c1","dest":"n1","body":{"type":"ok","msg_id":4}}"#;
+
+        let (output, result) = run_service(
+            service.clone(),
+            NodeOptions::default(),
+            &[INIT, &fail("c1", 2), stray, fine, &fail("n2", 5)],
+        )
+        .await;
+        assert!(result.is_none(), "node exited: {:?}", result);
+
+        let state = service.state.get().unwrap();
+        assert_eq!(
+            state.error_counts(),
+            ErrorCounts {
+                decode: 1,
+                handler: 2,
+                replies_to_replies: 0,
+            }
+        );
+        let failures = service.failures.lock().unwrap().clone();
+        assert_eq!(
+            failures
+                .iter()
+                .map(|meta| (&*meta.src, meta.id))
+                .collect::<Vec<_>>(),
+            [("c1", Some(2)), ("n2", Some(5))]
+        );
+
+        // Only the client is told about the failure.
+        assert_eq!(output.len(), 2, "{:?}", output);
+        let error = reply_to(&output, 2);
+        assert_eq!(error["body"]["type"], "error");
+        assert_eq!(error["body"]["code"], ErrorCode::Crash as u64);
+        assert!(error["body"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("out of luck"));
+    }
+
+    #[test]
+    fn test_peer_sequence() {
+        let mut sequence = PeerSequence::default();
+        assert_eq!(sequence.record(1), 0);
+        assert_eq!(sequence.record(4), 2);
+        // A late arrival is reordered rather than lost.
+        assert_eq!(sequence.record(3), 0);
+        assert_eq!(sequence.record(5), 0);
+        assert_eq!(
+            sequence,
+            PeerSequence {
+                received: 4,
+                last: 5,
+                missing: 1,
+                reordered: 1,
+            }
+        );
+    }
+}
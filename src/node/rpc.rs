@@ -0,0 +1,762 @@
+//! Requests that wait for their reply, see [`NodeState::rpc`].
+//!
+//! A request's ID is registered before the request is queued, and the run loop hands the reply
+//! that names it in `in_reply_to` back to the caller instead of to [`Node::handle_message`].
+//! Requests are capped by [`crate::node::NodeOptions::max_pending_rpcs`], the oldest making room
+//! for a new one, and swept once older than [`crate::node::NodeOptions::max_rpc_lifetime_ms`], so
+//! a dead peer can't pile them up.
+//!
+//! Every reply is sorted out before it reaches the service, see [`crate::node::RpcCounts`]: one a
+//! request is waiting for goes to it, a late one to [`Node::on_late_reply`], and one to a message
+//! the node never sent is dropped. Replies to messages sent without waiting are handled as usual.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use snafu::ResultExt as _;
+
+use super::{
+    Counted, InternalError, NoReplySnafu, Node, NodeState, ReplyDecompressionSnafu,
+    RetriesExhaustedSnafu, RpcEvictedSnafu, RpcExpiredSnafu, TimeoutSnafu,
+};
+use crate::{
+    message::{DataOrInit, Message, MessageId},
+    util::RetryPolicy,
+};
+
+/// A reply awaited by [`NodeState::rpc`]: the request went to `dest` at `sent`, and the reply goes
+/// to `reply`, or an error if the request expires first.
+pub(super) struct PendingReply<Data> {
+    dest: Arc<str>,
+    sent: tokio::time::Instant,
+    reply: tokio::sync::oneshot::Sender<Result<Message<DataOrInit<Data>>, InternalError>>,
+}
+
+/// Lives as long as a [`NodeState::rpc`] waits for reply `id`, so that a caller that stops waiting
+/// leaves nothing behind.
+struct AwaitingReply<'a, NodeImpl: Node + Send + Sync + 'static> {
+    state: &'a NodeState<NodeImpl>,
+    id: MessageId,
+}
+
+impl<NodeImpl: Node + Send + Sync + 'static> Drop for AwaitingReply<'_, NodeImpl> {
+    fn drop(&mut self) {
+        self.state.inner.pending_replies.remove(&self.id);
+        self.state
+            .inner
+            .finished_rpcs
+            .lock()
+            .unwrap()
+            .record(self.id);
+    }
+}
+
+/// The last few requests that stopped waiting for their reply, answered or not, oldest first.
+#[derive(Debug)]
+pub(super) struct FinishedRpcs {
+    order: VecDeque<MessageId>,
+    ids: HashSet<MessageId>,
+    capacity: usize,
+}
+
+impl FinishedRpcs {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+            capacity,
+        }
+    }
+
+    fn record(&mut self, id: MessageId) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, id: MessageId) -> bool {
+        self.ids.contains(&id)
+    }
+}
+
+impl<NodeImpl: Node + Send + Sync + 'static> NodeState<NodeImpl> {
+    /// Send `data` to `dest` as a request and wait for the reply, the message whose
+    /// `in_reply_to` is the request's ID. The reply comes back here instead of going to
+    /// [`Node::handle_message`]. Fails with [`InternalError::Timeout`] if no reply arrives within
+    /// [`crate::node::NodeOptions::rpc_timeout_ms`]. Replies that arrive once the caller stopped
+    /// waiting, and any after the first, go to [`Node::on_late_reply`].
+    pub async fn rpc(
+        &self,
+        dest: impl Into<Arc<str>>,
+        data: NodeImpl::Message,
+    ) -> crate::Result<Message<NodeImpl::Message>, NodeImpl::Error> {
+        self.call(dest.into(), data, self.inner.rpc_timeout).await
+    }
+
+    /// Like [`NodeState::rpc`], but waits `timeout` for the reply instead of the node's default.
+    pub async fn rpc_with_timeout(
+        &self,
+        dest: impl Into<Arc<str>>,
+        data: NodeImpl::Message,
+        timeout: Duration,
+    ) -> crate::Result<Message<NodeImpl::Message>, NodeImpl::Error> {
+        self.call(dest.into(), data, timeout).await
+    }
+
+    /// Like [`NodeState::rpc_with_timeout`], but sends the request again, under a new message ID,
+    /// whenever no reply arrives within `policy.attempt_timeout`, backing off longer before each
+    /// attempt as `policy` says. Fails with
+    /// [`InternalError::RetriesExhausted`] once `policy.max_attempts` attempts went unanswered.
+    /// Replies to earlier attempts that arrive late go to [`Node::on_late_reply`], and the request
+    /// should be safe to handle more than once.
+    pub async fn rpc_retry(
+        &self,
+        dest: impl Into<Arc<str>>,
+        data: NodeImpl::Message,
+        policy: RetryPolicy,
+    ) -> crate::Result<Message<NodeImpl::Message>, NodeImpl::Error>
+    where
+        NodeImpl::Message: Clone,
+    {
+        let dest = dest.into();
+        let started = tokio::time::Instant::now();
+        let attempts = policy.max_attempts.max(1);
+        let mut backoff = policy.backoff();
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                backoff.wait().await;
+            }
+            let timeout = policy.attempt_timeout;
+            match self.call(Arc::clone(&dest), data.clone(), timeout).await {
+                Err(crate::Error::Internal {
+                    source: InternalError::Timeout { id, .. },
+                }) => tracing::debug!(
+                    "No reply from {} to message {}, attempt {} of {}",
+                    dest,
+                    id,
+                    attempt,
+                    attempts
+                ),
+                result => return result,
+            }
+        }
+        Err(RetriesExhaustedSnafu {
+            dest,
+            attempts,
+            elapsed: started.elapsed(),
+        }
+        .build()
+        .into())
+    }
+
+    async fn call(
+        &self,
+        dest: Arc<str>,
+        data: NodeImpl::Message,
+        timeout: Duration,
+    ) -> crate::Result<Message<NodeImpl::Message>, NodeImpl::Error> {
+        let started = tokio::time::Instant::now();
+        let (reply, replied) = tokio::sync::oneshot::channel();
+        let permit = self.reserve(&dest).await?;
+        let (id, _waiting) = {
+            let _queueing = self.inner.queueing.lock().await;
+            // Requests are only registered under the lock, so none can slip in after the check.
+            while self.inner.pending_replies.len() >= self.inner.max_pending_rpcs {
+                self.evict_oldest_rpc();
+            }
+            let id = self.next_message_id();
+            // Registered before the request is queued, so that the reply can't arrive first.
+            let pending = PendingReply {
+                dest: Arc::clone(&dest),
+                sent: started,
+                reply,
+            };
+            self.inner.pending_replies.insert(id, pending).await;
+            let waiting = AwaitingReply { state: self, id };
+            self.queue(permit, Arc::clone(&dest), id, None, DataOrInit::Data(data))?;
+            (id, waiting)
+        };
+
+        let replied = tokio::time::timeout_at(started + timeout, replied)
+            .await
+            .map_err(|_| {
+                TimeoutSnafu {
+                    dest: Arc::clone(&dest),
+                    id,
+                    elapsed: started.elapsed(),
+                }
+                .build()
+            })?;
+        let mut reply = replied.map_err(|_| {
+            NoReplySnafu {
+                dest: Arc::clone(&dest),
+                id,
+            }
+            .build()
+        })??;
+        if let DataOrInit::GossipZ(envelope) = &reply.body.data {
+            reply.body.data = envelope
+                .open()
+                .context(ReplyDecompressionSnafu { dest, id })?;
+        }
+        reply.into_data()
+    }
+
+    /// Hand `msg` to the [`NodeState::rpc`] waiting for it if it is a reply one is waiting for,
+    /// to [`Node::on_late_reply`] if it answers one that stopped waiting, or drop it if it answers
+    /// nothing this node sent. Gives back the rest, to be handled as usual.
+    pub(super) fn complete_rpc(
+        &self,
+        msg: Message<DataOrInit<NodeImpl::Message>>,
+    ) -> Option<Message<DataOrInit<NodeImpl::Message>>> {
+        let Some(re) = msg.body.re else {
+            return Some(msg);
+        };
+        // Removed as it is completed, so that a duplicate reply finds nothing.
+        let pending = self
+            .inner
+            .pending_replies
+            .remove_if(&re, |_, pending| pending.dest == msg.src);
+        let late = match pending {
+            Some((_, pending)) => match pending.reply.send(Ok(msg)) {
+                Ok(()) => {
+                    self.inner.replies_matched.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                // The caller stopped waiting just as the reply arrived.
+                Err(unsent) => unsent.ok()?,
+            },
+            None if self.inner.finished_rpcs.lock().unwrap().contains(re) => msg,
+            None if self.is_allocated(re) => return Some(msg),
+            None => {
+                self.inner.replies_unknown.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!(
+                    "Reply from {} to message {}, which was never sent",
+                    msg.src,
+                    re
+                );
+                return self.inner.lenient_replies.then_some(msg);
+            }
+        };
+        self.inner.replies_late.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("Late reply from {} to message {}", late.src, re);
+        let state = self.clone();
+        tokio::spawn(async move { state.handle_late_reply(late).await });
+        None
+    }
+
+    /// Hand a late reply to [`Node::on_late_reply`]. Error replies and the runner's own messages
+    /// are only of use to the caller that stopped waiting, and are dropped.
+    async fn handle_late_reply(&self, mut msg: Message<DataOrInit<NodeImpl::Message>>) {
+        let _active = Counted::new(&self.inner.active_handlers);
+        if let DataOrInit::GossipZ(envelope) = &msg.body.data {
+            match envelope.open() {
+                Ok(data) => msg.body.data = data,
+                Err(e) => {
+                    tracing::debug!("Failed to decompress late reply from {}: {}", msg.src, e);
+                    return;
+                }
+            }
+        }
+        let Ok(reply) = msg.into_data::<NodeImpl::Error>() else {
+            return;
+        };
+        if let Err(e) = self.inner.node.on_late_reply(reply, self).await {
+            tracing::warn!(
+                "Error handling late reply: {}",
+                snafu::Report::from_error(e)
+            );
+        }
+    }
+
+    /// Fail every request older than [`crate::node::NodeOptions::max_rpc_lifetime_ms`] with
+    /// [`InternalError::RpcExpired`], whether or not its caller is still waiting.
+    fn expire_rpcs(&self) {
+        let lifetime = self.inner.max_rpc_lifetime;
+        let expired =
+            |pending: &PendingReply<NodeImpl::Message>| pending.sent.elapsed() >= lifetime;
+        let mut ids = Vec::new();
+        self.inner.pending_replies.retain(|id, pending| {
+            if expired(pending) {
+                ids.push(*id);
+            }
+            true
+        });
+        // Removed one at a time, since `retain` only lends each entry and sending the error takes
+        // the sender.
+        for id in ids {
+            let Some((_, pending)) = self
+                .inner
+                .pending_replies
+                .remove_if(&id, |_, pending| expired(pending))
+            else {
+                continue;
+            };
+            self.inner.rpcs_expired.fetch_add(1, Ordering::Relaxed);
+            self.inner.finished_rpcs.lock().unwrap().record(id);
+            let error = RpcExpiredSnafu {
+                dest: pending.dest,
+                id,
+                elapsed: pending.sent.elapsed(),
+            }
+            .build();
+            // Fails only if the caller stopped waiting in the meantime.
+            let _ = pending.reply.send(Err(error));
+        }
+    }
+
+    /// Fail the request that has waited longest with [`InternalError::RpcEvicted`], to make room
+    /// for a new one.
+    fn evict_oldest_rpc(&self) {
+        // IDs only grow, so the lowest pending one was registered first.
+        let mut oldest = None;
+        self.inner.pending_replies.retain(|id, _| {
+            oldest = Some(oldest.map_or(*id, |oldest: MessageId| oldest.min(*id)));
+            true
+        });
+        let Some((id, pending)) = oldest.and_then(|id| self.inner.pending_replies.remove(&id))
+        else {
+            return;
+        };
+        self.inner.rpcs_evicted.fetch_add(1, Ordering::Relaxed);
+        self.inner.finished_rpcs.lock().unwrap().record(id);
+        let error = RpcEvictedSnafu {
+            dest: pending.dest,
+            id,
+            limit: self.inner.max_pending_rpcs,
+        }
+        .build();
+        // Fails only if the caller stopped waiting in the meantime.
+        let _ = pending.reply.send(Err(error));
+    }
+
+    /// Expire old requests every quarter of [`crate::node::NodeOptions::max_rpc_lifetime_ms`], so
+    /// that none waits more than a quarter longer than that.
+    pub(super) fn start_rpc_sweeper(&self) {
+        let period = (self.inner.max_rpc_lifetime / 4).max(Duration::from_millis(1));
+        let state = self.clone();
+        self.spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                state.expire_rpcs();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncBufReadExt;
+
+    use super::*;
+    use crate::node::{tests::RecordingService, NodeOptions, RpcCounts};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rpc_awaits_its_reply() {
+        let service = RecordingService::default();
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state = NodeState::with_output(service.clone(), "n1".into(), &options, writer);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let reply = |src: &str, re: u64, seq: u64| {
+            serde_json::from_value(serde_json::json!({
+                "src": src,
+                "dest": "n1",
+                "body": { "type": "pong", "in_reply_to": re, "seq": seq },
+            }))
+            .unwrap()
+        };
+        let call = || {
+            let state = state.clone();
+            tokio::spawn(
+                async move { state.rpc("n2", serde_json::json!({ "type": "ping" })).await },
+            )
+        };
+        let mut next_request = async || {
+            let line = lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"]["msg_id"]
+                .as_u64()
+                .unwrap()
+        };
+
+        let answered = call();
+        let id = next_request().await;
+        // Only the peer asked can answer.
+        state.dispatch(reply("n3", id, 1)).await;
+        state.dispatch(reply("n2", id, 2)).await;
+        let answer = answered.await.unwrap().unwrap();
+        assert_eq!(
+            (answer.body.re, &answer.body.data["seq"]),
+            (Some(id), &2.into())
+        );
+        // A duplicate reply is late.
+        state.dispatch(reply("n2", id, 3)).await;
+
+        // As is one that arrives after the caller stopped waiting.
+        let abandoned = call();
+        let id = next_request().await;
+        abandoned.abort();
+        assert!(abandoned.await.unwrap_err().is_cancelled());
+        assert_eq!(state.inner.pending_replies.len(), 0);
+        state.dispatch(reply("n2", id, 4)).await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let seqs = |handled: &std::sync::Mutex<Vec<(Arc<str>, u64)>>| {
+            let mut seqs = handled
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(src, seq)| (src.to_string(), *seq))
+                .collect::<Vec<_>>();
+            seqs.sort();
+            seqs
+        };
+        assert_eq!(seqs(&service.handled), [("n3".to_owned(), 1)]);
+        let expected = [("n2", 3), ("n2", 4)].map(|(src, seq)| (src.to_owned(), seq));
+        assert_eq!(seqs(&service.late), expected);
+        let counts = state.rpc_counts();
+        assert_eq!((counts.matched, counts.late, counts.unknown), (1, 2, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rpc_times_out() {
+        let service = RecordingService::default();
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state = NodeState::with_output(service.clone(), "n1".into(), &options, writer);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+
+        let called = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let ping = serde_json::json!({ "type": "ping" });
+                state
+                    .rpc_with_timeout("n2", ping, Duration::from_millis(100))
+                    .await
+            }
+        });
+        let line = lines.next_line().await.unwrap().unwrap();
+        let id = serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"]["msg_id"]
+            .as_u64()
+            .unwrap();
+        match called.await.unwrap() {
+            Err(crate::Error::Internal {
+                source:
+                    InternalError::Timeout {
+                        dest,
+                        id: timed_out,
+                        elapsed,
+                    },
+            }) => {
+                assert_eq!((&*dest, timed_out), ("n2", id));
+                assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+            }
+            other => panic!("rpc didn't time out: {other:?}"),
+        }
+        assert_eq!(state.inner.pending_replies.len(), 0);
+
+        // The late reply goes to the hook, not the service.
+        let late = serde_json::from_value(serde_json::json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": { "type": "pong", "in_reply_to": id, "seq": 1 },
+        }))
+        .unwrap();
+        state.dispatch(late).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(service.handled.lock().unwrap().is_empty());
+        assert_eq!(*service.late.lock().unwrap(), [("n2".into(), 1)]);
+        let counts = state.rpc_counts();
+        assert_eq!((counts.matched, counts.late, counts.unknown), (0, 1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unknown_replies_are_dropped() {
+        for lenient_replies in [false, true] {
+            let service = RecordingService::default();
+            let options = NodeOptions {
+                lenient_destinations: true,
+                lenient_replies,
+                ..Default::default()
+            };
+            let state =
+                NodeState::with_output(service.clone(), "n1".into(), &options, tokio::io::sink());
+            // A plain send allocates an ID without waiting for the reply, which is handled as
+            // usual; nothing was sent with the other.
+            state
+                .send("n2", serde_json::json!({ "type": "ping" }))
+                .await
+                .unwrap();
+            for (re, seq) in [(0, 1), (1000, 2)] {
+                let reply = serde_json::from_value(serde_json::json!({
+                    "src": "n2",
+                    "dest": "n1",
+                    "body": { "type": "pong", "in_reply_to": re, "seq": seq },
+                }))
+                .unwrap();
+                state.dispatch(reply).await;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let mut handled = service
+                .handled
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, seq)| *seq)
+                .collect::<Vec<_>>();
+            handled.sort();
+            let expected: &[u64] = if lenient_replies { &[1, 2] } else { &[1] };
+            assert_eq!(handled, expected, "lenient_replies: {lenient_replies}");
+            assert!(service.late.lock().unwrap().is_empty());
+            let counts = state.rpc_counts();
+            assert_eq!((counts.matched, counts.late, counts.unknown), (0, 0, 1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rpc_has_a_default_deadline() {
+        let options = NodeOptions {
+            lenient_destinations: true,
+            rpc_timeout_ms: Some(250),
+            ..Default::default()
+        };
+        let state = NodeState::with_output(
+            RecordingService::default(),
+            "n1".into(),
+            &options,
+            tokio::io::sink(),
+        );
+
+        let started = tokio::time::Instant::now();
+        let ping = serde_json::json!({ "type": "ping" });
+        match state.rpc("n2", ping).await {
+            Err(crate::Error::Internal {
+                source: InternalError::Timeout { dest, elapsed, .. },
+            }) => {
+                assert_eq!(&*dest, "n2");
+                assert_eq!(elapsed, Duration::from_millis(250));
+            }
+            other => panic!("rpc didn't time out: {other:?}"),
+        }
+        assert_eq!(started.elapsed(), Duration::from_millis(250));
+        assert_eq!(state.inner.pending_replies.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pending_rpcs_are_capped_and_expire() {
+        const REQUESTS: usize = 10_000;
+        const LIMIT: usize = 1000;
+
+        let options = NodeOptions {
+            lenient_destinations: true,
+            max_pending_rpcs: Some(LIMIT),
+            max_rpc_lifetime_ms: Some(1000),
+            ..Default::default()
+        };
+        let state = NodeState::with_output(
+            RecordingService::default(),
+            "n1".into(),
+            &options,
+            tokio::io::sink(),
+        );
+        state.start_rpc_sweeper();
+
+        // Nothing ever answers, and no request sets a deadline of its own worth speaking of.
+        let calls = (0..REQUESTS)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let ping = serde_json::json!({ "type": "ping" });
+                    state
+                        .rpc_with_timeout("n2", ping, Duration::from_secs(3600))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(state.inner.pending_replies.len(), LIMIT);
+
+        // The oldest requests made room for the newest, which waited until they expired.
+        let (mut expired, mut evicted) = (Vec::new(), Vec::new());
+        for call in calls {
+            match call.await.unwrap() {
+                Err(crate::Error::Internal {
+                    source: InternalError::RpcExpired { id, elapsed, .. },
+                }) => {
+                    assert!(elapsed >= Duration::from_millis(1000), "{elapsed:?}");
+                    assert!(elapsed <= Duration::from_millis(1250), "{elapsed:?}");
+                    expired.push(id);
+                }
+                Err(crate::Error::Internal {
+                    source: InternalError::RpcEvicted { id, limit, .. },
+                }) => {
+                    assert_eq!(limit, LIMIT);
+                    evicted.push(id);
+                }
+                other => panic!("rpc didn't fail: {other:?}"),
+            }
+        }
+        assert_eq!((expired.len(), evicted.len()), (LIMIT, REQUESTS - LIMIT));
+        expired.sort_unstable();
+        evicted.sort_unstable();
+        assert_eq!(
+            evicted,
+            (0..(REQUESTS - LIMIT) as MessageId).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            expired,
+            ((REQUESTS - LIMIT) as MessageId..REQUESTS as MessageId).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            state.rpc_counts(),
+            RpcCounts {
+                pending: 0,
+                expired: LIMIT as u64,
+                evicted: (REQUESTS - LIMIT) as u64,
+                matched: 0,
+                late: 0,
+                unknown: 0,
+            }
+        );
+        state.inner.tasks.lock().unwrap().abort_all();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rpc_retry() {
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state =
+            NodeState::with_output(RecordingService::default(), "n1".into(), &options, writer);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let policy = RetryPolicy {
+            attempt_timeout: Duration::from_millis(100),
+            initial_delay: Duration::from_millis(50),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            max_attempts: 3,
+            jitter: crate::util::Jitter::None,
+        };
+        let call = |state: &NodeState<RecordingService>| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let ping = serde_json::json!({ "type": "ping" });
+                state.rpc_retry("n2", ping, policy).await
+            })
+        };
+        let mut next_id = async || {
+            let line = lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"]["msg_id"]
+                .as_u64()
+                .unwrap()
+        };
+
+        // The first attempt goes unanswered, the second is answered.
+        let called = call(&state);
+        let first = next_id().await;
+        let started = tokio::time::Instant::now();
+        let second = next_id().await;
+        assert_ne!(first, second);
+        assert_eq!(started.elapsed(), Duration::from_millis(150));
+        let reply = serde_json::from_value(serde_json::json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": { "type": "pong", "in_reply_to": second },
+        }))
+        .unwrap();
+        state.dispatch(reply).await;
+        let reply = called.await.unwrap().unwrap();
+        assert_eq!(reply.body.re, Some(second));
+
+        // Nothing is answered: the request is sent three times, each waiting 100ms for a reply,
+        // 50 and then 100ms apart.
+        let started = tokio::time::Instant::now();
+        let called = call(&state);
+        for _ in 0..3 {
+            next_id().await;
+        }
+        match called.await.unwrap() {
+            Err(crate::Error::Internal {
+                source:
+                    InternalError::RetriesExhausted {
+                        dest,
+                        attempts,
+                        elapsed,
+                    },
+            }) => {
+                assert_eq!((&*dest, attempts), ("n2", 3));
+                assert_eq!(elapsed, Duration::from_millis(450));
+            }
+            other => panic!("rpc didn't give up: {other:?}"),
+        }
+        assert_eq!(started.elapsed(), Duration::from_millis(450));
+        assert_eq!(state.inner.pending_replies.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rpc_retry_with_full_jitter() {
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let (writer, reader) = tokio::io::duplex(4096);
+        let state =
+            NodeState::with_output(RecordingService::default(), "n1".into(), &options, writer);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let policy = RetryPolicy {
+            attempt_timeout: Duration::from_millis(100),
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            max_attempts: 3,
+            jitter: crate::util::Jitter::Full,
+        };
+
+        // Jitter spreads out the attempts, but never cuts short the wait for a peer that answers
+        // in time.
+        for _ in 0..20 {
+            let called = tokio::spawn({
+                let state = state.clone();
+                async move {
+                    let ping = serde_json::json!({ "type": "ping" });
+                    state.rpc_retry("n2", ping, policy).await
+                }
+            });
+            let line = lines.next_line().await.unwrap().unwrap();
+            let id = serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"]["msg_id"]
+                .as_u64()
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(90)).await;
+            let reply = serde_json::from_value(serde_json::json!({
+                "src": "n2",
+                "dest": "n1",
+                "body": { "type": "pong", "in_reply_to": id },
+            }))
+            .unwrap();
+            state.dispatch(reply).await;
+            let reply = called.await.unwrap().unwrap();
+            assert_eq!(reply.body.re, Some(id));
+        }
+    }
+}
@@ -1,33 +1,24 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
-use snafu::Snafu;
+use snafu::{OptionExt as _, Snafu};
 
-use crate::async_dashmap::AsyncDashMap;
+use super::gossip::{mix, CvState as _, GSet, Gossip, GossipOptions};
+pub use super::gossip::{
+    GossipConfig, GossipParams, DEFAULT_FALLBACK_FANOUT, DEFAULT_GOSSIP_INTERVAL,
+};
+use crate::async_dashmap::AsyncKeyedState;
+use crate::audit::StateDigest;
+use crate::breaker::{Admission, BreakerOptions, CircuitBreakers, CircuitState};
+use crate::config::Configurable;
+use crate::describe::{Describe, FieldDescription, MessageDescription, ServiceDescription};
 pub use crate::error::*;
-use crate::message::{DataOrInit, Message};
-use crate::node::{Node, NodeState};
-
-/// A Maelstrom error code.
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
-#[serde(rename_all = "snake_case")]
-#[repr(u64)]
-pub enum ErrorCode {
-    Timeout = 0,
-    NodeNotFound = 1,
-    NotSupported = 10,
-    TemporarilyUnavailable = 11,
-    MalformedRequest = 12,
-    Crash = 13,
-    Abort = 14,
-    KeyDoesNotExist = 20,
-    KeyAlreadyExists = 21,
-    PreconditionFailed = 22,
-    TxnConflict = 30,
-}
+use crate::message::{check_strict, DataOrInit, ErrorCode, Message, MessageId, Subsystem};
+use crate::node::{in_subsystem, AdmissionRequest, InlineResult, Node, NodeState, ServiceGauges};
+use crate::timer::TimerSpec;
 
 type BroadcastValue = u64;
 
@@ -40,11 +31,17 @@ pub enum BroadcastMessage {
         text: String,
     },
     Topology {
-        topology: HashMap<String, HashSet<String>>,
+        #[serde(flatten)]
+        topology: Topology,
+        /// Apply the topology even if it leaves us without neighbors. Otherwise such a topology
+        /// is ignored.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        allow_isolation: bool,
     },
     TopologyOk,
     Read,
     ReadOk {
+        #[serde(serialize_with = "crate::message::serialize_set")]
         messages: HashSet<BroadcastValue>,
     },
     Broadcast {
@@ -52,14 +49,237 @@ pub enum BroadcastMessage {
     },
     BroadcastOk,
     Gossip {
+        #[serde(serialize_with = "crate::message::serialize_set")]
         seen: HashSet<BroadcastValue>,
     },
+    /// Sent by a node that has just started, asking a peer for everything it has. `have_digest`
+    /// is the [`digest`] of what the node already has. `chunks` asks again for chunks of the
+    /// transfer in progress that never arrived; without it, a new transfer is started.
+    StateRequest {
+        have_digest: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunks: Option<Vec<u64>>,
+    },
+    /// Chunk `seq` of a state transfer. `checksum` is the [`digest`] of the whole transfer. A
+    /// transfer of zero chunks means there is nothing to send.
+    StateChunk {
+        seq: u64,
+        values: Vec<BroadcastValue>,
+        total_chunks: u64,
+        checksum: u64,
+    },
+}
+
+/// The cluster's layout in a `topology` message: a `topology` field mapping each node to its
+/// neighbors, as Maelstrom sends it, or an `edges` field listing pairs of neighbors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawTopology", into = "RawTopology")]
+pub enum Topology {
+    Neighbors(HashMap<String, HashSet<String>>),
+    /// Undirected: each pair are neighbors of each other, whichever way round it is listed.
+    Edges(Vec<(String, String)>),
+}
+
+impl Topology {
+    /// The neighbors of `node`, empty if the topology doesn't mention it. A node is never its own
+    /// neighbor; such entries are ignored with a warning.
+    pub fn neighbors_of(&self, node: &str) -> HashSet<String> {
+        let mut neighbors = match self {
+            Topology::Neighbors(topology) => topology.get(node).cloned().unwrap_or_default(),
+            Topology::Edges(edges) => edges
+                .iter()
+                .filter_map(|(a, b)| match (a == node, b == node) {
+                    (true, _) => Some(b.clone()),
+                    (false, true) => Some(a.clone()),
+                    (false, false) => None,
+                })
+                .collect(),
+        };
+        if neighbors.remove(node) {
+            tracing::warn!("Ignoring an edge from {} to itself", node);
+        }
+        neighbors
+    }
+}
+
+/// The fields [`Topology`] is read from and written to. Ordered, so that the same topology always
+/// produces the same bytes.
+#[derive(Serialize, Deserialize)]
+struct RawTopology {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    topology: Option<BTreeMap<String, BTreeSet<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edges: Option<Vec<(String, String)>>,
+}
+
+impl TryFrom<RawTopology> for Topology {
+    type Error = &'static str;
+
+    fn try_from(raw: RawTopology) -> std::result::Result<Self, Self::Error> {
+        match (raw.topology, raw.edges) {
+            (Some(topology), None) => Ok(Topology::Neighbors(
+                topology
+                    .into_iter()
+                    .map(|(node, neighbors)| (node, neighbors.into_iter().collect()))
+                    .collect(),
+            )),
+            (None, Some(edges)) => Ok(Topology::Edges(edges)),
+            (None, None) => Err("missing field `topology` or `edges`"),
+            (Some(_), Some(_)) => Err("expected `topology` or `edges`, not both"),
+        }
+    }
+}
+
+impl From<Topology> for RawTopology {
+    fn from(topology: Topology) -> Self {
+        match topology {
+            Topology::Neighbors(topology) => RawTopology {
+                topology: Some(
+                    topology
+                        .into_iter()
+                        .map(|(node, neighbors)| (node, neighbors.into_iter().collect()))
+                        .collect(),
+                ),
+                edges: None,
+            },
+            Topology::Edges(edges) => RawTopology {
+                topology: None,
+                edges: Some(edges),
+            },
+        }
+    }
+}
+
+/// An order-independent digest of a set of values, so that two nodes can tell whether they hold
+/// the same values without sending them.
+pub fn digest<'a>(values: impl IntoIterator<Item = &'a BroadcastValue>) -> u64 {
+    values
+        .into_iter()
+        .fold(0, |digest, value| digest.wrapping_add(mix(*value)))
+}
+
+/// How long to wait for a peer to acknowledge a forwarded value before forgetting about it. Gossip
+/// covers the value either way; the ack only saves re-sending it.
+const FORWARD_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most values sent in one `state_chunk`.
+pub const DEFAULT_STATE_CHUNK_SIZE: usize = 1024;
+
+/// How long a bootstrapping node waits on a state transfer before asking again for the chunks it
+/// is missing, or asking another peer if nothing arrived.
+const BOOTSTRAP_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a client's read waits before asking again whether bootstrapping is done.
+const BOOTSTRAP_READ_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a snapshot sent to a bootstrapping peer is kept around for resending lost chunks, after
+/// the peer last asked for any.
+const STATE_TRANSFER_TTL: Duration = Duration::from_secs(30);
+
+/// A state transfer being received from `peer`, see [`BroadcastService::bootstrap`].
+struct Transfer {
+    peer: String,
+    /// The transfer's checksum, once its first chunk has arrived.
+    checksum: Option<u64>,
+    total_chunks: u64,
+    chunks: BTreeMap<u64, Vec<BroadcastValue>>,
+    /// How many chunks had arrived when we last asked `peer` for more.
+    progress: usize,
+}
+
+/// A snapshot being sent to a bootstrapping peer. Lost chunks are sent again from the same
+/// snapshot, so that every chunk agrees with the checksum.
+#[derive(Clone)]
+struct Outgoing {
+    values: Arc<Vec<BroadcastValue>>,
+    started: tokio::time::Instant,
+}
+
+/// How often unacknowledged forwards and stale state transfers are dropped.
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(250);
+
+const EXPIRE_TIMER: &str = "expire";
+const INVARIANT_CHECK_TIMER: &str = "check_invariants";
+
+/// How often debug builds check the service's bookkeeping for impossible states.
+pub const DEFAULT_INVARIANT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct BroadcastOptions {
+    /// The gossip parameters to start with.
+    pub gossip: GossipParams,
+    /// See [`GossipOptions::anti_entropy_every`]. Off by default.
+    pub anti_entropy_every: Option<u64>,
+    /// See [`GossipOptions::hot_rounds`]. Off by default.
+    pub hot_rounds: Option<u64>,
+    /// How often to check for and repair impossible states, see
+    /// [`BroadcastService::check_invariants`]. On by default in debug builds only.
+    pub invariant_check_interval: Option<Duration>,
+    /// Whether to fetch a peer's state on startup, so that a restarted node gets back what
+    /// gossip will never resend. Off by default, since reads wait for the transfer and a fresh
+    /// cluster has nothing to fetch.
+    pub bootstrap: bool,
+    /// The most values sent in one `state_chunk`.
+    pub state_chunk_size: usize,
+    /// When to stop gossiping and forwarding to a peer that doesn't acknowledge forwards, see
+    /// [`crate::breaker`].
+    pub breaker: BreakerOptions,
+}
+
+impl Default for BroadcastOptions {
+    fn default() -> Self {
+        Self {
+            gossip: GossipParams::default(),
+            anti_entropy_every: None,
+            hot_rounds: None,
+            invariant_check_interval: cfg!(debug_assertions)
+                .then_some(DEFAULT_INVARIANT_CHECK_INTERVAL),
+            bootstrap: false,
+            state_chunk_size: DEFAULT_STATE_CHUNK_SIZE,
+            breaker: BreakerOptions::default(),
+        }
+    }
+}
+
+/// The [`BroadcastOptions`] that can be set in a config file, see [`crate::config`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastConfig {
+    pub gossip: GossipConfig,
+    pub bootstrap: bool,
+    pub state_chunk_size: usize,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        let options = BroadcastOptions::default();
+        Self {
+            gossip: GossipConfig::default(),
+            bootstrap: options.bootstrap,
+            state_chunk_size: options.state_chunk_size,
+        }
+    }
 }
 
 pub struct BroadcastServiceInner {
-    neighbors: arc_swap::ArcSwap<HashSet<String>>,
-    received: AsyncDashMap<u64, ()>,
-    known: AsyncDashMap<String, HashSet<u64>>,
+    options: BroadcastOptions,
+    /// The values received, and the values each peer is known to have. Forwards are tracked as
+    /// deltas waiting for an ack.
+    gossip: Gossip<GSet<BroadcastValue>>,
+    /// The number of distinct values received. Only bumped the first time a value is seen.
+    distinct: AtomicU64,
+    /// The number of invariant violations found and repaired so far.
+    violations: AtomicU64,
+    /// See [`BroadcastService::forward_rounds`].
+    forward_rounds: AtomicU64,
+    /// The state transfer we are receiving while bootstrapping.
+    incoming: std::sync::Mutex<Option<Transfer>>,
+    /// Set once a state transfer has been merged, or there turned out to be nothing to fetch.
+    bootstrapped: AtomicBool,
+    /// Snapshots being sent to bootstrapping peers, by peer.
+    outgoing: AsyncKeyedState<String, Outgoing>,
+    /// Fed by forwards: an ack is a success, and a forward that expires unacknowledged is a
+    /// failure. Only gossip and forwards are held back by an open circuit.
+    breakers: CircuitBreakers,
 }
 
 #[derive(Clone)]
@@ -69,11 +289,28 @@ pub struct BroadcastService {
 
 impl Default for BroadcastService {
     fn default() -> Self {
+        Self::new(BroadcastOptions::default())
+    }
+}
+
+impl BroadcastService {
+    pub fn new(options: BroadcastOptions) -> Self {
         Self {
             inner: Arc::new(BroadcastServiceInner {
-                neighbors: arc_swap::ArcSwap::new(Arc::new(HashSet::new())),
-                received: AsyncDashMap::new(),
-                known: AsyncDashMap::new(),
+                gossip: Gossip::new(GossipOptions {
+                    params: options.gossip.clone(),
+                    anti_entropy_every: options.anti_entropy_every,
+                    hot_rounds: options.hot_rounds,
+                    ..Default::default()
+                }),
+                breakers: CircuitBreakers::new(options.breaker.clone()),
+                options,
+                distinct: AtomicU64::new(0),
+                violations: AtomicU64::new(0),
+                forward_rounds: AtomicU64::new(0),
+                incoming: std::sync::Mutex::new(None),
+                bootstrapped: AtomicBool::new(false),
+                outgoing: AsyncKeyedState::new(),
             }),
         }
     }
@@ -81,6 +318,12 @@ impl Default for BroadcastService {
 
 #[derive(Debug, Snafu)]
 pub enum BroadcastError {
+    #[snafu(display("Missing message ID"))]
+    MissingMessageId,
+    #[snafu(display("No known messages for peer {peer}"))]
+    UnknownPeer { peer: String },
+    #[snafu(display("The circuit to {peer} is open"))]
+    CircuitOpen { peer: String },
     #[snafu(whatever, display("{message}"))]
     Whatever {
         message: String,
@@ -89,75 +332,775 @@ pub enum BroadcastError {
     },
 }
 
-impl Into<Error<Self>> for BroadcastError {
-    fn into(self) -> Error<Self> {
-        Error::Node { source: self }
+impl From<BroadcastError> for Error<BroadcastError> {
+    fn from(source: BroadcastError) -> Self {
+        Error::Node { source }
     }
 }
 
 impl BroadcastService {
-    pub async fn gossip(&self, node: NodeState<Self>) -> crate::Result<(), BroadcastError> {
-        for neighbor in self.inner.neighbors.load().iter() {
-            let known_to_neighbor =
-                self.inner
-                    .known
-                    .get(neighbor)
-                    .await
-                    .ok_or_else(|| Error::Node {
-                        source: BroadcastError::Whatever {
-                            message: "No known messages for neighbor".into(),
-                            source: None,
-                        },
-                    })?;
-
-            let (_already_known, notify_of) = self
+    /// Record `message` as received, returning whether this is the first time it was seen.
+    ///
+    /// Anything that must happen exactly once per value belongs behind this check, since the same
+    /// value routinely arrives from several peers at once.
+    async fn receive(&self, message: BroadcastValue) -> bool {
+        if self
+            .inner
+            .gossip
+            .update(&GSet::from_iter([message]))
+            .is_empty()
+        {
+            return false;
+        }
+
+        let distinct = self.inner.distinct.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::debug!("First saw {} ({} distinct values)", message, distinct);
+        true
+    }
+
+    /// Record `values` as received from `peer`, and that `peer` holds them, see
+    /// [`Gossip::receive`]. Large batches yield to other handlers while they are merged.
+    async fn receive_from(&self, peer: &str, values: GSet<BroadcastValue>) {
+        let new = self.inner.gossip.receive(peer, values).await;
+        if new.is_empty() {
+            return;
+        }
+        let distinct = self
+            .inner
+            .distinct
+            .fetch_add(new.len() as u64, Ordering::Relaxed)
+            + new.len() as u64;
+        tracing::debug!("First saw {} values ({} distinct)", new.len(), distinct);
+    }
+
+    pub fn gossip_params(&self) -> GossipParams {
+        self.inner.gossip.params()
+    }
+
+    fn is_peer(&self, node: &str) -> bool {
+        self.inner.gossip.is_peer(node)
+    }
+
+    /// Send `values` to this round's gossip targets right away, instead of waiting for the next
+    /// round. Each forward is remembered until the peer acknowledges it, see
+    /// [`BroadcastService::acknowledge`].
+    async fn forward(
+        &self,
+        node: &NodeState<Self>,
+        values: &[BroadcastValue],
+    ) -> crate::Result<(), BroadcastError> {
+        in_subsystem(Subsystem::Gossip, async {
+            self.inner.forward_rounds.fetch_add(1, Ordering::Relaxed);
+            for peer in self.inner.gossip.targets() {
+                match self.admit(node, &peer).await {
+                    Err(Error::Node {
+                        source: BroadcastError::CircuitOpen { .. },
+                    }) => continue,
+                    result => result?,
+                }
+
+                for &value in values {
+                    let id = node.reserve_message_id();
+                    self.inner.gossip.track(id, &peer, GSet::from_iter([value]));
+                    node.send_message_with_id(
+                        peer.as_str(),
+                        id,
+                        None,
+                        DataOrInit::Data(BroadcastMessage::Broadcast { message: value }),
+                    )
+                    .await?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// How many times [`BroadcastService::forward`] has worked out where to forward values. A
+    /// batch of broadcasts is forwarded in one round.
+    pub fn forward_rounds(&self) -> u64 {
+        self.inner.forward_rounds.load(Ordering::Relaxed)
+    }
+
+    /// Handle `peer`'s `broadcast_ok` for message `re`: if it acknowledges a forward, the peer has
+    /// the value and gossip no longer needs to send it.
+    fn acknowledge(&self, peer: &str, re: MessageId) {
+        if self.inner.gossip.acknowledge(peer, re) {
+            self.inner.breakers.success(peer);
+        }
+    }
+
+    /// Forget forwards that were never acknowledged, counting each against its peer's circuit.
+    fn expire_forwards(&self) {
+        let now = tokio::time::Instant::now();
+        for peer in self.inner.gossip.expire(FORWARD_ACK_TIMEOUT) {
+            self.inner.breakers.failure(&peer, now);
+        }
+    }
+
+    /// Check `peer`'s circuit before gossiping or forwarding to it. If it is open, this fails
+    /// with [`BroadcastError::CircuitOpen`], after sending the peer a `read` if a probe is due:
+    /// the `read_ok` closes the circuit.
+    async fn admit(&self, node: &NodeState<Self>, peer: &str) -> crate::Result<(), BroadcastError> {
+        match self.inner.breakers.check(peer, tokio::time::Instant::now()) {
+            Admission::Send => return Ok(()),
+            Admission::Probe => {
+                tracing::debug!("Probing {}", peer);
+                node.send(peer, BroadcastMessage::Read).await?;
+            }
+            Admission::Skip => {}
+        }
+        Err(CircuitOpenSnafu { peer }.build().into())
+    }
+
+    /// How many values are hot and how many cold, see [`BroadcastOptions::hot_rounds`].
+    pub fn generations(&self) -> (usize, usize) {
+        self.inner.gossip.generations()
+    }
+
+    /// The state of the circuit to every peer that has acknowledged a forward or failed to.
+    pub fn circuits(&self) -> BTreeMap<String, CircuitState> {
+        self.inner.breakers.states()
+    }
+
+    /// The number of invariant violations found by [`BroadcastService::check_invariants`].
+    pub fn invariant_violations(&self) -> u64 {
+        self.inner.violations.load(Ordering::Relaxed)
+    }
+
+    /// Look for peers that supposedly know values we never received, which can only be the
+    /// result of a bug, see [`Gossip::check_invariants`]. Returns the number of violations found.
+    pub async fn check_invariants(&self) -> usize {
+        let found = self.inner.gossip.check_invariants();
+        self.inner
+            .violations
+            .fetch_add(found as u64, Ordering::Relaxed);
+        found
+    }
+
+    /// Run a gossip round, returning how many values went to each target.
+    pub async fn gossip(
+        &self,
+        node: NodeState<Self>,
+    ) -> crate::Result<BTreeMap<String, u64>, BroadcastError> {
+        self.gossip_with(&node, self.inner.gossip.targets()).await
+    }
+
+    /// Gossip with `targets`, skipping those whose circuit is open. Returns how many values went
+    /// to each of the others.
+    async fn gossip_with(
+        &self,
+        node: &NodeState<Self>,
+        targets: Vec<String>,
+    ) -> crate::Result<BTreeMap<String, u64>, BroadcastError> {
+        let mut sent = BTreeMap::new();
+        for neighbor in targets {
+            match self.gossip_to(node, &neighbor).await {
+                Err(Error::Node {
+                    source: BroadcastError::CircuitOpen { .. },
+                }) => tracing::trace!("Not gossiping to {}: its circuit is open", neighbor),
+                result => {
+                    sent.insert(neighbor, result?);
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Send `peer` every value it isn't known to have, in batches of at most
+    /// [`GossipParams::batch_size`] values. Returns how many were sent.
+    async fn gossip_to(
+        &self,
+        node: &NodeState<Self>,
+        peer: &str,
+    ) -> crate::Result<u64, BroadcastError> {
+        in_subsystem(Subsystem::Gossip, async {
+            self.admit(node, peer).await?;
+
+            let chunks = self
                 .inner
-                .received
-                .clone()
-                .into_iter()
-                .map(|(x, _)| x)
-                .partition(|m| known_to_neighbor.contains(m));
+                .gossip
+                .delta_for(peer)
+                .context(UnknownPeerSnafu { peer })?;
+            let mut sent = 0;
+            for chunk in chunks {
+                sent += chunk.len() as u64;
+                let seen = chunk.into_inner();
+                node.send(peer, BroadcastMessage::Gossip { seen }).await?;
+            }
+            Ok(sent)
+        })
+        .await
+    }
 
+    /// Everything we have received, sorted.
+    fn snapshot(&self) -> Vec<BroadcastValue> {
+        let mut values = self
+            .inner
+            .gossip
+            .state()
+            .into_inner()
+            .into_iter()
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values
+    }
+
+    /// Fetch everything a peer has after starting up. Peers believe a restarted node still has
+    /// whatever they sent it before, so gossip alone would never resend it.
+    ///
+    /// Peers are asked in turn until one answers. Chunks that don't arrive are asked for again,
+    /// and a transfer that stops making progress is abandoned for the next peer. Nothing is
+    /// merged until every chunk is in and the whole transfer matches its checksum.
+    async fn bootstrap(&self, node: NodeState<Self>) {
+        let peers = self.inner.gossip.peers();
+        if peers.is_empty() {
+            return;
+        }
+        let mut next_peer = 0;
+        let mut interval = tokio::time::interval(BOOTSTRAP_RETRY_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+            if self.inner.bootstrapped.load(Ordering::Acquire) {
+                return;
+            }
+
+            let (peer, chunks) = {
+                let mut incoming = self.inner.incoming.lock().unwrap();
+                match incoming.as_mut() {
+                    Some(transfer)
+                        if transfer.checksum.is_some()
+                            && transfer.chunks.len() > transfer.progress =>
+                    {
+                        transfer.progress = transfer.chunks.len();
+                        let missing = (0..transfer.total_chunks)
+                            .filter(|seq| !transfer.chunks.contains_key(seq))
+                            .collect();
+                        (transfer.peer.clone(), Some(missing))
+                    }
+                    // No answer, or no progress since we last asked: start over with the next
+                    // peer.
+                    _ => {
+                        let peer = peers[next_peer % peers.len()].clone();
+                        next_peer += 1;
+                        *incoming = Some(Transfer {
+                            peer: peer.clone(),
+                            checksum: None,
+                            total_chunks: 0,
+                            chunks: BTreeMap::new(),
+                            progress: 0,
+                        });
+                        (peer, None)
+                    }
+                }
+            };
+
+            let have_digest = digest(&self.snapshot());
             node.send(
-                neighbor.as_str(),
-                BroadcastMessage::Gossip { seen: notify_of },
+                peer.as_str(),
+                BroadcastMessage::StateRequest {
+                    have_digest,
+                    chunks,
+                },
             )
-            .await?;
+            .await
+            .ok();
+        }
+    }
+
+    /// Send `peer` the chunks of our state it asked for. A new transfer is started unless `chunks`
+    /// names chunks of one still in progress.
+    async fn send_state(
+        &self,
+        node: &NodeState<Self>,
+        peer: &str,
+        have_digest: u64,
+        chunks: Option<Vec<u64>>,
+    ) -> crate::Result<(), BroadcastError> {
+        let resend = match chunks {
+            Some(_) => {
+                // Still being fetched, so it is kept for another STATE_TRANSFER_TTL.
+                let peer = peer.to_owned();
+                let now = tokio::time::Instant::now();
+                let outgoing = &self.inner.outgoing;
+                outgoing
+                    .update(&peer, |outgoing| outgoing.started = now)
+                    .await;
+                outgoing
+                    .get_owned(&peer)
+                    .await
+                    .map(|outgoing| Arc::clone(&outgoing.values))
+            }
+            None => None,
+        };
+        let (values, chunks) = match resend {
+            Some(values) => (values, chunks),
+            None => {
+                let values = Arc::new(self.snapshot());
+                let outgoing = Outgoing {
+                    values: Arc::clone(&values),
+                    started: tokio::time::Instant::now(),
+                };
+                self.inner.outgoing.insert(peer.to_owned(), outgoing).await;
+                (values, None)
+            }
+        };
+
+        let checksum = digest(values.iter());
+        let chunk_size = self.inner.options.state_chunk_size.max(1);
+        if checksum == have_digest || values.is_empty() {
+            self.inner.outgoing.remove(&peer.to_owned());
+            let nothing = BroadcastMessage::StateChunk {
+                seq: 0,
+                values: Vec::new(),
+                total_chunks: 0,
+                checksum,
+            };
+            node.send(peer, nothing).await?;
+            return Ok(());
+        }
+
+        let total_chunks = values.len().div_ceil(chunk_size) as u64;
+        let seqs = chunks.unwrap_or_else(|| (0..total_chunks).collect());
+        for seq in seqs.into_iter().filter(|seq| *seq < total_chunks) {
+            let start = seq as usize * chunk_size;
+            let chunk = BroadcastMessage::StateChunk {
+                seq,
+                values: values[start..values.len().min(start + chunk_size)].to_vec(),
+                total_chunks,
+                checksum,
+            };
+            node.send(peer, chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Take one chunk of the state transfer from `peer`, and merge the whole transfer once every
+    /// chunk is in and it matches its checksum.
+    async fn receive_chunk(
+        &self,
+        peer: &str,
+        seq: u64,
+        values: Vec<BroadcastValue>,
+        total_chunks: u64,
+        checksum: u64,
+    ) {
+        let complete = {
+            let mut incoming = self.inner.incoming.lock().unwrap();
+            // Chunks from a peer we gave up on are ignored.
+            let Some(transfer) = incoming.as_mut().filter(|transfer| transfer.peer == peer) else {
+                return;
+            };
+            if total_chunks == 0 {
+                incoming.take();
+                self.inner.bootstrapped.store(true, Ordering::Release);
+                tracing::info!("Nothing to fetch from {}", peer);
+                return;
+            }
+            // A different checksum means the peer had to start the transfer over.
+            if transfer.checksum != Some(checksum) {
+                transfer.checksum = Some(checksum);
+                transfer.total_chunks = total_chunks;
+                transfer.chunks.clear();
+                transfer.progress = 0;
+            }
+            if seq < total_chunks {
+                transfer.chunks.insert(seq, values);
+            }
+            if transfer.chunks.len() as u64 != transfer.total_chunks {
+                return;
+            }
+            incoming.take().expect("transfer in progress")
+        };
+
+        let values = complete.chunks.into_values().flatten().collect::<Vec<_>>();
+        if digest(&values) != checksum {
+            tracing::warn!("State transfer from {} failed its checksum", peer);
+            return;
+        }
+
+        let fetched = values.len();
+        self.receive_from(peer, values.into_iter().collect()).await;
+        self.inner.bootstrapped.store(true, Ordering::Release);
+        tracing::info!("Fetched {} values from {}", fetched, peer);
+    }
+
+    /// Whether a state transfer is being fetched, see [`BroadcastService::bootstrap`].
+    fn is_bootstrapping(&self) -> bool {
+        self.inner.incoming.lock().unwrap().is_some()
+    }
+
+    /// Forget snapshots that bootstrapping peers haven't asked about for long enough.
+    fn expire_transfers(&self) {
+        self.inner
+            .outgoing
+            .retain(|_, outgoing| outgoing.started.elapsed() < STATE_TRANSFER_TTL);
+    }
+
+    /// Apply our slice of a `topology` message.
+    ///
+    /// Maelstrom may send the same topology more than once; reapplying the neighbors we already
+    /// have does nothing. A topology that leaves us without neighbors, or doesn't mention us at
+    /// all, keeps the current ones unless `allow_isolation` is set, since losing every neighbor
+    /// mid-run stops values from reaching us.
+    async fn apply_topology(
+        &self,
+        node: &NodeState<Self>,
+        neighbors: HashSet<String>,
+        allow_isolation: bool,
+    ) -> crate::Result<(), BroadcastError> {
+        if neighbors.is_empty() && !allow_isolation {
+            tracing::warn!("Ignoring a topology that isolates us without allow_isolation");
+            return Ok(());
+        }
+        if self.inner.gossip.neighbors().as_deref() == Some(&neighbors) {
+            tracing::debug!("Topology unchanged");
+            return Ok(());
         }
+        self.set_neighbors(node, neighbors).await
+    }
 
+    /// Switch to a new neighbor set, see [`Gossip::set_neighbors`]. Neighbors that are new to us
+    /// are caught up right away instead of at the next gossip round: we send them what they are
+    /// missing and read back what they have.
+    async fn set_neighbors(
+        &self,
+        node: &NodeState<Self>,
+        neighbors: HashSet<String>,
+    ) -> crate::Result<(), BroadcastError> {
+        let added = self.inner.gossip.set_neighbors(neighbors);
+        for peer in &added {
+            match self.gossip_to(node, peer).await {
+                Err(Error::Node {
+                    source: BroadcastError::CircuitOpen { .. },
+                }) => continue,
+                result => {
+                    result?;
+                }
+            }
+            node.send(peer.as_str(), BroadcastMessage::Read).await?;
+        }
         Ok(())
     }
 }
 
+/// Every message but `topology` also travels between peers: values are forwarded with
+/// `broadcast` and read from peers with `read`.
+impl Describe for BroadcastService {
+    fn describe() -> ServiceDescription {
+        let replies = ["topology_ok"];
+        let message =
+            |tag, fields, reply, peer| MessageDescription::new(tag, fields, reply, peer, &replies);
+        ServiceDescription::new(
+            "broadcast",
+            vec![
+                message(
+                    "error",
+                    vec![
+                        FieldDescription::of::<ErrorCode>("code"),
+                        FieldDescription::of::<String>("text"),
+                    ],
+                    None,
+                    true,
+                ),
+                message(
+                    "topology",
+                    vec![
+                        FieldDescription::of::<HashMap<String, HashSet<String>>>("topology"),
+                        FieldDescription::of::<Vec<(String, String)>>("edges"),
+                        FieldDescription::of::<bool>("allow_isolation"),
+                    ],
+                    Some("topology_ok"),
+                    false,
+                ),
+                message("topology_ok", vec![], None, false),
+                message("read", vec![], Some("read_ok"), true),
+                message(
+                    "read_ok",
+                    vec![FieldDescription::of::<HashSet<BroadcastValue>>("messages")],
+                    None,
+                    true,
+                ),
+                message(
+                    "broadcast",
+                    vec![FieldDescription::of::<BroadcastValue>("message")],
+                    Some("broadcast_ok"),
+                    true,
+                ),
+                message("broadcast_ok", vec![], None, true),
+                message(
+                    "gossip",
+                    vec![FieldDescription::of::<HashSet<BroadcastValue>>("seen")],
+                    None,
+                    true,
+                ),
+                message(
+                    "state_request",
+                    vec![
+                        FieldDescription::of::<u64>("have_digest"),
+                        FieldDescription::of::<Vec<u64>>("chunks"),
+                    ],
+                    None,
+                    true,
+                ),
+                message(
+                    "state_chunk",
+                    vec![
+                        FieldDescription::of::<u64>("seq"),
+                        FieldDescription::of::<Vec<BroadcastValue>>("values"),
+                        FieldDescription::of::<u64>("total_chunks"),
+                        FieldDescription::of::<u64>("checksum"),
+                    ],
+                    None,
+                    true,
+                ),
+            ],
+            &[ErrorCode::Crash],
+        )
+    }
+}
+
+impl Configurable for BroadcastService {
+    const NAME: &'static str = "broadcast";
+
+    type Config = BroadcastConfig;
+
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String> {
+        if config.state_chunk_size == 0 {
+            return Err("state_chunk_size must be at least 1".into());
+        }
+        Ok(Self::new(BroadcastOptions {
+            gossip: config.gossip.params()?,
+            bootstrap: config.bootstrap,
+            state_chunk_size: config.state_chunk_size,
+            ..Default::default()
+        }))
+    }
+}
+
 impl Node for BroadcastService {
     type Message = BroadcastMessage;
     type Error = BroadcastError;
 
+    fn message_tags(&self) -> &[&'static str] {
+        &[
+            "error",
+            "topology",
+            "topology_ok",
+            "read",
+            "read_ok",
+            "broadcast",
+            "broadcast_ok",
+            "gossip",
+            "state_request",
+            "state_chunk",
+        ]
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        Some(match message {
+            BroadcastMessage::Error { .. } => "error",
+            BroadcastMessage::Topology { .. } => "topology",
+            BroadcastMessage::TopologyOk => "topology_ok",
+            BroadcastMessage::Read => "read",
+            BroadcastMessage::ReadOk { .. } => "read_ok",
+            BroadcastMessage::Broadcast { .. } => "broadcast",
+            BroadcastMessage::BroadcastOk => "broadcast_ok",
+            BroadcastMessage::Gossip { .. } => "gossip",
+            BroadcastMessage::StateRequest { .. } => "state_request",
+            BroadcastMessage::StateChunk { .. } => "state_chunk",
+        })
+    }
+
+    fn reconfigure(&self, params: &serde_json::Value) -> std::result::Result<(), String> {
+        self.inner.gossip.tune(params)
+    }
+
+    /// Reads wait for a state transfer in progress, which would leave out whatever the node held
+    /// before it restarted.
+    fn admit(
+        &self,
+        request: &AdmissionRequest<'_, Self::Message>,
+        _node: &NodeState<Self>,
+    ) -> crate::node::Admission {
+        match request.data {
+            BroadcastMessage::Read if self.is_bootstrapping() => crate::node::Admission::Defer {
+                delay: BOOTSTRAP_READ_DELAY,
+            },
+            _ => crate::node::Admission::Accept,
+        }
+    }
+
+    fn check_strict(&self, body: &serde_json::Value) -> std::result::Result<(), String> {
+        match body["type"].as_str() {
+            Some("broadcast") => check_strict::<strict::Broadcast>(body),
+            Some("read") => check_strict::<strict::Read>(body),
+            Some("topology") if body.get("edges").is_some() => {
+                check_strict::<strict::TopologyEdges>(body)
+            }
+            Some("topology") => check_strict::<strict::Topology>(body),
+            _ => Err(format!("type: unexpected request type {}", body["type"])),
+        }
+    }
+
     async fn init(
         &self,
         node: &NodeState<Self>,
         node_ids: Vec<String>,
     ) -> crate::Result<(), Self::Error> {
-        for node_id in node_ids {
-            self.inner.known.insert(node_id, HashSet::new()).await;
-        }
+        self.inner.gossip.init(&node.id(), &node_ids);
 
         let service = self.clone();
-        let node = node.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_millis(250));
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let gossip_node = node.clone();
+        node.spawn(async move {
+            let round = || async {
+                service.gossip(gossip_node.clone()).await.ok();
+            };
+            service.inner.gossip.run(round).await;
+        });
+
+        if self.inner.options.bootstrap {
+            let service = self.clone();
+            let bootstrap_node = node.clone();
+            node.spawn(async move { service.bootstrap(bootstrap_node).await });
+        }
 
-            loop {
-                interval.tick().await;
+        Ok(())
+    }
 
-                service.gossip(node.clone()).await.ok();
-            }
-        });
+    /// Nodes that join are known to hold nothing. Once a topology is in effect they become
+    /// neighbors too, and are caught up right away; before that, they are among the random gossip
+    /// targets already. Nodes that leave are forgotten.
+    async fn on_membership_change(
+        &self,
+        node: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> crate::Result<(), Self::Error> {
+        let (joined, left) = self.inner.gossip.set_members(&node.id(), &node_ids);
+        tracing::info!("Peers joined: {:?}, left: {:?}", joined, left);
+        for peer in &left {
+            self.inner.breakers.forget(peer);
+            self.inner.outgoing.remove(peer);
+        }
+        if let Some(neighbors) = self.inner.gossip.neighbors().filter(|_| !joined.is_empty()) {
+            let mut neighbors = (*neighbors).clone();
+            neighbors.extend(joined);
+            self.set_neighbors(node, neighbors).await?;
+        }
+        Ok(())
+    }
+
+    fn state_digest(&self) -> Option<StateDigest> {
+        Some(self.inner.gossip.state_digest())
+    }
+
+    fn gauges(&self) -> ServiceGauges {
+        self.inner.gossip.gauges()
+    }
+
+    async fn flush_gossip(
+        &self,
+        node: &NodeState<Self>,
+        full: bool,
+    ) -> crate::Result<Option<BTreeMap<String, u64>>, Self::Error> {
+        let targets = self.inner.gossip.flush(full);
+        self.gossip_with(node, targets).await.map(Some)
+    }
+
+    /// Gossip isn't one of these: its interval can be tuned at runtime, so it has a loop of its
+    /// own, started in [`Node::init`].
+    fn timers(&self) -> Vec<TimerSpec> {
+        let mut timers = vec![TimerSpec::new(EXPIRE_TIMER, EXPIRE_INTERVAL)];
+        if let Some(period) = self.inner.options.invariant_check_interval {
+            timers.push(TimerSpec::new(INVARIANT_CHECK_TIMER, period));
+        }
+        timers
+    }
 
+    async fn on_timer(&self, name: &str, _node: &NodeState<Self>) -> Result<(), Self::Error> {
+        match name {
+            EXPIRE_TIMER => {
+                self.expire_forwards();
+                self.expire_transfers();
+            }
+            INVARIANT_CHECK_TIMER => {
+                self.check_invariants().await;
+            }
+            _ => tracing::warn!("Unknown timer {}", name),
+        }
         Ok(())
     }
 
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        matches!(message, BroadcastMessage::Broadcast { .. })
+    }
+
+    /// Acks only need bookkeeping, so they never wait for a handler.
+    fn try_handle_inline(
+        &self,
+        message: &Message<Self::Message>,
+        _node: &NodeState<Self>,
+    ) -> InlineResult {
+        match (&message.body.data, message.body.re) {
+            (BroadcastMessage::BroadcastOk, Some(re)) => {
+                self.acknowledge(&message.src, re);
+                InlineResult::Handled
+            }
+            _ => InlineResult::Fallback,
+        }
+    }
+
+    fn batches_with(&self, first: &Self::Message, next: &Self::Message) -> bool {
+        matches!(
+            (first, next),
+            (
+                BroadcastMessage::Broadcast { .. },
+                BroadcastMessage::Broadcast { .. }
+            )
+        )
+    }
+
+    /// Every value is received before any is acknowledged, and the new ones from a client are
+    /// forwarded together, in one round.
+    async fn handle_batch(
+        &self,
+        messages: Vec<Message<Self::Message>>,
+        node: &NodeState<Self>,
+    ) -> Vec<Result<(), Self::Error>> {
+        let mut first_seen = Vec::new();
+        for message in &messages {
+            if let BroadcastMessage::Broadcast { message: value } = message.body.data {
+                if self.receive(value).await {
+                    first_seen.push(value);
+                }
+            }
+        }
+
+        let mut results = Vec::with_capacity(messages.len());
+        for message in &messages {
+            let ack = DataOrInit::Data(BroadcastMessage::BroadcastOk);
+            let sent = node
+                .send_message(Arc::clone(&message.src), message.body.id, ack)
+                .await;
+            results.push(sent.map(|_| ()));
+        }
+
+        // Every message in a batch is from the same source.
+        let from_client = messages.first().is_some_and(|m| !self.is_peer(&m.src));
+        if from_client && !first_seen.is_empty() {
+            if let Err(e) = self.forward(node, &first_seen).await {
+                tracing::warn!(
+                    "Failed to forward a batch: {}",
+                    snafu::Report::from_error(e)
+                );
+            }
+        }
+        results
+    }
+
     async fn handle_message(
         &self,
         Message { src, body, .. }: Message<Self::Message>,
@@ -165,39 +1108,27 @@ impl Node for BroadcastService {
     ) -> Result<(), Self::Error> {
         match body.data {
             BroadcastMessage::Gossip { seen } => {
-                self.inner
-                    .known
-                    .get_mut(&src.to_string())
-                    .await
-                    .ok_or_else(|| Error::Node {
-                        source: BroadcastError::Whatever {
-                            message: "No known messages for neighbor".into(),
-                            source: None,
-                        },
-                    })?
-                    .extend(&seen);
-                for message in seen {
-                    self.inner.received.insert(message, ()).await;
-                }
+                self.receive_from(&src, GSet::from(seen)).await;
+                self.is_peer(&src)
+                    .then_some(())
+                    .context(UnknownPeerSnafu { peer: &*src })?;
             }
-            BroadcastMessage::Topology { topology } => {
+            BroadcastMessage::Topology {
+                topology,
+                allow_isolation,
+            } => {
                 tracing::info!("{:?}", topology);
 
-                let reply = body.id.ok_or_else(|| Error::Node {
-                    source: BroadcastError::Whatever {
-                        message: "No ID in message".into(),
-                        source: None,
-                    },
-                })?;
+                let reply = body.id.context(MissingMessageIdSnafu)?;
 
                 node.reply(src, reply, BroadcastMessage::TopologyOk).await?;
 
-                self.inner.neighbors.store(Arc::new(
-                    topology.get(&*node.id()).cloned().expect("topology"),
-                ));
+                let neighbors = topology.neighbors_of(&node.id());
+                self.apply_topology(node, neighbors, allow_isolation)
+                    .await?;
             }
             BroadcastMessage::Broadcast { message } => {
-                self.inner.received.insert(message, ()).await;
+                let first_seen = self.receive(message).await;
 
                 node.send_message(
                     src.clone(),
@@ -205,17 +1136,30 @@ impl Node for BroadcastService {
                     crate::message::DataOrInit::Data(BroadcastMessage::BroadcastOk),
                 )
                 .await?;
+
+                // Values from clients go out right away. Values from peers are left to gossip,
+                // which keeps forwards from flooding the cluster.
+                if first_seen && !self.is_peer(&src) {
+                    self.forward(node, &[message]).await?;
+                }
+            }
+            // Acks are replies, so they are never answered, see
+            // `NodeOptions::allow_reply_to_reply`.
+            BroadcastMessage::BroadcastOk => {
+                if let Some(re) = body.re {
+                    self.acknowledge(&src, re);
+                }
+            }
+            BroadcastMessage::ReadOk { messages } => {
+                // Peers only send these in answer to our reads, so the peer is up.
+                if self.is_peer(&src) {
+                    self.inner.breakers.success(&src);
+                }
+                // Everything a peer has read back to us.
+                self.receive_from(&src, GSet::from(messages)).await;
             }
-            BroadcastMessage::BroadcastOk => {}
-            BroadcastMessage::ReadOk { .. } => {}
             BroadcastMessage::Read => {
-                let messages = self
-                    .inner
-                    .received
-                    .clone()
-                    .iter()
-                    .map(|x| *x.key())
-                    .collect::<HashSet<_>>();
+                let messages = self.inner.gossip.state().into_inner();
 
                 node.send_message(
                     src,
@@ -225,6 +1169,21 @@ impl Node for BroadcastService {
                 .await
                 .ok();
             }
+            BroadcastMessage::StateRequest {
+                have_digest,
+                chunks,
+            } => {
+                self.send_state(node, &src, have_digest, chunks).await?;
+            }
+            BroadcastMessage::StateChunk {
+                seq,
+                values,
+                total_chunks,
+                checksum,
+            } => {
+                self.receive_chunk(&src, seq, values, total_chunks, checksum)
+                    .await;
+            }
             unexpected => {
                 tracing::warn!("Unexpected message: {:?}", unexpected);
             }
@@ -232,3 +1191,1619 @@ impl Node for BroadcastService {
         Ok(())
     }
 }
+
+/// Strict mirrors of the client requests, see [`Node::check_strict`].
+pub mod strict {
+    use std::collections::{HashMap, HashSet};
+
+    use serde::Deserialize;
+
+    use super::BroadcastValue;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Broadcast {
+        pub message: BroadcastValue,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Read {}
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Topology {
+        pub topology: HashMap<String, HashSet<String>>,
+        #[serde(default)]
+        pub allow_isolation: bool,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct TopologyEdges {
+        pub edges: Vec<(String, String)>,
+        #[serde(default)]
+        pub allow_isolation: bool,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use tokio_util::codec::Encoder;
+
+    use crate::node::NodeOptions;
+    use crate::services::gossip::RECEIVE_CHUNK;
+    use crate::standby::StandbyOptions;
+    use crate::testing::{checker, workload, Cluster, LatencyMatrix};
+    use crate::tokio_serde::formats::SymmetricalJson;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_standby_takes_over_without_losing_values() {
+        let standby = NodeOptions {
+            standby: Some(StandbyOptions {
+                active: "n0".into(),
+                promote_after_ms: Some(500),
+            }),
+            ..Default::default()
+        };
+        let mut cluster = Cluster::builder()
+            .nodes(3)
+            .node_options("n2", standby)
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        let client = cluster.client();
+        let read = serde_json::json!({ "type": "read" });
+        let reply = client.rpc("n2", read.clone()).await.unwrap();
+        assert_eq!(reply["code"], ErrorCode::TemporarilyUnavailable as u64);
+        assert_eq!(reply["active"], "n0");
+
+        for value in 0..50 {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            let reply = client.rpc("n0", broadcast).await.unwrap();
+            assert_eq!(reply["type"], "broadcast_ok");
+        }
+        cluster.kill("n0").await;
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let reply = client.rpc("n2", read).await.unwrap();
+        let mut values = serde_json::from_value::<Vec<u64>>(reply["messages"].clone()).unwrap();
+        values.sort_unstable();
+        assert_eq!(values, (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_value_from_many_peers_is_received_once() {
+        let service = BroadcastService::default();
+        let peers = ["n1", "n2", "n3", "n4"];
+        let node_ids = ["n0", "n1", "n2", "n3", "n4"].map(String::from);
+        service.inner.gossip.init("n0", &node_ids);
+        let state = NodeState::with_output(
+            service.clone(),
+            "n0".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+
+        for value in 0..100 {
+            let handlers = peers
+                .into_iter()
+                .map(|peer| {
+                    let service = service.clone();
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let gossip = Message {
+                            src: peer.into(),
+                            dest: "n0".into(),
+                            body: crate::message::MessageBody {
+                                id: None,
+                                re: None,
+                                trace_id: None,
+                                seq: None,
+                                sent_at_ms: None,
+                                request_times: None,
+                                data: BroadcastMessage::Gossip {
+                                    seen: HashSet::from([value]),
+                                },
+                            },
+                        };
+                        service.handle_message(gossip, &state).await.unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handler in handlers {
+                handler.await.unwrap();
+            }
+
+            assert_eq!(service.inner.distinct.load(Ordering::Relaxed), value + 1);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_large_gossip_lets_other_handlers_run() {
+        const VALUES: u64 = 50_000;
+        let service = BroadcastService::default();
+        let node_ids = ["n0", "n1", "n2"].map(String::from);
+        service.inner.gossip.init("n0", &node_ids);
+        let state = NodeState::with_output(
+            service.clone(),
+            "n0".into(),
+            &NodeOptions::default(),
+            tokio::io::sink(),
+        );
+        let message = |src: &str, data| Message {
+            src: src.into(),
+            dest: "n0".into(),
+            body: crate::message::MessageBody {
+                id: Some(1),
+                re: None,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data,
+            },
+        };
+
+        let gossip = tokio::spawn({
+            let (service, state) = (service.clone(), state.clone());
+            let seen = (0..VALUES).collect();
+            let gossip = message("n1", BroadcastMessage::Gossip { seen });
+            async move { service.handle_message(gossip, &state).await.unwrap() }
+        });
+
+        // Client broadcasts, each in its own task as the runner would, while the delta is merged.
+        // Each one should only wait for a chunk of it, not the whole thing.
+        let mut waits = Vec::new();
+        let mut broadcasts = 0;
+        while !gossip.is_finished() {
+            let before = service.inner.gossip.state().len();
+            let broadcast = message(
+                "c1",
+                BroadcastMessage::Broadcast {
+                    message: VALUES + broadcasts,
+                },
+            );
+            tokio::spawn({
+                let (service, state) = (service.clone(), state.clone());
+                async move { service.handle_message(broadcast, &state).await.unwrap() }
+            })
+            .await
+            .unwrap();
+            broadcasts += 1;
+            let after = service.inner.gossip.state().len();
+            // Values merged from the delta while the broadcast was handled.
+            waits.push(after - before - 1);
+        }
+        gossip.await.unwrap();
+
+        assert!(broadcasts >= VALUES / RECEIVE_CHUNK as u64 - 1);
+        waits.sort_unstable();
+        let p99 = waits[waits.len() * 99 / 100];
+        assert!(p99 <= RECEIVE_CHUNK, "p99 wait {p99}");
+        assert_eq!(
+            service.inner.distinct.load(Ordering::Relaxed),
+            VALUES + broadcasts
+        );
+        assert_eq!(
+            service.inner.gossip.state().len() as u64,
+            VALUES + broadcasts
+        );
+    }
+
+    fn encode(data: BroadcastMessage) -> Vec<u8> {
+        let message = Message {
+            src: "n0".into(),
+            dest: "n1".into(),
+            body: crate::message::MessageBody {
+                id: Some(1),
+                re: None,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data,
+            },
+        };
+        let mut buf = bytes::BytesMut::new();
+        SymmetricalJson::default()
+            .encode(message, &mut buf)
+            .unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let values = (0..1000).collect::<Vec<u64>>();
+        let forward = values.iter().copied().collect::<HashSet<_>>();
+        let backward = values.iter().rev().copied().collect::<HashSet<_>>();
+
+        let first = encode(BroadcastMessage::Gossip {
+            seen: forward.clone(),
+        });
+        assert_eq!(first, encode(BroadcastMessage::Gossip { seen: forward }));
+        assert_eq!(first, encode(BroadcastMessage::Gossip { seen: backward }));
+
+        let expected = serde_json::json!({
+            "src": "n0",
+            "dest": "n1",
+            "body": { "msg_id": 1, "in_reply_to": null, "type": "gossip", "seen": values },
+        });
+        let decoded: serde_json::Value = serde_json::from_slice(&first).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_topology_output_is_deterministic() {
+        // Each map and set gets a hasher of its own, so building the same topology twice iterates
+        // it in different orders.
+        let topology = || {
+            let nodes = (0..16).map(|n| format!("n{n}")).collect::<Vec<_>>();
+            let neighbors = |n: usize| {
+                let mut neighbors = HashSet::with_hasher(std::hash::RandomState::new());
+                neighbors.extend(nodes.iter().filter(|node| **node != nodes[n]).cloned());
+                neighbors
+            };
+            let mut topology = HashMap::with_hasher(std::hash::RandomState::new());
+            topology.extend((0..nodes.len()).map(|n| (nodes[n].clone(), neighbors(n))));
+            BroadcastMessage::Topology {
+                topology: Topology::Neighbors(topology),
+                allow_isolation: false,
+            }
+        };
+
+        let first = encode(topology());
+        assert_eq!(first, encode(topology()));
+        let decoded: Message<BroadcastMessage> = serde_json::from_slice(&first).unwrap();
+        let BroadcastMessage::Topology {
+            topology: Topology::Neighbors(decoded),
+            ..
+        } = decoded.body.data
+        else {
+            panic!("Expected topology, got {:?}", decoded.body.data);
+        };
+        assert_eq!(decoded.len(), 16);
+        assert!(decoded.values().all(|neighbors| neighbors.len() == 15));
+    }
+
+    #[test]
+    fn test_gossip_round_trips() {
+        let seen = (0..1000).collect::<HashSet<u64>>();
+        let encoded = encode(BroadcastMessage::Gossip { seen: seen.clone() });
+
+        let decoded: Message<BroadcastMessage> = serde_json::from_slice(&encoded).unwrap();
+        let BroadcastMessage::Gossip { seen: decoded } = decoded.body.data else {
+            panic!("Expected gossip, got {:?}", decoded.body.data);
+        };
+        assert_eq!(decoded, seen);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_without_topology() {
+        let cluster = Cluster::new(5, BroadcastService::default).await;
+        let history =
+            workload::broadcast_without_topology(&cluster, 10.0, Duration::from_secs(20)).await;
+
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_with_late_topology() {
+        let cluster = Cluster::new(5, BroadcastService::default).await;
+        let client = cluster.client();
+        let late_topology = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            workload::send_topology(
+                &cluster,
+                &client,
+                &workload::grid_topology(cluster.node_ids()),
+            )
+            .await;
+        };
+
+        let (history, ()) = tokio::join!(
+            workload::broadcast_without_topology(&cluster, 10.0, Duration::from_secs(20)),
+            late_topology
+        );
+
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_workload() {
+        let cluster = Cluster::new(5, BroadcastService::default).await;
+        let history = workload::broadcast(&cluster, 10.0, Duration::from_secs(20)).await;
+
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+        // Regression thresholds, a little above the current figures.
+        assert!(report.latency_quantile(0.5) <= Duration::from_millis(500));
+        assert!(report.latency_quantile(1.0) <= Duration::from_secs(1));
+        cluster.traffic().assert_msgs_per_op_below(&history, 8.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_workload_with_grid_latency() {
+        let cluster =
+            Cluster::with_latency(9, LatencyMatrix::grid(9, 100), BroadcastService::default).await;
+        let history = workload::broadcast(&cluster, 10.0, Duration::from_secs(20)).await;
+
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+        // The far corners of the grid are four hops apart, so values can't become stable in
+        // under 400ms.
+        assert!(report.latency_quantile(1.0) <= Duration::from_millis(600));
+        // Mostly gossip, which goes out on a timer whatever the request rate.
+        cluster.traffic().assert_msgs_per_op_below(&history, 19.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_with_topology_change() {
+        let cluster = Cluster::new(5, BroadcastService::default).await;
+        let client = cluster.client();
+        let nodes = cluster.node_ids();
+        // Midway through, switch from the grid to a line.
+        let line = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let neighbors = [i.checked_sub(1), Some(i + 1)]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|j| nodes.get(j).cloned())
+                    .collect();
+                (node.clone(), neighbors)
+            })
+            .collect();
+        let change = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            workload::send_topology(&cluster, &client, &line).await;
+        };
+
+        let (history, ()) = tokio::join!(
+            workload::broadcast(&cluster, 10.0, Duration::from_secs(20)),
+            change
+        );
+
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_gossip_spreads_a_hop_at_a_time() {
+        // Only flushes gossip, and values are never forwarded.
+        let service = || {
+            BroadcastService::new(BroadcastOptions {
+                gossip: GossipParams {
+                    interval: Duration::from_secs(3600),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        };
+        let cluster = Cluster::new(3, service).await;
+        cluster.drop_frames(|src, _, frame| {
+            src.starts_with('n') && frame["body"]["type"] == "broadcast"
+        });
+        let client = cluster.client();
+        // n0 in the middle, n1 and n2 on either side.
+        let topology = workload::grid_topology(cluster.node_ids());
+        workload::send_topology(&cluster, &client, &topology).await;
+        let broadcast = serde_json::json!({ "type": "broadcast", "message": 7 });
+        client.rpc("n1", broadcast).await.unwrap();
+
+        let sent = |pairs: &[(&str, &str, u64)]| {
+            let mut sent = BTreeMap::<String, BTreeMap<String, u64>>::new();
+            for (src, dest, count) in pairs {
+                sent.entry(src.to_string())
+                    .or_default()
+                    .insert(dest.to_string(), *count);
+            }
+            sent
+        };
+        assert_eq!(
+            cluster.flush_gossip(false).await,
+            sent(&[
+                ("n0", "n1", 0),
+                ("n0", "n2", 0),
+                ("n1", "n0", 1),
+                ("n2", "n0", 0)
+            ])
+        );
+        // Gossip isn't acknowledged, so n1 sends 7 again until it hears n0 has it.
+        assert_eq!(
+            cluster.flush_gossip(false).await,
+            sent(&[
+                ("n0", "n1", 0),
+                ("n0", "n2", 1),
+                ("n1", "n0", 1),
+                ("n2", "n0", 0)
+            ])
+        );
+        let read = serde_json::json!({ "type": "read" });
+        let reply = client.rpc("n2", read).await.unwrap();
+        assert_eq!(reply["messages"], serde_json::json!([7]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_joined_node_converges() {
+        let mut cluster = Cluster::builder()
+            .nodes(3)
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        let mut history = workload::broadcast(&cluster, 10.0, Duration::from_secs(5)).await;
+
+        let joined = cluster.add_node(BroadcastService::default()).await;
+        history.extend(
+            workload::broadcast_without_topology(&cluster, 10.0, Duration::from_secs(5)).await,
+        );
+
+        // The final reads include the new node, which has to hold values from before it joined.
+        assert!(history.iter().any(|op| op.node == joined));
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_left_node_is_not_sent_to() {
+        // Sends to unknown destinations would still reach the wire, where they are counted.
+        let options = NodeOptions {
+            lenient_destinations: true,
+            ..Default::default()
+        };
+        let mut cluster = Cluster::builder()
+            .nodes(4)
+            .options(options)
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        let mut history = workload::broadcast(&cluster, 10.0, Duration::from_secs(3)).await;
+
+        cluster.remove_node("n3").await;
+        let sent_to_left = Arc::new(AtomicU64::new(0));
+        cluster.drop_frames({
+            let sent_to_left = Arc::clone(&sent_to_left);
+            move |_, dest, _| {
+                if dest == "n3" {
+                    sent_to_left.fetch_add(1, Ordering::Relaxed);
+                }
+                false
+            }
+        });
+        history.extend(
+            workload::broadcast_without_topology(&cluster, 10.0, Duration::from_secs(3)).await,
+        );
+
+        assert_eq!(sent_to_left.load(Ordering::Relaxed), 0);
+        // n3's final read is from before it left, so only the remaining nodes are checked.
+        history.retain(|op| op.node != "n3");
+        let report = checker::broadcast(&history);
+        assert!(report.is_valid(), "{:?}", report.lost);
+    }
+
+    fn audited() -> NodeOptions {
+        NodeOptions {
+            audit_timeout_ms: Some(500),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_ids_name_their_subsystem() {
+        let cluster = Cluster::builder()
+            .nodes(3)
+            .options(audited())
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        cluster.drop_frames({
+            let sent = Arc::clone(&sent);
+            move |src, _, frame| {
+                let body = &frame["body"];
+                let kind = body["type"].as_str().unwrap().to_owned();
+                let id = body["msg_id"].as_u64().unwrap();
+                sent.lock().unwrap().push((src.to_owned(), kind, id));
+                false
+            }
+        });
+        let history = workload::broadcast(&cluster, 10.0, Duration::from_secs(3)).await;
+        assert!(checker::broadcast(&history).is_valid());
+        cluster.drain().await;
+
+        let sent = sent.lock().unwrap().clone();
+        let mut kinds = HashSet::new();
+        for (src, kind, id) in &sent {
+            let expected = match kind.as_str() {
+                "gossip" | "broadcast" => Subsystem::Gossip,
+                "capabilities" | "capabilities_ok" | "audit" | "audit_ok" | "drain_ok" => {
+                    Subsystem::Runner
+                }
+                _ => Subsystem::Service,
+            };
+            assert_eq!(
+                Subsystem::of(*id),
+                Some(expected),
+                "{kind} {id:#x} from {src}"
+            );
+            kinds.insert(kind.as_str());
+        }
+        for kind in [
+            "gossip",
+            "broadcast",
+            "broadcast_ok",
+            "audit",
+            "audit_ok",
+            "drain_ok",
+        ] {
+            assert!(kinds.contains(kind), "no {kind} sent");
+        }
+        // Each subsystem counts on its own, but no two messages from a node share an ID.
+        let ids = sent
+            .iter()
+            .map(|(src, _, id)| (src, id))
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), sent.len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_audit_of_converged_cluster() {
+        let cluster = Cluster::builder()
+            .nodes(3)
+            .options(audited())
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        let history = workload::broadcast(&cluster, 10.0, Duration::from_secs(3)).await;
+        assert!(checker::broadcast(&history).is_valid());
+
+        let audits = cluster.drain().await;
+        assert_eq!(audits.len(), 3);
+        for (node, audit) in audits {
+            let audit = audit.unwrap_or_else(|| panic!("no audit from {node}"));
+            assert!(audit.is_consistent(), "{node}: {audit}");
+            assert_eq!(audit.matched.len(), 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_peer_messages_are_counted() {
+        let options = NodeOptions {
+            sequence_peer_messages: true,
+            ..audited()
+        };
+        let cluster = Cluster::builder()
+            .nodes(3)
+            .options(options)
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        // Every third gossip between each pair of nodes is lost.
+        let dropped = Arc::new(std::sync::Mutex::new(
+            BTreeMap::<(String, String), u64>::new(),
+        ));
+        let mut sent = HashMap::<(String, String), u64>::new();
+        cluster.drop_frames({
+            let dropped = Arc::clone(&dropped);
+            move |src, dest, frame| {
+                if !dest.starts_with('n') || frame["body"]["type"] != "gossip" {
+                    return false;
+                }
+                let link = (src.to_owned(), dest.to_owned());
+                let sent = sent.entry(link.clone()).or_default();
+                *sent += 1;
+                let drop = sent.is_multiple_of(3);
+                if drop {
+                    *dropped.lock().unwrap().entry(link).or_default() += 1;
+                }
+                drop
+            }
+        });
+        workload::broadcast(&cluster, 10.0, Duration::from_secs(3)).await;
+        cluster.heal();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let dropped = dropped.lock().unwrap().clone();
+        assert!(!dropped.is_empty());
+        // Each node hears from every peer during the audit, so no loss goes unnoticed.
+        let audits = cluster.drain().await;
+        for (node, audit) in audits {
+            let audit = audit.unwrap_or_else(|| panic!("no audit from {node}"));
+            let expected = dropped
+                .iter()
+                .filter(|((_, dest), _)| *dest == node)
+                .map(|((src, _), count)| (src.clone(), *count))
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(audit.lost, expected, "{node}: {audit}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_audit_reports_divergent_nodes() {
+        let mut cluster = Cluster::builder()
+            .nodes(3)
+            .options(audited())
+            .service(BroadcastService::default)
+            .build()
+            .await;
+        // Only audits get through between nodes, so n0 keeps its value to itself.
+        cluster.drop_frames(|_, dest, frame| {
+            dest.starts_with('n')
+                && !frame["body"]["type"]
+                    .as_str()
+                    .is_some_and(|kind| kind.starts_with("audit"))
+        });
+        let broadcast = serde_json::json!({ "type": "broadcast", "message": 7 });
+        cluster.client().rpc("n0", broadcast).await.unwrap();
+        cluster.kill("n2").await;
+
+        let audits = cluster.drain().await;
+        assert_eq!(audits["n2"], None);
+        let n0 = audits["n0"].clone().unwrap();
+        assert!(!n0.is_consistent());
+        assert_eq!(n0.local.count, 1);
+        assert_eq!(n0.mismatched["n1"].count, 0);
+        assert_eq!(n0.unanswered, ["n2".to_owned()].into());
+        assert_eq!(
+            n0.to_string(),
+            format!(
+                "0 peers hold {}, n1 holds {}, no answer from n2",
+                n0.local, n0.mismatched["n1"]
+            )
+        );
+        let n1 = audits["n1"].clone().unwrap();
+        assert_eq!(n1.mismatched["n0"], n0.local);
+    }
+
+    #[tokio::test]
+    async fn test_topology_change_keeps_known() {
+        let (service, state, mut lines) = forwarding_node().await;
+        for value in [1, 2, 3] {
+            service.receive(value).await;
+        }
+        service.inner.gossip.learn("n1", &GSet::from_iter([1, 2]));
+
+        let topology = |neighbors: &[&str]| {
+            let topology = HashMap::from([(
+                "n0".to_owned(),
+                neighbors.iter().map(|n| n.to_string()).collect(),
+            )]);
+            message(
+                "c1",
+                Some(1),
+                None,
+                BroadcastMessage::Topology {
+                    topology: Topology::Neighbors(topology),
+                    allow_isolation: false,
+                },
+            )
+        };
+        let seen = |frame: &serde_json::Value| {
+            serde_json::from_value::<HashSet<u64>>(frame["body"]["seen"].clone()).unwrap()
+        };
+
+        // n2 is caught up right away; n1 is left alone.
+        service
+            .handle_message(topology(&["n1", "n2"]), &state)
+            .await
+            .unwrap();
+        assert_eq!(next_frame(&mut lines).await["body"]["type"], "topology_ok");
+        let catch_up = next_frame(&mut lines).await;
+        assert_eq!(catch_up["dest"], "n2");
+        assert_eq!(seen(&catch_up), HashSet::from([1, 2, 3]));
+        let read = next_frame(&mut lines).await;
+        assert_eq!(
+            (&read["dest"], &read["body"]["type"]),
+            (&"n2".into(), &"read".into())
+        );
+
+        // The retained neighbor only gets what it is missing.
+        service.gossip(state.clone()).await.unwrap();
+        for _ in 0..2 {
+            let gossip = next_frame(&mut lines).await;
+            if gossip["dest"] == "n1" {
+                assert_eq!(seen(&gossip), HashSet::from([3]));
+            }
+        }
+
+        // A removed neighbor is no longer gossiped with, but what it knows is kept.
+        service
+            .handle_message(topology(&["n2"]), &state)
+            .await
+            .unwrap();
+        assert_eq!(next_frame(&mut lines).await["body"]["type"], "topology_ok");
+        service.gossip(state.clone()).await.unwrap();
+        assert_eq!(next_frame(&mut lines).await["dest"], "n2");
+        assert_eq!(known(&service, "n1"), HashSet::from([1, 2]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cluster_teardown_stops_gossip() {
+        for _ in 0..50 {
+            let mut cluster = Cluster::new(3, BroadcastService::default).await;
+            // Each node runs gossip, the expiry and invariant check timers, the gauges and the
+            // request sweeper.
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert_eq!(cluster.live_node_tasks(), 15);
+
+            cluster.shutdown().await;
+            assert_eq!(cluster.live_node_tasks(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invariant_check_repairs_known() {
+        let service = BroadcastService::default();
+        let node_ids = ["n0", "n1", "n2"].map(String::from);
+        service.inner.gossip.init("n0", &node_ids);
+        service.receive(1).await;
+        service.receive(2).await;
+        service.inner.gossip.learn("n1", &GSet::from_iter([1, 2]));
+        // n2 supposedly knows values we never received.
+        service
+            .inner
+            .gossip
+            .learn("n2", &GSet::from_iter([1, 3, 4]));
+
+        assert_eq!(service.check_invariants().await, 2);
+        assert_eq!(service.invariant_violations(), 2);
+        assert_eq!(known(&service, "n2"), HashSet::from([1]));
+        assert_eq!(known(&service, "n1"), HashSet::from([1, 2]));
+
+        // Once repaired, there is nothing left to find.
+        assert_eq!(service.check_invariants().await, 0);
+        assert_eq!(service.invariant_violations(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invariants_hold_under_load() {
+        let services = std::sync::Mutex::new(Vec::new());
+        let cluster = Cluster::new(5, || {
+            let service = BroadcastService::default();
+            services.lock().unwrap().push(service.clone());
+            service
+        })
+        .await;
+
+        let history = workload::broadcast(&cluster, 200.0, Duration::from_secs(10)).await;
+        assert!(history.len() >= 2000);
+
+        let services = services.into_inner().unwrap();
+        for service in &services {
+            assert_eq!(service.check_invariants().await, 0);
+            assert_eq!(service.invariant_violations(), 0);
+        }
+    }
+
+    type Frames = tokio::io::Lines<tokio::io::BufReader<tokio::io::DuplexStream>>;
+
+    fn message(
+        src: &str,
+        id: Option<MessageId>,
+        re: Option<MessageId>,
+        data: BroadcastMessage,
+    ) -> Message<BroadcastMessage> {
+        Message {
+            src: src.into(),
+            dest: "n0".into(),
+            body: crate::message::MessageBody {
+                id,
+                re,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data,
+            },
+        }
+    }
+
+    /// The values `peer` is known to have.
+    fn known(service: &BroadcastService, peer: &str) -> HashSet<BroadcastValue> {
+        service.inner.gossip.knowledge(peer).unwrap().into_inner()
+    }
+
+    /// A node `n0` whose only neighbor is `n1`, along with everything it writes.
+    async fn forwarding_node() -> (BroadcastService, NodeState<BroadcastService>, Frames) {
+        let service = BroadcastService::default();
+        let node_ids = ["n0", "n1"].map(String::from);
+        service.inner.gossip.init("n0", &node_ids);
+        service
+            .inner
+            .gossip
+            .set_neighbors(HashSet::from(["n1".into()]));
+
+        let (output, stdout) = tokio::io::duplex(64 * 1024);
+        let state = NodeState::with_output(
+            service.clone(),
+            "n0".into(),
+            &NodeOptions::default(),
+            output,
+        );
+        let lines = tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(stdout));
+        (service, state, lines)
+    }
+
+    async fn next_frame(lines: &mut Frames) -> serde_json::Value {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Skip to the next frame of type `kind`, returning when it arrived.
+    async fn next_of_type(lines: &mut Frames, kind: &str) -> tokio::time::Instant {
+        loop {
+            let frame = next_frame(lines).await;
+            if frame["body"]["type"] == kind {
+                return tokio::time::Instant::now();
+            }
+        }
+    }
+
+    /// Broadcast 7 to `n0` from a client and return the ID of the message forwarding it to `n1`.
+    async fn broadcast_and_forward(
+        service: &BroadcastService,
+        state: &NodeState<BroadcastService>,
+        lines: &mut Frames,
+    ) -> MessageId {
+        let broadcast = message(
+            "c1",
+            Some(1),
+            None,
+            BroadcastMessage::Broadcast { message: 7 },
+        );
+        service.handle_message(broadcast, state).await.unwrap();
+
+        assert_eq!(next_frame(lines).await["body"]["type"], "broadcast_ok");
+        let forward = next_frame(lines).await;
+        assert_eq!(forward["dest"], "n1");
+        assert_eq!(forward["body"]["type"], "broadcast");
+        assert_eq!(forward["body"]["message"], 7);
+        forward["body"]["msg_id"].as_u64().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ack_suppresses_resend() {
+        let (service, state, mut lines) = forwarding_node().await;
+        let id = broadcast_and_forward(&service, &state, &mut lines).await;
+
+        let ack = message("n1", None, Some(id), BroadcastMessage::BroadcastOk);
+        service.handle_message(ack, &state).await.unwrap();
+        assert!(known(&service, "n1").contains(&7));
+        assert_eq!(service.inner.gossip.pending(), 0);
+
+        service.gossip(state.clone()).await.unwrap();
+        let gossip = next_frame(&mut lines).await;
+        assert_eq!(gossip["body"]["type"], "gossip");
+        assert_eq!(gossip["body"]["seen"], serde_json::json!([]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_forwards_expire() {
+        let (service, state, mut lines) = forwarding_node().await;
+        let id = broadcast_and_forward(&service, &state, &mut lines).await;
+        assert_eq!(service.inner.gossip.pending(), 1);
+
+        tokio::time::advance(FORWARD_ACK_TIMEOUT + Duration::from_secs(1)).await;
+        service.expire_forwards();
+        assert_eq!(service.inner.gossip.pending(), 0);
+
+        // A late ack is ignored, so the value is still gossiped.
+        let ack = message("n1", None, Some(id), BroadcastMessage::BroadcastOk);
+        service.handle_message(ack, &state).await.unwrap();
+        assert!(!known(&service, "n1").contains(&7));
+
+        service.gossip(state.clone()).await.unwrap();
+        let gossip = next_frame(&mut lines).await;
+        assert_eq!(gossip["body"]["seen"], serde_json::json!([7]));
+    }
+
+    #[tokio::test]
+    async fn test_peer_read_ok_updates_known() {
+        let (service, state, _lines) = forwarding_node().await;
+
+        let read_ok = message(
+            "n1",
+            None,
+            Some(3),
+            BroadcastMessage::ReadOk {
+                messages: HashSet::from([1, 2]),
+            },
+        );
+        service.handle_message(read_ok, &state).await.unwrap();
+
+        assert_eq!(known(&service, "n1"), HashSet::from([1, 2]));
+        assert_eq!(service.check_invariants().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_only_mode() {
+        let cluster = Cluster::new(2, BroadcastService::default).await;
+        let client = cluster.client();
+        let set_read_only = |read_only: bool| serde_json::json!({ "type": "set_read_only", "read_only": read_only });
+        let broadcast = |value: u64| serde_json::json!({ "type": "broadcast", "message": value });
+        let read = || async {
+            let reply = client
+                .rpc("n0", serde_json::json!({ "type": "read" }))
+                .await;
+            let mut messages =
+                serde_json::from_value::<Vec<u64>>(reply.expect("read reply")["messages"].clone())
+                    .unwrap();
+            messages.sort();
+            messages
+        };
+
+        let reply = client.rpc("n0", set_read_only(true)).await.unwrap();
+        assert_eq!(reply["type"], "set_read_only_ok");
+
+        let reply = client.rpc("n0", broadcast(1)).await.unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], ErrorCode::TemporarilyUnavailable as u64);
+
+        // Other nodes still accept values, and gossip still reaches the read-only node.
+        let reply = client.rpc("n1", broadcast(2)).await.unwrap();
+        assert_eq!(reply["type"], "broadcast_ok");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(read().await, [2]);
+
+        let reply = client.rpc("n0", set_read_only(false)).await.unwrap();
+        assert_eq!(reply["type"], "set_read_only_ok");
+        let reply = client.rpc("n0", broadcast(3)).await.unwrap();
+        assert_eq!(reply["type"], "broadcast_ok");
+        assert_eq!(read().await, [2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_strict_client_input() {
+        let options = NodeOptions {
+            strict_client_input: true,
+            ..Default::default()
+        };
+        let cluster = Cluster::with_options(
+            2,
+            LatencyMatrix::default(),
+            options,
+            BroadcastService::default,
+        )
+        .await;
+        let client = cluster.client();
+        let rejection = |body: serde_json::Value| {
+            let client = client.clone();
+            async move {
+                let reply = client.rpc("n0", body).await.expect("reply");
+                assert_eq!(reply["type"], "error", "{reply}");
+                assert_eq!(reply["code"], ErrorCode::MalformedRequest as u64);
+                reply["text"].as_str().unwrap().to_owned()
+            }
+        };
+
+        let text = rejection(serde_json::json!({ "type": "broadcast", "mesage": 1 })).await;
+        assert!(text.starts_with("mesage: unknown field"), "{text}");
+        let text = rejection(serde_json::json!({
+            "type": "topology",
+            "topology": { "n0": ["n1"], "n1": ["n0", 0] },
+        }))
+        .await;
+        assert!(text.starts_with("topology.n1[1]: invalid type"), "{text}");
+        let text = rejection(serde_json::json!({
+            "type": "topology",
+            "edges": [["n0", "n1"], ["n1"]],
+        }))
+        .await;
+        assert!(text.starts_with("edges[1]: invalid length 1"), "{text}");
+        let text = rejection(serde_json::json!({ "type": "raed" })).await;
+        assert!(text.starts_with("type:"), "{text}");
+
+        // Well-formed requests are handled, and gossip between the nodes is still read leniently.
+        let reply = client
+            .rpc(
+                "n0",
+                serde_json::json!({ "type": "broadcast", "message": 5 }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply["type"], "broadcast_ok");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let reply = client
+            .rpc("n1", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        assert_eq!(reply["messages"], serde_json::json!([5]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tune_gossip_interval() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut input, node_stdin) = tokio::io::duplex(64 * 1024);
+        let (node_stdout, output) = tokio::io::duplex(64 * 1024);
+        let _node = tokio::spawn(NodeState::run_with_io(
+            BroadcastService::default(),
+            NodeOptions::default(),
+            node_stdin,
+            node_stdout,
+        ));
+        let mut lines = tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(output));
+
+        let init = serde_json::json!({
+            "src": "c0",
+            "dest": "n0",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": ["n0", "n1"] },
+        });
+        input
+            .write_all(format!("{init}\n").as_bytes())
+            .await
+            .unwrap();
+        let first = next_of_type(&mut lines, "gossip").await;
+        let second = next_of_type(&mut lines, "gossip").await;
+        assert_eq!(second - first, DEFAULT_GOSSIP_INTERVAL);
+
+        let tune = serde_json::json!({
+            "src": "c1",
+            "dest": "n0",
+            "body": { "type": "tune", "msg_id": 2, "tune": { "gossip_interval_ms": 50 } },
+        });
+        input
+            .write_all(format!("{tune}\n").as_bytes())
+            .await
+            .unwrap();
+        let tuned = next_of_type(&mut lines, "tune_ok").await;
+
+        let mut rounds = Vec::new();
+        for _ in 0..3 {
+            rounds.push(next_of_type(&mut lines, "gossip").await);
+        }
+        assert!(rounds[0] - tuned <= DEFAULT_GOSSIP_INTERVAL);
+        assert_eq!(rounds[1] - rounds[0], Duration::from_millis(50));
+        assert_eq!(rounds[2] - rounds[1], Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invalid_tune_is_rejected() {
+        let services = std::sync::Mutex::new(Vec::new());
+        let cluster = Cluster::new(1, || {
+            let service = BroadcastService::default();
+            services.lock().unwrap().push(service.clone());
+            service
+        })
+        .await;
+        let service = services.lock().unwrap()[0].clone();
+        let client = cluster.client();
+        let tune = |tune: serde_json::Value| {
+            client.rpc("n0", serde_json::json!({ "type": "tune", "tune": tune }))
+        };
+
+        // Nothing is applied unless everything is valid.
+        for invalid in [
+            serde_json::json!({ "fanout": 5, "gossip_interval_ms": 0 }),
+            serde_json::json!({ "fanout": 5, "gosip_interval_ms": 100 }),
+            serde_json::json!([5]),
+        ] {
+            let reply = tune(invalid).await.unwrap();
+            assert_eq!(reply["type"], "error");
+            assert_eq!(reply["code"], ErrorCode::MalformedRequest as u64);
+            let text = reply["text"].as_str().unwrap();
+            assert!(text.contains(GossipParams::TUNABLE), "{text}");
+            assert_eq!(service.gossip_params(), GossipParams::default());
+        }
+
+        let reply = tune(serde_json::json!({ "fanout": 5, "batch_size": 100 }))
+            .await
+            .unwrap();
+        assert_eq!(reply["type"], "tune_ok");
+        assert_eq!(
+            service.gossip_params(),
+            GossipParams {
+                fallback_fanout: 5,
+                batch_size: Some(100),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gossip_batch_size() {
+        let (service, state, mut lines) = forwarding_node().await;
+        for value in 0..5 {
+            service.receive(value).await;
+        }
+        service
+            .reconfigure(&serde_json::json!({ "batch_size": 2 }))
+            .unwrap();
+
+        service.gossip(state.clone()).await.unwrap();
+        let mut sizes = Vec::new();
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let gossip = next_frame(&mut lines).await;
+            let batch = serde_json::from_value::<Vec<u64>>(gossip["body"]["seen"].clone()).unwrap();
+            sizes.push(batch.len());
+            seen.extend(batch);
+        }
+        seen.sort();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert_eq!(seen, [0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarted_node_bootstraps_over_lossy_link() {
+        let options = BroadcastOptions {
+            bootstrap: true,
+            state_chunk_size: 16,
+            ..Default::default()
+        };
+        let service = || BroadcastService::new(options.clone());
+        let mut cluster = Cluster::new(3, service).await;
+        let client = cluster.client();
+        for value in 0..100 {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            client.rpc("n0", broadcast).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Peers believe n2 still has everything, so only the state transfer can catch it up. The
+        // first copy of chunk 1 is lost on the way.
+        let chunk_1_sent = Arc::new(AtomicU64::new(0));
+        cluster.drop_frames({
+            let chunk_1_sent = Arc::clone(&chunk_1_sent);
+            move |_, dest, frame| {
+                let body = &frame["body"];
+                dest == "n2"
+                    && match body["type"].as_str() {
+                        Some("gossip") | Some("broadcast") => true,
+                        Some("state_chunk") => {
+                            body["seq"] == 1 && chunk_1_sent.fetch_add(1, Ordering::Relaxed) == 0
+                        }
+                        _ => false,
+                    }
+            }
+        });
+        cluster.restart("n2", service()).await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        cluster.heal();
+
+        let reply = client
+            .rpc("n2", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        let mut messages = serde_json::from_value::<Vec<u64>>(reply["messages"].clone()).unwrap();
+        messages.sort();
+        assert_eq!(messages, (0..100).collect::<Vec<_>>());
+        assert_eq!(chunk_1_sent.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reads_wait_for_bootstrap() {
+        let service = || {
+            BroadcastService::new(BroadcastOptions {
+                bootstrap: true,
+                ..Default::default()
+            })
+        };
+        let mut cluster = Cluster::new(3, service).await;
+        let client = cluster.client();
+        for value in 0..10 {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            client.rpc("n0", broadcast).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // The first transfer never arrives, so n2 is still bootstrapping when the read comes in.
+        cluster.drop_frames(|_, dest, frame| {
+            dest == "n2"
+                && matches!(
+                    frame["body"]["type"].as_str(),
+                    Some("gossip" | "broadcast" | "state_chunk")
+                )
+        });
+        cluster.restart("n2", service()).await;
+        let heal = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cluster.heal();
+        };
+        let (reply, ()) = tokio::join!(
+            client.rpc("n2", serde_json::json!({ "type": "read" })),
+            heal
+        );
+
+        let mut messages =
+            serde_json::from_value::<Vec<u64>>(reply.unwrap()["messages"].clone()).unwrap();
+        messages.sort();
+        assert_eq!(messages, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fresh_cluster_reads_without_delay() {
+        let cluster = Cluster::new(3, BroadcastService::default).await;
+        let client = cluster.client();
+
+        let started = tokio::time::Instant::now();
+        let reply = client
+            .rpc("n2", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        assert_eq!(reply["messages"], serde_json::json!([]));
+        assert!(
+            started.elapsed() < BOOTSTRAP_READ_DELAY,
+            "{:?}",
+            started.elapsed()
+        );
+        // Nobody asked anybody for state there is none of.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(cluster.traffic().by_type().get("state_request"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_is_kept_while_fetched() {
+        let (service, state, mut lines) = forwarding_node().await;
+        for value in 0..3 {
+            service.receive(value).await;
+        }
+        let kept = || async {
+            service
+                .inner
+                .outgoing
+                .get_owned(&"n1".into())
+                .await
+                .is_some()
+        };
+
+        service.send_state(&state, "n1", 0, None).await.unwrap();
+        next_of_type(&mut lines, "state_chunk").await;
+        tokio::time::advance(STATE_TRANSFER_TTL * 2 / 3).await;
+        // Asking for a lost chunk starts the clock over.
+        service
+            .send_state(&state, "n1", 0, Some(vec![0]))
+            .await
+            .unwrap();
+        next_of_type(&mut lines, "state_chunk").await;
+        tokio::time::advance(STATE_TRANSFER_TTL * 2 / 3).await;
+        service.expire_transfers();
+        assert!(kept().await);
+
+        tokio::time::advance(STATE_TRANSFER_TTL / 2).await;
+        service.expire_transfers();
+        assert!(!kept().await);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_failing_checksum_is_not_merged() {
+        let service = BroadcastService::default();
+        let node_ids = ["n0", "n1"].map(String::from);
+        service.inner.gossip.init("n0", &node_ids);
+        *service.inner.incoming.lock().unwrap() = Some(Transfer {
+            peer: "n1".into(),
+            checksum: None,
+            total_chunks: 0,
+            chunks: BTreeMap::new(),
+            progress: 0,
+        });
+
+        let checksum = digest(&[1, 2, 3, 4]);
+        service
+            .receive_chunk("n1", 0, vec![1, 2], 2, checksum)
+            .await;
+        // Corrupted on the way: 4 became 5.
+        service
+            .receive_chunk("n1", 1, vec![3, 5], 2, checksum)
+            .await;
+
+        assert!(service.inner.gossip.state().is_empty());
+        assert!(service.inner.incoming.lock().unwrap().is_none());
+        assert!(!service.inner.bootstrapped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_topology_formats() {
+        let neighbors = |body: serde_json::Value, node: &str| {
+            let BroadcastMessage::Topology { topology, .. } = serde_json::from_value(body).unwrap()
+            else {
+                panic!("not a topology");
+            };
+            let mut neighbors = topology.neighbors_of(node).into_iter().collect::<Vec<_>>();
+            neighbors.sort_unstable();
+            neighbors
+        };
+
+        let map = serde_json::json!({
+            "type": "topology",
+            "topology": { "n0": ["n1", "n2"], "n1": ["n0"] },
+        });
+        assert_eq!(neighbors(map.clone(), "n0"), ["n1", "n2"]);
+        assert_eq!(neighbors(map.clone(), "n1"), ["n0"]);
+        assert!(neighbors(map, "n3").is_empty());
+
+        // Edges go both ways, and listing one both ways round is no different.
+        let edges = serde_json::json!({
+            "type": "topology",
+            "edges": [["n0", "n1"], ["n2", "n0"], ["n1", "n0"], ["n1", "n2"]],
+        });
+        assert_eq!(neighbors(edges.clone(), "n0"), ["n1", "n2"]);
+        assert_eq!(neighbors(edges.clone(), "n2"), ["n0", "n1"]);
+        assert!(neighbors(edges, "n3").is_empty());
+
+        // A node is never its own neighbor, whichever the format.
+        let edges = serde_json::json!({
+            "type": "topology",
+            "edges": [["n0", "n0"], ["n0", "n1"]],
+        });
+        assert_eq!(neighbors(edges, "n0"), ["n1"]);
+        let map = serde_json::json!({ "type": "topology", "topology": { "n0": ["n0", "n1"] } });
+        assert_eq!(neighbors(map, "n0"), ["n1"]);
+
+        let topology = Topology::Edges(vec![("n0".into(), "n1".into())]);
+        let message = BroadcastMessage::Topology {
+            topology: topology.clone(),
+            allow_isolation: true,
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({ "type": "topology", "edges": [["n0", "n1"]], "allow_isolation": true }),
+        );
+        let BroadcastMessage::Topology {
+            topology: decoded,
+            allow_isolation,
+        } = serde_json::from_value(serde_json::to_value(&message).unwrap()).unwrap()
+        else {
+            panic!("not a topology");
+        };
+        assert_eq!(decoded, topology);
+        assert!(allow_isolation);
+
+        for invalid in [
+            serde_json::json!({ "type": "topology" }),
+            serde_json::json!({ "type": "topology", "topology": {}, "edges": [] }),
+        ] {
+            assert!(serde_json::from_value::<BroadcastMessage>(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_topology_edge_cases() {
+        let (service, state, mut lines) = forwarding_node().await;
+        let topology = |neighbors: Option<&[&str]>, allow_isolation: bool| {
+            let topology = neighbors
+                .map(|neighbors| {
+                    let neighbors = neighbors.iter().map(|n| n.to_string()).collect();
+                    HashMap::from([("n0".to_owned(), neighbors)])
+                })
+                .unwrap_or_default();
+            message(
+                "c1",
+                Some(1),
+                None,
+                BroadcastMessage::Topology {
+                    topology: Topology::Neighbors(topology),
+                    allow_isolation,
+                },
+            )
+        };
+        let apply = |topology| {
+            let service = service.clone();
+            let state = state.clone();
+            async move { service.handle_message(topology, &state).await.unwrap() }
+        };
+
+        // Reapplying the current topology leaves the neighbor set alone.
+        let neighbors = service.inner.gossip.neighbors().unwrap();
+        apply(topology(Some(&["n1"]), false)).await;
+        assert_eq!(next_frame(&mut lines).await["body"]["type"], "topology_ok");
+        assert!(Arc::ptr_eq(
+            &neighbors,
+            &service.inner.gossip.neighbors().unwrap()
+        ));
+
+        // Without allow_isolation, an empty or missing slice keeps the current neighbors.
+        for empty in [topology(Some(&[]), false), topology(None, false)] {
+            apply(empty).await;
+            assert_eq!(next_frame(&mut lines).await["body"]["type"], "topology_ok");
+            assert_eq!(
+                service.inner.gossip.neighbors().as_deref(),
+                Some(&HashSet::from(["n1".to_owned()]))
+            );
+        }
+
+        apply(topology(Some(&[]), true)).await;
+        assert_eq!(next_frame(&mut lines).await["body"]["type"], "topology_ok");
+        assert_eq!(
+            service.inner.gossip.neighbors().as_deref(),
+            Some(&HashSet::new())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forwards_carry_trace_id() {
+        let cluster = Cluster::new(3, BroadcastService::default).await;
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        cluster.drop_frames({
+            let frames = Arc::clone(&frames);
+            move |_, _, frame| {
+                frames.lock().unwrap().push(frame.clone());
+                false
+            }
+        });
+
+        let client = cluster.client();
+        let broadcast = serde_json::json!({ "type": "broadcast", "message": 1 });
+        let reply = client.rpc("n0", broadcast).await.unwrap();
+        assert!(reply.get("trace_id").is_none(), "{reply}");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The forward from n0 and the ack from n1, handled on different nodes, share one ID.
+        let frames = frames.lock().unwrap();
+        let find = |src: &str, dest: &str, kind: &str| {
+            frames
+                .iter()
+                .find(|f| f["src"] == src && f["dest"] == dest && f["body"]["type"] == kind)
+                .unwrap_or_else(|| panic!("no {kind} from {src} to {dest} in {frames:?}"))
+        };
+        let trace_id = &find("n0", "n1", "broadcast")["body"]["trace_id"];
+        assert!(trace_id.is_u64(), "{trace_id}");
+        assert_eq!(
+            &find("n1", "n0", "broadcast_ok")["body"]["trace_id"],
+            trace_id
+        );
+        assert_eq!(&find("n0", "n2", "broadcast")["body"]["trace_id"], trace_id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dead_peer_trips_circuit() {
+        let services = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cluster = Cluster::new(3, {
+            let services = Arc::clone(&services);
+            move || {
+                let service = BroadcastService::default();
+                services.lock().unwrap().push(service.clone());
+                service
+            }
+        })
+        .await;
+        let n0 = services.lock().unwrap()[0].clone();
+
+        // Nothing gets through to n2. Note when n0 tried.
+        let start = tokio::time::Instant::now();
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        cluster.drop_frames({
+            let attempts = Arc::clone(&attempts);
+            move |src, dest, _| {
+                if src == "n0" && dest == "n2" {
+                    attempts.lock().unwrap().push(start.elapsed());
+                }
+                dest == "n2"
+            }
+        });
+
+        let client = cluster.client();
+        for value in 0..120 {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            client.rpc("n0", broadcast).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(n0.circuits().get("n2"), Some(&CircuitState::Open));
+        assert_eq!(n0.circuits().get("n1"), Some(&CircuitState::Closed));
+
+        // Forwards take FORWARD_ACK_TIMEOUT to count as failures, so the circuit opens a little
+        // after that.
+        let attempts_between = |from: u64, to: u64| {
+            let range = Duration::from_secs(from)..Duration::from_secs(to);
+            let attempts = attempts.lock().unwrap();
+            attempts.iter().filter(|at| range.contains(at)).count()
+        };
+        let closed = attempts_between(0, 4);
+        let open = attempts_between(8, 12);
+        assert!(open * 5 < closed, "{closed} sends before, {open} after");
+        assert!(open > 0, "no probes while open");
+
+        cluster.heal();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(n0.circuits().get("n2"), Some(&CircuitState::Closed));
+        let reply = client
+            .rpc("n2", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        assert_eq!(reply["messages"].as_array().unwrap().len(), 120);
+    }
+
+    /// Broadcast 100 values to n0 while n2 is cut off from its peers, then heal. Returns how many
+    /// values were gossiped to n2 in the meantime, and how many it reads once healed.
+    async fn partitioned_broadcast(options: BroadcastOptions) -> (usize, usize) {
+        let cluster = Cluster::new(3, move || BroadcastService::new(options.clone())).await;
+        let gossiped = Arc::new(AtomicU64::new(0));
+        cluster.drop_frames({
+            let gossiped = Arc::clone(&gossiped);
+            move |src, dest, frame| {
+                let cut =
+                    src.starts_with('n') && dest.starts_with('n') && (src == "n2" || dest == "n2");
+                if cut && dest == "n2" && frame["body"]["type"] == "gossip" {
+                    let seen = frame["body"]["seen"].as_array().map_or(0, Vec::len);
+                    gossiped.fetch_add(seen as u64, Ordering::Relaxed);
+                }
+                cut
+            }
+        });
+
+        let client = cluster.client();
+        for value in 0..100 {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            client.rpc("n0", broadcast).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        cluster.heal();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        let reply = client
+            .rpc("n2", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        let read = reply["messages"].as_array().unwrap().len();
+        (gossiped.load(Ordering::Relaxed) as usize, read)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cold_values_reach_partitioned_node() {
+        let (untiered, read) = partitioned_broadcast(BroadcastOptions {
+            anti_entropy_every: Some(8),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(read, 100);
+
+        let (tiered, read) = partitioned_broadcast(BroadcastOptions {
+            anti_entropy_every: Some(8),
+            hot_rounds: Some(4),
+            ..Default::default()
+        })
+        .await;
+        // Values that went cold during the partition only reach n2 through anti-entropy.
+        assert_eq!(read, 100);
+        assert!(
+            tiered * 2 < untiered,
+            "{tiered} values gossiped to n2, {untiered} untiered"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queued_broadcasts_are_forwarded_together() {
+        let services = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = NodeOptions {
+            execution: Some(crate::node::Execution::OrderedPool { workers: 1 }),
+            ..Default::default()
+        };
+        let cluster = Cluster::with_options(3, LatencyMatrix::default(), options, {
+            let services = Arc::clone(&services);
+            move || {
+                let service = BroadcastService::default();
+                services.lock().unwrap().push(service.clone());
+                service
+            }
+        })
+        .await;
+        let n0 = services.lock().unwrap()[0].clone();
+
+        let client = cluster.client();
+        let replies = futures::future::join_all((0..200).map(|value| {
+            let broadcast = serde_json::json!({ "type": "broadcast", "message": value });
+            client.rpc("n0", broadcast)
+        }))
+        .await;
+        for reply in replies {
+            assert_eq!(reply.expect("reply")["type"], "broadcast_ok");
+        }
+        let rounds = n0.forward_rounds();
+        assert!(rounds > 0 && rounds < 200, "{rounds} forward rounds");
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for node in ["n1", "n2"] {
+            let reply = client
+                .rpc(node, serde_json::json!({ "type": "read" }))
+                .await
+                .unwrap();
+            assert_eq!(reply["messages"].as_array().unwrap().len(), 200, "{node}");
+        }
+    }
+}
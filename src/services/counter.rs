@@ -1,59 +1,256 @@
-use std::collections::{HashMap, HashSet};
+//! Maelstrom's `g-counter` workload: a grow-only counter replicated with [`Gossip`].
+//!
+//! Each node counts the adds it was sent under a key of its own, and the counter's value is the
+//! sum over every key. Rounds send peers the totals they are missing in `replicate` messages,
+//! acknowledged like the g-set's, see [`super::g_set`]. A restarted node counts under a new key,
+//! since it starts from zero and its old total is only raised by adds it no longer remembers.
+//!
+//! Unlike a set's, an add isn't idempotent: an `add` delivered twice would be counted twice. Each
+//! one is only applied the first time its client and message ID are seen.
+
+use std::collections::BTreeMap;
+use std::hash::BuildHasher as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
-use snafu::Snafu;
+use snafu::{OptionExt as _, Snafu};
 
+use super::gossip::{mix, CvState, Gossip, GossipConfig, GossipOptions};
+use crate::async_dashmap::AsyncDashMap;
+use crate::audit::StateDigest;
+use crate::config::Configurable;
+use crate::describe::{Describe, ServiceDescription};
 pub use crate::error::*;
-use crate::message::Message;
-use crate::node::{Node, NodeState};
-
-/// A Maelstrom error code.
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
-#[serde(rename_all = "snake_case")]
-#[repr(u64)]
-pub enum ErrorCode {
-    Timeout = 0,
-    NodeNotFound = 1,
-    NotSupported = 10,
-    TemporarilyUnavailable = 11,
-    MalformedRequest = 12,
-    Crash = 13,
-    Abort = 14,
-    KeyDoesNotExist = 20,
-    KeyAlreadyExists = 21,
-    PreconditionFailed = 22,
-    TxnConflict = 30,
+use crate::macros::define_service_messages;
+use crate::message::{check_strict, DataOrInit, ErrorCode, Message, MessageId, Subsystem};
+use crate::node::{in_subsystem, Node, NodeState, ServiceGauges};
+use crate::timer::TimerSpec;
+
+/// How long to wait for a peer to acknowledge a `replicate` before forgetting about it. The next
+/// round sends the totals again either way.
+const REPLICATE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an applied `add` is remembered, so that a copy arriving later isn't counted again.
+/// Copies of a message arrive close together, see Maelstrom's `duplicate` nemesis.
+const APPLIED_TTL: Duration = Duration::from_secs(30);
+
+const EXPIRE_TIMER: &str = "expire";
+
+/// Every this many rounds, a target is sent everything, see [`GossipOptions::anti_entropy_every`].
+pub const DEFAULT_ANTI_ENTROPY_EVERY: u64 = 20;
+
+/// The totals of a grow-only counter, by the key each was counted under. Every key is only ever
+/// raised by the node counting under it, so merging keeps the larger of two totals.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter(BTreeMap<String, u64>);
+
+impl GCounter {
+    /// The counter's value: every total added up.
+    pub fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+impl FromIterator<(String, u64)> for GCounter {
+    fn from_iter<I: IntoIterator<Item = (String, u64)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl CvState for GCounter {
+    fn merge(&mut self, other: &Self) -> Self {
+        let mut new = Self::default();
+        for (key, &total) in &other.0 {
+            if self.0.get(key).is_some_and(|&ours| ours >= total) {
+                continue;
+            }
+            self.0.insert(key.clone(), total);
+            new.0.insert(key.clone(), total);
+        }
+        new
+    }
+
+    fn delta_since(&self, known: &Self) -> Self {
+        self.0
+            .iter()
+            .filter(|(key, total)| known.0.get(*key).is_none_or(|known| known < *total))
+            .map(|(key, total)| (key.clone(), *total))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn chunks(self, size: usize) -> Vec<Self> {
+        if self.0.is_empty() {
+            return vec![self];
+        }
+        let totals = self.0.into_iter().collect::<Vec<_>>();
+        totals
+            .chunks(size.max(1))
+            .map(|chunk| chunk.iter().cloned().collect())
+            .collect()
+    }
+
+    fn digest(&self) -> u64 {
+        // Fixed keys, so every node hashes a total the same way.
+        let hasher = std::hash::BuildHasherDefault::<std::hash::DefaultHasher>::default();
+        self.0.iter().fold(0, |digest, entry| {
+            digest.wrapping_add(mix(hasher.hash_one(entry)))
+        })
+    }
+}
+
+define_service_messages! {
+    /// The message body of a Maelstrom message.
+    pub enum CounterMessage {
+        Error { code: ErrorCode, text: String },
+
+        #[mutating]
+        Add { delta: u64 } => AddOk,
+        AddOk,
+        Read => ReadOk,
+        ReadOk { value: u64 },
+        #[peer]
+        Replicate { totals: GCounter, digest: u64 } => ReplicateOk,
+        #[peer]
+        ReplicateOk { digest: u64 },
+    }
+
+    #[derive(Debug, Snafu)]
+    pub enum CounterError {
+        #[code(MalformedRequest)]
+        #[snafu(display("Missing message ID"))]
+        MissingMessageId,
+    }
+}
+
+#[derive(Clone)]
+pub struct CounterService {
+    inner: Arc<CounterInner>,
+}
+
+struct CounterInner {
+    gossip: Gossip<GCounter>,
+    /// The key this node counts under, new for every incarnation.
+    key: String,
+    /// What this node has counted under `key`. Bumped before the total is merged into the
+    /// gossiped state, so that of two concurrent adds, the larger total wins.
+    total: AtomicU64,
+    /// The `add`s applied in the last [`APPLIED_TTL`], by client and message ID, and when.
+    applied: AsyncDashMap<(String, MessageId), tokio::time::Instant>,
 }
 
-/// The message body of a Maelstrom message.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum CounterMessage {
-    Error { code: ErrorCode, text: String },
+impl Default for CounterService {
+    fn default() -> Self {
+        Self::new(GossipOptions {
+            anti_entropy_every: Some(DEFAULT_ANTI_ENTROPY_EVERY),
+            ..Default::default()
+        })
+    }
+}
 
-    Add { delta: u64 },
-    AddOk,
-    Read,
-    ReadOk { value: u64 },
+/// The [`GossipOptions`] that can be set in a config file, see [`crate::config`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CounterConfig {
+    pub gossip: GossipConfig,
+    pub anti_entropy_every: Option<u64>,
 }
 
-#[derive(Default, Clone)]
-pub struct CounterService {}
-
-#[derive(Debug, Snafu)]
-pub enum CounterError {
-    #[snafu(whatever, display("{message}"))]
-    Whatever {
-        message: String,
-        #[snafu(source(from(Box<dyn std::error::Error+Send+Sync+ 'static>, Some)))]
-        source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
-    },
+impl Default for CounterConfig {
+    fn default() -> Self {
+        Self {
+            gossip: GossipConfig::default(),
+            anti_entropy_every: Some(DEFAULT_ANTI_ENTROPY_EVERY),
+        }
+    }
+}
+
+impl Describe for CounterService {
+    fn describe() -> ServiceDescription {
+        ServiceDescription::new("counter", CounterMessage::describe(), CounterError::CODES)
+    }
+}
+
+impl Configurable for CounterService {
+    const NAME: &'static str = "counter";
+
+    type Config = CounterConfig;
+
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String> {
+        if config.anti_entropy_every == Some(0) {
+            return Err("anti_entropy_every must be at least 1, or null".into());
+        }
+        Ok(Self::new(GossipOptions {
+            params: config.gossip.params()?,
+            anti_entropy_every: config.anti_entropy_every,
+            ..Default::default()
+        }))
+    }
 }
 
-impl Into<Error<Self>> for CounterError {
-    fn into(self) -> Error<Self> {
-        Error::Node { source: self }
+impl CounterService {
+    pub fn new(options: GossipOptions) -> Self {
+        Self {
+            inner: Arc::new(CounterInner {
+                gossip: Gossip::new(options),
+                key: ulid::Ulid::new().to_string(),
+                total: AtomicU64::new(0),
+                applied: AsyncDashMap::new(),
+            }),
+        }
+    }
+
+    /// Count `delta` from `client`'s add `id`, unless a copy of it was counted already. The first
+    /// of several copies handled at once is the one counted.
+    async fn apply(&self, client: &str, id: MessageId, delta: u64) {
+        let now = tokio::time::Instant::now();
+        let key = (client.to_owned(), id);
+        if self.inner.applied.insert_if_absent(key, now).await {
+            self.add(delta);
+        }
+    }
+
+    /// Count `delta` more under this node's key.
+    fn add(&self, delta: u64) {
+        let total = self.inner.total.fetch_add(delta, Ordering::Relaxed) + delta;
+        let key = self.inner.key.clone();
+        self.inner
+            .gossip
+            .update(&GCounter::from_iter([(key, total)]));
+    }
+
+    /// Send each of `targets` the totals it is missing, returning how many each was sent.
+    async fn replicate(
+        &self,
+        node: &NodeState<Self>,
+        targets: Vec<String>,
+    ) -> crate::Result<BTreeMap<String, u64>, CounterError> {
+        in_subsystem(Subsystem::Gossip, async {
+            let gossip = &self.inner.gossip;
+            let digest = gossip.digest();
+            let mut sent = BTreeMap::new();
+            for peer in targets {
+                let Some(chunks) = gossip.delta_for(&peer) else {
+                    continue;
+                };
+                let count = sent.entry(peer.clone()).or_default();
+                for totals in chunks.into_iter().filter(|chunk| !chunk.is_empty()) {
+                    *count += totals.len() as u64;
+                    let id = node.reserve_message_id();
+                    gossip.track(id, &peer, totals.clone());
+                    let replicate = CounterMessage::Replicate { totals, digest };
+                    node.send_message_with_id(peer.as_str(), id, None, DataOrInit::Data(replicate))
+                        .await?;
+                }
+            }
+            Ok(sent)
+        })
+        .await
     }
 }
 
@@ -61,12 +258,122 @@ impl Node for CounterService {
     type Message = CounterMessage;
     type Error = CounterError;
 
+    fn message_tags(&self) -> &[&'static str] {
+        CounterMessage::TAGS
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        Some(message.tag())
+    }
+
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        message.is_mutating()
+    }
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    fn check_strict(&self, body: &serde_json::Value) -> std::result::Result<(), String> {
+        match body["type"].as_str() {
+            Some("add") => check_strict::<strict::Add>(body),
+            Some("read") => check_strict::<strict::Read>(body),
+            _ => Err(format!("type: unexpected request type {}", body["type"])),
+        }
+    }
+
+    fn state_digest(&self) -> Option<StateDigest> {
+        Some(self.inner.gossip.state_digest())
+    }
+
+    fn gauges(&self) -> ServiceGauges {
+        self.inner.gossip.gauges()
+    }
+
+    async fn flush_gossip(
+        &self,
+        node: &NodeState<Self>,
+        full: bool,
+    ) -> crate::Result<Option<BTreeMap<String, u64>>, Self::Error> {
+        let targets = self.inner.gossip.flush(full);
+        self.replicate(node, targets).await.map(Some)
+    }
+
+    fn reconfigure(&self, params: &serde_json::Value) -> std::result::Result<(), String> {
+        self.inner.gossip.tune(params)
+    }
+
+    async fn init(
+        &self,
+        node: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> crate::Result<(), Self::Error> {
+        self.inner.gossip.init(&node.id(), &node_ids);
+
+        let service = self.clone();
+        let gossip_node = node.clone();
+        node.spawn(async move {
+            let round = || async {
+                let targets = service.inner.gossip.targets();
+                if let Err(e) = service.replicate(&gossip_node, targets).await {
+                    tracing::warn!("Failed to replicate: {}", snafu::Report::from_error(e));
+                }
+            };
+            service.inner.gossip.run(round).await;
+        });
+        Ok(())
+    }
+
+    fn timers(&self) -> Vec<TimerSpec> {
+        vec![TimerSpec::new(EXPIRE_TIMER, REPLICATE_ACK_TIMEOUT)]
+    }
+
+    async fn on_timer(&self, name: &str, _node: &NodeState<Self>) -> Result<(), Self::Error> {
+        match name {
+            EXPIRE_TIMER => {
+                self.inner.gossip.expire(REPLICATE_ACK_TIMEOUT);
+                self.inner
+                    .applied
+                    .retain(|_, applied| applied.elapsed() < APPLIED_TTL);
+            }
+            _ => tracing::warn!("Unknown timer {}", name),
+        }
+        Ok(())
+    }
+
     async fn handle_message(
         &self,
         Message { src, body, .. }: Message<Self::Message>,
         node: &NodeState<Self>,
     ) -> Result<(), Self::Error> {
+        let gossip = &self.inner.gossip;
         match body.data {
+            CounterMessage::Add { delta } => {
+                let re = body.id.context(MissingMessageIdSnafu)?;
+                self.apply(&src, re, delta).await;
+                node.reply(src, re, CounterMessage::AddOk).await?;
+            }
+            CounterMessage::Read => {
+                let re = body.id.context(MissingMessageIdSnafu)?;
+                let value = gossip.state().value();
+                node.reply(src, re, CounterMessage::ReadOk { value })
+                    .await?;
+            }
+            CounterMessage::Replicate { totals, digest } => {
+                gossip.receive(&src, totals).await;
+                gossip.observe_digest(&src, digest);
+                if let Some(re) = body.id {
+                    let digest = gossip.digest();
+                    node.reply(src, re, CounterMessage::ReplicateOk { digest })
+                        .await?;
+                }
+            }
+            CounterMessage::ReplicateOk { digest } => {
+                if let Some(re) = body.re {
+                    gossip.acknowledge(&src, re);
+                }
+                gossip.observe_digest(&src, digest);
+            }
             unexpected => {
                 tracing::warn!("Unexpected message: {:?}", unexpected);
             }
@@ -74,3 +381,117 @@ impl Node for CounterService {
         Ok(())
     }
 }
+
+/// Strict mirrors of the client requests, see [`Node::check_strict`]. Only ever deserialized, to
+/// check the shape of a request.
+pub mod strict {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Add {
+        pub delta: u64,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Read {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{checker, workload, Cluster};
+
+    #[test]
+    fn test_merge_keeps_larger_totals() {
+        let totals = |entries: &[(&str, u64)]| {
+            entries
+                .iter()
+                .map(|(key, total)| (key.to_string(), *total))
+                .collect::<GCounter>()
+        };
+        let mut ours = totals(&[("a", 3), ("b", 5)]);
+        let new = ours.merge(&totals(&[("a", 4), ("b", 2), ("c", 1)]));
+        assert_eq!(new, totals(&[("a", 4), ("c", 1)]));
+        assert_eq!(ours, totals(&[("a", 4), ("b", 5), ("c", 1)]));
+        assert_eq!(ours.value(), 10);
+        assert_eq!(
+            ours.delta_since(&totals(&[("a", 4), ("b", 1)])),
+            totals(&[("b", 5), ("c", 1)])
+        );
+        assert_eq!(
+            ours.digest(),
+            totals(&[("c", 1), ("b", 5), ("a", 4)]).digest()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_adds_converge() {
+        let cluster = Cluster::new(3, CounterService::default).await;
+        let history = workload::counter(&cluster, 50.0, Duration::from_secs(5)).await;
+        let report = checker::counter(&history);
+        assert!(report.is_valid(), "{report:?}");
+
+        // Once everyone holds every total, rounds send nothing.
+        cluster.reset_traffic();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(cluster.traffic().by_type().get("replicate"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicate_adds_are_counted_once() {
+        let cluster = Cluster::new(2, CounterService::default).await;
+        cluster.duplicate_frames(|_, _, frame| frame["body"]["type"] == "add");
+        let client = cluster.client();
+        for delta in 1..=4 {
+            let add = serde_json::json!({ "type": "add", "delta": delta });
+            client.rpc("n0", add).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for node in ["n0", "n1"] {
+            let reply = client
+                .rpc(node, serde_json::json!({ "type": "read" }))
+                .await
+                .unwrap();
+            assert_eq!(reply["value"], 10, "{node}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_copies_are_counted_once() {
+        let service = CounterService::default();
+        let copies = (0..4)
+            .map(|_| {
+                let service = service.clone();
+                tokio::spawn(async move { service.apply("c1", 1, 5).await })
+            })
+            .collect::<Vec<_>>();
+        for copy in copies {
+            copy.await.unwrap();
+        }
+        service.apply("c2", 1, 1).await;
+        assert_eq!(service.inner.gossip.state().value(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarted_node_counts_on() {
+        let mut cluster = Cluster::new(2, CounterService::default).await;
+        let client = cluster.client();
+        let add = |delta: u64| serde_json::json!({ "type": "add", "delta": delta });
+        let read = serde_json::json!({ "type": "read" });
+        client.rpc("n1", add(5)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // n1 comes back knowing nothing; what it counts now adds to what it counted before. n0
+        // believes n1 still holds its old total, so only anti-entropy sends it back.
+        cluster.restart("n1", CounterService::default()).await;
+        client.rpc("n1", add(2)).await.unwrap();
+        let rounds = DEFAULT_ANTI_ENTROPY_EVERY as u32 + 4;
+        tokio::time::sleep(crate::services::gossip::DEFAULT_GOSSIP_INTERVAL * rounds).await;
+        for node in ["n0", "n1"] {
+            let reply = client.rpc(node, read.clone()).await.unwrap();
+            assert_eq!(reply["value"], 7, "{node}");
+        }
+    }
+}
@@ -1,62 +1,57 @@
-use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::sync::Arc;
+
 use snafu::Snafu;
 
+use crate::config::Configurable;
+use crate::describe::{Describe, ServiceDescription};
 pub use crate::error::*;
-use crate::message::Message;
-use crate::node::{Node, NodeState};
-
-/// A Maelstrom error code.
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
-#[serde(rename_all = "snake_case")]
-#[repr(u64)]
-pub enum ErrorCode {
-    Timeout = 0,
-    NodeNotFound = 1,
-    NotSupported = 10,
-    TemporarilyUnavailable = 11,
-    MalformedRequest = 12,
-    Crash = 13,
-    Abort = 14,
-    KeyDoesNotExist = 20,
-    KeyAlreadyExists = 21,
-    PreconditionFailed = 22,
-    TxnConflict = 30,
-}
+use crate::macros::define_service_messages;
+use crate::message::{ArcValue, DataOrInit, ErrorCode, Message};
+use crate::node::{InlineResult, Node, NodeState};
 
 // Valid message for testing: { "src": "a", "dest": "b", "body": { "type": "error", "code": 1, "text": "test", "msg_id": 1, "in_reply_to": 1 }}
 // { "src": "a", "dest": "b", "body": { "type": "init", "node_id": "a", "node_ids": ["a", "b"] }}
 
-/// The message body of a Maelstrom message.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "type")]
-pub enum EchoServiceMessage {
-    Error { code: ErrorCode, text: String },
+define_service_messages! {
+    /// The message body of a Maelstrom message.
+    pub enum EchoServiceMessage {
+        Error { code: ErrorCode, text: String },
 
-    // Application messages
-    Echo { echo: serde_json::Value },
-    EchoOk { echo: serde_json::Value },
+        // Application messages
+        // Shared, so that echoing a large payload doesn't copy it.
+        Echo { echo: ArcValue } => EchoOk,
+        EchoOk { echo: ArcValue },
+    }
+
+    #[derive(Debug, Snafu)]
+    pub enum EchoServiceError {
+        #[code(MalformedRequest)]
+        #[snafu(display("Missing message ID"))]
+        MissingMessageId,
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct EchoService;
 
-#[derive(Debug, Snafu)]
-pub enum EchoServiceError {
-    #[snafu(display("Missing message ID"))]
-    MissingMessageId,
-    #[snafu(whatever, display("{message}"))]
-    Whatever {
-        message: String,
-        #[snafu(source(from(Box<dyn std::error::Error+Send+Sync+ 'static>, Some)))]
-        source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
-    },
+impl Describe for EchoService {
+    fn describe() -> ServiceDescription {
+        ServiceDescription::new(
+            "echo",
+            EchoServiceMessage::describe(),
+            EchoServiceError::CODES,
+        )
+    }
 }
 
-impl Into<Error<Self>> for EchoServiceError {
-    fn into(self) -> Error<Self> {
-        Error::Node { source: self }
+impl Configurable for EchoService {
+    const NAME: &'static str = "echo";
+
+    /// Nothing to configure.
+    type Config = ();
+
+    fn from_config((): Self::Config) -> std::result::Result<Self, String> {
+        Ok(Self)
     }
 }
 
@@ -64,6 +59,34 @@ impl Node for EchoService {
     type Message = EchoServiceMessage;
     type Error = EchoServiceError;
 
+    fn message_tags(&self) -> &[&'static str] {
+        EchoServiceMessage::TAGS
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        Some(message.tag())
+    }
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    /// Echoes are answered inline, unless the output is busy or the request is malformed.
+    fn try_handle_inline(
+        &self,
+        Message { src, body, .. }: &Message<Self::Message>,
+        node: &NodeState<Self>,
+    ) -> InlineResult {
+        let (EchoServiceMessage::Echo { echo }, Some(id)) = (&body.data, body.id) else {
+            return InlineResult::Fallback;
+        };
+        let reply = EchoServiceMessage::EchoOk { echo: echo.clone() };
+        match node.try_send_message(Arc::clone(src), Some(id), DataOrInit::Data(reply)) {
+            Ok(Some(_)) => InlineResult::Handled,
+            Ok(None) | Err(_) => InlineResult::Fallback,
+        }
+    }
+
     async fn handle_message(
         &self,
         Message { src, body, .. }: Message<Self::Message>,
@@ -0,0 +1,396 @@
+//! Maelstrom's `g-set` workload: a grow-only set replicated with [`Gossip`].
+//!
+//! Each round, a node sends every target what it is missing in `replicate` messages. A peer
+//! acknowledges each one with its digest; an ack marks the delta as held by the peer, and a digest
+//! matching ours marks everything we have as held.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use snafu::Snafu;
+
+use serde::{Deserialize, Serialize};
+
+use super::gossip::{CvState as _, GSet, Gossip, GossipConfig, GossipOptions};
+use crate::audit::StateDigest;
+use crate::config::Configurable;
+use crate::describe::{Describe, ServiceDescription};
+pub use crate::error::*;
+use crate::macros::define_service_messages;
+use crate::message::{DataOrInit, ErrorCode, Message, Subsystem};
+use crate::node::{in_subsystem, Node, NodeState, ServiceGauges};
+use crate::timer::TimerSpec;
+
+/// How long to wait for a peer to acknowledge a `replicate` before forgetting about it. The next
+/// round sends the delta again either way.
+const REPLICATE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const EXPIRE_TIMER: &str = "expire";
+
+/// Every this many rounds, a target is sent everything, see [`GossipOptions::anti_entropy_every`].
+pub const DEFAULT_ANTI_ENTROPY_EVERY: u64 = 20;
+
+define_service_messages! {
+    /// The message body of a Maelstrom message.
+    pub enum GSetMessage {
+        Error { code: ErrorCode, text: String },
+
+        #[mutating]
+        Add { element: u64 } => AddOk,
+        AddOk,
+        Read => ReadOk,
+        ReadOk { value: Vec<u64> },
+        #[peer]
+        Replicate { elements: GSet<u64>, digest: u64 } => ReplicateOk,
+        #[peer]
+        ReplicateOk { digest: u64 },
+    }
+
+    #[derive(Debug, Snafu)]
+    pub enum GSetError {
+        #[code(MalformedRequest)]
+        #[snafu(display("Missing message ID"))]
+        MissingMessageId,
+    }
+}
+
+#[derive(Clone)]
+pub struct GSetService {
+    gossip: std::sync::Arc<Gossip<GSet<u64>>>,
+}
+
+/// A node that a [`GSetService`] can run in: itself, or a service that hands it the g-set's
+/// messages, like [`ProxiedGSetService`](super::proxy::ProxiedGSetService).
+pub trait GSetHost: Node<Message: From<GSetMessage>, Error: From<GSetError>> {}
+
+impl<N: Node<Message: From<GSetMessage>, Error: From<GSetError>>> GSetHost for N {}
+
+impl Default for GSetService {
+    fn default() -> Self {
+        Self::new(GossipOptions {
+            anti_entropy_every: Some(DEFAULT_ANTI_ENTROPY_EVERY),
+            ..Default::default()
+        })
+    }
+}
+
+/// The [`GossipOptions`] that can be set in a config file, see [`crate::config`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GSetConfig {
+    pub gossip: GossipConfig,
+    pub anti_entropy_every: Option<u64>,
+}
+
+impl Default for GSetConfig {
+    fn default() -> Self {
+        Self {
+            gossip: GossipConfig::default(),
+            anti_entropy_every: Some(DEFAULT_ANTI_ENTROPY_EVERY),
+        }
+    }
+}
+
+impl Describe for GSetService {
+    fn describe() -> ServiceDescription {
+        ServiceDescription::new("g-set", GSetMessage::describe(), GSetError::CODES)
+    }
+}
+
+impl Configurable for GSetService {
+    const NAME: &'static str = "g_set";
+
+    type Config = GSetConfig;
+
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String> {
+        if config.anti_entropy_every == Some(0) {
+            return Err("anti_entropy_every must be at least 1, or null".into());
+        }
+        Ok(Self::new(GossipOptions {
+            params: config.gossip.params()?,
+            anti_entropy_every: config.anti_entropy_every,
+            ..Default::default()
+        }))
+    }
+}
+
+impl GSetService {
+    pub fn new(options: GossipOptions) -> Self {
+        Self {
+            gossip: std::sync::Arc::new(Gossip::new(options)),
+        }
+    }
+
+    /// Send each of `targets` what it is missing, returning how many elements each was sent.
+    async fn replicate<N: GSetHost>(
+        &self,
+        node: &NodeState<N>,
+        targets: Vec<String>,
+    ) -> crate::Result<BTreeMap<String, u64>, N::Error> {
+        in_subsystem(Subsystem::Gossip, async {
+            let digest = self.gossip.digest();
+            let mut sent = BTreeMap::new();
+            for peer in targets {
+                let Some(chunks) = self.gossip.delta_for(&peer) else {
+                    continue;
+                };
+                let count = sent.entry(peer.clone()).or_default();
+                for elements in chunks.into_iter().filter(|chunk| !chunk.is_empty()) {
+                    *count += elements.len() as u64;
+                    let id = node.reserve_message_id();
+                    self.gossip.track(id, &peer, elements.clone());
+                    let replicate = GSetMessage::Replicate { elements, digest };
+                    node.send_message_with_id(
+                        peer.as_str(),
+                        id,
+                        None,
+                        DataOrInit::Data(replicate.into()),
+                    )
+                    .await?;
+                }
+            }
+            Ok(sent)
+        })
+        .await
+    }
+
+    /// Join the cluster and start gossiping, as [`Node::init`] on `node`.
+    pub async fn start<N: GSetHost>(
+        &self,
+        node: &NodeState<N>,
+        node_ids: Vec<String>,
+    ) -> crate::Result<(), N::Error> {
+        self.gossip.init(&node.id(), &node_ids);
+
+        let service = self.clone();
+        let gossip_node = node.clone();
+        node.spawn(async move {
+            let round = || async {
+                let targets = service.gossip.targets();
+                if let Err(e) = service.replicate(&gossip_node, targets).await {
+                    tracing::warn!("Failed to replicate: {}", snafu::Report::from_error(e));
+                }
+            };
+            service.gossip.run(round).await;
+        });
+        Ok(())
+    }
+
+    /// As [`Node::flush_gossip`] on `node`.
+    pub async fn flush<N: GSetHost>(
+        &self,
+        node: &NodeState<N>,
+        full: bool,
+    ) -> crate::Result<Option<BTreeMap<String, u64>>, N::Error> {
+        let targets = self.gossip.flush(full);
+        self.replicate(node, targets).await.map(Some)
+    }
+
+    /// As [`Node::on_timer`], for the timers in [`Node::timers`].
+    pub fn handle_timer(&self, name: &str) {
+        match name {
+            EXPIRE_TIMER => {
+                self.gossip.expire(REPLICATE_ACK_TIMEOUT);
+            }
+            _ => tracing::warn!("Unknown timer {}", name),
+        }
+    }
+
+    /// Handle a message, as [`Node::handle_message`] on `node`.
+    pub async fn handle<N: GSetHost>(
+        &self,
+        Message { src, body, .. }: Message<GSetMessage>,
+        node: &NodeState<N>,
+    ) -> Result<(), N::Error> {
+        let missing_id = || crate::Error::node(GSetError::MissingMessageId);
+        match body.data {
+            GSetMessage::Add { element } => {
+                let re = body.id.ok_or_else(missing_id)?;
+                self.gossip.update(&GSet::from_iter([element]));
+                node.reply(src, re, GSetMessage::AddOk.into()).await?;
+            }
+            GSetMessage::Read => {
+                let re = body.id.ok_or_else(missing_id)?;
+                let mut value = self
+                    .gossip
+                    .state()
+                    .into_inner()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                value.sort_unstable();
+                node.reply(src, re, GSetMessage::ReadOk { value }.into())
+                    .await?;
+            }
+            GSetMessage::Replicate { elements, digest } => {
+                self.gossip.receive(&src, elements).await;
+                self.gossip.observe_digest(&src, digest);
+                if let Some(re) = body.id {
+                    let digest = self.gossip.digest();
+                    node.reply(src, re, GSetMessage::ReplicateOk { digest }.into())
+                        .await?;
+                }
+            }
+            GSetMessage::ReplicateOk { digest } => {
+                if let Some(re) = body.re {
+                    self.gossip.acknowledge(&src, re);
+                }
+                self.gossip.observe_digest(&src, digest);
+            }
+            unexpected => {
+                tracing::warn!("Unexpected message: {:?}", unexpected);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node for GSetService {
+    type Message = GSetMessage;
+    type Error = GSetError;
+
+    fn message_tags(&self) -> &[&'static str] {
+        GSetMessage::TAGS
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        Some(message.tag())
+    }
+
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        message.is_mutating()
+    }
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    fn state_digest(&self) -> Option<StateDigest> {
+        Some(self.gossip.state_digest())
+    }
+
+    fn gauges(&self) -> ServiceGauges {
+        self.gossip.gauges()
+    }
+
+    async fn flush_gossip(
+        &self,
+        node: &NodeState<Self>,
+        full: bool,
+    ) -> crate::Result<Option<BTreeMap<String, u64>>, Self::Error> {
+        self.flush(node, full).await
+    }
+
+    fn reconfigure(&self, params: &serde_json::Value) -> std::result::Result<(), String> {
+        self.gossip.tune(params)
+    }
+
+    async fn init(
+        &self,
+        node: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> crate::Result<(), Self::Error> {
+        self.start(node, node_ids).await
+    }
+
+    fn timers(&self) -> Vec<TimerSpec> {
+        vec![TimerSpec::new(EXPIRE_TIMER, REPLICATE_ACK_TIMEOUT)]
+    }
+
+    async fn on_timer(&self, name: &str, _node: &NodeState<Self>) -> Result<(), Self::Error> {
+        self.handle_timer(name);
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        message: Message<Self::Message>,
+        node: &NodeState<Self>,
+    ) -> Result<(), Self::Error> {
+        self.handle(message, node).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Cluster;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_elements_spread() {
+        let cluster = Cluster::new(3, GSetService::default).await;
+        let client = cluster.client();
+        for (element, node) in [(1, "n0"), (2, "n1"), (3, "n2"), (4, "n0")] {
+            let add = serde_json::json!({ "type": "add", "element": element });
+            let reply = client.rpc(node, add).await.unwrap();
+            assert_eq!(reply["type"], "add_ok");
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for node in cluster.node_ids() {
+            let reply = client
+                .rpc(node, serde_json::json!({ "type": "read" }))
+                .await
+                .unwrap();
+            assert_eq!(reply["value"], serde_json::json!([1, 2, 3, 4]), "{node}");
+        }
+
+        // Once everyone holds everything, rounds send nothing.
+        cluster.reset_traffic();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(cluster.traffic().by_type().get("replicate"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_anti_entropy_catches_up_restarted_node() {
+        let service = || {
+            GSetService::new(GossipOptions {
+                anti_entropy_every: Some(4),
+                ..Default::default()
+            })
+        };
+        let mut cluster = Cluster::new(2, service).await;
+        let client = cluster.client();
+        let add = serde_json::json!({ "type": "add", "element": 7 });
+        client.rpc("n0", add).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // n0 knows n1 holds 7, so only anti-entropy sends it again.
+        cluster.restart("n1", service()).await;
+        let read = serde_json::json!({ "type": "read" });
+        assert_eq!(
+            client.rpc("n1", read.clone()).await.unwrap()["value"],
+            serde_json::json!([])
+        );
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            client.rpc("n1", read).await.unwrap()["value"],
+            serde_json::json!([7])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_gossip() {
+        let mut cluster = Cluster::new(2, GSetService::default).await;
+        let client = cluster.client();
+        for element in [1, 2] {
+            let add = serde_json::json!({ "type": "add", "element": element });
+            client.rpc("n0", add).await.unwrap();
+        }
+
+        let flushed = cluster.flush_gossip(false).await;
+        assert_eq!(flushed["n0"], BTreeMap::from([("n1".to_owned(), 2)]));
+        assert_eq!(flushed["n1"], BTreeMap::from([("n0".to_owned(), 0)]));
+        let read = serde_json::json!({ "type": "read" });
+        assert_eq!(
+            client.rpc("n1", read.clone()).await.unwrap()["value"],
+            serde_json::json!([1, 2])
+        );
+
+        // n0 believes n1 already holds everything, so only a full flush sends it again.
+        cluster.restart("n1", GSetService::default()).await;
+        assert_eq!(cluster.flush_gossip(false).await["n0"]["n1"], 0);
+        assert_eq!(cluster.flush_gossip(true).await["n0"]["n1"], 2);
+        assert_eq!(
+            client.rpc("n1", read).await.unwrap()["value"],
+            serde_json::json!([1, 2])
+        );
+    }
+}
@@ -0,0 +1,1336 @@
+//! A reusable engine for replicating join-semilattice state by gossip.
+//!
+//! A service keeps its replicated state in a [`Gossip`], parameterized by a [`CvState`]: a
+//! state-based CRDT whose deltas are states themselves. The engine holds the state, what each peer
+//! is known to hold, and the deltas sent but not yet acknowledged. It works out who to gossip with
+//! and what each of them is missing, but never sends anything itself: the service wraps the deltas
+//! in its own messages, so each service keeps its wire format.
+//!
+//! What a peer is known to hold only ever grows from what it has shown it has: deltas it sent us,
+//! deltas it acknowledged, or a digest matching ours. Anti-entropy, if enabled, periodically forgets
+//! what one peer is known to hold, so the next round sends it everything, repairing whatever was
+//! lost on the way.
+//!
+//! A peer that can't be reached is sent everything it is missing every round, which grows as long
+//! as the partition lasts. With [`GossipOptions::hot_rounds`], rounds only carry what was merged
+//! recently, the hot elements. Older ones, the cold elements, only go to the target that
+//! anti-entropy picked, so a peer that was unreachable the whole time an element was hot still
+//! gets it, just later.
+//!
+//! Nodes that start together would gossip in lock step, every round's messages arriving at once.
+//! Each node's first round is delayed by a random part of the interval instead, and optionally
+//! every round is jittered and only some of the neighbors are gossiped with each round. The
+//! randomness is seeded from the node ID, so a run can be reproduced.
+//!
+//! Whatever every peer is known to hold is kept once, rather than once per peer, so the
+//! bookkeeping for a long run grows with what is still in flight rather than with the state.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher as _, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng as _, SeedableRng as _};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::StateDigest;
+use crate::message::MessageId;
+use crate::node::ServiceGauges;
+
+/// How many random peers to gossip with each round until a topology arrives.
+pub const DEFAULT_FALLBACK_FANOUT: usize = 3;
+
+/// How often to gossip.
+pub const DEFAULT_GOSSIP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How far [`GossipParams::jitter`] moves a round, as a fraction of the interval either way.
+pub const JITTER: f64 = 0.2;
+
+/// The most deltas waiting for an ack at once, by default. Beyond this, deltas are sent without
+/// tracking.
+pub const DEFAULT_MAX_PENDING: usize = 16 * 1024;
+
+/// The most elements a delta from a peer is merged in at once, see [`Gossip::receive`].
+pub const RECEIVE_CHUNK: usize = 1024;
+
+/// State that replicas converge on by merging: merging is commutative, associative and idempotent.
+pub trait CvState: Clone + Default + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Merge `other` into this state, returning the part of `other` that was new to it.
+    fn merge(&mut self, other: &Self) -> Self;
+
+    /// The part of this state that `known` doesn't already cover.
+    fn delta_since(&self, known: &Self) -> Self;
+
+    /// How many elements make up the state, the unit [`GossipParams::batch_size`] counts in.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Split the state into parts of at most `size` elements. An empty state is one empty part.
+    fn chunks(self, size: usize) -> Vec<Self>;
+
+    /// An order-independent digest, equal for equal states, so that two nodes can tell whether
+    /// they hold the same state without sending it.
+    fn digest(&self) -> u64;
+}
+
+/// A grow-only set. Serialized as a sorted sequence, see [`crate::message::serialize_set`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent, bound(deserialize = "T: Deserialize<'de> + Eq + Hash"))]
+pub struct GSet<T: Eq + Hash>(HashSet<T>);
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> Self {
+        Self(HashSet::new())
+    }
+}
+
+impl<T: Eq + Hash> GSet<T> {
+    pub fn contains(&self, value: &T) -> bool {
+        self.0.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.0.iter()
+    }
+
+    pub fn into_inner(self) -> HashSet<T> {
+        self.0
+    }
+}
+
+impl<T: Eq + Hash + Ord + Serialize> Serialize for GSet<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::message::serialize_set(&self.0, serializer)
+    }
+}
+
+impl<T: Eq + Hash> From<HashSet<T>> for GSet<T> {
+    fn from(set: HashSet<T>) -> Self {
+        Self(set)
+    }
+}
+
+impl<T: Eq + Hash> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<T> CvState for GSet<T>
+where
+    T: Clone + Eq + Hash + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn merge(&mut self, other: &Self) -> Self {
+        other
+            .0
+            .iter()
+            .filter(|value| self.0.insert((*value).clone()))
+            .cloned()
+            .collect()
+    }
+
+    fn delta_since(&self, known: &Self) -> Self {
+        self.0.difference(&known.0).cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn chunks(self, size: usize) -> Vec<Self> {
+        if self.0.is_empty() {
+            return vec![self];
+        }
+        let values = self.0.into_iter().collect::<Vec<_>>();
+        values
+            .chunks(size.max(1))
+            .map(|chunk| chunk.iter().cloned().collect())
+            .collect()
+    }
+
+    fn digest(&self) -> u64 {
+        // Fixed keys, so every node hashes a value the same way.
+        let hasher = std::hash::BuildHasherDefault::<std::hash::DefaultHasher>::default();
+        self.0.iter().fold(0, |digest, value| {
+            digest.wrapping_add(mix(hasher.hash_one(value)))
+        })
+    }
+}
+
+/// The splitmix64 finalizer, so that the digests of nearby values don't cancel out.
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// The gossip parameters that can be changed at runtime with a `tune` message, see
+/// [`GossipParams::tuned`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GossipParams {
+    pub interval: Duration,
+    /// How many random peers to gossip with each round until a topology arrives.
+    pub fallback_fanout: usize,
+    /// The most elements sent to a peer in one gossip message. Larger deltas are split over
+    /// several messages. Unlimited if `None`.
+    pub batch_size: Option<usize>,
+    /// Move each round by up to [`JITTER`] of the interval, either way.
+    pub jitter: bool,
+    /// Gossip with only some of the neighbors each round, taking turns so that every neighbor is
+    /// gossiped with at least once every this many rounds. Every neighbor, every round, if `None`.
+    pub rotate_over: Option<u64>,
+}
+
+impl Default for GossipParams {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_GOSSIP_INTERVAL,
+            fallback_fanout: DEFAULT_FALLBACK_FANOUT,
+            batch_size: None,
+            jitter: false,
+            rotate_over: None,
+        }
+    }
+}
+
+impl GossipParams {
+    /// The keys of a `tune` message, and the values they accept.
+    pub const TUNABLE: &str = "gossip_interval_ms (10..=60000), fanout (1..=64), \
+                               batch_size (1..=1000000, or null for no limit), jitter (a boolean), \
+                               rotate_over (1..=64, or null for every neighbor every round)";
+
+    /// These parameters with the changes in `tune` applied, e.g. `{"fanout": 5}`. Fails without
+    /// applying anything if any key is unknown or any value out of range.
+    pub fn tuned(&self, tune: &serde_json::Value) -> std::result::Result<Self, String> {
+        let invalid = |problem: String| format!("{problem}; accepted keys: {}", Self::TUNABLE);
+        let changes = tune
+            .as_object()
+            .ok_or_else(|| invalid(format!("expected an object, got {tune}")))?;
+
+        let mut tuned = self.clone();
+        for (key, value) in changes {
+            let in_range = |range: std::ops::RangeInclusive<u64>| {
+                value
+                    .as_u64()
+                    .filter(|value| range.contains(value))
+                    .ok_or_else(|| invalid(format!("invalid {key} {value}")))
+            };
+            match key.as_str() {
+                "gossip_interval_ms" => {
+                    tuned.interval = Duration::from_millis(in_range(10..=60_000)?);
+                }
+                "fanout" => tuned.fallback_fanout = in_range(1..=64)? as usize,
+                "batch_size" if value.is_null() => tuned.batch_size = None,
+                "batch_size" => tuned.batch_size = Some(in_range(1..=1_000_000)? as usize),
+                "jitter" => {
+                    tuned.jitter = value
+                        .as_bool()
+                        .ok_or_else(|| invalid(format!("invalid {key} {value}")))?;
+                }
+                "rotate_over" if value.is_null() => tuned.rotate_over = None,
+                "rotate_over" => tuned.rotate_over = Some(in_range(1..=64)?),
+                _ => return Err(invalid(format!("unknown key {key}"))),
+            }
+        }
+        Ok(tuned)
+    }
+}
+
+/// The [`GossipParams`] to start with, as read from a config file (see [`crate::config`]). The
+/// keys and their ranges are the same as a `tune` message's.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GossipConfig {
+    pub gossip_interval_ms: u64,
+    pub fanout: usize,
+    pub batch_size: Option<usize>,
+    pub jitter: bool,
+    pub rotate_over: Option<u64>,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        let params = GossipParams::default();
+        Self {
+            gossip_interval_ms: params.interval.as_millis() as u64,
+            fanout: params.fallback_fanout,
+            batch_size: params.batch_size,
+            jitter: params.jitter,
+            rotate_over: params.rotate_over,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn params(&self) -> std::result::Result<GossipParams, String> {
+        let tune = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        GossipParams::default().tuned(&tune)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GossipOptions {
+    /// The gossip parameters to start with.
+    pub params: GossipParams,
+    /// Every this many rounds, forget what one random target is known to hold. Off if `None`.
+    pub anti_entropy_every: Option<u64>,
+    /// Send peers only the elements merged in the last this many rounds, except for the target of
+    /// anti-entropy and new neighbors, which are sent everything. Every element, every round, if
+    /// `None`. Ignored without [`GossipOptions::anti_entropy_every`], which is all that sends older
+    /// elements.
+    pub hot_rounds: Option<u64>,
+    /// The most deltas waiting for an ack at once.
+    pub max_pending: usize,
+    /// Delay the first round by a random part of the interval, so that nodes started together
+    /// don't gossip in lock step. On by default.
+    pub phase_offset: bool,
+    /// Seed the randomness with this rather than the node ID.
+    pub seed: Option<u64>,
+}
+
+impl Default for GossipOptions {
+    fn default() -> Self {
+        Self {
+            params: GossipParams::default(),
+            anti_entropy_every: None,
+            hot_rounds: None,
+            max_pending: DEFAULT_MAX_PENDING,
+            phase_offset: true,
+            seed: None,
+        }
+    }
+}
+
+/// What each peer is known to hold, split into the part every peer holds and what each holds
+/// beyond that. The per-peer parts never overlap the common one.
+struct Knowledge<S> {
+    /// Held by every peer: acknowledged by all, in effect.
+    common: S,
+    peers: HashMap<String, S>,
+}
+
+impl<S: CvState> Knowledge<S> {
+    fn add_peer(&mut self, peer: &str) {
+        if self.peers.contains_key(peer) {
+            return;
+        }
+        // The newcomer holds nothing, so nothing is common anymore.
+        self.demote(None);
+        self.peers.insert(peer.to_owned(), S::default());
+    }
+
+    /// What `peer` holds, or `None` if it is not a peer.
+    fn get(&self, peer: &str) -> Option<S> {
+        let mut known = self.common.clone();
+        known.merge(self.peers.get(peer)?);
+        Some(known)
+    }
+
+    /// Forget `peer`. What every peer holds still holds for those left, so the common part stays.
+    fn remove_peer(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    /// Record that `peer` holds `delta`, moving whatever that makes common out of the per-peer
+    /// parts. Returns false if `peer` is not a peer.
+    fn learn(&mut self, peer: &str, delta: &S) -> bool {
+        let delta = delta.delta_since(&self.common);
+        let Some(known) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let new = known.merge(&delta);
+        // Only what `peer` just learned can have become common.
+        let mut common = new;
+        for (other, known) in &self.peers {
+            if other != peer {
+                // The part of `common` that `known` covers.
+                common = common.delta_since(&common.delta_since(known));
+            }
+        }
+        if !common.is_empty() {
+            for known in self.peers.values_mut() {
+                *known = known.delta_since(&common);
+            }
+            self.common.merge(&common);
+        }
+        true
+    }
+
+    /// Forget what `peer` holds, or every peer if `None`, pushing the common part back to the
+    /// others first.
+    fn demote(&mut self, peer: Option<&str>) {
+        if !self.common.is_empty() {
+            for (other, known) in self.peers.iter_mut() {
+                if Some(other.as_str()) != peer {
+                    known.merge(&self.common);
+                }
+            }
+            self.common = S::default();
+        }
+        if let Some(known) = peer.and_then(|peer| self.peers.get_mut(peer)) {
+            *known = S::default();
+        }
+    }
+
+    /// What `peer` is missing from `state`, or `None` if it is not a peer.
+    fn missing(&self, state: &S, peer: &str) -> Option<S> {
+        let known = self.peers.get(peer)?;
+        Some(state.delta_since(&self.common).delta_since(known))
+    }
+}
+
+/// A delta sent to a peer, waiting for its ack.
+struct Pending<S> {
+    peer: String,
+    delta: S,
+    sent: tokio::time::Instant,
+}
+
+/// Replicated state and the bookkeeping to gossip it, see the [module docs](self).
+pub struct Gossip<S: CvState> {
+    options: GossipOptions,
+    /// The current gossip parameters. [`Gossip::run`] watches for changes.
+    params: tokio::sync::watch::Sender<GossipParams>,
+    /// Every other node in the cluster, known from init and changed by
+    /// [`Gossip::set_members`].
+    peers: arc_swap::ArcSwap<Vec<String>>,
+    /// Our neighbors in the topology, or `None` if no topology has arrived yet.
+    neighbors: arc_swap::ArcSwapOption<HashSet<String>>,
+    state: std::sync::Mutex<S>,
+    /// What each peer is known to hold. Always covered by `state`. Locked after `state` when both
+    /// are needed.
+    known: std::sync::Mutex<Knowledge<S>>,
+    /// Deltas sent by the ID of the message that carried them.
+    pending: std::sync::Mutex<HashMap<MessageId, Pending<S>>>,
+    /// What was merged for each of the last [`GossipOptions::hot_rounds`] rounds to send, by the
+    /// first round that sends it, oldest first. Empty if those are off. Locked after `state`, and
+    /// held while starting a round, so that nothing is tagged with a round already pruned.
+    generations: std::sync::Mutex<VecDeque<(u64, S)>>,
+    /// The peers sent cold elements as well this round, see [`GossipOptions::hot_rounds`].
+    repairing: std::sync::Mutex<HashSet<String>>,
+    rounds: AtomicU64,
+    /// Seeded by [`Gossip::init`], see [`GossipOptions::seed`].
+    rng: std::sync::Mutex<StdRng>,
+}
+
+impl<S: CvState> Default for Gossip<S> {
+    fn default() -> Self {
+        Self::new(GossipOptions::default())
+    }
+}
+
+impl<S: CvState> Gossip<S> {
+    pub fn new(options: GossipOptions) -> Self {
+        let seed = options.seed.unwrap_or_default();
+        Self {
+            params: tokio::sync::watch::Sender::new(options.params.clone()),
+            options,
+            peers: arc_swap::ArcSwap::default(),
+            neighbors: arc_swap::ArcSwapOption::empty(),
+            state: std::sync::Mutex::new(S::default()),
+            known: std::sync::Mutex::new(Knowledge {
+                common: S::default(),
+                peers: HashMap::new(),
+            }),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            generations: std::sync::Mutex::new(VecDeque::new()),
+            repairing: std::sync::Mutex::new(HashSet::new()),
+            rounds: AtomicU64::new(0),
+            rng: std::sync::Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Learn the cluster from init: every node in `node_ids` other than `id` is a peer, known to
+    /// hold nothing yet. Seeds the randomness from `id`, unless [`GossipOptions::seed`] is set.
+    pub fn init(&self, id: &str, node_ids: &[String]) {
+        let seed = self.options.seed.unwrap_or_else(|| {
+            // Fixed keys, so that a node ID always gives the same seed.
+            std::hash::BuildHasherDefault::<std::hash::DefaultHasher>::default().hash_one(id)
+        });
+        *self.rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+
+        let peers = node_ids
+            .iter()
+            .filter(|node| *node != id)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut known = self.known.lock().unwrap();
+        for peer in &peers {
+            known.add_peer(peer);
+        }
+        self.peers.store(Arc::new(peers));
+    }
+
+    /// Switch to a new cluster membership, where every node in `node_ids` other than `id` is a
+    /// peer. Returns the peers that joined and those that left, each sorted.
+    ///
+    /// Peers that joined are known to hold nothing. Peers that left are dropped from the
+    /// neighbors, and what they hold and the deltas sent to them are forgotten.
+    pub fn set_members(&self, id: &str, node_ids: &[String]) -> (Vec<String>, Vec<String>) {
+        let peers = node_ids
+            .iter()
+            .filter(|node| *node != id)
+            .cloned()
+            .collect::<Vec<_>>();
+        let previous = self.peers.load_full();
+        let mut joined = peers
+            .iter()
+            .filter(|peer| !previous.contains(peer))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut left = previous
+            .iter()
+            .filter(|peer| !peers.contains(peer))
+            .cloned()
+            .collect::<Vec<_>>();
+        joined.sort();
+        left.sort();
+
+        {
+            let mut known = self.known.lock().unwrap();
+            for peer in &joined {
+                known.add_peer(peer);
+            }
+            for peer in &left {
+                known.remove_peer(peer);
+            }
+        }
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, pending| !left.contains(&pending.peer));
+        if let Some(neighbors) = self.neighbors.load_full() {
+            if left.iter().any(|peer| neighbors.contains(peer)) {
+                let mut neighbors = (*neighbors).clone();
+                neighbors.retain(|neighbor| !left.contains(neighbor));
+                self.neighbors.store(Some(Arc::new(neighbors)));
+            }
+        }
+        self.peers.store(Arc::new(peers));
+        (joined, left)
+    }
+
+    pub fn peers(&self) -> Arc<Vec<String>> {
+        self.peers.load_full()
+    }
+
+    pub fn is_peer(&self, node: &str) -> bool {
+        self.peers.load().iter().any(|peer| peer == node)
+    }
+
+    pub fn params(&self) -> GossipParams {
+        self.params.borrow().clone()
+    }
+
+    /// Apply a `tune` message, see [`GossipParams::tuned`]. Nothing changes if it fails.
+    pub fn tune(&self, tune: &serde_json::Value) -> std::result::Result<(), String> {
+        let mut result = Ok(());
+        self.params
+            .send_if_modified(|params| match params.tuned(tune) {
+                Ok(tuned) => {
+                    *params = tuned;
+                    true
+                }
+                Err(e) => {
+                    result = Err(e);
+                    false
+                }
+            });
+        result
+    }
+
+    pub fn neighbors(&self) -> Option<Arc<HashSet<String>>> {
+        self.neighbors.load_full()
+    }
+
+    /// Switch to a new neighbor set, returning the neighbors that are new, sorted.
+    ///
+    /// What each peer is known to hold is kept whether or not it remains a neighbor: retained
+    /// neighbors only get what they are missing, and removed ones may come back. New neighbors get
+    /// cold elements as well until the next round.
+    pub fn set_neighbors(&self, neighbors: HashSet<String>) -> Vec<String> {
+        let previous = self.neighbors.load_full();
+        let previous = previous.as_deref().cloned().unwrap_or_default();
+        let mut added = neighbors.difference(&previous).cloned().collect::<Vec<_>>();
+        let mut removed = previous.difference(&neighbors).cloned().collect::<Vec<_>>();
+        added.sort();
+        removed.sort();
+        tracing::info!(
+            "Neighbors changed: added {:?}, removed {:?}",
+            added,
+            removed
+        );
+
+        {
+            let mut known = self.known.lock().unwrap();
+            for peer in &added {
+                known.add_peer(peer);
+            }
+        }
+        self.repairing.lock().unwrap().extend(added.iter().cloned());
+        self.neighbors.store(Some(Arc::new(neighbors)));
+        added
+    }
+
+    /// The peers to gossip with this round: our neighbors once a topology has arrived, and a
+    /// random sample of the cluster before that, so state spreads even if no topology ever
+    /// arrives. With [`GossipParams::rotate_over`] set, only this round's turn of the neighbors.
+    pub fn targets(&self) -> Vec<String> {
+        let params = self.params();
+        if let Some(neighbors) = &*self.neighbors.load() {
+            let Some(rounds) = params.rotate_over.filter(|_| !neighbors.is_empty()) else {
+                return neighbors.iter().cloned().collect();
+            };
+            let mut neighbors = neighbors.iter().cloned().collect::<Vec<_>>();
+            neighbors.sort();
+            // Consecutive turns, wrapping around, so that `rounds` of them cover everyone.
+            let turn = neighbors.len().div_ceil(rounds as usize);
+            let start = (self.rounds() as usize * turn) % neighbors.len();
+            neighbors.rotate_left(start);
+            neighbors.truncate(turn);
+            return neighbors;
+        }
+        self.peers()
+            .choose_multiple(&mut *self.rng.lock().unwrap(), params.fallback_fanout)
+            .cloned()
+            .collect()
+    }
+
+    /// Merge `delta` into the state, returning the part of it that was new. Both local updates
+    /// and deltas from peers go through here.
+    pub fn update(&self, delta: &S) -> S {
+        let mut state = self.state.lock().unwrap();
+        let new = state.merge(delta);
+        if self.hot_rounds().is_some() && !new.is_empty() {
+            let mut generations = self.generations.lock().unwrap();
+            // The first round that sends it. Read under the lock, so that generations stay in
+            // round order.
+            let round = self.rounds() + 1;
+            match generations.back_mut() {
+                Some((newest, generation)) if *newest == round => {
+                    generation.merge(&new);
+                }
+                _ => generations.push_back((round, new.clone())),
+            }
+        }
+        new
+    }
+
+    /// Record that `peer` holds `delta`. Returns false if `peer` is not a peer. Call
+    /// [`Gossip::update`] first, so that what a peer is known to hold stays covered by the state.
+    pub fn learn(&self, peer: &str, delta: &S) -> bool {
+        self.known.lock().unwrap().learn(peer, delta)
+    }
+
+    /// Merge `delta` from `peer` into the state and record that `peer` holds it, returning the
+    /// part that was new. What `peer` holds is only recorded if it is a peer.
+    ///
+    /// A large delta, such as a catch-up after a partition, is taken [`RECEIVE_CHUNK`] elements
+    /// at a time, yielding in between, so that other handlers aren't held up until all of it is
+    /// merged. Each chunk is merged before it is recorded, as for [`Gossip::learn`].
+    pub async fn receive(&self, peer: &str, delta: S) -> S {
+        let started = tokio::time::Instant::now();
+        let len = delta.len();
+        let mut new = S::default();
+        for (i, chunk) in delta.chunks(RECEIVE_CHUNK).into_iter().enumerate() {
+            if i > 0 {
+                tokio::task::yield_now().await;
+            }
+            new.merge(&self.update(&chunk));
+            self.learn(peer, &chunk);
+        }
+        if len > RECEIVE_CHUNK {
+            tracing::debug!(
+                "Received {} elements from {} ({} new) in {:?}",
+                len,
+                peer,
+                new.len(),
+                started.elapsed()
+            );
+        }
+        new
+    }
+
+    /// What `peer` is known to hold, or `None` if it is not a peer.
+    pub fn knowledge(&self, peer: &str) -> Option<S> {
+        self.known.lock().unwrap().get(peer)
+    }
+
+    /// What every peer is known to hold.
+    pub fn acked_by_all(&self) -> S {
+        self.known.lock().unwrap().common.clone()
+    }
+
+    /// How many elements are tracked for individual peers, beyond what every peer holds.
+    pub fn tracked(&self) -> usize {
+        let known = self.known.lock().unwrap();
+        known.peers.values().map(S::len).sum()
+    }
+
+    /// A copy of the whole state.
+    pub fn state(&self) -> S {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn digest(&self) -> u64 {
+        self.state.lock().unwrap().digest()
+    }
+
+    /// The digest and size of the state, taken together, for the shutdown audit.
+    pub fn state_digest(&self) -> StateDigest {
+        let state = self.state.lock().unwrap();
+        StateDigest {
+            digest: state.digest(),
+            count: state.len() as u64,
+        }
+    }
+
+    /// If `peer` reports holding state with our digest, it holds everything we do.
+    pub fn observe_digest(&self, peer: &str, digest: u64) {
+        let state = self.state.lock().unwrap();
+        if state.digest() != digest {
+            return;
+        }
+        self.known.lock().unwrap().learn(peer, &state);
+    }
+
+    /// The peers to gossip with in a round run out of turn, for
+    /// [`Node::flush_gossip`](crate::node::Node::flush_gossip): every neighbor, whatever
+    /// [`GossipParams::rotate_over`] says, or every peer before a topology arrives. With `full`,
+    /// what they are known to hold is forgotten, so that they are sent everything, as by
+    /// anti-entropy. Not counted in [`Gossip::rounds`].
+    pub fn flush(&self, full: bool) -> Vec<String> {
+        let targets = match &*self.neighbors.load() {
+            Some(neighbors) => neighbors.iter().cloned().collect::<Vec<_>>(),
+            None => self.peers().to_vec(),
+        };
+        if full {
+            let mut known = self.known.lock().unwrap();
+            for peer in &targets {
+                known.demote(Some(peer));
+            }
+        }
+        targets
+    }
+
+    /// What `peer` is missing, in chunks of at most [`GossipParams::batch_size`] elements, or
+    /// `None` if it is not a peer. A peer that is missing nothing gets one empty chunk. Only the
+    /// hot part, unless `peer` is being repaired, see [`GossipOptions::hot_rounds`].
+    pub fn delta_for(&self, peer: &str) -> Option<Vec<S>> {
+        let mut delta = {
+            let state = self.state.lock().unwrap();
+            let known = self.known.lock().unwrap();
+            known.missing(&state, peer)?
+        };
+        if self.hot_rounds().is_some() && !self.repairing.lock().unwrap().contains(peer) {
+            let mut hot = S::default();
+            for (_, generation) in self.generations.lock().unwrap().iter() {
+                hot.merge(generation);
+            }
+            // The part of `delta` that `hot` covers.
+            delta = delta.delta_since(&delta.delta_since(&hot));
+        }
+        Some(delta.chunks(self.params().batch_size.unwrap_or(usize::MAX)))
+    }
+
+    /// How many elements are hot and how many cold, see [`GossipOptions::hot_rounds`]. Every
+    /// element is hot if those are off.
+    pub fn generations(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        if self.hot_rounds().is_none() {
+            return (state.len(), 0);
+        }
+        let generations = self.generations.lock().unwrap();
+        // Generations never overlap, since each only holds what was new.
+        let hot = generations
+            .iter()
+            .map(|(_, generation)| generation.len())
+            .sum::<usize>();
+        (hot, state.len() - hot)
+    }
+
+    /// [`GossipOptions::hot_rounds`], if they apply.
+    fn hot_rounds(&self) -> Option<u64> {
+        self.options
+            .hot_rounds
+            .filter(|_| self.options.anti_entropy_every.is_some())
+    }
+
+    /// Remember that message `id` carried `delta` to `peer`, until [`Gossip::acknowledge`] or
+    /// [`Gossip::expire`]. Call this before sending, so that the ack can't arrive first.
+    pub fn track(&self, id: MessageId, peer: &str, delta: S) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() < self.options.max_pending {
+            let sent = tokio::time::Instant::now();
+            let peer = peer.to_owned();
+            pending.insert(id, Pending { peer, delta, sent });
+        }
+    }
+
+    /// Handle `peer`'s ack of message `re`: if it carried a tracked delta, the peer holds it.
+    /// Returns whether it did.
+    pub fn acknowledge(&self, peer: &str, re: MessageId) -> bool {
+        let Some(pending) = self.pending.lock().unwrap().remove(&re) else {
+            return false;
+        };
+        if pending.peer != peer {
+            tracing::warn!("{} acknowledged a delta sent to {}", peer, pending.peer);
+            return false;
+        }
+        self.learn(peer, &pending.delta)
+    }
+
+    /// Forget deltas that went unacknowledged for `timeout`, returning the peer of each.
+    pub fn expire(&self, timeout: Duration) -> Vec<String> {
+        let now = tokio::time::Instant::now();
+        let mut expired = Vec::new();
+        self.pending.lock().unwrap().retain(|_, pending| {
+            let keep = now.duration_since(pending.sent) < timeout;
+            if !keep {
+                expired.push(pending.peer.clone());
+            }
+            keep
+        });
+        expired
+    }
+
+    /// How many deltas are waiting for an ack.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// The gossip's part of a service's [`ServiceGauges`]: deltas waiting for an ack, the most
+    /// values any one peer is missing, and the elements of the state and of what peers hold.
+    pub fn gauges(&self) -> ServiceGauges {
+        let pending = self.pending() as u64;
+        let state = self.state.lock().unwrap();
+        let known = self.known.lock().unwrap();
+        // What each peer holds is covered by the state, and its part never overlaps the common
+        // one, so what it is missing is just the difference in size.
+        let held = |peer: &S| known.common.len() + peer.len();
+        let lag = known
+            .peers
+            .values()
+            .map(|peer| state.len().saturating_sub(held(peer)));
+        let tracked = known.peers.values().map(S::len).sum::<usize>();
+        ServiceGauges {
+            pending_rpcs: pending,
+            gossip_lag: lag.max().unwrap_or(0) as u64,
+            elements: (state.len() + known.common.len() + tracked) as u64,
+        }
+    }
+
+    /// Look for peers that supposedly hold something the state doesn't, which can only be the
+    /// result of a bug. Violations are logged and repaired by forgetting the offending part, which
+    /// at worst sends it again. Returns the number of elements repaired.
+    pub fn check_invariants(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        let mut known = self.known.lock().unwrap();
+        let impossible = known.common.delta_since(&state);
+        if !impossible.is_empty() {
+            tracing::error!(
+                "Every peer supposedly holds {} elements we don't",
+                impossible.len()
+            );
+            // Repaired per peer below.
+            known.demote(None);
+        }
+        let mut found = 0;
+        for (peer, known) in known.peers.iter_mut() {
+            let impossible = known.delta_since(&state);
+            if impossible.is_empty() {
+                continue;
+            }
+            tracing::error!(
+                "{} supposedly holds {} elements we don't",
+                peer,
+                impossible.len()
+            );
+            found += impossible.len();
+            *known = known.delta_since(&impossible);
+        }
+        found
+    }
+
+    /// How many rounds [`Gossip::run`] has started.
+    pub fn rounds(&self) -> u64 {
+        self.rounds.load(Ordering::Relaxed)
+    }
+
+    /// Call `round` every [`GossipParams::interval`], forever, starting after
+    /// [`Gossip::first_round_delay`]. A tuned interval takes effect right away, rather than after
+    /// the old one runs out. Rounds missed while one ran long are skipped.
+    pub async fn run<F, Fut>(&self, mut round: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let mut params = self.params.subscribe();
+        let interval = params.borrow_and_update().interval;
+        let mut next = tokio::time::Instant::now() + self.first_round_delay(interval);
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => {
+                    self.start_round();
+                    round().await;
+                    let now = tokio::time::Instant::now();
+                    next += self.next_round_in(&self.params());
+                    if next <= now {
+                        next = now + self.next_round_in(&self.params());
+                    }
+                }
+                Ok(()) = params.changed() => {
+                    params.borrow_and_update();
+                    next = tokio::time::Instant::now();
+                }
+            }
+        }
+    }
+
+    /// How long after [`Gossip::run`] starts the first round does, see
+    /// [`GossipOptions::phase_offset`].
+    pub fn first_round_delay(&self, interval: Duration) -> Duration {
+        if !self.options.phase_offset || interval.is_zero() {
+            return Duration::ZERO;
+        }
+        self.rng.lock().unwrap().gen_range(Duration::ZERO..interval)
+    }
+
+    /// How long until the round after this one, see [`GossipParams::jitter`].
+    fn next_round_in(&self, params: &GossipParams) -> Duration {
+        if !params.jitter {
+            return params.interval;
+        }
+        let factor = self
+            .rng
+            .lock()
+            .unwrap()
+            .gen_range(1.0 - JITTER..=1.0 + JITTER);
+        params.interval.mul_f64(factor)
+    }
+
+    fn start_round(&self) {
+        let rounds = {
+            let mut generations = self.generations.lock().unwrap();
+            let rounds = self.rounds.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(hot_rounds) = self.hot_rounds() {
+                while generations
+                    .front()
+                    .is_some_and(|(round, _)| round + hot_rounds <= rounds)
+                {
+                    generations.pop_front();
+                }
+            }
+            rounds
+        };
+        self.repairing.lock().unwrap().clear();
+        let Some(every) = self.options.anti_entropy_every else {
+            return;
+        };
+        if !rounds.is_multiple_of(every.max(1)) {
+            return;
+        }
+        // At random, so that every target's turn comes.
+        let targets = self.targets();
+        let Some(peer) = targets.choose(&mut *self.rng.lock().unwrap()) else {
+            return;
+        };
+        tracing::debug!("Anti-entropy: resending everything to {}", peer);
+        self.known.lock().unwrap().demote(Some(peer));
+        self.repairing.lock().unwrap().insert(peer.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(values: impl IntoIterator<Item = u64>) -> GSet<u64> {
+        values.into_iter().collect()
+    }
+
+    fn cluster() -> Gossip<GSet<u64>> {
+        let gossip = Gossip::default();
+        let node_ids = ["n0", "n1", "n2"].map(String::from);
+        gossip.init("n0", &node_ids);
+        gossip
+    }
+
+    #[test]
+    fn test_g_set_laws() {
+        let a = set([1, 2]);
+        let b = set([2, 3]);
+
+        let mut ab = a.clone();
+        assert_eq!(ab.merge(&b), set([3]));
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.merge(&b), set([]));
+
+        assert_eq!(ab.digest(), ba.digest());
+        assert_ne!(a.digest(), b.digest());
+        assert_eq!(ab.delta_since(&a), set([3]));
+
+        let chunks = set(0..10).chunks(4);
+        assert_eq!(chunks.iter().map(GSet::len).collect::<Vec<_>>(), [4, 4, 2]);
+        assert_eq!(chunks.into_iter().flat_map(GSet::into_inner).count(), 10);
+        assert_eq!(set([]).chunks(4), [set([])]);
+    }
+
+    #[test]
+    fn test_membership_change() {
+        let gossip = cluster();
+        gossip.update(&set([1, 2]));
+        gossip.set_neighbors(["n1", "n2"].map(String::from).into());
+        assert!(gossip.learn("n2", &set([1])));
+        gossip.track(7, "n2", set([2]));
+
+        let node_ids = ["n0", "n1", "n3"].map(String::from);
+        let (joined, left) = gossip.set_members("n0", &node_ids);
+        assert_eq!(joined, ["n3"]);
+        assert_eq!(left, ["n2"]);
+        assert_eq!(*gossip.peers(), ["n1", "n3"]);
+        assert!(!gossip.is_peer("n2"));
+        assert_eq!(*gossip.neighbors().unwrap(), ["n1".to_owned()].into());
+        assert_eq!(gossip.pending(), 0);
+        assert_eq!(gossip.delta_for("n2"), None);
+        assert_eq!(gossip.delta_for("n3"), Some(vec![set([1, 2])]));
+    }
+
+    #[test]
+    fn test_deltas_follow_knowledge() {
+        let gossip = cluster();
+        assert_eq!(gossip.update(&set([1, 2])), set([1, 2]));
+        assert_eq!(gossip.update(&set([2, 3])), set([3]));
+
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([1, 2, 3])]));
+        assert!(gossip.learn("n1", &set([1])));
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([2, 3])]));
+        assert!(!gossip.learn("n9", &set([1])));
+        assert_eq!(gossip.delta_for("n9"), None);
+
+        gossip.observe_digest("n2", set([1, 2]).digest());
+        assert_eq!(gossip.knowledge("n2"), Some(set([])));
+        gossip.observe_digest("n2", gossip.digest());
+        assert_eq!(gossip.delta_for("n2"), Some(vec![set([])]));
+    }
+
+    #[test]
+    fn test_common_knowledge_is_kept_once() {
+        let gossip = cluster();
+        gossip.update(&set([1, 2, 3, 4]));
+        gossip.learn("n1", &set([1, 2]));
+        assert_eq!(gossip.acked_by_all(), set([]));
+        gossip.learn("n2", &set([2, 3]));
+        assert_eq!(gossip.acked_by_all(), set([2]));
+        assert_eq!(gossip.tracked(), 2);
+
+        // Deltas and knowledge see through the split.
+        assert_eq!(gossip.knowledge("n1"), Some(set([1, 2])));
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([3, 4])]));
+        assert_eq!(gossip.delta_for("n2"), Some(vec![set([1, 4])]));
+        // Relearning something common changes nothing.
+        gossip.learn("n1", &set([2]));
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([3, 4])]));
+
+        gossip.learn("n1", &set([3, 4]));
+        assert_eq!(gossip.acked_by_all(), set([2, 3]));
+        assert_eq!(gossip.delta_for("n2"), Some(vec![set([1, 4])]));
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([])]));
+
+        // A peer forgotten by anti-entropy gets everything again, and the others nothing new.
+        gossip.known.lock().unwrap().demote(Some("n2"));
+        assert_eq!(gossip.acked_by_all(), set([]));
+        assert_eq!(gossip.delta_for("n2"), Some(vec![set([1, 2, 3, 4])]));
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([])]));
+
+        // A new neighbor holds nothing, so nothing is common until it catches up.
+        gossip.learn("n2", &set([1, 2, 3, 4]));
+        assert_eq!(gossip.acked_by_all(), set([1, 2, 3, 4]));
+        gossip.set_neighbors(HashSet::from(["n1".to_owned(), "n3".to_owned()]));
+        assert_eq!(gossip.acked_by_all(), set([]));
+        assert_eq!(gossip.delta_for("n3"), Some(vec![set([1, 2, 3, 4])]));
+        assert_eq!(gossip.delta_for("n2"), Some(vec![set([])]));
+    }
+
+    #[test]
+    fn test_tracking_stays_bounded() {
+        const VALUES: u64 = 100_000;
+        let gossip = cluster();
+        for start in (0..VALUES).step_by(1000) {
+            let batch = set(start..start + 1000);
+            gossip.update(&batch);
+            gossip.learn("n1", &batch);
+            // n2 lags a batch behind.
+            if start > 0 {
+                gossip.learn("n2", &set(start - 1000..start));
+            }
+            assert!(gossip.tracked() <= 1000, "{}", gossip.tracked());
+        }
+        assert_eq!(gossip.acked_by_all().len(), VALUES as usize - 1000);
+        assert_eq!(
+            gossip.delta_for("n2"),
+            Some(vec![set(VALUES - 1000..VALUES)])
+        );
+        assert_eq!(gossip.delta_for("n1"), Some(vec![set([])]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acks_and_expiry() {
+        let gossip = cluster();
+        gossip.update(&set([1, 2]));
+        gossip.track(1, "n1", set([1]));
+        gossip.track(2, "n2", set([2]));
+
+        assert!(!gossip.acknowledge("n2", 1), "acked by the wrong peer");
+        assert!(gossip.acknowledge("n2", 2));
+        assert_eq!(gossip.knowledge("n2"), Some(set([2])));
+
+        gossip.track(3, "n1", set([1]));
+        tokio::time::advance(Duration::from_secs(2)).await;
+        gossip.track(4, "n2", set([1]));
+        assert_eq!(gossip.expire(Duration::from_secs(1)), ["n1"]);
+        assert_eq!(gossip.pending(), 1);
+        assert!(!gossip.acknowledge("n1", 3), "acked after expiring");
+    }
+
+    #[test]
+    fn test_gauges() {
+        let gossip = cluster();
+        assert_eq!(gossip.gauges(), ServiceGauges::default());
+
+        gossip.update(&set([1, 2, 3, 4]));
+        gossip.learn("n1", &set([1, 2, 3]));
+        gossip.learn("n2", &set([2]));
+        gossip.track(1, "n2", set([3, 4]));
+        // n2 is missing three values. Both hold 2, and n1 holds two more.
+        let expected = ServiceGauges {
+            pending_rpcs: 1,
+            gossip_lag: 3,
+            elements: 4 + 1 + 2,
+        };
+        assert_eq!(gossip.gauges(), expected);
+
+        assert!(gossip.acknowledge("n2", 1));
+        let expected = ServiceGauges {
+            pending_rpcs: 0,
+            gossip_lag: 1,
+            elements: 4 + 2 + 2,
+        };
+        assert_eq!(gossip.gauges(), expected);
+    }
+
+    #[test]
+    fn test_invariants_are_repaired() {
+        let gossip = cluster();
+        gossip.update(&set([1]));
+        gossip.learn("n1", &set([1, 2, 3]));
+        assert_eq!(gossip.check_invariants(), 2);
+        assert_eq!(gossip.knowledge("n1"), Some(set([1])));
+        assert_eq!(gossip.check_invariants(), 0);
+    }
+
+    #[test]
+    fn test_cold_elements_wait_for_anti_entropy() {
+        let tiered = |anti_entropy_every| {
+            let gossip = Gossip::new(GossipOptions {
+                anti_entropy_every,
+                hot_rounds: Some(2),
+                ..Default::default()
+            });
+            gossip.init("n0", &["n0", "n1", "n2", "n3"].map(String::from));
+            gossip.set_neighbors(["n1", "n2"].map(String::from).into());
+            gossip
+        };
+        let delta = |gossip: &Gossip<GSet<u64>>, peer| {
+            let chunks = gossip.delta_for(peer).unwrap();
+            chunks
+                .into_iter()
+                .flat_map(GSet::into_inner)
+                .collect::<GSet<_>>()
+        };
+
+        let gossip = tiered(Some(4));
+        gossip.update(&set([1]));
+        gossip.start_round();
+        gossip.start_round();
+        assert_eq!(delta(&gossip, "n1"), set([1]));
+        gossip.update(&set([2]));
+        // 1 was sent in two rounds, and has gone cold.
+        gossip.start_round();
+        assert_eq!(gossip.generations(), (1, 1));
+        assert_eq!(delta(&gossip, "n1"), set([2]));
+        // A new neighbor gets everything until the next round.
+        gossip.set_neighbors(["n1", "n2", "n3"].map(String::from).into());
+        assert_eq!(delta(&gossip, "n3"), set([1, 2]));
+        assert_eq!(delta(&gossip, "n2"), set([2]));
+
+        // Anti-entropy sends one target everything.
+        gossip.start_round();
+        let mut deltas = ["n1", "n2", "n3"].map(|peer| delta(&gossip, peer));
+        deltas.sort_by_key(GSet::len);
+        assert_eq!(deltas, [set([2]), set([2]), set([1, 2])]);
+
+        // Nothing would ever send cold elements without anti-entropy.
+        let gossip = tiered(None);
+        gossip.update(&set([1]));
+        for _ in 0..3 {
+            gossip.start_round();
+        }
+        assert_eq!(gossip.generations(), (1, 0));
+        assert_eq!(delta(&gossip, "n1"), set([1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rounds_follow_params() {
+        let gossip = Arc::new(Gossip::<GSet<u64>>::new(GossipOptions {
+            anti_entropy_every: Some(2),
+            phase_offset: false,
+            ..Default::default()
+        }));
+        let node_ids = ["n0", "n1"].map(String::from);
+        gossip.init("n0", &node_ids);
+        gossip.set_neighbors(HashSet::from(["n1".to_owned()]));
+        gossip.update(&set([1]));
+
+        let task = tokio::spawn({
+            let gossip = Arc::clone(&gossip);
+            async move { gossip.run(|| async {}).await }
+        });
+        // The first round starts right away.
+        tokio::time::sleep(DEFAULT_GOSSIP_INTERVAL / 2).await;
+        assert_eq!(gossip.rounds(), 1);
+
+        gossip.learn("n1", &set([1]));
+        tokio::time::sleep(DEFAULT_GOSSIP_INTERVAL).await;
+        assert_eq!(gossip.rounds(), 2);
+        // The second round forgot what n1 holds.
+        assert_eq!(gossip.knowledge("n1"), Some(set([])));
+
+        gossip
+            .tune(&serde_json::json!({ "gossip_interval_ms": 10 }))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(105)).await;
+        assert_eq!(gossip.rounds(), 13);
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_rounds_are_spread_over_the_interval() {
+        let interval = DEFAULT_GOSSIP_INTERVAL;
+        let delay = |id: &str, options: GossipOptions| {
+            let gossip = Gossip::<GSet<u64>>::new(options);
+            gossip.init(id, &[]);
+            gossip.first_round_delay(interval)
+        };
+
+        let mut quarters = [0; 4];
+        for n in 0..100 {
+            let delay = delay(&format!("n{n}"), GossipOptions::default());
+            assert!(delay < interval);
+            quarters[(delay.as_micros() * 4 / interval.as_micros()) as usize] += 1;
+        }
+        assert!(quarters.iter().all(|&count| count >= 10), "{quarters:?}");
+
+        // Reproducible from the node ID, or from the seed if there is one.
+        assert_eq!(
+            delay("n1", GossipOptions::default()),
+            delay("n1", GossipOptions::default())
+        );
+        let seeded = || GossipOptions {
+            seed: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(delay("n1", seeded()), delay("n2", seeded()));
+        let aligned = GossipOptions {
+            phase_offset: false,
+            ..Default::default()
+        };
+        assert_eq!(delay("n1", aligned), Duration::ZERO);
+
+        // The first round really waits that long.
+        let expected = delay("n1", GossipOptions::default());
+        let gossip = Arc::new(Gossip::<GSet<u64>>::default());
+        gossip.init("n1", &[]);
+        let task = tokio::spawn({
+            let gossip = Arc::clone(&gossip);
+            async move { gossip.run(|| async {}).await }
+        });
+        tokio::time::sleep(expected.saturating_sub(Duration::from_millis(1))).await;
+        assert_eq!(gossip.rounds(), 0);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        assert_eq!(gossip.rounds(), 1);
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_jittered_rounds() {
+        let gossip = Arc::new(Gossip::<GSet<u64>>::new(GossipOptions {
+            phase_offset: false,
+            ..Default::default()
+        }));
+        gossip.init("n1", &[]);
+        gossip.tune(&serde_json::json!({ "jitter": true })).unwrap();
+        let times = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let task = tokio::spawn({
+            let (gossip, times) = (Arc::clone(&gossip), Arc::clone(&times));
+            async move {
+                gossip
+                    .run(|| {
+                        times.lock().unwrap().push(tokio::time::Instant::now());
+                        async {}
+                    })
+                    .await
+            }
+        });
+        tokio::time::sleep(DEFAULT_GOSSIP_INTERVAL * 20).await;
+        task.abort();
+
+        let times = times.lock().unwrap();
+        let gaps = times.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        assert!(gaps.len() >= 15, "{gaps:?}");
+        for gap in &gaps {
+            assert!(
+                gap.as_secs_f64() >= DEFAULT_GOSSIP_INTERVAL.as_secs_f64() * (1.0 - JITTER)
+                    && gap.as_secs_f64() <= DEFAULT_GOSSIP_INTERVAL.as_secs_f64() * (1.0 + JITTER),
+                "{gap:?}"
+            );
+        }
+        assert!(gaps.iter().any(|gap| *gap != gaps[0]), "{gaps:?}");
+    }
+
+    #[test]
+    fn test_rotation_covers_every_neighbor_within_k_rounds() {
+        let neighbors = ["n1", "n2", "n3", "n4", "n5"].map(String::from);
+        for k in 1..=7 {
+            let gossip = Gossip::<GSet<u64>>::default();
+            gossip.init("n0", &neighbors);
+            gossip.set_neighbors(neighbors.iter().cloned().collect());
+            gossip
+                .tune(&serde_json::json!({ "rotate_over": k }))
+                .unwrap();
+
+            let turn = neighbors.len().div_ceil(k as usize);
+            let mut rounds = Vec::new();
+            for _ in 0..3 * k {
+                gossip.start_round();
+                let targets = gossip.targets();
+                assert_eq!(targets.len(), turn, "k = {k}");
+                rounds.push(targets);
+            }
+            // Any k consecutive rounds cover every neighbor.
+            for window in rounds.windows(k as usize) {
+                let covered = window.iter().flatten().collect::<HashSet<_>>();
+                assert_eq!(covered.len(), neighbors.len(), "k = {k}: {window:?}");
+            }
+        }
+    }
+}
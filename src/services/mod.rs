@@ -1,4 +1,8 @@
 pub mod broadcast;
 pub mod counter;
 pub mod echo;
+pub mod g_set;
+pub mod gossip;
+pub mod proxy;
+pub mod sequence;
 pub mod unique_ids;
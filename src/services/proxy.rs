@@ -0,0 +1,708 @@
+//! A service whose handler is a child process, so that handlers can be prototyped in another
+//! language on top of this runner.
+//!
+//! The runner still does everything but handle requests: init, message IDs, error replies and
+//! writing the output. Each request is written to the child's stdin as a line of JSON,
+//! `{"id": 1, "src": "c1", "body": {"type": "echo", "echo": "hi"}}`, and the child answers on its
+//! stdout with a line carrying the same `id`, `{"id": 1, "body": {"type": "echo_ok", ...}}`, whose
+//! body is the reply. Bodies carry neither `msg_id` nor `in_reply_to`, which are the runner's
+//! business. A child that echoes its input, like `cat`, answers each request with a copy of it.
+//!
+//! A request that the child doesn't answer within [`ProxyConfig::timeout_ms`] fails with
+//! `timeout`, and one it was working on when it exited with `crash`. The child is then started
+//! again, after a backoff that grows while it keeps exiting; requests that arrive in between fail
+//! with `temporarily_unavailable`. Messages from peers are forwarded like any other, so the child
+//! has to do its own gossip if it wants any.
+//!
+//! [`ProxiedGSetService`] leaves gossip in Rust instead: it serves Maelstrom's `g-set` messages
+//! with a [`GSetService`], gossip between nodes included, and forwards everything else to the
+//! child. The `proxy` workload runs it.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use snafu::{OptionExt as _, Snafu};
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::oneshot;
+
+use super::g_set::{GSetConfig, GSetError, GSetMessage, GSetService};
+use crate::audit::StateDigest;
+use crate::config::Configurable;
+pub use crate::error::*;
+use crate::message::{ErrorCode, Message};
+use crate::node::{Node, NodeState, ServiceGauges};
+use crate::timer::TimerSpec;
+use crate::util::{Backoff, Jitter};
+
+/// The `proxy` section of a config file, see [`crate::config`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// The program to run and its arguments, e.g. `["python3", "handler.py"]`.
+    pub command: Vec<String>,
+    /// How long the child has to answer a request.
+    pub timeout_ms: u64,
+    /// How long to wait before starting the child again once it exits. The wait doubles with
+    /// every exit, up to `max_restart_backoff_ms`, and starts over once the child has stayed up
+    /// that long.
+    pub restart_backoff_ms: u64,
+    pub max_restart_backoff_ms: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            command: Vec::new(),
+            timeout_ms: 1000,
+            restart_backoff_ms: 100,
+            max_restart_backoff_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ProxyError {
+    #[snafu(display("Missing message ID"))]
+    MissingMessageId,
+    #[snafu(display("The handler process is not running"))]
+    NotRunning,
+    #[snafu(display("Failed to write to the handler process"))]
+    Write { source: std::io::Error },
+    #[snafu(display("The handler process exited before answering"))]
+    Exited,
+    #[snafu(display("The handler process didn't answer within {timeout:?}"))]
+    Timeout { timeout: Duration },
+    #[snafu(display("Failed to handle a g-set message"))]
+    GSet { source: GSetError },
+}
+
+impl ProxyError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::MissingMessageId => ErrorCode::MalformedRequest,
+            // The request never reached the child.
+            Self::NotRunning => ErrorCode::TemporarilyUnavailable,
+            // The child may have handled it before dying.
+            Self::Write { .. } | Self::Exited => ErrorCode::Crash,
+            Self::Timeout { .. } => ErrorCode::Timeout,
+            Self::GSet { source } => source.code(),
+        }
+    }
+}
+
+impl From<ProxyError> for Error<ProxyError> {
+    fn from(source: ProxyError) -> Self {
+        Error::Node { source }
+    }
+}
+
+impl From<GSetError> for ProxyError {
+    fn from(source: GSetError) -> Self {
+        Self::GSet { source }
+    }
+}
+
+/// A node that a [`ProxyService`] can run in: itself, or a service that hands it the messages
+/// for the child, like [`ProxiedGSetService`].
+pub trait ProxyHost: Node<Message: From<Value>, Error: From<ProxyError>> {}
+
+impl<N: Node<Message: From<Value>, Error: From<ProxyError>>> ProxyHost for N {}
+
+/// A request, as written to the child.
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    src: &'a str,
+    body: &'a Value,
+}
+
+/// The child's answer to request `id`.
+#[derive(Deserialize)]
+struct Answer {
+    id: u64,
+    body: Map<String, Value>,
+}
+
+/// Who is waiting for the answer to each request, by request ID.
+type Waiting = HashMap<u64, oneshot::Sender<Map<String, Value>>>;
+
+/// A running child: where requests are written, and who is waiting for which answer.
+struct Relay {
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    /// `None` once the child is gone, so that nothing starts waiting on it after that.
+    waiting: Mutex<Option<Waiting>>,
+}
+
+impl Relay {
+    fn new(stdin: ChildStdin) -> Self {
+        Self {
+            stdin: tokio::sync::Mutex::new(stdin),
+            waiting: Mutex::new(Some(HashMap::new())),
+        }
+    }
+
+    /// Wait for the answer to request `id`, or `None` if the child is gone.
+    fn register(&self, id: u64) -> Option<oneshot::Receiver<Map<String, Value>>> {
+        let (answer, answered) = oneshot::channel();
+        self.waiting.lock().unwrap().as_mut()?.insert(id, answer);
+        Some(answered)
+    }
+
+    fn forget(&self, id: u64) {
+        if let Some(waiting) = self.waiting.lock().unwrap().as_mut() {
+            waiting.remove(&id);
+        }
+    }
+
+    /// Drop everyone still waiting, failing their requests.
+    fn close(&self) {
+        self.waiting.lock().unwrap().take();
+    }
+
+    async fn write(&self, request: &Request<'_>) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(request).expect("requests serialize");
+        line.push(b'\n');
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(&line).await?;
+        stdin.flush().await
+    }
+
+    /// Hand each answer on `stdout` to whoever waits for it, until the child closes it.
+    async fn read_answers(&self, stdout: ChildStdout) {
+        let mut lines = BufReader::new(stdout).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::warn!("Failed to read from the handler process: {}", e);
+                    return;
+                }
+            };
+            let answer = match serde_json::from_str::<Answer>(&line) {
+                Ok(answer) => answer,
+                Err(e) => {
+                    tracing::warn!("Ignoring malformed line from the handler process: {}", e);
+                    continue;
+                }
+            };
+            let waiter = self
+                .waiting
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|waiting| waiting.remove(&answer.id));
+            match waiter {
+                Some(waiter) => {
+                    waiter.send(answer.body).ok();
+                }
+                None => tracing::debug!("Nobody is waiting for answer {}", answer.id),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyService {
+    inner: Arc<ProxyInner>,
+}
+
+struct ProxyInner {
+    config: ProxyConfig,
+    /// The running child, if any.
+    relay: ArcSwapOption<Relay>,
+    next_id: AtomicU64,
+}
+
+impl Configurable for ProxyService {
+    const NAME: &'static str = "proxy";
+
+    type Config = ProxyConfig;
+
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String> {
+        if config.command.is_empty() {
+            return Err("command must name the handler to run".into());
+        }
+        Ok(Self::new(config))
+    }
+}
+
+impl ProxyService {
+    pub fn new(config: ProxyConfig) -> Self {
+        Self {
+            inner: Arc::new(ProxyInner {
+                config,
+                relay: ArcSwapOption::empty(),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.inner.config.timeout_ms)
+    }
+
+    /// Start the child and relay requests to it. Its stdout is returned for
+    /// [`ProxyService::supervise`] to read.
+    fn start(&self) -> std::io::Result<(Child, Arc<Relay>, ChildStdout)> {
+        let (program, args) =
+            self.inner.config.command.split_first().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "No command")
+            })?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // The child's logs go where ours do.
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let relay = Arc::new(Relay::new(stdin));
+        self.inner.relay.store(Some(Arc::clone(&relay)));
+        Ok((child, relay, stdout))
+    }
+
+    /// Relay answers from the child, starting it again whenever it exits.
+    async fn supervise(self, mut started: std::io::Result<(Child, Arc<Relay>, ChildStdout)>) {
+        let config = &self.inner.config;
+        let cap = Duration::from_millis(config.max_restart_backoff_ms);
+        let mut backoff = Backoff::builder()
+            .base(Duration::from_millis(config.restart_backoff_ms))
+            .cap(cap)
+            .jitter(Jitter::None)
+            .build();
+        loop {
+            let since = tokio::time::Instant::now();
+            match started {
+                Ok((mut child, relay, stdout)) => {
+                    relay.read_answers(stdout).await;
+                    self.inner.relay.store(None);
+                    relay.close();
+                    // It may have only closed its stdout.
+                    child.kill().await.ok();
+                    tracing::warn!("Handler process exited: {:?}", child.wait().await);
+                }
+                Err(e) => tracing::error!("Failed to start the handler process: {}", e),
+            }
+            if since.elapsed() >= cap {
+                backoff.reset();
+            }
+            backoff.wait().await;
+            started = self.start();
+        }
+    }
+
+    /// Have the child answer `body`, from `src`.
+    async fn forward(
+        &self,
+        src: &str,
+        body: &Value,
+    ) -> std::result::Result<Map<String, Value>, ProxyError> {
+        let relay = self.inner.relay.load_full().context(NotRunningSnafu)?;
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let answered = relay.register(id).context(NotRunningSnafu)?;
+        if let Err(source) = relay.write(&Request { id, src, body }).await {
+            relay.forget(id);
+            return Err(ProxyError::Write { source });
+        }
+        let timeout = self.timeout();
+        match tokio::time::timeout(timeout, answered).await {
+            Ok(Ok(answer)) => Ok(answer),
+            Ok(Err(_)) => ExitedSnafu.fail(),
+            Err(_) => {
+                relay.forget(id);
+                TimeoutSnafu { timeout }.fail()
+            }
+        }
+    }
+
+    /// Start the child and keep it running for as long as `node` is alive, as [`Node::init`].
+    /// It is started before init is answered, so that the first request finds it running.
+    pub fn start_child<N: Node>(&self, node: &NodeState<N>) {
+        let started = self.start();
+        node.spawn(self.clone().supervise(started));
+    }
+
+    /// Have the child answer a message, as [`Node::handle_message`] on `node`.
+    pub async fn handle<N: ProxyHost>(
+        &self,
+        Message { src, body, .. }: Message<Value>,
+        node: &NodeState<N>,
+    ) -> Result<(), N::Error> {
+        let re = body
+            .id
+            .context(MissingMessageIdSnafu)
+            .map_err(Error::node)?;
+        let mut answer = self.forward(&src, &body.data).await.map_err(Error::node)?;
+        answer.remove("msg_id");
+        answer.remove("in_reply_to");
+        node.reply(src, re, Value::Object(answer).into()).await?;
+        Ok(())
+    }
+}
+
+impl Node for ProxyService {
+    type Message = Value;
+    type Error = ProxyError;
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    async fn init(
+        &self,
+        node: &NodeState<Self>,
+        _node_ids: Vec<String>,
+    ) -> crate::Result<(), Self::Error> {
+        self.start_child(node);
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        message: Message<Self::Message>,
+        node: &NodeState<Self>,
+    ) -> Result<(), Self::Error> {
+        self.handle(message, node).await
+    }
+}
+
+/// A message for a [`ProxiedGSetService`]: one of the g-set's, or anything else, for the child.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ProxiedMessage {
+    GSet(GSetMessage),
+    Handler(Value),
+}
+
+impl From<GSetMessage> for ProxiedMessage {
+    fn from(message: GSetMessage) -> Self {
+        Self::GSet(message)
+    }
+}
+
+impl From<Value> for ProxiedMessage {
+    fn from(message: Value) -> Self {
+        Self::Handler(message)
+    }
+}
+
+/// By `type`, so that a malformed g-set message is refused rather than handed to the child.
+impl<'de> Deserialize<'de> for ProxiedMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let body = Value::deserialize(deserializer)?;
+        let is_g_set = body
+            .get("type")
+            .and_then(Value::as_str)
+            .is_some_and(|tag| GSetMessage::TAGS.contains(&tag));
+        if !is_g_set {
+            return Ok(Self::Handler(body));
+        }
+        GSetMessage::deserialize(body)
+            .map(Self::GSet)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The `proxy` section of a config file for a [`ProxiedGSetService`]: the child's settings, and
+/// the g-set's under `g_set`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxiedGSetConfig {
+    #[serde(flatten)]
+    pub handler: ProxyConfig,
+    pub g_set: GSetConfig,
+}
+
+/// A [`ProxyService`] that serves Maelstrom's `g-set` messages itself, with a [`GSetService`]
+/// that gossips them between nodes, see the [module docs](self).
+#[derive(Clone)]
+pub struct ProxiedGSetService {
+    proxy: ProxyService,
+    g_set: GSetService,
+}
+
+impl Configurable for ProxiedGSetService {
+    const NAME: &'static str = "proxy";
+
+    type Config = ProxiedGSetConfig;
+
+    fn from_config(config: Self::Config) -> std::result::Result<Self, String> {
+        Ok(Self::new(
+            ProxyService::from_config(config.handler)?,
+            GSetService::from_config(config.g_set)?,
+        ))
+    }
+}
+
+impl ProxiedGSetService {
+    pub fn new(proxy: ProxyService, g_set: GSetService) -> Self {
+        Self { proxy, g_set }
+    }
+}
+
+impl Node for ProxiedGSetService {
+    type Message = ProxiedMessage;
+    type Error = ProxyError;
+
+    fn message_tags(&self) -> &[&'static str] {
+        GSetMessage::TAGS
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        match message {
+            ProxiedMessage::GSet(message) => Some(message.tag()),
+            ProxiedMessage::Handler(_) => None,
+        }
+    }
+
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        match message {
+            ProxiedMessage::GSet(message) => message.is_mutating(),
+            ProxiedMessage::Handler(_) => false,
+        }
+    }
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    fn state_digest(&self) -> Option<StateDigest> {
+        self.g_set.state_digest()
+    }
+
+    fn gauges(&self) -> ServiceGauges {
+        self.g_set.gauges()
+    }
+
+    async fn flush_gossip(
+        &self,
+        node: &NodeState<Self>,
+        full: bool,
+    ) -> crate::Result<Option<std::collections::BTreeMap<String, u64>>, Self::Error> {
+        self.g_set.flush(node, full).await
+    }
+
+    fn reconfigure(&self, params: &Value) -> std::result::Result<(), String> {
+        self.g_set.reconfigure(params)
+    }
+
+    async fn init(
+        &self,
+        node: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> crate::Result<(), Self::Error> {
+        self.proxy.start_child(node);
+        self.g_set.start(node, node_ids).await
+    }
+
+    fn timers(&self) -> Vec<TimerSpec> {
+        self.g_set.timers()
+    }
+
+    async fn on_timer(&self, name: &str, _node: &NodeState<Self>) -> Result<(), Self::Error> {
+        self.g_set.handle_timer(name);
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        message: Message<Self::Message>,
+        node: &NodeState<Self>,
+    ) -> Result<(), Self::Error> {
+        match message.split() {
+            (message, ProxiedMessage::GSet(data)) => {
+                self.g_set.handle(message.with_data(data), node).await
+            }
+            (message, ProxiedMessage::Handler(data)) => {
+                self.proxy.handle(message.with_data(data), node).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::services::gossip::DEFAULT_GOSSIP_INTERVAL;
+    use crate::testing::Cluster;
+
+    fn shell(script: &str, timeout_ms: u64) -> ProxyService {
+        ProxyService::new(ProxyConfig {
+            command: vec!["sh".into(), "-c".into(), script.into()],
+            timeout_ms,
+            restart_backoff_ms: 10,
+            max_restart_backoff_ms: 100,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_relays_answers() {
+        let cluster = Cluster::new(1, || shell("cat", 1000)).await;
+        let client = cluster.client();
+        for i in 0..10 {
+            let echo = serde_json::json!({ "type": "echo", "echo": i });
+            let reply = client.rpc("n0", echo).await.unwrap();
+            assert_eq!(reply["type"], "echo");
+            assert_eq!(reply["echo"], i);
+            assert!(
+                reply["msg_id"].is_u64(),
+                "Expected the runner to assign an ID"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_request_times_out() {
+        let cluster = Cluster::new(1, || shell("cat > /dev/null", 50)).await;
+        let reply = cluster
+            .client()
+            .rpc("n0", serde_json::json!({ "type": "echo", "echo": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], ErrorCode::Timeout as u64);
+    }
+
+    #[tokio::test]
+    async fn test_crashed_child_is_restarted() {
+        let marker = std::env::temp_dir().join(format!("proxy-{}", ulid::Ulid::new()));
+        // The first child dies on its first request, the next ones echo.
+        let script = format!(
+            "if [ -e {0} ]; then cat; else touch {0}; read line; exit 1; fi",
+            marker.display()
+        );
+        let cluster = Cluster::new(1, || shell(&script, 1000)).await;
+        let client = cluster.client();
+        let echo = serde_json::json!({ "type": "echo", "echo": 1 });
+
+        let reply = client.rpc("n0", echo.clone()).await.unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], ErrorCode::Crash as u64);
+
+        let mut reply = client.rpc("n0", echo.clone()).await.unwrap();
+        for _ in 0..50 {
+            if reply["code"] != ErrorCode::TemporarilyUnavailable as u64 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            reply = client.rpc("n0", echo.clone()).await.unwrap();
+        }
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(reply["type"], "echo", "{reply}");
+        assert_eq!(reply["echo"], 1);
+    }
+
+    #[test]
+    fn test_g_set_messages_are_routed_by_type() {
+        let parse = |body| serde_json::from_value::<ProxiedMessage>(body);
+        let add = parse(serde_json::json!({ "type": "add", "element": 1 }));
+        assert!(matches!(
+            add,
+            Ok(ProxiedMessage::GSet(GSetMessage::Add { element: 1 }))
+        ));
+        let echo = parse(serde_json::json!({ "type": "echo", "echo": 1 }));
+        assert!(matches!(echo, Ok(ProxiedMessage::Handler(_))));
+        // Not the child's to answer.
+        assert!(parse(serde_json::json!({ "type": "add" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_g_set_is_gossiped_beside_the_child() {
+        let service = || ProxiedGSetService::new(shell("cat", 1000), GSetService::default());
+        let cluster = Cluster::new(2, service).await;
+        let client = cluster.client();
+        let add = serde_json::json!({ "type": "add", "element": 7 });
+        assert_eq!(client.rpc("n0", add).await.unwrap()["type"], "add_ok");
+        let echo = serde_json::json!({ "type": "echo", "echo": 1 });
+        assert_eq!(client.rpc("n0", echo).await.unwrap()["echo"], 1);
+
+        tokio::time::sleep(DEFAULT_GOSSIP_INTERVAL * 4).await;
+        let read = client
+            .rpc("n1", serde_json::json!({ "type": "read" }))
+            .await
+            .unwrap();
+        assert_eq!(read["value"], serde_json::json!([7]));
+        assert!(cluster.traffic().by_type().contains_key("replicate"));
+    }
+
+    #[tokio::test]
+    async fn test_runs_from_config() {
+        let config = serde_json::from_value::<Config>(serde_json::json!({
+            "services": {
+                "proxy": {
+                    "command": ["sh", "-c", "cat"],
+                    "g_set": { "anti_entropy_every": 4 },
+                },
+            },
+        }))
+        .unwrap();
+        let service = config.build::<ProxiedGSetService>().unwrap();
+        let options = config.node_options(Vec::new()).unwrap();
+
+        let (mut input, node_stdin) = tokio::io::duplex(64 * 1024);
+        let (node_stdout, output) = tokio::io::duplex(64 * 1024);
+        let node = tokio::spawn(NodeState::run_with_io(
+            service,
+            options,
+            node_stdin,
+            node_stdout,
+        ));
+        let mut lines = BufReader::new(output).lines();
+        let requests = [
+            serde_json::json!({ "type": "init", "node_id": "n0", "node_ids": ["n0", "n1"] }),
+            serde_json::json!({ "type": "echo", "echo": "hi" }),
+            serde_json::json!({ "type": "add", "element": 3 }),
+            serde_json::json!({ "type": "read" }),
+        ];
+        // Each request is answered before the next is sent, and anything sent to n1 meanwhile
+        // is kept.
+        let mut replies = Vec::new();
+        let mut to_peer = Vec::new();
+        for (id, mut body) in requests.into_iter().enumerate() {
+            body["msg_id"] = id.into();
+            let frame = serde_json::json!({ "src": "c1", "dest": "n0", "body": body });
+            input
+                .write_all(format!("{frame}\n").as_bytes())
+                .await
+                .unwrap();
+            loop {
+                let line = lines.next_line().await.unwrap().unwrap();
+                let frame = serde_json::from_str::<Value>(&line).unwrap();
+                if frame["dest"] == "n1" {
+                    to_peer.push(frame["body"]["type"].clone());
+                    continue;
+                }
+                assert_eq!(frame["body"]["in_reply_to"], id, "{frame}");
+                replies.push(frame["body"].clone());
+                break;
+            }
+        }
+        while to_peer.is_empty() {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let frame = serde_json::from_str::<Value>(&line).unwrap();
+            assert_eq!(frame["dest"], "n1", "{frame}");
+            to_peer.push(frame["body"]["type"].clone());
+        }
+
+        assert_eq!(replies[0]["type"], "init_ok");
+        assert_eq!(replies[1]["type"], "echo");
+        assert_eq!(replies[1]["echo"], "hi");
+        assert_eq!(replies[2]["type"], "add_ok");
+        assert_eq!(replies[3]["value"], serde_json::json!([3]));
+        assert_eq!(to_peer[0], "replicate");
+
+        drop(input);
+        node.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! A log that numbers appends in the order they arrive, written as an event loop (see
+//! [`Execution::EventLoop`]) rather than as handlers: the loop owns the log outright, so nothing
+//! is locked, and a timer of its own takes turns with the messages.
+
+use std::time::Duration;
+
+use snafu::Snafu;
+
+pub use crate::error::*;
+use crate::macros::define_service_messages;
+use crate::message::{ErrorCode, Message};
+use crate::node::{Execution, Node, NodeState};
+
+/// How many messages wait for the loop before the runner stops reading input.
+pub const INBOX_CAPACITY: usize = 1024;
+
+/// How often the loop logs how far the log has grown.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+define_service_messages! {
+    /// The message body of a Maelstrom message.
+    pub enum SequenceMessage {
+        Error { code: ErrorCode, text: String },
+
+        #[mutating]
+        Append { value: serde_json::Value } => AppendOk,
+        AppendOk { offset: u64 },
+        Read => ReadOk,
+        ReadOk { values: Vec<serde_json::Value> },
+    }
+
+    #[derive(Debug, Snafu)]
+    pub enum SequenceError {
+        #[code(NotSupported)]
+        #[snafu(display("Messages are only handled by the event loop"))]
+        NotEventLoop,
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SequenceService;
+
+impl Node for SequenceService {
+    type Message = SequenceMessage;
+    type Error = SequenceError;
+
+    fn message_tags(&self) -> &[&'static str] {
+        SequenceMessage::TAGS
+    }
+
+    fn message_tag(&self, message: &Self::Message) -> Option<&'static str> {
+        Some(message.tag())
+    }
+
+    fn is_mutating(&self, message: &Self::Message) -> bool {
+        message.is_mutating()
+    }
+
+    fn error_code(&self, error: &Self::Error) -> ErrorCode {
+        error.code()
+    }
+
+    fn execution(&self) -> Execution {
+        Execution::EventLoop {
+            capacity: INBOX_CAPACITY,
+        }
+    }
+
+    /// Only reached if [`NodeOptions::execution`](crate::node::NodeOptions::execution) asks for
+    /// handlers instead of the event loop.
+    async fn handle_message(
+        &self,
+        _message: Message<Self::Message>,
+        _node: &NodeState<Self>,
+    ) -> Result<(), Self::Error> {
+        Err(SequenceError::NotEventLoop.into())
+    }
+
+    async fn run_event_loop(
+        &self,
+        mut inbox: tokio::sync::mpsc::Receiver<Message<Self::Message>>,
+        node: NodeState<Self>,
+    ) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        let mut status = tokio::time::interval(STATUS_INTERVAL);
+        loop {
+            let Message { src, body, .. } = tokio::select! {
+                message = inbox.recv() => match message {
+                    Some(message) => message,
+                    None => return Ok(()),
+                },
+                _ = status.tick() => {
+                    tracing::info!("{} values appended", log.len());
+                    continue;
+                }
+            };
+
+            let reply = match body.data {
+                SequenceMessage::Append { value } => {
+                    log.push(value);
+                    SequenceMessage::AppendOk {
+                        offset: log.len() as u64 - 1,
+                    }
+                }
+                SequenceMessage::Read => SequenceMessage::ReadOk {
+                    values: log.clone(),
+                },
+                unexpected => {
+                    tracing::warn!("Unexpected message: {:?}", unexpected);
+                    continue;
+                }
+            };
+            let Some(id) = body.id else {
+                tracing::warn!("Request from {} has no msg_id, not answering it", src);
+                continue;
+            };
+            if let Err(e) = node.reply(src, id, reply).await {
+                tracing::warn!("Failed to reply: {}", snafu::Report::from_error(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+    use crate::node::NodeOptions;
+
+    type Lines = tokio::io::Lines<tokio::io::BufReader<tokio::io::DuplexStream>>;
+
+    async fn next_body(lines: &mut Lines) -> serde_json::Value {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str::<serde_json::Value>(&line).unwrap()["body"].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_appends_are_numbered_in_read_order() {
+        let (mut input, node_stdin) = tokio::io::duplex(64 * 1024);
+        let (node_stdout, output) = tokio::io::duplex(64 * 1024);
+        let _node = tokio::spawn(NodeState::run_with_io(
+            SequenceService,
+            NodeOptions::default(),
+            node_stdin,
+            node_stdout,
+        ));
+        let mut lines = tokio::io::BufReader::new(output).lines();
+
+        let mut frames = vec![serde_json::json!({
+            "src": "c0",
+            "dest": "n0",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": ["n0"] },
+        })];
+        // Several clients at once, all written before the loop sees the first.
+        for value in 0..100 {
+            frames.push(serde_json::json!({
+                "src": format!("c{}", value % 3 + 1),
+                "dest": "n0",
+                "body": { "type": "append", "msg_id": value, "value": value },
+            }));
+        }
+        let frames = frames
+            .iter()
+            .map(|frame| format!("{frame}\n"))
+            .collect::<String>();
+        input.write_all(frames.as_bytes()).await.unwrap();
+
+        assert_eq!(next_body(&mut lines).await["type"], "init_ok");
+        for value in 0..100 {
+            let body = next_body(&mut lines).await;
+            assert_eq!(body["in_reply_to"], value);
+            assert_eq!(body["offset"], value);
+        }
+
+        let read = serde_json::json!({
+            "src": "c1",
+            "dest": "n0",
+            "body": { "type": "read", "msg_id": 100 },
+        });
+        input
+            .write_all(format!("{read}\n").as_bytes())
+            .await
+            .unwrap();
+        let values =
+            serde_json::from_value::<Vec<u64>>(next_body(&mut lines).await["values"].clone());
+        assert_eq!(values.unwrap(), (0..100).collect::<Vec<_>>());
+    }
+}
@@ -2,31 +2,14 @@ use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
 use snafu::Snafu;
 
+use crate::config::Configurable;
+use crate::describe::{Describe, FieldDescription, MessageDescription, ServiceDescription};
 pub use crate::error::*;
-use crate::message::Message;
+use crate::message::{ErrorCode, Message};
 use crate::node::{Node, NodeState};
 
-/// A Maelstrom error code.
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
-#[serde(rename_all = "snake_case")]
-#[repr(u64)]
-pub enum ErrorCode {
-    Timeout = 0,
-    NodeNotFound = 1,
-    NotSupported = 10,
-    TemporarilyUnavailable = 11,
-    MalformedRequest = 12,
-    Crash = 13,
-    Abort = 14,
-    KeyDoesNotExist = 20,
-    KeyAlreadyExists = 21,
-    PreconditionFailed = 22,
-    TxnConflict = 30,
-}
-
 /// The message body of a Maelstrom message.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -56,9 +39,50 @@ pub enum UniqueIdServiceError {
     },
 }
 
-impl Into<Error<Self>> for UniqueIdServiceError {
-    fn into(self) -> Error<Self> {
-        Error::Node { source: self }
+impl From<UniqueIdServiceError> for Error<UniqueIdServiceError> {
+    fn from(source: UniqueIdServiceError) -> Self {
+        Error::Node { source }
+    }
+}
+
+impl Describe for UniqueIdService {
+    fn describe() -> ServiceDescription {
+        let replies = ["generate_ok"];
+        ServiceDescription::new(
+            "unique-ids",
+            vec![
+                MessageDescription::new(
+                    "error",
+                    vec![
+                        FieldDescription::of::<ErrorCode>("code"),
+                        FieldDescription::of::<String>("text"),
+                    ],
+                    None,
+                    false,
+                    &replies,
+                ),
+                MessageDescription::new("generate", vec![], Some("generate_ok"), false, &replies),
+                MessageDescription::new(
+                    "generate_ok",
+                    vec![FieldDescription::of::<String>("id")],
+                    None,
+                    false,
+                    &replies,
+                ),
+            ],
+            &[ErrorCode::Crash],
+        )
+    }
+}
+
+impl Configurable for UniqueIdService {
+    const NAME: &'static str = "unique-ids";
+
+    /// Nothing to configure.
+    type Config = ();
+
+    fn from_config((): Self::Config) -> std::result::Result<Self, String> {
+        Ok(Self::default())
     }
 }
 
@@ -66,6 +90,10 @@ impl Node for UniqueIdService {
     type Message = UniqueIdServiceMessage;
     type Error = UniqueIdServiceError;
 
+    fn message_tags(&self) -> &[&'static str] {
+        &["error", "generate", "generate_ok"]
+    }
+
     async fn handle_message(
         &self,
         Message { src, body, .. }: Message<Self::Message>,
@@ -103,3 +131,21 @@ impl Node for UniqueIdService {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{checker, workload, Cluster};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unique_ids_workload() {
+        let cluster = Cluster::new(3, UniqueIdService::default).await;
+        let history = workload::unique_ids(&cluster, 1000.0, Duration::from_secs(10)).await;
+
+        let report = checker::unique_ids(&history);
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.failed, 0);
+    }
+}
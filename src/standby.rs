@@ -0,0 +1,139 @@
+//! Warm standby: a node that keeps up with its peers but turns clients away until it is promoted.
+//!
+//! A standby runs its service as usual, so it replicates whatever its peers send it. The runner
+//! answers every client request with `temporarily_unavailable` instead, naming the active node in
+//! an `active` field. The standby starts serving once promoted: by a `promote` message, or, with
+//! [`StandbyOptions::promote_after_ms`] set, once the active node has been silent that long. To
+//! tell a dead active node from an idle one, the standby sends it a `heartbeat` every quarter of
+//! that time.
+//!
+//! Like [`crate::breaker`], everything takes the current time so it can be tested without a
+//! clock.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StandbyOptions {
+    /// The node clients are sent to until this one is promoted.
+    pub active: String,
+    /// Promote automatically once nothing has been heard from `active` for this many
+    /// milliseconds. Only by a `promote` message if `None`.
+    pub promote_after_ms: Option<u64>,
+}
+
+impl StandbyOptions {
+    pub fn promote_after(&self) -> Option<Duration> {
+        self.promote_after_ms.map(Duration::from_millis)
+    }
+}
+
+/// Whether a standby is serving yet, see the [module docs](self).
+#[derive(Debug)]
+pub struct Standby {
+    options: StandbyOptions,
+    /// Set once a promotion starts, so that only one ever runs.
+    promoting: AtomicBool,
+    serving: AtomicBool,
+    last_heard: std::sync::Mutex<Instant>,
+}
+
+impl Standby {
+    pub fn new(options: StandbyOptions, now: Instant) -> Self {
+        Self {
+            options,
+            promoting: AtomicBool::new(false),
+            serving: AtomicBool::new(false),
+            last_heard: std::sync::Mutex::new(now),
+        }
+    }
+
+    /// The node clients are sent to until this one is promoted.
+    pub fn active(&self) -> &str {
+        &self.options.active
+    }
+
+    /// Whether client requests are handled yet.
+    pub fn is_serving(&self) -> bool {
+        self.serving.load(Ordering::SeqCst)
+    }
+
+    /// Note a message from `src` at `now`.
+    pub fn heard_from(&self, src: &str, now: Instant) {
+        if src == self.options.active {
+            *self.last_heard.lock().unwrap() = now;
+        }
+    }
+
+    /// How often to heartbeat the active node, if it is watched at all.
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.options.promote_after().map(|after| after / 4)
+    }
+
+    /// Whether the active node has been silent long enough to promote without being told to.
+    pub fn active_is_dead(&self, now: Instant) -> bool {
+        self.options.promote_after().is_some_and(|after| {
+            now.saturating_duration_since(*self.last_heard.lock().unwrap()) >= after
+        })
+    }
+
+    /// Claim the promotion, returning false if another one already started.
+    pub fn begin_promotion(&self) -> bool {
+        !self.promoting.swap(true, Ordering::SeqCst)
+    }
+
+    /// Start serving clients.
+    pub fn finish_promotion(&self) {
+        self.serving.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_is_dead_after_silence() {
+        let start = Instant::now();
+        let standby = Standby::new(
+            StandbyOptions {
+                active: "n0".into(),
+                promote_after_ms: Some(400),
+            },
+            start,
+        );
+        assert_eq!(
+            standby.heartbeat_interval(),
+            Some(Duration::from_millis(100))
+        );
+        assert!(!standby.active_is_dead(start + Duration::from_millis(399)));
+
+        // Only the active node counts.
+        standby.heard_from("n2", start + Duration::from_millis(300));
+        assert!(standby.active_is_dead(start + Duration::from_millis(400)));
+        standby.heard_from("n0", start + Duration::from_millis(300));
+        assert!(!standby.active_is_dead(start + Duration::from_millis(400)));
+        assert!(standby.active_is_dead(start + Duration::from_millis(700)));
+    }
+
+    #[test]
+    fn test_promotes_once() {
+        let standby = Standby::new(
+            StandbyOptions {
+                active: "n0".into(),
+                promote_after_ms: None,
+            },
+            Instant::now(),
+        );
+        assert!(!standby.active_is_dead(Instant::now() + Duration::from_secs(3600)));
+
+        assert!(standby.begin_promotion());
+        assert!(!standby.begin_promotion());
+        assert!(!standby.is_serving());
+        standby.finish_promotion();
+        assert!(standby.is_serving());
+    }
+}
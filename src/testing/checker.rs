@@ -0,0 +1,183 @@
+//! Checkers validating a workload [`History`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    time::Duration,
+};
+
+use super::workload::{History, Op};
+
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    /// The number of broadcasts that were acknowledged.
+    pub acknowledged: usize,
+    /// Acknowledged values missing from each node's final read.
+    pub lost: BTreeMap<String, BTreeSet<u64>>,
+    /// For every acknowledged value that was not lost, how long after it was broadcast every
+    /// subsequent read contained it.
+    pub stable_latencies: Vec<Duration>,
+}
+
+impl BroadcastReport {
+    pub fn is_valid(&self) -> bool {
+        self.acknowledged > 0 && self.lost.is_empty()
+    }
+
+    /// The latency below which `fraction` of the acknowledged values became stable.
+    pub fn latency_quantile(&self, fraction: f64) -> Duration {
+        let mut latencies = self.stable_latencies.clone();
+        latencies.sort();
+        let Some(last) = latencies.len().checked_sub(1) else {
+            return Duration::ZERO;
+        };
+        latencies[((last as f64) * fraction).round() as usize]
+    }
+}
+
+fn read_values(op: &Op) -> HashSet<u64> {
+    op.response.as_ref().expect("completed read")["messages"]
+        .as_array()
+        .map(|messages| messages.iter().filter_map(|m| m.as_u64()).collect())
+        .unwrap_or_default()
+}
+
+/// Check that every acknowledged broadcast value made it to every node, and how quickly.
+///
+/// The last successful read against each node is taken as its final state.
+pub fn broadcast(history: &History) -> BroadcastReport {
+    let acknowledged = history
+        .iter()
+        .filter(|op| op.request_type() == "broadcast" && op.completed_with("broadcast_ok"))
+        .map(|op| (op.request["message"].as_u64().expect("broadcast value"), op))
+        .collect::<Vec<_>>();
+
+    let reads = history
+        .iter()
+        .filter(|op| op.request_type() == "read" && op.completed_with("read_ok"))
+        .map(|op| (op, read_values(op)))
+        .collect::<Vec<_>>();
+
+    let mut finals = HashMap::new();
+    for (op, values) in &reads {
+        finals.insert(op.node.as_str(), values);
+    }
+
+    let mut lost = BTreeMap::<String, BTreeSet<u64>>::new();
+    for (node, values) in &finals {
+        let missing = acknowledged
+            .iter()
+            .map(|(value, _)| *value)
+            .filter(|value| !values.contains(value))
+            .collect::<BTreeSet<_>>();
+        if !missing.is_empty() {
+            lost.insert(node.to_string(), missing);
+        }
+    }
+
+    let stable_latencies = acknowledged
+        .iter()
+        .filter(|(value, _)| !lost.values().any(|missing| missing.contains(value)))
+        .map(|(value, broadcast)| {
+            reads
+                .iter()
+                .filter(|(read, values)| read.invoke >= broadcast.invoke && !values.contains(value))
+                .map(|(read, _)| read.complete.duration_since(broadcast.invoke))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    BroadcastReport {
+        acknowledged: acknowledged.len(),
+        lost,
+        stable_latencies,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CounterReport {
+    /// The sum of the adds that were acknowledged.
+    pub acknowledged: u64,
+    /// The sum of every add, acknowledged or not.
+    pub attempted: u64,
+    /// Each node's final read, by node.
+    pub finals: BTreeMap<String, u64>,
+}
+
+impl CounterReport {
+    /// Whether every node ended up counting every acknowledged add, and nothing that wasn't
+    /// attempted. Adds that timed out may or may not have been counted.
+    pub fn is_valid(&self) -> bool {
+        self.acknowledged > 0
+            && !self.finals.is_empty()
+            && self
+                .finals
+                .values()
+                .all(|value| (self.acknowledged..=self.attempted).contains(value))
+    }
+}
+
+/// Check that the counter's final value on every node adds up to what was added.
+///
+/// The last successful read against each node is taken as its final state.
+pub fn counter(history: &History) -> CounterReport {
+    let mut report = CounterReport::default();
+    for op in history {
+        match op.request_type() {
+            "add" => {
+                let delta = op.request["delta"].as_u64().expect("add delta");
+                report.attempted += delta;
+                if op.completed_with("add_ok") {
+                    report.acknowledged += delta;
+                }
+            }
+            "read" if op.completed_with("read_ok") => {
+                let value = op.response.as_ref().expect("completed read")["value"]
+                    .as_u64()
+                    .expect("read value");
+                report.finals.insert(op.node.clone(), value);
+            }
+            _ => {}
+        }
+    }
+    report
+}
+
+#[derive(Debug, Default)]
+pub struct UniqueIdsReport {
+    /// The number of IDs that were generated.
+    pub generated: usize,
+    /// Requests that did not complete.
+    pub failed: usize,
+    /// IDs that were handed out more than once.
+    pub duplicates: BTreeSet<String>,
+}
+
+impl UniqueIdsReport {
+    pub fn is_valid(&self) -> bool {
+        self.generated > 0 && self.duplicates.is_empty()
+    }
+}
+
+/// Check that no ID was generated twice.
+pub fn unique_ids(history: &History) -> UniqueIdsReport {
+    let mut report = UniqueIdsReport::default();
+    let mut seen = HashSet::new();
+    for op in history {
+        let Some(id) = op
+            .response
+            .as_ref()
+            .filter(|_| op.completed_with("generate_ok"))
+            .map(|response| response["id"].to_string())
+        else {
+            report.failed += 1;
+            continue;
+        };
+
+        report.generated += 1;
+        if !seen.insert(id.clone()) {
+            report.duplicates.insert(id);
+        }
+    }
+    report
+}
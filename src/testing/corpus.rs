@@ -0,0 +1,253 @@
+//! Regression tests replayed from the input of failed runs.
+//!
+//! A corpus entry is a journal: the frames a node read from stdin, one JSON object per line, as
+//! Maelstrom sent them. Next to it, a sidecar named like the journal with `.expected.json` in
+//! place of `.jsonl` holds the replies a known-good build sent, see [`Expectations`].
+//!
+//! [`replay`] runs a journal against a fresh node. [`Replay::check`] then fails if the node
+//! exited with an error, left a client request with a `msg_id` unanswered, or answered one
+//! differently than the sidecar says. [`record`] writes the sidecar from a replay, for turning a
+//! journal into a corpus entry once the build it runs against is known to be good.
+//!
+//! [`diff`] compares two replays of the same journal instead, e.g. against the code before and
+//! after a change that shouldn't be visible from outside, see [`Divergence`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::node::{Node, NodeOptions, NodeState};
+
+/// How long a replay waits for more output before deciding the node is done.
+pub const QUIET: Duration = Duration::from_millis(200);
+
+/// Expected reply bodies by the request they answer, written `<src>/<msg_id>`. Replies are
+/// compared without their own `msg_id`, which depends on how many messages the node sent before.
+pub type Expectations = BTreeMap<String, Value>;
+
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// The replies the node sent, by request, in the same form as [`Expectations`].
+    pub replies: Expectations,
+    /// Messages the node sent to other nodes that aren't replies, by type, without their
+    /// `msg_id` and sorted, since their order depends on timing.
+    pub peer: BTreeMap<String, Vec<Value>>,
+    /// Client requests with a `msg_id` that were never answered.
+    pub unanswered: Vec<String>,
+    /// Why the node exited, if it did.
+    pub exit: Option<String>,
+}
+
+impl Replay {
+    /// Check the replay against `expected`, describing every problem found.
+    pub fn check(&self, expected: &Expectations) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if let Some(exit) = &self.exit {
+            problems.push(format!("node exited: {exit}"));
+        }
+        for request in &self.unanswered {
+            problems.push(format!("{request} was never answered"));
+        }
+        for (request, reply) in expected {
+            match self.replies.get(request) {
+                Some(actual) if actual == reply => {}
+                Some(actual) => {
+                    problems.push(format!("{request} got {actual}, expected {reply}"));
+                }
+                // Already reported if the request was unanswered.
+                None => {}
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join("\n")),
+        }
+    }
+}
+
+fn request_key(src: &Value, msg_id: &Value) -> String {
+    format!("{}/{}", src.as_str().unwrap_or_default(), msg_id)
+}
+
+/// Feed `journal` to a fresh node running `service` and collect its replies.
+pub async fn replay<S: Node>(service: S, journal: &str) -> Replay {
+    let (mut stdin, node_stdin) = tokio::io::duplex(64 * 1024);
+    let (node_stdout, stdout) = tokio::io::duplex(64 * 1024);
+    let options = NodeOptions::default();
+    let mut node = tokio::spawn(NodeState::run_with_io(
+        service,
+        options,
+        node_stdin,
+        node_stdout,
+    ));
+
+    let mut requests = Vec::new();
+    for line in journal.lines().filter(|line| !line.trim().is_empty()) {
+        if let Ok(frame) = serde_json::from_str::<Value>(line) {
+            let body = &frame["body"];
+            let from_client = frame["src"]
+                .as_str()
+                .is_some_and(|src| src.starts_with('c'));
+            if from_client && !body["msg_id"].is_null() && body["type"] != "init" {
+                requests.push(request_key(&frame["src"], &body["msg_id"]));
+            }
+        }
+        stdin.write_all(line.as_bytes()).await.unwrap();
+        stdin.write_all(b"\n").await.unwrap();
+    }
+
+    let mut replay = Replay::default();
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    loop {
+        tokio::select! {
+            exit = &mut node => {
+                replay.exit = Some(match exit {
+                    Ok(Ok(())) => "cleanly".to_owned(),
+                    Ok(Err(e)) => snafu::Report::from_error(e).to_string(),
+                    Err(e) => e.to_string(),
+                });
+                break;
+            }
+            line = tokio::time::timeout(QUIET, lines.next_line()) => {
+                let Ok(Ok(Some(line))) = line else { break };
+                let Ok(mut frame) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                if let Some(body) = frame["body"].as_object_mut() {
+                    body.remove("msg_id");
+                }
+                if frame["body"]["in_reply_to"].is_null() {
+                    let tag = frame["body"]["type"].as_str().unwrap_or_default().to_owned();
+                    if let Some(frame) = frame.as_object_mut() {
+                        frame.remove("src");
+                    }
+                    replay.peer.entry(tag).or_default().push(frame);
+                    continue;
+                }
+                let key = request_key(&frame["dest"], &frame["body"]["in_reply_to"]);
+                replay.replies.insert(key, frame["body"].take());
+            }
+        }
+    }
+    // Aborted rather than sent EOF, which the runner doesn't stop on.
+    node.abort();
+
+    for messages in replay.peer.values_mut() {
+        messages.sort_by_cached_key(Value::to_string);
+    }
+    replay.unanswered = requests
+        .into_iter()
+        .filter(|request| !replay.replies.contains_key(request))
+        .collect();
+    replay
+}
+
+/// How two replays of the same journal differ, from [`diff`]. Empty if they don't.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Divergence {
+    /// Requests that only one replay answered, and which one: `"a"` or `"b"`.
+    pub missing: BTreeMap<String, &'static str>,
+    /// Requests answered differently, with the reply from each replay.
+    pub replies: BTreeMap<String, [Value; 2]>,
+    /// Peer message types sent differently, with the messages of that type from each replay.
+    pub peer: BTreeMap<String, [Vec<Value>; 2]>,
+    /// How each replay's node exited, if either did and they differ.
+    pub exit: Option<[Option<String>; 2]>,
+}
+
+impl Divergence {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Compare two replays of the same journal. Peer messages with a type in `ignore_peer_tags` are
+/// left out, for those whose timing may legitimately differ, like gossip. Object keys are
+/// compared regardless of order, and `msg_id`s not at all.
+pub fn diff(a: &Replay, b: &Replay, ignore_peer_tags: &[&str]) -> Divergence {
+    let mut divergence = Divergence::default();
+    for (request, reply) in &a.replies {
+        match b.replies.get(request) {
+            Some(other) if other == reply => {}
+            Some(other) => {
+                divergence
+                    .replies
+                    .insert(request.clone(), [reply.clone(), other.clone()]);
+            }
+            None => {
+                divergence.missing.insert(request.clone(), "a");
+            }
+        }
+    }
+    for request in b.replies.keys() {
+        if !a.replies.contains_key(request) {
+            divergence.missing.insert(request.clone(), "b");
+        }
+    }
+
+    let tags = a.peer.keys().chain(b.peer.keys());
+    for tag in tags.filter(|tag| !ignore_peer_tags.contains(&tag.as_str())) {
+        let [a_messages, b_messages] = [a, b].map(|replay| replay.peer.get(tag).cloned());
+        if a_messages != b_messages {
+            divergence.peer.insert(
+                tag.clone(),
+                [
+                    a_messages.unwrap_or_default(),
+                    b_messages.unwrap_or_default(),
+                ],
+            );
+        }
+    }
+
+    if a.exit != b.exit {
+        divergence.exit = Some([a.exit.clone(), b.exit.clone()]);
+    }
+    divergence
+}
+
+/// The sidecar holding the expectations for `journal`.
+pub fn expectations_path(journal: &Path) -> PathBuf {
+    journal.with_extension("expected.json")
+}
+
+/// Replay `journal` against `service` and write what it answered as the journal's expectations.
+pub async fn record<S: Node>(service: S, journal: &Path) -> std::io::Result<Replay> {
+    let replay = replay(service, &std::fs::read_to_string(journal)?).await;
+    let expectations = serde_json::to_string_pretty(&replay.replies)?;
+    std::fs::write(expectations_path(journal), expectations + "\n")?;
+    Ok(replay)
+}
+
+/// Every journal in `dir`, sorted, along with the service it is for: the part of its file name
+/// before the first `-`.
+pub fn journals(dir: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut journals = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "jsonl")
+        {
+            continue;
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let service = name.split('-').next().unwrap_or_default().to_owned();
+        journals.push((service, path));
+    }
+    journals.sort();
+    Ok(journals)
+}
+
+/// Load the expectations for `journal`, or none if it has no sidecar yet.
+pub fn expectations(journal: &Path) -> std::io::Result<Expectations> {
+    match std::fs::read_to_string(expectations_path(journal)) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Expectations::new()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,75 @@
+//! Per-link network latency, like Maelstrom's `--latency`.
+
+use std::{sync::Arc, time::Duration};
+
+type LatencyFn = dyn Fn(&str, &str) -> Duration + Send + Sync;
+
+/// The one-way latency of every link between two nodes.
+///
+/// Only frames between nodes are delayed; clients talk to nodes without latency, so that
+/// measurements taken by the workloads reflect how long the nodes take to agree.
+#[derive(Clone)]
+pub struct LatencyMatrix {
+    latency: Arc<LatencyFn>,
+}
+
+impl Default for LatencyMatrix {
+    fn default() -> Self {
+        Self::uniform(0)
+    }
+}
+
+impl LatencyMatrix {
+    /// The same latency on every link.
+    pub fn uniform(ms: u64) -> Self {
+        Self::from_fn(move |_, _| Duration::from_millis(ms))
+    }
+
+    /// The latency from `src` to `dest` is `latency(src, dest)`. It should always return the same
+    /// latency for the same link.
+    pub fn from_fn(latency: impl Fn(&str, &str) -> Duration + Send + Sync + 'static) -> Self {
+        Self {
+            latency: Arc::new(latency),
+        }
+    }
+
+    /// Nodes `n0`, `n1`, ... laid out on the same square grid as
+    /// [`grid_topology`](super::workload::grid_topology), with `per_hop_ms` of latency for every
+    /// step between them along the grid.
+    pub fn grid(node_count: usize, per_hop_ms: u64) -> Self {
+        let side = (node_count as f64).sqrt().ceil().max(1.0) as usize;
+        let position = move |node: &str| {
+            let i = node.strip_prefix('n')?.parse::<usize>().ok()?;
+            Some((i / side, i % side))
+        };
+        Self::from_fn(move |src, dest| match (position(src), position(dest)) {
+            (Some((row_a, col_a)), Some((row_b, col_b))) => {
+                let hops = row_a.abs_diff(row_b) + col_a.abs_diff(col_b);
+                Duration::from_millis(per_hop_ms * hops as u64)
+            }
+            _ => Duration::ZERO,
+        })
+    }
+
+    pub fn latency(&self, src: &str, dest: &str) -> Duration {
+        (self.latency)(src, dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_latency() {
+        // n0 n1 n2
+        // n3 n4
+        let matrix = LatencyMatrix::grid(5, 10);
+        assert_eq!(matrix.latency("n0", "n1"), Duration::from_millis(10));
+        assert_eq!(matrix.latency("n0", "n4"), Duration::from_millis(20));
+        assert_eq!(matrix.latency("n2", "n3"), Duration::from_millis(30));
+        assert_eq!(matrix.latency("n3", "n2"), Duration::from_millis(30));
+        assert_eq!(matrix.latency("n4", "n4"), Duration::ZERO);
+        assert_eq!(matrix.latency("c0", "n4"), Duration::ZERO);
+    }
+}
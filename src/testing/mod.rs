@@ -0,0 +1,753 @@
+//! An in-process stand-in for Maelstrom.
+//!
+//! A [`Cluster`] runs several nodes on the current runtime, connected by in-memory pipes instead of
+//! stdin/stdout, and routes frames between them. Tests talk to the nodes through [`Client`]s, the
+//! same way Maelstrom's clients do. Everything runs on tokio time, so tests should use
+//! `#[tokio::test(start_paused = true)]` to make long workloads finish quickly.
+//!
+//! Outside this crate, the module is behind the `test-util` feature:
+//!
+//! ```ignore
+//! let cluster = Cluster::builder()
+//!     .nodes(3)
+//!     .latency(LatencyMatrix::uniform(10))
+//!     .service(MyService::default)
+//!     .build()
+//!     .await;
+//! let history = workload::broadcast(&cluster, 100.0, Duration::from_secs(10)).await;
+//! assert!(checker::broadcast(&history).is_valid());
+//! cluster.traffic().assert_msgs_per_op_below(&history, 20.0);
+//! ```
+
+pub mod checker;
+pub mod corpus;
+mod latency;
+mod traffic;
+pub mod workload;
+
+pub use latency::LatencyMatrix;
+pub use traffic::Traffic;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::audit::AuditReport;
+use crate::node::{Node, NodeOptions, NodeState, TaskCounter};
+
+/// How long a client waits for a reply before giving up on a request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Builds the body sent to every node when the cluster changes, given the new node IDs, see
+/// [`ClusterBuilder::membership_notice`]. `None` sends nothing.
+pub type MembershipNotice = fn(&[String]) -> Option<Value>;
+
+/// The default [`MembershipNotice`]: the runner's `membership_change`, which ends up in
+/// [`Node::on_membership_change`].
+pub fn membership_change(node_ids: &[String]) -> Option<Value> {
+    Some(serde_json::json!({ "type": "membership_change", "node_ids": node_ids }))
+}
+
+/// A frame in flight on a delayed link, with the time it is due.
+type InFlight = (Instant, String);
+
+/// Decides whether a fault applies to a frame, given its `src`, `dest` and the frame itself.
+type FrameRule = Box<dyn FnMut(&str, &str, &Value) -> bool + Send>;
+
+/// Routes frames between nodes and clients.
+#[derive(Default)]
+struct Network {
+    /// Frames waiting to be written to each node's stdin.
+    nodes: Mutex<HashMap<String, mpsc::UnboundedSender<String>>>,
+    /// Mailboxes of the clients currently connected to the cluster.
+    clients: Mutex<HashMap<String, mpsc::UnboundedSender<Value>>>,
+    latency: LatencyMatrix,
+    /// Frames in flight on each delayed link, in the order they were sent.
+    links: Mutex<HashMap<(String, String), mpsc::UnboundedSender<InFlight>>>,
+    /// Frames sent by nodes that match this rule are lost.
+    drop_rule: Mutex<Option<FrameRule>>,
+    /// Frames that match this rule, from nodes or clients, are delivered twice.
+    duplicate_rule: Mutex<Option<FrameRule>>,
+    /// Every frame sent from one node to another.
+    traffic: Mutex<Traffic>,
+}
+
+impl Network {
+    fn deliver(&self, src: &str, dest: &str, frame: String) {
+        let nodes = self.nodes.lock().unwrap();
+        if let Some(node) = nodes.get(dest) {
+            let latency = match nodes.contains_key(src) {
+                true => self.latency.latency(src, dest),
+                false => Duration::ZERO,
+            };
+            if latency.is_zero() {
+                node.send(frame).ok();
+            } else {
+                self.delay(src, dest, node, latency, frame);
+            }
+            return;
+        }
+        drop(nodes);
+
+        let clients = self.clients.lock().unwrap();
+        match clients.get(dest) {
+            Some(client) => match serde_json::from_str(&frame) {
+                Ok(frame) => {
+                    client.send(frame).ok();
+                }
+                Err(e) => tracing::warn!("Dropping malformed frame for {}: {}", dest, e),
+            },
+            None => tracing::debug!("Dropping frame for unknown destination {}", dest),
+        }
+    }
+
+    /// Deliver `frame` to `node` after `latency`. Each link has a single task delivering its
+    /// frames one after the other, so frames on a link are never reordered.
+    fn delay(
+        &self,
+        src: &str,
+        dest: &str,
+        node: &mpsc::UnboundedSender<String>,
+        latency: Duration,
+        frame: String,
+    ) {
+        let mut links = self.links.lock().unwrap();
+        let link = links
+            .entry((src.to_owned(), dest.to_owned()))
+            .or_insert_with(|| {
+                let (link, mut in_flight) = mpsc::unbounded_channel::<InFlight>();
+                let node = node.clone();
+                tokio::spawn(async move {
+                    while let Some((due, frame)) = in_flight.recv().await {
+                        tokio::time::sleep_until(due).await;
+                        if node.send(frame).is_err() {
+                            break;
+                        }
+                    }
+                });
+                link
+            });
+        link.send((Instant::now() + latency, frame)).ok();
+    }
+
+    /// Count `frame` if it is from one node to another.
+    fn count(&self, src: &str, dest: &str, frame: &Value) {
+        let nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(src) && nodes.contains_key(dest) {
+            let kind = frame["body"]["type"].as_str().unwrap_or_default();
+            self.traffic.lock().unwrap().record(src, dest, kind);
+        }
+    }
+
+    fn dropped(&self, src: &str, dest: &str, frame: &Value) -> bool {
+        match self.drop_rule.lock().unwrap().as_mut() {
+            Some(rule) => rule(src, dest, frame),
+            None => false,
+        }
+    }
+
+    fn duplicated(&self, src: &str, dest: &str, frame: &Value) -> bool {
+        match self.duplicate_rule.lock().unwrap().as_mut() {
+            Some(rule) => rule(src, dest, frame),
+            None => false,
+        }
+    }
+
+    /// Start `node` as `node_id`, replacing whatever was running under that id before, and return
+    /// the tasks that run it.
+    fn start<S: Node>(
+        self: &Arc<Self>,
+        node_id: &str,
+        node: S,
+        options: NodeOptions,
+    ) -> Vec<JoinHandle<()>> {
+        let (mut stdin, node_stdin) = tokio::io::duplex(64 * 1024);
+        let (node_stdout, stdout) = tokio::io::duplex(64 * 1024);
+
+        let (frames, mut pending) = mpsc::unbounded_channel::<String>();
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(node_id.to_owned(), frames);
+        // Delayed links hold on to the stdin of the node they lead to.
+        self.links
+            .lock()
+            .unwrap()
+            .retain(|(src, dest), _| src != node_id && dest != node_id);
+
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = pending.recv().await {
+                if stdin.write_all(frame.as_bytes()).await.is_err()
+                    || stdin.write_all(b"\n").await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let runner = tokio::spawn({
+            let node_id = node_id.to_owned();
+            async move {
+                if let Err(e) = NodeState::run_with_io(node, options, node_stdin, node_stdout).await
+                {
+                    tracing::error!("Node {} exited: {}", node_id, e);
+                }
+            }
+        });
+
+        let reader = tokio::spawn({
+            let network = Arc::clone(self);
+            async move {
+                let mut lines = tokio::io::BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let frame = match serde_json::from_str::<Value>(&line) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            tracing::warn!("Node wrote a malformed frame: {}", e);
+                            continue;
+                        }
+                    };
+                    let src = frame["src"].as_str().unwrap_or_default();
+                    let dest = frame["dest"].as_str().unwrap_or_default();
+                    network.count(src, dest, &frame);
+                    if !network.dropped(src, dest, &frame) {
+                        if network.duplicated(src, dest, &frame) {
+                            network.deliver(src, dest, line.clone());
+                        }
+                        network.deliver(src, dest, line);
+                    }
+                }
+            }
+        });
+
+        vec![writer, runner, reader]
+    }
+}
+
+/// A cluster of nodes running in the current process.
+pub struct Cluster {
+    node_ids: Vec<String>,
+    network: Arc<Network>,
+    next_client: AtomicU64,
+    options: NodeOptions,
+    /// Options for particular nodes, in place of `options`.
+    node_options: HashMap<String, NodeOptions>,
+    /// The tasks running each node.
+    tasks: HashMap<String, Vec<JoinHandle<()>>>,
+    /// Background tasks spawned by the nodes themselves.
+    node_tasks: TaskCounter,
+    /// The number in the name of the next node [`Cluster::add_node`] starts.
+    next_node: usize,
+    membership_notice: MembershipNotice,
+}
+
+/// Configures a [`Cluster`], see [`Cluster::builder`].
+pub struct ClusterBuilder<F = ()> {
+    nodes: usize,
+    latency: LatencyMatrix,
+    options: NodeOptions,
+    node_options: HashMap<String, NodeOptions>,
+    membership_notice: MembershipNotice,
+    service: F,
+}
+
+impl<F> ClusterBuilder<F> {
+    /// How many nodes to start. One by default.
+    pub fn nodes(mut self, count: usize) -> Self {
+        self.nodes = count;
+        self
+    }
+
+    /// The latency of the links between nodes. None by default.
+    pub fn latency(mut self, latency: LatencyMatrix) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The options every node runs with.
+    pub fn options(mut self, options: NodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The options `node_id` runs with, in place of [`ClusterBuilder::options`]. Its background
+    /// tasks are still counted with every other node's.
+    pub fn node_options(mut self, node_id: &str, options: NodeOptions) -> Self {
+        self.node_options.insert(node_id.to_owned(), options);
+        self
+    }
+
+    /// What to tell the nodes when [`Cluster::add_node`] or [`Cluster::remove_node`] changes the
+    /// cluster. [`membership_change`] by default.
+    pub fn membership_notice(mut self, notice: MembershipNotice) -> Self {
+        self.membership_notice = notice;
+        self
+    }
+
+    /// Build each node's service with `service`.
+    pub fn service<S: Node, G: Fn() -> S>(self, service: G) -> ClusterBuilder<G> {
+        ClusterBuilder {
+            nodes: self.nodes,
+            latency: self.latency,
+            options: self.options,
+            node_options: self.node_options,
+            membership_notice: self.membership_notice,
+            service,
+        }
+    }
+}
+
+impl<S: Node, F: Fn() -> S> ClusterBuilder<F> {
+    /// Start the nodes and initialize them.
+    pub async fn build(self) -> Cluster {
+        let mut cluster = Cluster::start(
+            self.nodes,
+            self.latency,
+            self.options,
+            self.node_options,
+            self.service,
+        )
+        .await;
+        cluster.membership_notice = self.membership_notice;
+        cluster
+    }
+}
+
+impl Cluster {
+    pub fn builder() -> ClusterBuilder {
+        ClusterBuilder {
+            nodes: 1,
+            latency: LatencyMatrix::default(),
+            options: NodeOptions::default(),
+            node_options: HashMap::new(),
+            membership_notice: membership_change,
+            service: (),
+        }
+    }
+
+    /// Start `count` nodes named `n0`, `n1`, ... and send each of them `init`.
+    pub async fn new<S: Node>(count: usize, service: impl Fn() -> S) -> Self {
+        Self::with_latency(count, LatencyMatrix::default(), service).await
+    }
+
+    /// Like [`Cluster::new`], but with `latency` on the links between nodes.
+    pub async fn with_latency<S: Node>(
+        count: usize,
+        latency: LatencyMatrix,
+        service: impl Fn() -> S,
+    ) -> Self {
+        Self::with_options(count, latency, NodeOptions::default(), service).await
+    }
+
+    /// Like [`Cluster::with_latency`], with every node running with `options`.
+    pub async fn with_options<S: Node>(
+        count: usize,
+        latency: LatencyMatrix,
+        options: NodeOptions,
+        service: impl Fn() -> S,
+    ) -> Self {
+        Self::start(count, latency, options, HashMap::new(), service).await
+    }
+
+    async fn start<S: Node>(
+        count: usize,
+        latency: LatencyMatrix,
+        options: NodeOptions,
+        mut node_options: HashMap<String, NodeOptions>,
+        service: impl Fn() -> S,
+    ) -> Self {
+        let node_ids = (0..count).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        for overridden in node_options.values_mut() {
+            overridden.task_counter = options.task_counter.clone();
+        }
+
+        let network = Arc::new(Network {
+            latency,
+            ..Default::default()
+        });
+        let mut cluster = Self {
+            node_ids,
+            network,
+            next_client: AtomicU64::new(0),
+            node_tasks: options.task_counter.clone(),
+            options,
+            node_options,
+            tasks: HashMap::new(),
+            next_node: count,
+            membership_notice: membership_change,
+        };
+        for node_id in cluster.node_ids.clone() {
+            let tasks =
+                cluster
+                    .network
+                    .start(&node_id, service(), cluster.options_for(&node_id).clone());
+            cluster.tasks.insert(node_id, tasks);
+        }
+
+        for node_id in &cluster.node_ids {
+            cluster.init(node_id).await;
+        }
+
+        cluster
+    }
+
+    async fn init(&self, node_id: &str) {
+        let reply = self
+            .client()
+            .rpc(
+                node_id,
+                serde_json::json!({
+                    "type": "init",
+                    "node_id": node_id,
+                    "node_ids": self.node_ids,
+                }),
+            )
+            .await;
+        assert_eq!(
+            reply.as_ref().map(|body| &body["type"]),
+            Some(&Value::from("init_ok")),
+            "{node_id} did not acknowledge init"
+        );
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    fn options_for(&self, node_id: &str) -> &NodeOptions {
+        self.node_options.get(node_id).unwrap_or(&self.options)
+    }
+
+    /// The number of background tasks the nodes currently have running.
+    pub fn live_node_tasks(&self) -> usize {
+        self.node_tasks.live()
+    }
+
+    /// Kill `node_id` and start `service` in its place, as if the process had crashed and been
+    /// restarted with nothing but its id. Frames already in flight to the old node are lost.
+    pub async fn restart<S: Node>(&mut self, node_id: &str, service: S) {
+        self.kill(node_id).await;
+        let tasks = self
+            .network
+            .start(node_id, service, self.options_for(node_id).clone());
+        self.tasks.insert(node_id.to_owned(), tasks);
+        self.init(node_id).await;
+    }
+
+    /// Kill `node_id` for good, as if its process had crashed. Frames sent to it are lost.
+    pub async fn kill(&mut self, node_id: &str) {
+        for task in self.tasks.remove(node_id).unwrap_or_default() {
+            task.abort();
+            task.await.ok();
+        }
+    }
+
+    /// Start `service` as a new node, named after the last one, and initialize it with the whole
+    /// cluster. Every other node is then told, see [`ClusterBuilder::membership_notice`]. Returns
+    /// the new node's ID.
+    pub async fn add_node<S: Node>(&mut self, service: S) -> String {
+        let node_id = format!("n{}", self.next_node);
+        self.next_node += 1;
+        self.node_ids.push(node_id.clone());
+        let tasks = self
+            .network
+            .start(&node_id, service, self.options_for(&node_id).clone());
+        self.tasks.insert(node_id.clone(), tasks);
+        self.init(&node_id).await;
+        self.announce_membership(&node_id).await;
+        node_id
+    }
+
+    /// Stop `node_id` and take it out of the cluster, so that frames sent to it go nowhere, then
+    /// tell every remaining node, see [`ClusterBuilder::membership_notice`].
+    pub async fn remove_node(&mut self, node_id: &str) {
+        self.kill(node_id).await;
+        self.node_ids.retain(|node| node != node_id);
+        self.network.nodes.lock().unwrap().remove(node_id);
+        self.network
+            .links
+            .lock()
+            .unwrap()
+            .retain(|(src, dest), _| src != node_id && dest != node_id);
+        self.announce_membership(node_id).await;
+    }
+
+    /// Send the membership notice to every node but `changed`, waiting for each to answer.
+    async fn announce_membership(&self, changed: &str) {
+        let Some(notice) = (self.membership_notice)(&self.node_ids) else {
+            return;
+        };
+        let client = self.client();
+        for node_id in self.node_ids.iter().filter(|node| *node != changed) {
+            if client.rpc(node_id, notice.clone()).await.is_none() {
+                tracing::warn!("{} did not answer the membership notice", node_id);
+            }
+        }
+    }
+
+    /// Lose every frame sent between nodes for which `rule` returns true, until
+    /// [`Cluster::heal`] is called. The rule is given the frame's `src`, `dest` and the frame.
+    pub fn drop_frames(&self, rule: impl FnMut(&str, &str, &Value) -> bool + Send + 'static) {
+        *self.network.drop_rule.lock().unwrap() = Some(Box::new(rule));
+    }
+
+    /// Deliver every frame for which `rule` returns true twice, as Maelstrom's `duplicate`
+    /// nemesis does, until [`Cluster::heal`] is called. Unlike [`Cluster::drop_frames`], this
+    /// applies to requests from clients too.
+    pub fn duplicate_frames(&self, rule: impl FnMut(&str, &str, &Value) -> bool + Send + 'static) {
+        *self.network.duplicate_rule.lock().unwrap() = Some(Box::new(rule));
+    }
+
+    /// Stop losing and duplicating frames.
+    pub fn heal(&self) {
+        *self.network.drop_rule.lock().unwrap() = None;
+        *self.network.duplicate_rule.lock().unwrap() = None;
+    }
+
+    /// The messages nodes have sent each other since the cluster started, or since the last
+    /// [`Cluster::reset_traffic`].
+    pub fn traffic(&self) -> Traffic {
+        self.network.traffic.lock().unwrap().clone()
+    }
+
+    /// Start counting messages from zero, say to leave out a warm-up.
+    pub fn reset_traffic(&self) {
+        *self.network.traffic.lock().unwrap() = Traffic::default();
+    }
+
+    /// Send every node a `drain`, as a run would before stopping them, and collect their audit
+    /// reports, see [`crate::audit`]. A node's report is `None` if it has audits off, its service
+    /// has no digest, or it didn't answer.
+    pub async fn drain(&self) -> BTreeMap<String, Option<AuditReport>> {
+        let client = self.client();
+        let drains = self.node_ids.iter().map(|node_id| {
+            let timeout = self
+                .options_for(node_id)
+                .audit_timeout_ms
+                .map_or(Duration::ZERO, Duration::from_millis);
+            let drain = serde_json::json!({ "type": "drain" });
+            let client = &client;
+            async move {
+                let reply = client
+                    .rpc_with_timeout(node_id, drain, timeout + DEFAULT_TIMEOUT)
+                    .await;
+                let audit =
+                    reply.and_then(|reply| serde_json::from_value(reply["audit"].clone()).ok());
+                (node_id.clone(), audit)
+            }
+        });
+        futures::future::join_all(drains)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Have each node in turn run a gossip round right away, see [`Node::flush_gossip`], instead
+    /// of waiting for the next one. Returns how many values each node sent each peer, leaving out
+    /// nodes that don't gossip or didn't answer. A node answers once its round's messages are out,
+    /// so they reach their peers before anything sent after this returns.
+    pub async fn flush_gossip(&self, full: bool) -> BTreeMap<String, BTreeMap<String, u64>> {
+        let client = self.client();
+        let mut flushed = BTreeMap::new();
+        for node_id in &self.node_ids {
+            let flush = serde_json::json!({ "type": "flush_gossip", "full": full });
+            let sent = client
+                .rpc(node_id, flush)
+                .await
+                .and_then(|reply| serde_json::from_value(reply["sent"].clone()).ok());
+            if let Some(sent) = sent {
+                flushed.insert(node_id.clone(), sent);
+            }
+        }
+        flushed
+    }
+
+    /// Stop every node and wait until none of their background tasks are left.
+    pub async fn shutdown(&mut self) {
+        for task in self.tasks.drain().flat_map(|(_, tasks)| tasks) {
+            task.abort();
+            task.await.ok();
+        }
+        self.node_tasks.idle().await;
+    }
+
+    /// Connect a new client to the cluster. Clients are named `c0`, `c1`, ...
+    pub fn client(&self) -> Client {
+        let id = format!("c{}", self.next_client.fetch_add(1, Ordering::Relaxed));
+        Client::connect(id, Arc::clone(&self.network))
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for task in self.tasks.values().flatten() {
+            task.abort();
+        }
+    }
+}
+
+struct ClientInner {
+    id: String,
+    network: Arc<Network>,
+    next_id: AtomicU64,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ClientInner {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.network.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A simulated Maelstrom client. Cloning a client shares its id and outstanding requests.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<ClientInner>,
+}
+
+impl Client {
+    fn connect(id: String, network: Arc<Network>) -> Self {
+        let (mailbox, mut replies) = mpsc::unbounded_channel::<Value>();
+        network.clients.lock().unwrap().insert(id.clone(), mailbox);
+
+        let pending = Arc::new(Mutex::new(HashMap::<u64, oneshot::Sender<Value>>::new()));
+        let task = tokio::spawn({
+            let pending = Arc::clone(&pending);
+            async move {
+                while let Some(frame) = replies.recv().await {
+                    let Some(re) = frame["body"]["in_reply_to"].as_u64() else {
+                        continue;
+                    };
+                    if let Some(waiter) = pending.lock().unwrap().remove(&re) {
+                        waiter.send(frame["body"].clone()).ok();
+                    }
+                }
+            }
+        });
+
+        Self {
+            inner: Arc::new(ClientInner {
+                id,
+                network,
+                next_id: AtomicU64::new(1),
+                pending,
+                task,
+            }),
+        }
+    }
+
+    /// Send `body` to `node` and wait for the reply body, giving up after [`DEFAULT_TIMEOUT`].
+    pub async fn rpc(&self, node: &str, body: Value) -> Option<Value> {
+        self.rpc_with_timeout(node, body, DEFAULT_TIMEOUT).await
+    }
+
+    pub async fn rpc_with_timeout(
+        &self,
+        node: &str,
+        mut body: Value,
+        timeout: Duration,
+    ) -> Option<Value> {
+        let msg_id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        body["msg_id"] = msg_id.into();
+
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(msg_id, tx);
+
+        let frame = serde_json::json!({ "src": self.inner.id, "dest": node, "body": body });
+        let network = &self.inner.network;
+        if network.duplicated(&self.inner.id, node, &frame) {
+            network.deliver(&self.inner.id, node, frame.to_string());
+        }
+        network.deliver(&self.inner.id, node, frame.to_string());
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => Some(reply),
+            _ => {
+                self.inner.pending.lock().unwrap().remove(&msg_id);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delayed_links_stay_fifo() {
+        // Each frame on the link is given less latency than the one before it.
+        let sent = Arc::new(AtomicU64::new(0));
+        let latency = LatencyMatrix::from_fn({
+            let sent = Arc::clone(&sent);
+            move |_, _| Duration::from_millis(100 - 10 * sent.fetch_add(1, Ordering::Relaxed))
+        });
+
+        let (node, mut frames) = mpsc::unbounded_channel();
+        let network = Network {
+            nodes: Mutex::new(HashMap::from([
+                ("n0".to_owned(), node.clone()),
+                ("n1".to_owned(), node),
+            ])),
+            latency,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        for i in 0..5 {
+            network.deliver("n0", "n1", i.to_string());
+        }
+        for i in 0..5 {
+            assert_eq!(frames.recv().await.unwrap(), i.to_string());
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicated_requests_arrive_twice() {
+        let (node, mut frames) = mpsc::unbounded_channel();
+        let network = Arc::new(Network {
+            nodes: Mutex::new(HashMap::from([("n0".to_owned(), node)])),
+            duplicate_rule: Mutex::new(Some(Box::new(|_, _, _| true))),
+            ..Default::default()
+        });
+
+        let client = Client::connect("c0".to_owned(), network);
+        let request = serde_json::json!({ "type": "echo" });
+        client
+            .rpc_with_timeout("n0", request, Duration::from_millis(1))
+            .await;
+        assert_eq!(frames.recv().await.unwrap(), frames.recv().await.unwrap());
+        assert!(frames.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clients_see_no_latency() {
+        let (node, mut frames) = mpsc::unbounded_channel();
+        let network = Network {
+            nodes: Mutex::new(HashMap::from([("n0".to_owned(), node)])),
+            latency: LatencyMatrix::uniform(100),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        network.deliver("c0", "n0", "frame".to_owned());
+        assert_eq!(frames.recv().await.unwrap(), "frame");
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,158 @@
+//! Accounting of the messages nodes send each other, for tests that keep an eye on what a
+//! workload costs in messages.
+//!
+//! Only frames from one node to another are counted: client requests, replies to clients and the
+//! harness's own `init` never are. A frame counts when it is sent, whether or not it is lost on
+//! the way.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use super::workload::History;
+
+/// How many of the biggest contributors a failed budget lists.
+const TOP_CONTRIBUTORS: usize = 5;
+
+/// The peer messages sent since the cluster started, see [`super::Cluster::traffic`].
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    /// Messages sent by type, then by `(src, dest)` link.
+    counts: BTreeMap<String, BTreeMap<(String, String), u64>>,
+}
+
+impl Traffic {
+    pub(super) fn record(&mut self, src: &str, dest: &str, kind: &str) {
+        *self
+            .counts
+            .entry(kind.to_owned())
+            .or_default()
+            .entry((src.to_owned(), dest.to_owned()))
+            .or_default() += 1;
+    }
+
+    /// Every message sent between nodes.
+    pub fn total(&self) -> u64 {
+        self.by_type().values().sum()
+    }
+
+    /// Messages sent between nodes, by message type.
+    pub fn by_type(&self) -> BTreeMap<String, u64> {
+        self.counts
+            .iter()
+            .map(|(kind, links)| (kind.clone(), links.values().sum()))
+            .collect()
+    }
+
+    /// Messages sent from `src` to `dest`.
+    pub fn on_link(&self, src: &str, dest: &str) -> u64 {
+        let link = (src.to_owned(), dest.to_owned());
+        self.counts
+            .values()
+            .filter_map(|links| links.get(&link))
+            .sum()
+    }
+
+    /// Messages sent between nodes per client request in `history`, the figure Maelstrom reports
+    /// as `msgs-per-op`.
+    pub fn per_op(&self, history: &History) -> f64 {
+        self.total() as f64 / history.len().max(1) as f64
+    }
+
+    /// Panic if more than `budget` messages were sent between nodes per request in `history`.
+    pub fn assert_msgs_per_op_below(&self, history: &History, budget: f64) {
+        let per_op = self.per_op(history);
+        assert!(
+            per_op <= budget,
+            "{per_op:.1} peer messages per op over {} ops, above the budget of {budget:.1}\n{}",
+            history.len(),
+            self.breakdown(history.len().max(1) as f64, "/op"),
+        );
+    }
+
+    /// Panic if more than `budget` messages were sent between nodes in total.
+    pub fn assert_total_peer_messages_below(&self, budget: u64) {
+        let total = self.total();
+        assert!(
+            total <= budget,
+            "{total} peer messages, above the budget of {budget}\n{}",
+            self.breakdown(1.0, ""),
+        );
+    }
+
+    /// The message types that contributed the most, each as a count divided by `per`.
+    fn breakdown(&self, per: f64, unit: &str) -> String {
+        let mut by_type = self.by_type().into_iter().collect::<Vec<_>>();
+        by_type.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+        let width = by_type
+            .iter()
+            .map(|(kind, _)| kind.len())
+            .max()
+            .unwrap_or(0);
+        let mut breakdown = String::new();
+        for (kind, count) in by_type.iter().take(TOP_CONTRIBUTORS) {
+            let share = *count as f64 / per;
+            writeln!(breakdown, "  {kind:<width$}  {share:>8.1}{unit}").unwrap();
+        }
+        if by_type.len() > TOP_CONTRIBUTORS {
+            writeln!(
+                breakdown,
+                "  ...and {} more",
+                by_type.len() - TOP_CONTRIBUTORS
+            )
+            .unwrap();
+        }
+        breakdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::testing::workload::Op;
+
+    fn history(ops: usize) -> History {
+        let op = Op {
+            node: "n0".into(),
+            request: serde_json::json!({ "type": "read" }),
+            response: None,
+            invoke: Instant::now(),
+            complete: Instant::now(),
+        };
+        vec![op; ops]
+    }
+
+    fn traffic() -> Traffic {
+        let mut traffic = Traffic::default();
+        for _ in 0..30 {
+            traffic.record("n0", "n1", "gossip");
+        }
+        for _ in 0..10 {
+            traffic.record("n1", "n0", "broadcast");
+        }
+        traffic
+    }
+
+    #[test]
+    fn test_counts() {
+        let traffic = traffic();
+        assert_eq!(traffic.total(), 40);
+        assert_eq!(traffic.on_link("n0", "n1"), 30);
+        assert_eq!(traffic.on_link("n1", "n2"), 0);
+        assert_eq!(traffic.per_op(&history(10)), 4.0);
+        traffic.assert_msgs_per_op_below(&history(10), 4.0);
+        traffic.assert_total_peer_messages_below(40);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "4.0 peer messages per op over 10 ops, above the budget of 3.0\n\
+                               \x20 gossip          3.0/op\n\
+                               \x20 broadcast       1.0/op\n"
+    )]
+    fn test_failure_lists_contributors() {
+        traffic().assert_msgs_per_op_below(&history(10), 3.0);
+    }
+}
@@ -0,0 +1,203 @@
+//! Workload drivers mirroring Maelstrom's generators.
+//!
+//! Each driver issues requests from simulated clients at a fixed rate and returns the resulting
+//! [`History`], which can then be validated with the functions in [`super::checker`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+use super::{Client, Cluster};
+
+/// How long the broadcast and counter workloads wait for the cluster to converge before their final
+/// reads.
+pub const SETTLE: Duration = Duration::from_secs(5);
+
+/// A single client request and its outcome.
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub node: String,
+    pub request: Value,
+    /// The reply body, or `None` if the request timed out.
+    pub response: Option<Value>,
+    pub invoke: Instant,
+    pub complete: Instant,
+}
+
+impl Op {
+    pub fn request_type(&self) -> &str {
+        self.request["type"].as_str().unwrap_or_default()
+    }
+
+    /// Whether the request completed with a reply of type `reply_type`.
+    pub fn completed_with(&self, reply_type: &str) -> bool {
+        self.response
+            .as_ref()
+            .is_some_and(|response| response["type"] == reply_type)
+    }
+}
+
+pub type History = Vec<Op>;
+
+async fn invoke(client: &Client, node: &str, request: Value) -> Op {
+    let invoke = Instant::now();
+    let response = client.rpc(node, request.clone()).await;
+    Op {
+        node: node.to_owned(),
+        request,
+        response,
+        invoke,
+        complete: Instant::now(),
+    }
+}
+
+/// Issue `rate` requests per second for `duration`, one client per node. Request `k` is built by
+/// `request(k)` and sent to node `k % node_count`.
+async fn run(
+    cluster: &Cluster,
+    rate: f64,
+    duration: Duration,
+    mut request: impl FnMut(u64) -> Value,
+) -> History {
+    let nodes = cluster.node_ids().to_vec();
+    let clients = nodes.iter().map(|_| cluster.client()).collect::<Vec<_>>();
+
+    let deadline = Instant::now() + duration;
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    let mut ops = Vec::new();
+    for k in 0.. {
+        interval.tick().await;
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        let i = k as usize % nodes.len();
+        let client = clients[i].clone();
+        let node = nodes[i].clone();
+        let request = request(k);
+        ops.push(tokio::spawn(async move {
+            invoke(&client, &node, request).await
+        }));
+    }
+
+    let mut history = Vec::with_capacity(ops.len());
+    for op in ops {
+        history.push(op.await.expect("workload op panicked"));
+    }
+    history
+}
+
+/// The grid topology Maelstrom uses by default: nodes are laid out row by row on a square grid
+/// and connected to their horizontal and vertical neighbors.
+pub fn grid_topology(nodes: &[String]) -> BTreeMap<String, BTreeSet<String>> {
+    let side = (nodes.len() as f64).sqrt().ceil() as usize;
+    nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let mut neighbors = BTreeSet::new();
+            if i % side > 0 {
+                neighbors.insert(nodes[i - 1].clone());
+            }
+            if i % side + 1 < side && i + 1 < nodes.len() {
+                neighbors.insert(nodes[i + 1].clone());
+            }
+            if i >= side {
+                neighbors.insert(nodes[i - side].clone());
+            }
+            if i + side < nodes.len() {
+                neighbors.insert(nodes[i + side].clone());
+            }
+            (node.clone(), neighbors)
+        })
+        .collect()
+}
+
+/// Send `topology` to every node in the cluster.
+pub async fn send_topology(
+    cluster: &Cluster,
+    client: &Client,
+    topology: &BTreeMap<String, BTreeSet<String>>,
+) {
+    for node in cluster.node_ids() {
+        client
+            .rpc(
+                node,
+                serde_json::json!({ "type": "topology", "topology": topology }),
+            )
+            .await;
+    }
+}
+
+/// Send the grid topology, then run [`broadcast_without_topology`].
+pub async fn broadcast(cluster: &Cluster, rate: f64, duration: Duration) -> History {
+    let client = cluster.client();
+    send_topology(cluster, &client, &grid_topology(cluster.node_ids())).await;
+    broadcast_without_topology(cluster, rate, duration).await
+}
+
+/// Broadcast distinct values and read them back, alternating between the two. Once the workload
+/// is over and the cluster has had [`SETTLE`] to converge, every node is read one final time.
+pub async fn broadcast_without_topology(
+    cluster: &Cluster,
+    rate: f64,
+    duration: Duration,
+) -> History {
+    let nodes = cluster.node_ids().len() as u64;
+    let mut next_value = 0u64;
+    let mut history = run(cluster, rate, duration, |k| {
+        if (k / nodes).is_multiple_of(2) {
+            next_value += 1;
+            serde_json::json!({ "type": "broadcast", "message": next_value })
+        } else {
+            serde_json::json!({ "type": "read" })
+        }
+    })
+    .await;
+
+    tokio::time::sleep(SETTLE).await;
+    let client = cluster.client();
+    for node in cluster.node_ids() {
+        history.push(invoke(&client, node, serde_json::json!({ "type": "read" })).await);
+    }
+
+    history
+}
+
+/// Add to the counter and read it back, alternating between the two. Request `k` that adds, adds
+/// `k % 5 + 1`. Once the workload is over and the cluster has had [`SETTLE`] to converge, every
+/// node is read one final time.
+pub async fn counter(cluster: &Cluster, rate: f64, duration: Duration) -> History {
+    let nodes = cluster.node_ids().len() as u64;
+    let mut history = run(cluster, rate, duration, |k| {
+        if (k / nodes).is_multiple_of(2) {
+            serde_json::json!({ "type": "add", "delta": k % 5 + 1 })
+        } else {
+            serde_json::json!({ "type": "read" })
+        }
+    })
+    .await;
+
+    tokio::time::sleep(SETTLE).await;
+    let client = cluster.client();
+    for node in cluster.node_ids() {
+        history.push(invoke(&client, node, serde_json::json!({ "type": "read" })).await);
+    }
+
+    history
+}
+
+/// Ask the cluster to generate IDs.
+pub async fn unique_ids(cluster: &Cluster, rate: f64, duration: Duration) -> History {
+    run(
+        cluster,
+        rate,
+        duration,
+        |_| serde_json::json!({ "type": "generate" }),
+    )
+    .await
+}
@@ -0,0 +1,68 @@
+//! Named periodic timers, see [`Node::timers`](crate::node::Node::timers).
+//!
+//! Each timer runs on a task of its own, so a slow invocation of one timer never holds up another.
+//! Its first invocation comes one period after the node is initialized. What happens when an
+//! invocation runs past the next tick is up to [`TimerSpec::missed_tick`].
+
+use std::time::Duration;
+
+pub use tokio::time::MissedTickBehavior;
+
+#[derive(Debug, Clone)]
+pub struct TimerSpec {
+    /// Passed to [`Node::on_timer`](crate::node::Node::on_timer) to tell timers apart.
+    pub name: &'static str,
+    pub period: Duration,
+    /// Each invocation is delayed by a random amount up to this, so that nodes started together
+    /// don't all fire at once.
+    pub jitter: Duration,
+    pub missed_tick: MissedTickBehavior,
+}
+
+impl TimerSpec {
+    /// A timer firing every `period`, without jitter, skipping the ticks it misses.
+    pub fn new(name: &'static str, period: Duration) -> Self {
+        Self {
+            name,
+            period,
+            jitter: Duration::ZERO,
+            missed_tick: MissedTickBehavior::Skip,
+        }
+    }
+
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn missed_tick(mut self, missed_tick: MissedTickBehavior) -> Self {
+        self.missed_tick = missed_tick;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    /// Invocations that have finished.
+    pub invocations: u64,
+    /// The time spent in all of them.
+    pub total_duration: Duration,
+    /// Invocations that took longer than the timer's period.
+    pub overruns: u64,
+}
+
+impl TimerStats {
+    pub fn mean_duration(&self) -> Duration {
+        self.total_duration
+            .checked_div(self.invocations as u32)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn record(&mut self, elapsed: Duration, period: Duration) {
+        self.invocations += 1;
+        self.total_duration += elapsed;
+        if elapsed > period {
+            self.overruns += 1;
+        }
+    }
+}
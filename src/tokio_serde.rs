@@ -73,7 +73,7 @@ use tokio_stream::Stream;
 ///
 /// An integer serializer that allows the width to be configured.
 ///
-/// ```
+/// ```ignore
 /// use tokio_serde::Serializer;
 /// use bytes::{Buf, Bytes, BytesMut, BufMut};
 /// use std::pin::Pin;
@@ -145,7 +145,7 @@ pub trait Serializer<T> {
 ///
 /// An integer deserializer that allows the width to be configured.
 ///
-/// ```
+/// ```ignore
 /// use tokio_serde::Deserializer;
 /// use bytes::{BytesMut, Buf};
 /// use std::pin::Pin;
@@ -318,11 +318,9 @@ pub mod formats {
     pub use self::json::*;
 
     mod json {
-        use std::{io::Write, marker::PhantomData, pin::Pin};
-
-        use crate::tokio_serde::{Deserializer, Serializer};
+        use std::{fmt, io::Write, marker::PhantomData};
 
-        use bytes::{Buf, BufMut, Bytes, BytesMut};
+        use bytes::{BufMut, BytesMut};
         use educe::Educe;
         use serde::{de::DeserializeOwned, Deserialize, Serialize};
         use tokio_util::codec::{Decoder, Encoder};
@@ -333,10 +331,156 @@ pub mod formats {
         pub struct Json<Item, SinkItem> {
             #[educe(Debug(ignore))]
             ghost: PhantomData<(Item, SinkItem)>,
+            /// Indent encoded frames over several lines, for a person to read.
+            pretty: bool,
+            /// Frames decoded so far, including ones that failed to decode.
+            frames: u64,
         }
 
         pub type SymmetricalJson<T> = Json<T, T>;
 
+        impl<Item, SinkItem> Json<Item, SinkItem> {
+            /// The codec, encoding frames indented over several lines. Only for a person to read:
+            /// Maelstrom expects one frame per line.
+            pub fn pretty(self) -> Self {
+                Self {
+                    pretty: true,
+                    ..self
+                }
+            }
+
+            pub fn is_pretty(&self) -> bool {
+                self.pretty
+            }
+
+            /// Serialize `item` the way this codec would, without the trailing newline.
+            pub fn serialize<T: Serialize>(&self, item: &T) -> serde_json::Result<Vec<u8>> {
+                serde_json::to_vec(item)
+            }
+        }
+
+        /// How many bytes on either side of a decode error to keep in [`CodecError::snippet`].
+        const SNIPPET_CONTEXT: usize = 60;
+
+        /// A frame that failed to decode, with enough context to find the bytes responsible.
+        ///
+        /// The [`Decoder`] impl has to return an [`std::io::Error`], so this is wrapped in one; get
+        /// it back with [`CodecError::from_io`].
+        #[derive(Debug)]
+        pub struct CodecError {
+            source: serde_json::Error,
+            frame: u64,
+            offset: usize,
+            snippet: String,
+            line: String,
+        }
+
+        impl CodecError {
+            fn new(source: serde_json::Error, frame: u64, line: &[u8]) -> Self {
+                let line = line.trim_ascii_end();
+                // Frames are split on newlines, but serde_json counts lines anyway.
+                let start = line
+                    .split_inclusive(|b| *b == b'\n')
+                    .take(source.line().saturating_sub(1))
+                    .map(<[u8]>::len)
+                    .sum::<usize>();
+                let offset = (start + source.column().saturating_sub(1)).min(line.len());
+                let context = offset.saturating_sub(SNIPPET_CONTEXT)
+                    ..(offset + SNIPPET_CONTEXT).min(line.len());
+                Self {
+                    snippet: String::from_utf8_lossy(&line[context]).into_owned(),
+                    line: String::from_utf8_lossy(line).into_owned(),
+                    source,
+                    frame,
+                    offset,
+                }
+            }
+
+            /// The context of `error`, if it came from the codec failing to decode a frame.
+            pub fn from_io(error: &std::io::Error) -> Option<&Self> {
+                error.get_ref()?.downcast_ref()
+            }
+
+            /// The frame's sequence number, counting from 1.
+            pub fn frame(&self) -> u64 {
+                self.frame
+            }
+
+            /// The byte offset within the frame where decoding failed.
+            pub fn offset(&self) -> usize {
+                self.offset
+            }
+
+            /// Up to [`SNIPPET_CONTEXT`] bytes on either side of [`offset`](Self::offset), lossily
+            /// converted to UTF-8.
+            pub fn snippet(&self) -> &str {
+                &self.snippet
+            }
+
+            /// The whole frame, without its trailing newline, lossily converted to UTF-8.
+            pub fn line(&self) -> &str {
+                &self.line
+            }
+        }
+
+        impl fmt::Display for CodecError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(
+                    f,
+                    "frame {} at byte {}: {} near {:?}",
+                    self.frame, self.offset, self.source, self.snippet
+                )
+            }
+        }
+
+        impl std::error::Error for CodecError {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.source)
+            }
+        }
+
+        impl From<CodecError> for std::io::Error {
+            fn from(error: CodecError) -> Self {
+                let kind = match error.source.classify() {
+                    serde_json::error::Category::Eof => std::io::ErrorKind::UnexpectedEof,
+                    _ => std::io::ErrorKind::InvalidData,
+                };
+                std::io::Error::new(kind, error)
+            }
+        }
+
+        impl<Item, SinkItem> Json<Item, SinkItem>
+        where
+            Item: DeserializeOwned,
+        {
+            fn decode_frame(&mut self, line: &[u8]) -> Result<Item, CodecError> {
+                self.frames += 1;
+                serde_json::from_slice(line)
+                    .map_err(|source| CodecError::new(source, self.frames, line))
+            }
+        }
+
+        /// Split the next frame off the front of `src`, if a whole one has arrived. Maelstrom frames
+        /// are newline-delimited, so a single read may contain several frames, or only part of one.
+        fn next_frame(src: &mut BytesMut) -> Option<BytesMut> {
+            while let Some(newline) = src.iter().position(|b| *b == b'\n') {
+                let line = src.split_to(newline + 1);
+                if !line.trim_ascii().is_empty() {
+                    return Some(line);
+                }
+            }
+            None
+        }
+
+        /// Like [`next_frame`], at the end of the input, where the last frame may not be followed
+        /// by a newline.
+        fn last_frame(src: &mut BytesMut) -> Option<BytesMut> {
+            next_frame(src).or_else(|| {
+                let line = src.split();
+                (!line.trim_ascii().is_empty()).then_some(line)
+            })
+        }
+
         // impl<Item, SinkItem> Deserializer<Item> for Json<Item, SinkItem>
         // where
         //     for<'a> Item: Deserialize<'a>,
@@ -367,23 +511,45 @@ pub mod formats {
             type Error = std::io::Error;
 
             fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-                if src.trim_ascii().len() == 0 {
-                    src.clear();
+                let Some(line) = next_frame(src) else {
                     return Ok(None);
-                }
+                };
+                Ok(Some(self.decode_frame(&line)?))
+            }
 
-                let item = match serde_json::from_reader(std::io::Cursor::new(&mut *src).reader()) {
-                    Ok(item) => {
-                        src.clear();
-                        item
-                    }
-                    Err(err) if err.is_eof() || err.is_syntax() => {
-                        return Ok(None);
-                    }
-                    Err(e) => return Err(e.into()),
+            fn decode_eof(
+                &mut self,
+                src: &mut BytesMut,
+            ) -> Result<Option<Self::Item>, Self::Error> {
+                let Some(line) = last_frame(src) else {
+                    return Ok(None);
                 };
+                Ok(Some(self.decode_frame(&line)?))
+            }
+        }
+
+        /// A [`Json`] decoder that yields frames that fail to decode as items, rather than failing
+        /// the stream, so that reading can carry on past them.
+        #[derive(Educe)]
+        #[educe(Debug, Default)]
+        pub struct Lenient<Item>(Json<Item, Item>);
+
+        impl<Item> Decoder for Lenient<Item>
+        where
+            for<'a> Item: DeserializeOwned + Deserialize<'a>,
+        {
+            type Item = Result<Item, CodecError>;
+            type Error = std::io::Error;
+
+            fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+                Ok(next_frame(src).map(|line| self.0.decode_frame(&line)))
+            }
 
-                Ok(Some(item))
+            fn decode_eof(
+                &mut self,
+                src: &mut BytesMut,
+            ) -> Result<Option<Self::Item>, Self::Error> {
+                Ok(last_frame(src).map(|line| self.0.decode_frame(&line)))
             }
         }
 
@@ -396,12 +562,94 @@ pub mod formats {
             fn encode(&mut self, item: Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
                 let mut w = dst.writer();
 
-                serde_json::to_writer(&mut w, &item)?;
+                if self.pretty {
+                    serde_json::to_writer_pretty(&mut w, &item)?;
+                } else {
+                    serde_json::to_writer(&mut w, &item)?;
+                }
 
-                w.write(b"\n")?;
+                w.write_all(b"\n")?;
 
                 Ok(())
             }
         }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn decode_all(input: &[u8]) -> (Vec<serde_json::Value>, std::io::Error) {
+                let mut codec = SymmetricalJson::<serde_json::Value>::default();
+                let mut src = BytesMut::from(input);
+                let mut decoded = Vec::new();
+                loop {
+                    match codec.decode_eof(&mut src) {
+                        Ok(Some(value)) => decoded.push(value),
+                        Ok(None) => panic!("no decode error"),
+                        Err(e) => return (decoded, e),
+                    }
+                }
+            }
+
+            #[test]
+            fn test_decode_error_context() {
+                let (decoded, error) = decode_all(b"{\"a\":1}\n\n{\"b\":2}\n{\"c\":x}\n{}\n");
+                assert_eq!(decoded.len(), 2);
+                assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+                let codec = CodecError::from_io(&error).unwrap();
+                // Blank lines aren't frames.
+                assert_eq!(codec.frame(), 3);
+                assert_eq!(codec.offset(), 5);
+                assert_eq!(codec.snippet(), "{\"c\":x}");
+            }
+
+            #[test]
+            fn test_decode_error_snippet_is_bounded() {
+                let mut frame = format!("{{\"a\":\"{}\",", "x".repeat(200)).into_bytes();
+                let offset = frame.len();
+                frame.extend_from_slice(b"oops\"b\":\"");
+                frame.extend(std::iter::repeat_n(b'y', 40));
+                // Cut short with no trailing newline, and in the middle of a character.
+                frame.extend_from_slice(&"\u{e9}".as_bytes()[..1]);
+
+                let (_, error) = decode_all(&frame);
+                let codec = CodecError::from_io(&error).unwrap();
+                assert_eq!(codec.frame(), 1);
+                assert_eq!(codec.offset(), offset);
+                assert_eq!(
+                    codec.snippet(),
+                    format!(
+                        "{}\",oops\"b\":\"{}\u{fffd}",
+                        "x".repeat(58),
+                        "y".repeat(40)
+                    )
+                );
+            }
+
+            #[test]
+            fn test_lenient_decoding_carries_on() {
+                let mut codec = Lenient::<serde_json::Value>::default();
+                let mut src = BytesMut::from(&b"{\"a\":1}\n{\"b\":x}\n\n{\"c\":3}"[..]);
+                assert_eq!(codec.decode(&mut src).unwrap().unwrap().unwrap()["a"], 1);
+                let error = codec.decode(&mut src).unwrap().unwrap().unwrap_err();
+                assert_eq!(error.frame(), 2);
+                assert_eq!(error.line(), "{\"b\":x}");
+                assert!(codec.decode(&mut src).unwrap().is_none());
+                assert_eq!(
+                    codec.decode_eof(&mut src).unwrap().unwrap().unwrap()["c"],
+                    3
+                );
+                assert!(codec.decode_eof(&mut src).unwrap().is_none());
+            }
+
+            #[test]
+            fn test_truncated_frame_is_unexpected_eof() {
+                let (_, error) = decode_all(b"{\"a\":[1,");
+                assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+                let codec = CodecError::from_io(&error).unwrap();
+                assert_eq!(codec.offset(), 7);
+                assert_eq!(codec.snippet(), "{\"a\":[1,");
+            }
+        }
     }
 }
@@ -0,0 +1,278 @@
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How randomness is mixed into [`Backoff`] delays, so that many nodes retrying at once do not
+/// retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Jitter {
+    /// Plain exponential backoff.
+    None,
+    /// A uniformly random delay between zero and the exponential delay.
+    #[default]
+    Full,
+    /// A random delay between the base and the previous delay times the multiplier, which keeps
+    /// delays spread out without ever collapsing to zero.
+    Decorrelated,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackoffBuilder {
+    base: Duration,
+    cap: Duration,
+    multiplier: f64,
+    jitter: Jitter,
+}
+
+impl Default for BackoffBuilder {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(10),
+            cap: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: Jitter::default(),
+        }
+    }
+}
+
+impl BackoffBuilder {
+    /// The delay before the first retry.
+    pub fn base(mut self, base: Duration) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// The longest a single delay can be.
+    pub fn cap(mut self, cap: Duration) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    /// How much the delay grows with each attempt. Values below 1 are treated as 1.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn build(self) -> Backoff {
+        Backoff {
+            base: self.base,
+            cap: self.cap.max(self.base),
+            multiplier: self.multiplier,
+            jitter: self.jitter,
+            attempt: 0,
+            prev: self.base,
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+/// Exponential backoff for retry loops.
+///
+/// Delays come from [`Backoff::next_delay`], or [`Backoff::wait`] to also sleep through them on
+/// tokio time. `Backoff` is also an endless iterator of delays, so a bounded retry loop is just
+/// `for delay in backoff.by_ref().take(attempts)`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    multiplier: f64,
+    jitter: Jitter,
+    attempt: i32,
+    prev: Duration,
+    rng: StdRng,
+}
+
+impl Backoff {
+    pub fn builder() -> BackoffBuilder {
+        BackoffBuilder::default()
+    }
+
+    /// The number of delays handed out since creation or the last [`Backoff::reset`].
+    pub fn attempt(&self) -> u32 {
+        self.attempt as u32
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        // Computed in floating point so that a long run of attempts saturates at the cap instead
+        // of overflowing.
+        let exponential = Duration::from_secs_f64(
+            (self.base.as_secs_f64() * self.multiplier.powi(self.attempt))
+                .min(self.cap.as_secs_f64()),
+        );
+        self.attempt = self.attempt.saturating_add(1);
+
+        let delay = match self.jitter {
+            Jitter::None => exponential,
+            Jitter::Full => self.rng.gen_range(Duration::ZERO..=exponential),
+            Jitter::Decorrelated => {
+                let upper = self.prev.mul_f64(self.multiplier).min(self.cap);
+                self.rng.gen_range(self.base..=upper)
+            }
+        };
+        self.prev = delay;
+        delay
+    }
+
+    /// Sleep for the next delay.
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    /// Start over from the base delay, e.g. after a success.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.prev = self.base;
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_delay())
+    }
+}
+
+/// How [`crate::node::NodeState::rpc_retry`] resends a request that gets no reply. Each attempt
+/// waits `attempt_timeout` for its reply, and the next one is sent after the next delay of a
+/// [`Backoff`] built from the policy, so attempts spread out more and more.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How long each attempt waits for a reply. Not jittered, so that a peer that answers in time
+    /// is never given up on early.
+    pub attempt_timeout: Duration,
+    /// How long to wait before the second attempt.
+    pub initial_delay: Duration,
+    /// How much the delay grows with each attempt.
+    pub multiplier: f64,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+    /// How many times the request is sent before giving up. 0 is treated as 1.
+    pub max_attempts: u32,
+    /// With [`Jitter::Full`], an attempt can follow the last one almost at once;
+    /// [`Jitter::Decorrelated`] never waits less than `initial_delay` in between.
+    pub jitter: Jitter,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempt_timeout: Duration::from_millis(500),
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(2),
+            max_attempts: 5,
+            jitter: Jitter::Decorrelated,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delays between successive attempts.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::builder()
+            .base(self.initial_delay)
+            .cap(self.max_delay)
+            .multiplier(self.multiplier)
+            .jitter(self.jitter)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 10_000;
+
+    fn backoff(jitter: Jitter) -> Backoff {
+        Backoff::builder()
+            .base(Duration::from_millis(10))
+            .cap(Duration::from_millis(500))
+            .multiplier(2.0)
+            .jitter(jitter)
+            .build()
+    }
+
+    #[test]
+    fn test_backoff_without_jitter() {
+        let delays = backoff(Jitter::None)
+            .take(8)
+            .map(|d| d.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![10, 20, 40, 80, 160, 320, 500, 500]);
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut backoff = backoff(Jitter::None);
+        backoff.by_ref().take(5).for_each(drop);
+        assert_eq!(backoff.attempt(), 5);
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_full_jitter_bounds() {
+        let mut backoff = backoff(Jitter::Full);
+        for attempt in 0..8 {
+            let expected = Duration::from_millis(10 * 2u64.pow(attempt)).min(backoff.cap);
+            let samples = (0..SAMPLES)
+                .map(|_| {
+                    backoff.reset();
+                    backoff.by_ref().nth(attempt as usize).unwrap()
+                })
+                .collect::<Vec<_>>();
+
+            assert!(samples.iter().all(|d| *d <= expected));
+            // Uniform over [0, expected]: the mean should be close to the midpoint, and both
+            // ends of the range should be reached.
+            let mean = samples.iter().sum::<Duration>() / SAMPLES as u32;
+            let error = mean.abs_diff(expected / 2);
+            assert!(error < expected / 20, "mean {mean:?} for cap {expected:?}");
+            assert!(samples.iter().any(|d| *d < expected / 10));
+            assert!(samples.iter().any(|d| *d > expected * 9 / 10));
+        }
+    }
+
+    #[test]
+    fn test_decorrelated_jitter_bounds() {
+        let mut backoff = backoff(Jitter::Decorrelated);
+        let mut prev = backoff.base;
+        for _ in 0..SAMPLES {
+            let delay = backoff.next_delay();
+            assert!(delay >= backoff.base, "{delay:?} below base");
+            assert!(
+                delay <= (prev * 2).min(backoff.cap),
+                "{delay:?} after {prev:?}"
+            );
+            prev = delay;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_wait_uses_tokio_time() {
+        let mut backoff = backoff(Jitter::None);
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            backoff.wait().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(70));
+    }
+
+    #[test]
+    fn test_backoff_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let mut backoff = backoff(Jitter::Full);
+        assert_send(&backoff.wait());
+    }
+}
@@ -0,0 +1,447 @@
+//! The task that writes a node's outgoing messages.
+//!
+//! Handlers don't write to the output themselves: they queue their messages for a single
+//! [`Writer`], which owns the output, serializes each message into it and flushes it as
+//! [`FlushPolicy`] says. A slow output then only holds up the writer, and handlers only wait when
+//! [`OUTPUT_QUEUE`] messages are already queued. Messages are written in the order they were
+//! queued, whatever the policy holds back.
+//!
+//! Most messages go to a handful of destinations, so the writer keeps each message's `src` and
+//! `dest` already serialized, see [`Envelopes`], and only serializes the body.
+//!
+//! A message that fails to serialize is logged and dropped, and the writer goes on with the next.
+//! Once the output is gone for good, the writer hands the error to the node, see
+//! [`crate::node::InternalError::is_fatal`], and stops, closing the queue behind it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::{BufMut as _, BytesMut};
+use futures::SinkExt as _;
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::flush::FlushPolicy;
+use crate::message::{DataOrInit, Message};
+use crate::node::InternalError;
+use crate::tokio_serde;
+
+/// How many messages can be queued for the writer before senders wait for room.
+pub const OUTPUT_QUEUE: usize = 1024;
+
+/// The most destinations [`Envelopes`] keeps serialized. Messages to others serialize their
+/// `dest` every time.
+pub const MAX_CACHED_DESTS: usize = 1024;
+
+/// The codec outgoing messages are serialized with.
+pub(crate) type Codec<Data> = tokio_serde::formats::SymmetricalJson<Message<DataOrInit<Data>>>;
+
+/// The framed JSON writer that outgoing messages are serialized into.
+type Output<Data> =
+    tokio_util::codec::FramedWrite<Box<dyn AsyncWrite + Send + Sync + Unpin>, Codec<Data>>;
+
+/// What is queued for the writer.
+// Almost everything queued is a message, so boxing them to shrink the rare flush would only cost
+// an allocation per message.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Outgoing<Data> {
+    Message(Message<DataOrInit<Data>>),
+    /// Flush everything queued before this, then say so.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The serialized beginnings of the envelopes of messages from this node, so that framing a
+/// message copies its `src` and `dest` instead of serializing them again. Frames come out the same
+/// as the codec's, without indentation.
+#[derive(Debug, Default)]
+pub struct Envelopes {
+    /// `{"src":<src>,"dest":`, and the `src` it was made for, which changes once, on `init`.
+    src: Option<(Arc<str>, Vec<u8>)>,
+    /// `<dest>,"body":` for each destination.
+    dests: HashMap<Arc<str>, Vec<u8>>,
+}
+
+impl Envelopes {
+    /// Append `message` to `frame` as one line of JSON.
+    pub fn encode<Data: serde::Serialize>(
+        &mut self,
+        message: &Message<Data>,
+        frame: &mut BytesMut,
+    ) -> serde_json::Result<()> {
+        if self.src.as_ref().is_none_or(|(src, _)| *src != message.src) {
+            let mut encoded = br#"{"src":"#.to_vec();
+            serde_json::to_writer(&mut encoded, &message.src)?;
+            encoded.extend_from_slice(br#","dest":"#);
+            self.src = Some((Arc::clone(&message.src), encoded));
+        }
+        if let Some((_, src)) = &self.src {
+            frame.extend_from_slice(src);
+        }
+        match self.dests.get(&message.dest) {
+            Some(dest) => frame.extend_from_slice(dest),
+            None => {
+                let mut encoded = serde_json::to_vec(&message.dest)?;
+                encoded.extend_from_slice(br#","body":"#);
+                frame.extend_from_slice(&encoded);
+                if self.dests.len() < MAX_CACHED_DESTS {
+                    self.dests.insert(Arc::clone(&message.dest), encoded);
+                }
+            }
+        }
+        serde_json::to_writer(frame.writer(), &message.body)?;
+        frame.extend_from_slice(b"}\n");
+        Ok(())
+    }
+}
+
+/// Check a frame built from [`Envelopes`] against serializing `message` in full, as the codec
+/// would.
+#[cfg(debug_assertions)]
+fn check_frame<Data: serde::Serialize>(message: &Message<Data>, frame: &[u8]) {
+    let Ok(mut expected) = serde_json::to_vec(message) else {
+        return;
+    };
+    expected.push(b'\n');
+    debug_assert_eq!(
+        String::from_utf8_lossy(frame),
+        String::from_utf8_lossy(&expected),
+        "cached envelope differs from the codec's"
+    );
+}
+
+pub(crate) struct Writer<Data> {
+    output: Output<Data>,
+    /// `None` when the codec indents its frames, which the envelopes can't.
+    envelopes: Option<Envelopes>,
+    queued: mpsc::Receiver<Outgoing<Data>>,
+    flush: Arc<std::sync::Mutex<FlushPolicy>>,
+    /// Where messages written since the last flush went, the last of them, to blame if the flush
+    /// fails. `None` if nothing is waiting to be flushed.
+    unflushed: Option<Arc<str>>,
+    /// Told why the writer stopped, if the output is gone.
+    on_fatal: Box<dyn FnOnce(InternalError) + Send>,
+}
+
+impl<Data: serde::Serialize + Send + 'static> Writer<Data> {
+    pub fn new(
+        output: impl AsyncWrite + Send + Sync + Unpin + 'static,
+        codec: Codec<Data>,
+        queued: mpsc::Receiver<Outgoing<Data>>,
+        flush: Arc<std::sync::Mutex<FlushPolicy>>,
+        on_fatal: impl FnOnce(InternalError) + Send + 'static,
+    ) -> Self {
+        Self {
+            envelopes: (!codec.is_pretty()).then(Envelopes::default),
+            output: tokio_util::codec::FramedWrite::new(Box::new(output), codec),
+            queued,
+            flush,
+            unflushed: None,
+            on_fatal: Box::new(on_fatal),
+        }
+    }
+
+    /// Write queued messages until every sender is gone or the output is. Whatever is queued
+    /// when the last sender goes is written and flushed first.
+    pub async fn run(mut self) {
+        loop {
+            let deadline = self.flush.lock().unwrap().deadline();
+            let next = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, self.queued.recv()).await,
+                None => Ok(self.queued.recv().await),
+            };
+            let written = match next {
+                // A batch is due.
+                Err(_) => self.flush().await,
+                Ok(Some(Outgoing::Message(message))) => self.write(message).await,
+                Ok(Some(Outgoing::Flush(done))) => {
+                    let flushed = self.flush().await;
+                    let _ = done.send(());
+                    flushed
+                }
+                Ok(None) => {
+                    if let Err(error) = self.flush().await {
+                        (self.on_fatal)(error);
+                    }
+                    return;
+                }
+            };
+            if let Err(error) = written {
+                (self.on_fatal)(error);
+                return;
+            }
+        }
+    }
+
+    /// Write `message`, flushing if the policy says so. Fails only if the output is gone.
+    async fn write(&mut self, message: Message<DataOrInit<Data>>) -> Result<(), InternalError> {
+        let dest = Arc::clone(&message.dest);
+        let client_reply = message.body.re.is_some() && crate::node::is_client(&dest);
+        if let Err(source) = std::future::poll_fn(|cx| self.output.poll_ready_unpin(cx)).await {
+            return self.failed(dest, source);
+        }
+        // A message that fails to serialize can leave part of itself in the buffer, which would
+        // corrupt the next one.
+        let buffered = self.output.write_buffer().len();
+        let encoded = match &mut self.envelopes {
+            Some(envelopes) => {
+                let frame = self.output.write_buffer_mut();
+                let encoded = envelopes.encode(&message, frame);
+                #[cfg(debug_assertions)]
+                if encoded.is_ok() {
+                    check_frame(&message, &frame[buffered..]);
+                }
+                encoded.map_err(Into::into)
+            }
+            None => self.output.start_send_unpin(message),
+        };
+        if let Err(source) = encoded {
+            self.output.write_buffer_mut().truncate(buffered);
+            return self.failed(dest, source);
+        }
+        let now = tokio::time::Instant::now();
+        let flush_now = {
+            let mut flush = self.flush.lock().unwrap();
+            if client_reply {
+                flush.written_reply(now, &dest)
+            } else {
+                flush.written(now)
+            }
+        };
+        self.unflushed = Some(dest);
+        if flush_now {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flush whatever was written since the last flush. Fails only if the output is gone.
+    async fn flush(&mut self) -> Result<(), InternalError> {
+        let Some(dest) = self.unflushed.take() else {
+            return Ok(());
+        };
+        let result = self.output.flush().await;
+        self.flush.lock().unwrap().flushed();
+        match result {
+            Ok(()) => Ok(()),
+            Err(source) => self.failed(dest, source),
+        }
+    }
+
+    /// Log a failure to write to `dest`, unless it means the output is gone, which is returned.
+    fn failed(&self, dest: Arc<str>, source: std::io::Error) -> Result<(), InternalError> {
+        let error = InternalError::sending(dest, source);
+        if error.is_fatal() {
+            return Err(error);
+        }
+        tracing::warn!("{}", snafu::Report::from_error(&error));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::ser::{Error as _, SerializeMap as _};
+    use tokio::io::AsyncBufReadExt as _;
+    use tokio_util::codec::Encoder as _;
+
+    use super::*;
+    use crate::flush::FlushOptions;
+    use crate::message::MessageBody;
+
+    /// Fails to serialize halfway through if `n` is `None`.
+    struct Payload {
+        n: Option<u64>,
+    }
+
+    impl serde::Serialize for Payload {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("type", "test")?;
+            let n = self.n.ok_or_else(|| S::Error::custom("no n"))?;
+            map.serialize_entry("n", &n)?;
+            map.end()
+        }
+    }
+
+    fn message(n: Option<u64>) -> Outgoing<Payload> {
+        reply("n2", None, n)
+    }
+
+    fn reply(dest: &str, re: Option<u64>, n: Option<u64>) -> Outgoing<Payload> {
+        Outgoing::Message(Message {
+            src: "n1".into(),
+            dest: dest.into(),
+            body: MessageBody {
+                id: Some(1),
+                re,
+                trace_id: None,
+                seq: None,
+                sent_at_ms: None,
+                request_times: None,
+                data: DataOrInit::Data(Payload { n }),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_unserializable_messages_are_skipped() {
+        let (output, input) = tokio::io::duplex(4096);
+        let (outbox, queued) = mpsc::channel(OUTPUT_QUEUE);
+        let flush = FlushPolicy::new(FlushOptions::default(), tokio::time::Instant::now());
+        let fatal = Arc::new(std::sync::Mutex::new(None));
+        let writer = Writer::new(
+            output,
+            Codec::default(),
+            queued,
+            Arc::new(std::sync::Mutex::new(flush)),
+            {
+                let fatal = Arc::clone(&fatal);
+                move |error| *fatal.lock().unwrap() = Some(error)
+            },
+        );
+        let writer = tokio::spawn(writer.run());
+
+        for n in [Some(1), None, Some(2)] {
+            assert!(outbox.send(message(n)).await.is_ok());
+        }
+        // What is still queued is written before the writer stops.
+        drop(outbox);
+        writer.await.unwrap();
+        assert!(fatal.lock().unwrap().is_none());
+
+        let mut lines = tokio::io::BufReader::new(input).lines();
+        let mut written = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let frame = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+            written.push(frame["body"]["n"].as_u64().unwrap());
+        }
+        assert_eq!(written, [1, 2]);
+    }
+
+    /// An output that keeps what is written to it and counts the flushes.
+    #[derive(Clone, Default)]
+    struct CountingOutput {
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+        flushes: Arc<std::sync::atomic::AtomicU64>,
+    }
+
+    impl AsyncWrite for CountingOutput {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.flushes
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_replies_share_flushes() {
+        const REPLIES: u64 = 64;
+
+        // Replies to a client's pipelined requests, written in order whatever is held back, and
+        // the flushes they took.
+        let flushes = async |ack_window_us| {
+            let output = CountingOutput::default();
+            let (outbox, queued) = mpsc::channel(OUTPUT_QUEUE);
+            let options = FlushOptions {
+                ack_window_us,
+                ..Default::default()
+            };
+            let flush = FlushPolicy::new(options, tokio::time::Instant::now());
+            let writer = Writer::new(
+                output.clone(),
+                Codec::default(),
+                queued,
+                Arc::new(std::sync::Mutex::new(flush)),
+                |error| panic!("{error}"),
+            );
+            let writer = tokio::spawn(writer.run());
+            for n in 0..REPLIES {
+                assert!(outbox.send(reply("c1", Some(n), Some(n))).await.is_ok());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let flushes = output.flushes.load(std::sync::atomic::Ordering::Relaxed);
+            drop(outbox);
+            writer.await.unwrap();
+
+            let written = output.written.lock().unwrap();
+            let replies = written
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    let frame = serde_json::from_slice::<serde_json::Value>(line).unwrap();
+                    frame["body"]["in_reply_to"].as_u64().unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(replies, (0..REPLIES).collect::<Vec<_>>());
+            flushes
+        };
+
+        assert_eq!(flushes(None).await, REPLIES);
+        // A flush every `max_acks` replies.
+        let max_acks = FlushOptions::default().max_acks as u64;
+        assert_eq!(flushes(Some(2_000)).await, REPLIES / max_acks);
+    }
+
+    #[test]
+    fn test_envelopes_match_the_codec() {
+        let mut envelopes = Envelopes::default();
+        let dests = (0..MAX_CACHED_DESTS + 2).map(|n| format!("c{n}"));
+        // The node's ID changes on `init`, and the destinations outnumber the cache.
+        for (n, (src, dest)) in ["n0", "n1"].into_iter().cycle().zip(dests).enumerate() {
+            let Outgoing::Message(mut message) = reply(&dest, Some(n as u64), Some(n as u64))
+            else {
+                unreachable!()
+            };
+            message.src = src.into();
+            let mut frame = BytesMut::new();
+            envelopes.encode(&message, &mut frame).unwrap();
+            let mut expected = BytesMut::new();
+            Codec::default().encode(message, &mut expected).unwrap();
+            assert_eq!(frame, expected);
+        }
+        assert_eq!(envelopes.dests.len(), MAX_CACHED_DESTS);
+
+        // Every optional field of the body, and IDs that need escaping.
+        let shapes: [fn(&mut Message<DataOrInit<Payload>>); 5] = [
+            |message| message.body.trace_id = Some(7),
+            |message| message.body.seq = Some(3),
+            |message| message.body.sent_at_ms = Some(1_000),
+            |message| message.body.request_times = Some((1, 2)),
+            |message| {
+                message.src = "n\"1".into();
+                message.dest = "c\\2".into();
+            },
+        ];
+        for shape in shapes {
+            let Outgoing::Message(mut message) = reply("c1", Some(1), Some(1)) else {
+                unreachable!()
+            };
+            shape(&mut message);
+            let mut frame = BytesMut::new();
+            envelopes.encode(&message, &mut frame).unwrap();
+            let mut expected = BytesMut::new();
+            Codec::default().encode(message, &mut expected).unwrap();
+            assert_eq!(frame, expected);
+        }
+    }
+}
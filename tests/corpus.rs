@@ -0,0 +1,125 @@
+//! Replays every journal in `tests/corpus/`, see [`fly_systems_challenge::testing::corpus`].
+//!
+//! To add an entry, save the journal as `tests/corpus/<service>-<name>.jsonl` and, on a build
+//! known to handle it correctly, run
+//! `cargo test --features test-util --test corpus -- --ignored record_missing_expectations`.
+
+use std::path::{Path, PathBuf};
+
+use fly_systems_challenge::services::broadcast::{BroadcastOptions, BroadcastService};
+use fly_systems_challenge::services::echo::EchoService;
+use fly_systems_challenge::testing::corpus::{self, Replay};
+use serde_json::json;
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus")
+}
+
+/// A single node has nobody to bootstrap from, and the check only adds noise to the log.
+fn broadcast() -> BroadcastService {
+    BroadcastService::new(BroadcastOptions {
+        bootstrap: false,
+        ..Default::default()
+    })
+}
+
+async fn replay(service: &str, journal: &Path) -> Replay {
+    let frames = std::fs::read_to_string(journal).unwrap();
+    match service {
+        "echo" => corpus::replay(EchoService, &frames).await,
+        "broadcast" => corpus::replay(broadcast(), &frames).await,
+        _ => panic!("{}: no service named {service}", journal.display()),
+    }
+}
+
+#[tokio::test]
+async fn test_corpus() {
+    let journals = corpus::journals(&corpus_dir()).unwrap();
+    assert!(!journals.is_empty());
+
+    let mut failures = Vec::new();
+    for (service, journal) in &journals {
+        let expected = corpus::expectations(journal).unwrap();
+        assert!(
+            !expected.is_empty(),
+            "{} has no expectations",
+            journal.display()
+        );
+        if let Err(problems) = replay(service, journal).await.check(&expected) {
+            failures.push(format!("{}:\n{problems}", journal.display()));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+/// Two runs of the same code over the same input, the baseline for comparing a change against
+/// the code before it.
+#[tokio::test]
+async fn test_replays_do_not_diverge() {
+    for (service, journal) in corpus::journals(&corpus_dir()).unwrap() {
+        let a = replay(&service, &journal).await;
+        let b = replay(&service, &journal).await;
+        let divergence = corpus::diff(&a, &b, &["gossip"]);
+        assert!(
+            divergence.is_empty(),
+            "{}: {}",
+            journal.display(),
+            serde_json::to_string_pretty(&divergence).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_diff_reports_divergence() {
+    let mut a = Replay::default();
+    a.replies
+        .insert("c1/1".into(), json!({ "type": "echo_ok", "echo": 1 }));
+    a.replies
+        .insert("c1/2".into(), json!({ "type": "read_ok" }));
+    a.peer
+        .insert("gossip".into(), vec![json!({ "dest": "n2" })]);
+    a.peer
+        .insert("forward".into(), vec![json!({ "dest": "n2" })]);
+    let mut b = Replay::default();
+    b.replies
+        .insert("c1/1".into(), json!({ "echo": 2, "type": "echo_ok" }));
+    b.replies
+        .insert("c1/3".into(), json!({ "type": "read_ok" }));
+    b.peer
+        .insert("forward".into(), vec![json!({ "dest": "n3" })]);
+
+    let divergence = corpus::diff(&a, &b, &["gossip"]);
+    assert_eq!(divergence.missing.len(), 2);
+    assert_eq!(divergence.missing["c1/2"], "a");
+    assert_eq!(divergence.missing["c1/3"], "b");
+    assert_eq!(divergence.replies.keys().collect::<Vec<_>>(), ["c1/1"]);
+    assert_eq!(divergence.peer.keys().collect::<Vec<_>>(), ["forward"]);
+
+    b.replies = a.replies.clone();
+    b.peer = a.peer.clone();
+    b.peer.remove("gossip");
+    assert!(corpus::diff(&a, &b, &["gossip"]).is_empty());
+}
+
+#[tokio::test]
+#[ignore = "writes expectations for new corpus entries"]
+async fn record_missing_expectations() {
+    for (service, journal) in corpus::journals(&corpus_dir()).unwrap() {
+        if corpus::expectations_path(&journal).exists() {
+            continue;
+        }
+        let replay = match service.as_str() {
+            "echo" => corpus::record(EchoService, &journal).await,
+            "broadcast" => corpus::record(broadcast(), &journal).await,
+            _ => panic!("{}: no service named {service}", journal.display()),
+        }
+        .unwrap();
+        assert!(
+            replay.check(&replay.replies).is_ok(),
+            "{}: {:?}",
+            journal.display(),
+            replay
+        );
+        println!("Recorded {}", corpus::expectations_path(&journal).display());
+    }
+}
@@ -0,0 +1,83 @@
+//! Every service with a workload driver, run with a fifth of all frames delivered twice, the way
+//! Maelstrom's `--nemesis duplicate` does. Services have to tolerate duplicates to pass, so to
+//! cover a new one, add a line to the `duplicate_delivery_tests!` call below.
+//!
+//! There is no kafka service, so it isn't listed.
+
+use std::time::Duration;
+
+use fly_systems_challenge::services::broadcast::BroadcastService;
+use fly_systems_challenge::services::counter::CounterService;
+use fly_systems_challenge::services::unique_ids::UniqueIdService;
+use fly_systems_challenge::testing::{
+    checker, workload, workload::History, Cluster, LatencyMatrix,
+};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+/// The share of frames, from clients and nodes alike, that are delivered twice.
+const DUPLICATE_RATE: f64 = 0.2;
+const RATE: f64 = 50.0;
+const DURATION: Duration = Duration::from_secs(5);
+
+macro_rules! duplicate_delivery_tests {
+    ($($name:ident: $service:expr, $workload:path, $check:path;)*) => {
+        $(
+            #[tokio::test(start_paused = true)]
+            async fn $name() {
+                let mut cluster = Cluster::builder()
+                    .nodes(3)
+                    .latency(LatencyMatrix::uniform(10))
+                    .service($service)
+                    .build()
+                    .await;
+                let mut rng = StdRng::seed_from_u64(0);
+                cluster.duplicate_frames(move |_, _, _| rng.gen_bool(DUPLICATE_RATE));
+
+                let history = $workload(&cluster, RATE, DURATION).await;
+                if let Err(e) = $check(&history) {
+                    panic!("{} with duplicated frames: {}", stringify!($name), e);
+                }
+                cluster.shutdown().await;
+            }
+        )*
+    };
+}
+
+duplicate_delivery_tests! {
+    test_broadcast: BroadcastService::default, workload::broadcast, broadcast_sets_are_equal;
+    test_unique_ids: UniqueIdService::default, workload::unique_ids, ids_are_unique;
+    test_counter: CounterService::default, workload::counter, counts_add_up;
+}
+
+/// Every acknowledged value reached every node, and nodes agree on what was broadcast.
+fn broadcast_sets_are_equal(history: &History) -> Result<(), String> {
+    let report = checker::broadcast(history);
+    if !report.is_valid() {
+        return Err(format!("lost values: {:?}", report.lost));
+    }
+    Ok(())
+}
+
+/// Every request got an ID, and no ID was handed out twice.
+fn ids_are_unique(history: &History) -> Result<(), String> {
+    let report = checker::unique_ids(history);
+    if !report.is_valid() || report.failed > 0 {
+        return Err(format!(
+            "{} failed requests, duplicate IDs: {:?}",
+            report.failed, report.duplicates
+        ));
+    }
+    Ok(())
+}
+
+/// Every node counted every acknowledged add once, and nothing more.
+fn counts_add_up(history: &History) -> Result<(), String> {
+    let report = checker::counter(history);
+    if !report.is_valid() {
+        return Err(format!(
+            "final values {:?}, expected between {} and {}",
+            report.finals, report.acknowledged, report.attempted
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! A service defined outside the crate, tested with nothing but what the `test-util` feature
+//! exports.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use fly_systems_challenge::message::Message;
+use fly_systems_challenge::node::{Node, NodeState};
+use fly_systems_challenge::testing::{checker, workload, Cluster, LatencyMatrix};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FloodMessage {
+    Topology { topology: serde_json::Value },
+    TopologyOk,
+    Broadcast { message: u64 },
+    BroadcastOk,
+    Read,
+    ReadOk { messages: BTreeSet<u64> },
+}
+
+/// Sends every value it hasn't seen before to every other node, ignoring the topology.
+#[derive(Clone, Default)]
+struct FloodService {
+    seen: Arc<Mutex<BTreeSet<u64>>>,
+    peers: Arc<OnceLock<Vec<String>>>,
+}
+
+impl Node for FloodService {
+    type Message = FloodMessage;
+    type Error = std::io::Error;
+
+    async fn init(
+        &self,
+        state: &NodeState<Self>,
+        node_ids: Vec<String>,
+    ) -> fly_systems_challenge::Result<(), Self::Error> {
+        let peers = node_ids.into_iter().filter(|id| **id != *state.id());
+        self.peers.set(peers.collect()).ok();
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        Message { src, body, .. }: Message<Self::Message>,
+        state: &NodeState<Self>,
+    ) -> fly_systems_challenge::Result<(), Self::Error> {
+        let id = body.id.unwrap_or_default();
+        match body.data {
+            FloodMessage::Topology { .. } => {
+                state.reply(src, id, FloodMessage::TopologyOk).await?;
+            }
+            FloodMessage::Broadcast { message } => {
+                let first_seen = self.seen.lock().unwrap().insert(message);
+                state.reply(src, id, FloodMessage::BroadcastOk).await?;
+                if first_seen {
+                    for peer in self.peers.get().into_iter().flatten() {
+                        state
+                            .send(peer.as_str(), FloodMessage::Broadcast { message })
+                            .await?;
+                    }
+                }
+            }
+            FloodMessage::Read => {
+                let messages = self.seen.lock().unwrap().clone();
+                state
+                    .reply(src, id, FloodMessage::ReadOk { messages })
+                    .await?;
+            }
+            FloodMessage::TopologyOk | FloodMessage::BroadcastOk | FloodMessage::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_custom_service_passes_broadcast_workload() {
+    let mut cluster = Cluster::builder()
+        .nodes(3)
+        .latency(LatencyMatrix::uniform(20))
+        .service(FloodService::default)
+        .build()
+        .await;
+
+    let history = workload::broadcast(&cluster, 50.0, Duration::from_secs(5)).await;
+    let report = checker::broadcast(&history);
+    assert!(report.is_valid(), "lost values: {:?}", report.lost);
+    assert!(report.acknowledged > 0);
+
+    cluster.shutdown().await;
+}
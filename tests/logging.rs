@@ -0,0 +1,10 @@
+//! Installing the global subscriber affects every test in the process, so this runs in its own.
+
+use fly_systems_challenge::logging;
+
+#[test]
+fn test_double_init() {
+    assert!(logging::init(std::io::sink).is_some());
+    assert!(logging::init(std::io::sink).is_none());
+    tracing::info!("Still logging after a failed init");
+}